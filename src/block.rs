@@ -0,0 +1,330 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    decode::{Decoded, Op},
+    memory::Memory,
+};
+
+/// Most instructions in a block, so translating one doesn't run far ahead of
+/// the program
+const MAX_LEN: usize = 64;
+
+/// Whether a block has to end with `op`: it can jump, trap, or change what
+/// the next instructions do
+fn ends_block(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Beq
+            | Op::Bne
+            | Op::Blt
+            | Op::Bge
+            | Op::Bltu
+            | Op::Bgeu
+            | Op::Jal
+            | Op::Jalr
+            | Op::Ecall
+            | Op::Ebreak
+            | Op::Mret
+            | Op::Wfi
+            | Op::Fence
+            | Op::Csrrw
+            | Op::Csrrs
+            | Op::Csrrc
+            | Op::Csrrwi
+            | Op::Csrrsi
+            | Op::Csrrci
+            | Op::Unknown
+    )
+}
+
+/// An instruction of a block, or a common pair of them fused into one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOp {
+    /// Runs as `execute` would
+    Single(Decoded),
+    /// `lui` or `auipc` then an `addi` to the same register, as `li` and `la`
+    /// expand to, with the value they build worked out in translation
+    Constant {
+        rd: u8,
+        value: u32,
+        pair: [Decoded; 2],
+    },
+}
+
+impl BlockOp {
+    /// The instructions it stands for
+    pub fn decoded(&self) -> &[Decoded] {
+        match self {
+            BlockOp::Single(decoded) => std::slice::from_ref(decoded),
+            BlockOp::Constant { pair, .. } => pair,
+        }
+    }
+}
+
+/// Fuse the instructions of the block at `start` into superinstructions
+/// where they can be
+fn fuse(start: u32, decoded: &[Decoded]) -> Vec<BlockOp> {
+    let mut ops = Vec::new();
+    let mut i = 0;
+    while i < decoded.len() {
+        let first = decoded[i];
+        let base = match first.op {
+            Op::Lui => Some(0),
+            Op::Auipc => Some(start.wrapping_add(4 * i as u32)),
+            _ => None,
+        };
+        match (base, decoded.get(i + 1)) {
+            (Some(base), Some(&second))
+                if second.op == Op::Addi && second.rd == first.rd && second.ra == first.rd =>
+            {
+                ops.push(BlockOp::Constant {
+                    rd: first.rd,
+                    value: base.wrapping_add(first.imm).wrapping_add(second.imm),
+                    pair: [first, second],
+                });
+                i += 2;
+            }
+            _ => {
+                ops.push(BlockOp::Single(first));
+                i += 1;
+            }
+        }
+    }
+    ops
+}
+
+/// Where a link goes when it hasn't been followed yet
+const UNLINKED: (u32, usize) = (u32::MAX, 0);
+
+#[derive(Debug)]
+struct Block {
+    /// Addresses the block came from
+    start: u32,
+    end: u32,
+    ops: Rc<[BlockOp]>,
+    /// The last blocks to come after this one, by address and index, so
+    /// going to them doesn't look them up. One for each way a branch goes
+    links: [(u32, usize); 2],
+}
+
+/// Basic blocks of the program, translated into instructions that run one
+/// after another without looking anything up in between, with pairs like
+/// `li`'s fused into one.
+///
+/// A store to where a block came from throws that block away, it's
+/// translated again when it's reached.
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    /// `None` where a block was thrown away, until another takes its place
+    blocks: Vec<Option<Block>>,
+    /// Indices in `blocks` free to reuse
+    free: Vec<usize>,
+    /// Index of the block starting at each address
+    starts: HashMap<u32, usize>,
+    /// Start and end of the addresses the blocks came from, stores outside
+    /// it can't change them
+    span: Option<(u32, u32)>,
+    /// Times blocks were thrown away
+    flushes: u64,
+}
+
+impl BlockCache {
+    /// The block starting at `pc`, translating it if it hasn't been. `from`
+    /// is the block that just ran, to link it to this one.
+    ///
+    /// `None` if the instruction at `pc` isn't `runnable` or can't be fetched,
+    /// for the plain interpreter to deal with.
+    pub fn find(
+        &mut self,
+        memory: &Memory,
+        from: Option<usize>,
+        pc: u32,
+        runnable: impl Fn(u32) -> bool,
+    ) -> Option<usize> {
+        if let Some(Some(block)) = from.map(|from| &self.blocks[from]) {
+            if let Some(&(_, index)) = block.links.iter().find(|(at, _)| *at == pc) {
+                return Some(index);
+            }
+        }
+        let index = match self.starts.get(&pc) {
+            Some(&index) => index,
+            None => self.translate(memory, pc, &runnable)?,
+        };
+        if let Some(Some(block)) = from.map(|from| &mut self.blocks[from]) {
+            block.links.rotate_right(1);
+            block.links[0] = (pc, index);
+        }
+        Some(index)
+    }
+
+    fn translate(
+        &mut self,
+        memory: &Memory,
+        start: u32,
+        runnable: impl Fn(u32) -> bool,
+    ) -> Option<usize> {
+        let mut decoded = Vec::new();
+        let mut pc = start;
+        while decoded.len() < MAX_LEN && runnable(pc) {
+            let Ok(word) = memory.fetch(pc) else {
+                break;
+            };
+            let instruction = Decoded::new(word);
+            decoded.push(instruction);
+            pc = pc.wrapping_add(4);
+            if ends_block(instruction.op) {
+                break;
+            }
+        }
+        if decoded.is_empty() {
+            return None;
+        }
+
+        self.span = Some(match self.span {
+            Some((low, high)) => (low.min(start), high.max(pc)),
+            None => (start, pc),
+        });
+        let block = Block {
+            start,
+            end: pc,
+            ops: fuse(start, &decoded).into(),
+            links: [UNLINKED; 2],
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.blocks[index] = Some(block);
+                index
+            }
+            None => {
+                self.blocks.push(Some(block));
+                self.blocks.len() - 1
+            }
+        };
+        self.starts.insert(start, index);
+        Some(index)
+    }
+
+    /// Instructions of block `index`
+    pub fn ops(&self, index: usize) -> Rc<[BlockOp]> {
+        self.blocks[index]
+            .as_ref()
+            .map_or_else(|| Rc::from([]), |block| block.ops.clone())
+    }
+
+    /// Throw away the blocks `width` bytes at `addr` could be in
+    pub fn invalidate(&mut self, addr: u32, width: u32) {
+        let Some((low, high)) = self.span else {
+            return;
+        };
+        let end = addr.saturating_add(width);
+        if addr >= high || low >= end {
+            return;
+        }
+        let stale = (0..self.blocks.len())
+            .filter(|&i| {
+                self.blocks[i]
+                    .as_ref()
+                    .is_some_and(|block| addr < block.end && block.start < end)
+            })
+            .collect::<Vec<_>>();
+        if stale.is_empty() {
+            return;
+        }
+        for &index in &stale {
+            if let Some(block) = self.blocks[index].take() {
+                self.starts.remove(&block.start);
+                self.free.push(index);
+            }
+        }
+        for block in self.blocks.iter_mut().flatten() {
+            for link in &mut block.links {
+                if stale.contains(&link.1) {
+                    *link = UNLINKED;
+                }
+            }
+        }
+        self.flushes += 1;
+    }
+
+    /// Changes whenever blocks are thrown away, so a block that's running
+    /// can tell it may be out of date
+    pub fn flushes(&self) -> u64 {
+        self.flushes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockCache, BlockOp, UNLINKED};
+    use crate::{
+        env::Env,
+        execution::Stop,
+        tests::{load_program, run_program, step_to_exit},
+    };
+
+    #[test]
+    fn translate() {
+        let input = "li a0 0x12345678\nj next\nnext:\naddi a0 a0 1\nebreak";
+        let mut env = Env::new();
+        load_program(&mut env, input);
+        let mut blocks = BlockCache::default();
+        let first = blocks.find(&env.memory, None, 0, |pc| pc < 16).unwrap();
+        let ops = blocks.ops(first);
+        assert_eq!(ops.len(), 2);
+        assert!(matches!(
+            ops[0],
+            BlockOp::Constant {
+                rd: 10,
+                value: 0x12345678,
+                ..
+            }
+        ));
+        blocks
+            .find(&env.memory, Some(first), 12, |pc| pc < 16)
+            .unwrap();
+
+        // A store into the second block leaves the first
+        blocks.invalidate(12, 4);
+        assert_eq!(blocks.flushes(), 1);
+        assert_eq!(blocks.starts.get(&0), Some(&first));
+        assert!(!blocks.starts.contains_key(&12));
+        assert_eq!(blocks.blocks[first].as_ref().unwrap().links[0], UNLINKED);
+
+        // A limit can stop between the fused pair
+        let (count, stop) = run_program(&mut env, Some(1), 16);
+        assert_eq!((count, stop), (1, Stop::Limit));
+        assert_eq!((env.get_register(10), env.pc), (0x12345000, 4));
+    }
+
+    #[test]
+    fn blocks() {
+        use crate::device::Devices;
+
+        // Reads mtime in the middle of blocks, first with interrupts off then
+        // with the timer going off partway through
+        let input = "li t0 0x0200c000\nli t2 0x02004000\nla t3 handler\ncsrw mtvec t3\n\
+                     li s1 2\nstart:\nli t1 20\nloop:\naddi a1 a1 3\nlw a2 -8(t0)\n\
+                     add a0 a0 a2\naddi t1 t1 -1\nbnez t1 loop\naddi s1 s1 -1\n\
+                     beqz s1 done\nli t1 150\nsw t1 0(t2)\nsw zero 4(t2)\nli t1 128\n\
+                     csrs mie t1\ncsrsi mstatus 8\nj start\ndone:\ncsrw mtvec zero\n\
+                     li a7 93\necall\nhandler:\naddi s0 s0 1\nli t3 -1\nsw t3 4(t2)\nmret";
+        let load = || {
+            let mut env = Env::new();
+            env.devices = Devices::builtin(false);
+            load_program(&mut env, input);
+            env
+        };
+
+        let mut stepped = load();
+        step_to_exit(&mut stepped, 10_000).unwrap();
+        let mut env = load();
+        let (count, stop) = run_program(&mut env, None, 0x200);
+        assert_eq!(stop, Stop::Exit(stepped.exit.unwrap()));
+        assert_eq!(count, stepped.csrs.instret);
+        assert_eq!(stepped.get_register(8), 1);
+        for i in 0..32 {
+            assert_eq!(env.get_register(i), stepped.get_register(i));
+        }
+        assert_eq!(env.load(0x0200bff8, 4), stepped.load(0x0200bff8, 4));
+    }
+}
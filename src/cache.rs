@@ -0,0 +1,435 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use itertools::Itertools;
+
+/// Which line of a full set makes way for a new one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Replacement {
+    /// The one used longest ago
+    #[default]
+    Lru,
+    /// The one filled longest ago
+    Fifo,
+    /// Any, from a fixed seed so runs repeat
+    Random,
+}
+
+/// How a cache is laid out and what it does on writes.
+///
+/// Parsed from `SIZE,LINE,WAYS` then any of `lru`, `fifo` or `random`, `wb`
+/// or `wt`, and `wa` or `nwa`, like `4k,32,2,fifo,wt`. By default LRU,
+/// write-back and write-allocate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Bytes of data, a power of two
+    pub size: u32,
+    /// Bytes in a line, a power of two of at least 4
+    pub line: u32,
+    /// Lines in a set, 1 for direct-mapped
+    pub ways: u32,
+    pub replacement: Replacement,
+    /// Only write dirty lines to the next level once they are evicted, rather
+    /// than every write going through
+    pub write_back: bool,
+    /// Fill a line on a write miss, rather than only writing to the next level
+    pub write_allocate: bool,
+}
+
+impl CacheConfig {
+    pub fn sets(&self) -> u32 {
+        self.size / (self.line * self.ways)
+    }
+}
+
+/// Bytes, with an optional `k` or `m`
+fn parse_bytes(s: &str) -> Option<u32> {
+    let (digits, scale) = match s.to_ascii_lowercase() {
+        s if s.ends_with('k') => (s[..s.len() - 1].to_string(), 1 << 10),
+        s if s.ends_with('m') => (s[..s.len() - 1].to_string(), 1 << 20),
+        s => (s, 1),
+    };
+    digits.parse::<u32>().ok()?.checked_mul(scale)
+}
+
+impl FromStr for CacheConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(',').map(str::trim).collect::<Vec<_>>();
+        let [size, line, ways, options @ ..] = &parts[..] else {
+            return Err(format!("expected SIZE,LINE,WAYS in cache '{}'", s));
+        };
+        let number = |what: &str, text: &str| {
+            parse_bytes(text)
+                .filter(|n| n.is_power_of_two())
+                .ok_or(format!("{} '{}' isn't a power of two", what, text))
+        };
+        let mut config = CacheConfig {
+            size: number("size", size)?,
+            line: number("line size", line)?,
+            ways: ways
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or(format!("ways '{}' isn't a number of at least 1", ways))?,
+            replacement: Replacement::default(),
+            write_back: true,
+            write_allocate: true,
+        };
+        for option in options {
+            match *option {
+                "lru" => config.replacement = Replacement::Lru,
+                "fifo" => config.replacement = Replacement::Fifo,
+                "random" => config.replacement = Replacement::Random,
+                "wb" => config.write_back = true,
+                "wt" => config.write_back = false,
+                "wa" => config.write_allocate = true,
+                "nwa" => config.write_allocate = false,
+                _ => {
+                    return Err(format!(
+                        "unknown cache option '{}', expected lru, fifo, random, wb, wt, wa or nwa",
+                        option
+                    ))
+                }
+            }
+        }
+        if config.line < 4 || config.line * config.ways > config.size {
+            return Err(format!(
+                "cache '{}' needs lines of at least 4 bytes and room for a whole set",
+                s
+            ));
+        }
+        let sets = config.sets();
+        if !sets.is_power_of_two() || sets * config.line * config.ways != config.size {
+            return Err(format!(
+                "cache '{}' doesn't split into a power of two sets",
+                s
+            ));
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    /// Access it was last used on, for LRU
+    used: u64,
+    /// Access it was filled on, for FIFO
+    filled: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Valid lines replaced by another
+    pub evictions: u64,
+    /// Dirty lines written to the next level
+    pub writebacks: u64,
+}
+
+/// The last access to a cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Touch {
+    pub addr: u32,
+    pub write: bool,
+    pub set: usize,
+    /// The way it hit or filled, none for a write miss that doesn't allocate
+    pub way: Option<usize>,
+    pub hit: bool,
+}
+
+/// What an access needs from the next level down
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Outcome {
+    /// Address of a line to read in
+    fill: Option<u32>,
+    /// Address of a line to write out
+    writeback: Option<u32>,
+    /// Whether the write goes on down as well
+    through: bool,
+}
+
+/// A set-associative cache. It only keeps tags, the data is always in memory
+#[derive(Debug, Clone)]
+pub struct Cache {
+    pub name: &'static str,
+    pub config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    pub stats: CacheStats,
+    pub last: Option<Touch>,
+    /// Accesses so far, to order lines by
+    clock: u64,
+    /// State of the generator for random replacement
+    seed: u64,
+}
+
+impl Cache {
+    pub fn new(name: &'static str, config: CacheConfig) -> Self {
+        Self {
+            name,
+            config,
+            sets: vec![vec![Line::default(); config.ways as usize]; config.sets() as usize],
+            stats: CacheStats::default(),
+            last: None,
+            clock: 0,
+            seed: 0x9e3779b97f4a7c15,
+        }
+    }
+
+    /// Line number of `addr`
+    fn line_of(&self, addr: u32) -> u32 {
+        addr / self.config.line
+    }
+
+    fn access(&mut self, addr: u32, write: bool) -> Outcome {
+        self.clock += 1;
+        let line = self.line_of(addr);
+        let set = (line % self.config.sets()) as usize;
+        let tag = line / self.config.sets();
+        let config = self.config;
+        let ways = &mut self.sets[set];
+        let mut outcome = Outcome {
+            through: write && !config.write_back,
+            ..Outcome::default()
+        };
+
+        if let Some(way) = ways.iter().position(|l| l.valid && l.tag == tag) {
+            self.stats.hits += 1;
+            let hit = &mut ways[way];
+            hit.used = self.clock;
+            hit.dirty |= write && config.write_back;
+            self.last = Some(Touch {
+                addr,
+                write,
+                set,
+                way: Some(way),
+                hit: true,
+            });
+            return outcome;
+        }
+
+        self.stats.misses += 1;
+        if write && !config.write_allocate {
+            outcome.through = true;
+            self.last = Some(Touch {
+                addr,
+                write,
+                set,
+                way: None,
+                hit: false,
+            });
+            return outcome;
+        }
+        let way = match ways.iter().position(|l| !l.valid) {
+            Some(way) => way,
+            None => match config.replacement {
+                Replacement::Lru => ways.iter().position_min_by_key(|l| l.used).unwrap(),
+                Replacement::Fifo => ways.iter().position_min_by_key(|l| l.filled).unwrap(),
+                Replacement::Random => {
+                    // xorshift64
+                    self.seed ^= self.seed << 13;
+                    self.seed ^= self.seed >> 7;
+                    self.seed ^= self.seed << 17;
+                    (self.seed % config.ways as u64) as usize
+                }
+            },
+        };
+        let victim = ways[way];
+        if victim.valid {
+            self.stats.evictions += 1;
+            if victim.dirty {
+                self.stats.writebacks += 1;
+                let line = victim.tag * config.sets() + set as u32;
+                outcome.writeback = Some(line * config.line);
+            }
+        }
+        ways[way] = Line {
+            valid: true,
+            dirty: write && config.write_back,
+            tag,
+            used: self.clock,
+            filled: self.clock,
+        };
+        outcome.fill = Some(line * config.line);
+        self.last = Some(Touch {
+            addr,
+            write,
+            set,
+            way: Some(way),
+            hit: false,
+        });
+        outcome
+    }
+
+    /// Tags of the set the last access touched, that way marked with `*`
+    pub fn view(&self) -> Option<String> {
+        let touch = self.last?;
+        let ways = self.sets[touch.set]
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let tag = if line.valid {
+                    format!("{:#x}{}", line.tag, if line.dirty { " dirty" } else { "" })
+                } else {
+                    "-".to_string()
+                };
+                let mark = if touch.way == Some(i) { "*" } else { " " };
+                format!("{}{}: {}", mark, i, tag)
+            })
+            .join("  ");
+        Some(format!(
+            "set {}/{}  {}",
+            touch.set,
+            self.config.sets(),
+            ways
+        ))
+    }
+}
+
+impl Display for Cache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let stats = self.stats;
+        let rate = stats.hits as f64 * 100.0 / (stats.hits + stats.misses).max(1) as f64;
+        write!(
+            f,
+            "{}: {} hits, {} misses ({:.1}% hit), {} evictions, {} writebacks",
+            self.name, stats.hits, stats.misses, rate, stats.evictions, stats.writebacks
+        )?;
+        if let Some(touch) = self.last {
+            write!(
+                f,
+                ", last {} {:#010x} {}",
+                if touch.write { "write" } else { "read" },
+                touch.addr,
+                if touch.hit { "hit" } else { "miss" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// L1 instruction and data caches and an L2 behind them, any of which can be
+/// left out. Fed by every fetch and every load and store to memory
+#[derive(Debug, Clone, Default)]
+pub struct Caches {
+    pub l1i: Option<Cache>,
+    pub l1d: Option<Cache>,
+    pub l2: Option<Cache>,
+}
+
+impl Caches {
+    pub fn is_empty(&self) -> bool {
+        self.l1i.is_none() && self.l1d.is_none() && self.l2.is_none()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cache> {
+        [&self.l1i, &self.l1d, &self.l2].into_iter().flatten()
+    }
+
+    pub fn fetch(&mut self, addr: u32) {
+        Self::through(&mut self.l1i, &mut self.l2, addr, false);
+    }
+
+    /// A load or store of `width` bytes, touching both lines if it straddles two
+    pub fn data(&mut self, addr: u32, width: u32, write: bool) {
+        let end = addr.wrapping_add(width - 1);
+        Self::through(&mut self.l1d, &mut self.l2, addr, write);
+        let first = self.l1d.as_ref().or(self.l2.as_ref());
+        if first.is_some_and(|c| c.line_of(end) != c.line_of(addr)) {
+            Self::through(&mut self.l1d, &mut self.l2, end, write);
+        }
+    }
+
+    /// Access `l1`, then `l2` for whatever `l1` needs from it, or only `l2`
+    /// without an `l1`
+    fn through(l1: &mut Option<Cache>, l2: &mut Option<Cache>, addr: u32, write: bool) {
+        let Some(l1) = l1 else {
+            if let Some(l2) = l2 {
+                l2.access(addr, write);
+            }
+            return;
+        };
+        let outcome = l1.access(addr, write);
+        let Some(l2) = l2 else {
+            return;
+        };
+        if let Some(line) = outcome.writeback {
+            l2.access(line, true);
+        }
+        if let Some(line) = outcome.fill {
+            l2.access(line, false);
+        }
+        if outcome.through {
+            l2.access(addr, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn caches() {
+        use crate::cache::{Cache, CacheConfig, CacheStats, Caches};
+
+        let config = |spec: &str| spec.parse::<CacheConfig>().unwrap();
+        assert_eq!(config("1k,16,2").sets(), 32);
+        assert!("64,16,3".parse::<CacheConfig>().is_err());
+        assert!("64,2,1".parse::<CacheConfig>().is_err());
+
+        // Two sets of two ways, 0x00, 0x20 and 0x40 all go in set 0
+        let run = |spec: &str| {
+            let mut caches = Caches {
+                l1d: Some(Cache::new("L1d", config(spec))),
+                l2: Some(Cache::new("L2", config("256,16,1"))),
+                ..Caches::default()
+            };
+            for (addr, write) in [
+                (0x00, true),
+                (0x20, false),
+                (0x00, false),
+                (0x40, false),
+                (0x20, false),
+            ] {
+                caches.data(addr, 4, write);
+            }
+            caches
+        };
+        let lru = run("64,16,2,lru");
+        let l1d = lru.l1d.as_ref().unwrap();
+        assert_eq!(
+            l1d.stats,
+            CacheStats {
+                hits: 1,
+                misses: 4,
+                evictions: 2,
+                writebacks: 1,
+            }
+        );
+        assert_eq!(l1d.last.unwrap().way, Some(0));
+        // Three fills, then the writeback and the last fill hit
+        assert_eq!(lru.l2.as_ref().unwrap().stats.hits, 2);
+        assert_eq!(lru.l2.as_ref().unwrap().stats.misses, 3);
+
+        // FIFO throws out the dirty line filled first, even though it was just used
+        let fifo = run("64,16,2,fifo");
+        let stats = fifo.l1d.as_ref().unwrap().stats;
+        assert_eq!((stats.hits, stats.writebacks), (2, 1));
+
+        let mut caches = Caches {
+            l1d: Some(Cache::new("L1d", config("64,16,2,wt,nwa"))),
+            ..Caches::default()
+        };
+        caches.data(0x80, 4, true);
+        assert_eq!(caches.l1d.as_ref().unwrap().last.unwrap().way, None);
+        // Straddling two lines looks in both
+        caches.data(0x0e, 4, false);
+        assert_eq!(caches.l1d.as_ref().unwrap().stats.misses, 3);
+    }
+}
@@ -0,0 +1,242 @@
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const MCYCLE: u32 = 0xb00;
+pub const MINSTRET: u32 = 0xb02;
+pub const MCYCLEH: u32 = 0xb80;
+pub const MINSTRETH: u32 = 0xb82;
+pub const CYCLE: u32 = 0xc00;
+pub const TIME: u32 = 0xc01;
+pub const INSTRET: u32 = 0xc02;
+pub const CYCLEH: u32 = 0xc80;
+pub const TIMEH: u32 = 0xc81;
+pub const INSTRETH: u32 = 0xc82;
+pub const MVENDORID: u32 = 0xf11;
+pub const MARCHID: u32 = 0xf12;
+pub const MIMPID: u32 = 0xf13;
+pub const MHARTID: u32 = 0xf14;
+
+const NAMES: [(&str, u32); 23] = [
+    ("mstatus", MSTATUS),
+    ("misa", MISA),
+    ("mie", MIE),
+    ("mtvec", MTVEC),
+    ("mscratch", MSCRATCH),
+    ("mepc", MEPC),
+    ("mcause", MCAUSE),
+    ("mtval", MTVAL),
+    ("mip", MIP),
+    ("mcycle", MCYCLE),
+    ("minstret", MINSTRET),
+    ("mcycleh", MCYCLEH),
+    ("minstreth", MINSTRETH),
+    ("cycle", CYCLE),
+    ("time", TIME),
+    ("instret", INSTRET),
+    ("cycleh", CYCLEH),
+    ("timeh", TIMEH),
+    ("instreth", INSTRETH),
+    ("mvendorid", MVENDORID),
+    ("marchid", MARCHID),
+    ("mimpid", MIMPID),
+    ("mhartid", MHARTID),
+];
+
+/// Number of the CSR called `name`
+pub fn number(name: &str) -> Option<u32> {
+    NAMES.iter().find(|(n, _)| *n == name).map(|(_, csr)| *csr)
+}
+
+/// Name of CSR `csr`, if it's one rizz-v has
+pub fn name(csr: u32) -> Option<&'static str> {
+    NAMES.iter().find(|(_, c)| *c == csr).map(|(n, _)| *n)
+}
+
+/// Global interrupt enable
+pub const MSTATUS_MIE: u32 = 1 << 3;
+/// What `MSTATUS_MIE` was before the trap
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+/// Privilege before the trap, always machine mode
+pub const MSTATUS_MPP: u32 = 3 << 11;
+
+/// Interrupt causes, also the bit of each in `mie` and `mip`
+pub const SOFTWARE: u32 = 3;
+pub const TIMER: u32 = 7;
+pub const EXTERNAL: u32 = 11;
+
+/// Set in `mcause` for interrupts, as opposed to exceptions
+pub const INTERRUPT: u32 = 1 << 31;
+
+/// Exception cause of a word that isn't an instruction rizz-v can run
+pub const ILLEGAL_INSTRUCTION: u32 = 2;
+/// Exception cause of an `ebreak`
+pub const BREAKPOINT: u32 = 3;
+/// Exception cause of an `ecall` in machine mode
+pub const MACHINE_ECALL: u32 = 11;
+
+/// RV32 with I, M, A and F
+const ISA: u32 = 1 << 30 | 1 << 8 | 1 << 12 | 1 << 0 | 1 << 5;
+
+/// The machine-mode CSRs that hold state.
+///
+/// `mip` isn't one of them, the devices drive it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Csrs {
+    pub mstatus: u32,
+    pub mie: u32,
+    /// `None` until the program writes it, a handler at 0 is still one
+    pub mtvec: Option<u32>,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    /// Retired instructions, every instruction takes a cycle
+    pub instret: u64,
+    /// Number of the hart these belong to, `mhartid`
+    pub hartid: u32,
+    /// Cause of an exception taken before anything was written to `mtvec`,
+    /// until `run` stops for it
+    #[serde(skip)]
+    pub unhandled: Option<u32>,
+}
+
+impl Default for Csrs {
+    fn default() -> Self {
+        Self {
+            mstatus: MSTATUS_MPP,
+            mie: 0,
+            mtvec: None,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            instret: 0,
+            hartid: 0,
+            unhandled: None,
+        }
+    }
+}
+
+impl Csrs {
+    /// Value of `csr`, given the current `mip`. Unknown CSRs read as 0
+    pub fn read(&self, csr: u32, mip: u32) -> u32 {
+        match csr {
+            MSTATUS => self.mstatus,
+            MISA => ISA,
+            MIE => self.mie,
+            MTVEC => self.mtvec.unwrap_or(0),
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => mip,
+            MHARTID => self.hartid,
+            MCYCLE | MINSTRET | CYCLE | TIME | INSTRET => self.instret as u32,
+            MCYCLEH | MINSTRETH | CYCLEH | TIMEH | INSTRETH => (self.instret >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    /// Write `csr`, keeping read-only fields as they are. Read-only and unknown CSRs ignore it
+    pub fn write(&mut self, csr: u32, value: u32) {
+        let interrupts = 1 << SOFTWARE | 1 << TIMER | 1 << EXTERNAL;
+        match csr {
+            MSTATUS => self.mstatus = value & (MSTATUS_MIE | MSTATUS_MPIE) | MSTATUS_MPP,
+            MIE => self.mie = value & interrupts,
+            // Direct and vectored modes only
+            MTVEC => self.mtvec = Some(value & !2),
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !3,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            MCYCLE | MINSTRET => self.instret = self.instret & !0xffffffff | value as u64,
+            MCYCLEH | MINSTRETH => self.instret = self.instret & 0xffffffff | (value as u64) << 32,
+            _ => {}
+        }
+    }
+
+    /// Whether interrupts are on and one of `mip` is enabled, the cause to take if so
+    pub fn interrupt(&self, mip: u32) -> Option<u32> {
+        if self.mstatus & MSTATUS_MIE == 0 {
+            return None;
+        }
+        let pending = mip & self.mie;
+        // In the order the spec gives them priority
+        [EXTERNAL, SOFTWARE, TIMER]
+            .into_iter()
+            .find(|cause| pending & 1 << cause != 0)
+            .map(|cause| cause | INTERRUPT)
+    }
+
+    /// Enter the trap handler for `cause`, taken at `pc`, and return its address
+    pub fn trap(&mut self, pc: u32, cause: u32, tval: u32) -> u32 {
+        self.mepc = pc;
+        self.mcause = cause;
+        self.mtval = tval;
+        let enabled = self.mstatus & MSTATUS_MIE != 0;
+        self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE);
+        if enabled {
+            self.mstatus |= MSTATUS_MPIE;
+        }
+
+        let Some(mtvec) = self.mtvec else {
+            if cause & INTERRUPT == 0 {
+                self.unhandled = Some(cause);
+            }
+            return 0;
+        };
+        let base = mtvec & !3;
+        if mtvec & 1 == 1 && cause & INTERRUPT != 0 {
+            base.wrapping_add(4 * (cause & !INTERRUPT))
+        } else {
+            base
+        }
+    }
+
+    /// Leave the trap handler, returning where to go back to
+    pub fn mret(&mut self) -> u32 {
+        if self.mstatus & MSTATUS_MPIE != 0 {
+            self.mstatus |= MSTATUS_MIE;
+        } else {
+            self.mstatus &= !MSTATUS_MIE;
+        }
+        self.mstatus |= MSTATUS_MPIE;
+        self.mepc
+    }
+}
+
+/// Name of the exception `cause`, a value of `mcause` without `INTERRUPT`
+pub fn describe_exception(cause: u32) -> String {
+    match cause {
+        BREAKPOINT => "breakpoint".to_string(),
+        MACHINE_ECALL => "ecall".to_string(),
+        _ => format!("exception {}", cause),
+    }
+}
+
+/// Names of the interrupts set in `bits`, a value like `mie` or `mip`
+pub fn describe_interrupts(bits: u32) -> String {
+    let names = [
+        (SOFTWARE, "software"),
+        (TIMER, "timer"),
+        (EXTERNAL, "external"),
+    ]
+    .into_iter()
+    .filter(|(cause, _)| bits & 1 << cause != 0)
+    .map(|(_, name)| name)
+    .join(", ");
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names
+    }
+}
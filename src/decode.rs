@@ -0,0 +1,301 @@
+use crate::instructions::kind::Kind;
+
+/// What an instruction does, with the registers and immediate it does it to
+/// in `Decoded`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Lui,
+    Auipc,
+    Add,
+    Addi,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Sll,
+    Srl,
+    Sra,
+    Slt,
+    Sltu,
+    Andi,
+    Ori,
+    Xori,
+    Slti,
+    Sltiu,
+    Slli,
+    Srli,
+    Srai,
+    Mul,
+    Mulh,
+    Mulhsu,
+    Mulhu,
+    Div,
+    Divu,
+    Rem,
+    Remu,
+    Lb,
+    Lh,
+    Lw,
+    Lbu,
+    Lhu,
+    Sb,
+    Sh,
+    Sw,
+    Beq,
+    Bne,
+    Blt,
+    Bge,
+    Bltu,
+    Bgeu,
+    Jal,
+    Jalr,
+    Ecall,
+    Ebreak,
+    Fence,
+    LrW,
+    ScW,
+    AmoswapW,
+    AmoaddW,
+    AmoxorW,
+    AmoandW,
+    AmoorW,
+    AmominW,
+    AmomaxW,
+    AmominuW,
+    AmomaxuW,
+    Csrrw,
+    Csrrs,
+    Csrrc,
+    Csrrwi,
+    Csrrsi,
+    Csrrci,
+    Mret,
+    Wfi,
+    FaddS,
+    FdivS,
+    FmaddS,
+    FcvtSW,
+    FmvWX,
+    /// Not an instruction rizz-v can run, or not an instruction at all
+    Unknown,
+}
+
+impl Op {
+    fn from_name(name: &str) -> Self {
+        match name {
+            "lui" => Op::Lui,
+            "auipc" => Op::Auipc,
+            "add" => Op::Add,
+            "addi" => Op::Addi,
+            "sub" => Op::Sub,
+            "and" => Op::And,
+            "or" => Op::Or,
+            "xor" => Op::Xor,
+            "sll" => Op::Sll,
+            "srl" => Op::Srl,
+            "sra" => Op::Sra,
+            "slt" => Op::Slt,
+            "sltu" => Op::Sltu,
+            "andi" => Op::Andi,
+            "ori" => Op::Ori,
+            "xori" => Op::Xori,
+            "slti" => Op::Slti,
+            "sltiu" => Op::Sltiu,
+            "slli" => Op::Slli,
+            "srli" => Op::Srli,
+            "srai" => Op::Srai,
+            "mul" => Op::Mul,
+            "mulh" => Op::Mulh,
+            "mulhsu" => Op::Mulhsu,
+            "mulhu" => Op::Mulhu,
+            "div" => Op::Div,
+            "divu" => Op::Divu,
+            "rem" => Op::Rem,
+            "remu" => Op::Remu,
+            "lb" => Op::Lb,
+            "lh" => Op::Lh,
+            "lw" => Op::Lw,
+            "lbu" => Op::Lbu,
+            "lhu" => Op::Lhu,
+            "sb" => Op::Sb,
+            "sh" => Op::Sh,
+            "sw" => Op::Sw,
+            "beq" => Op::Beq,
+            "bne" => Op::Bne,
+            "blt" => Op::Blt,
+            "bge" => Op::Bge,
+            "bltu" => Op::Bltu,
+            "bgeu" => Op::Bgeu,
+            "jal" => Op::Jal,
+            "jalr" => Op::Jalr,
+            "ecall" => Op::Ecall,
+            "ebreak" => Op::Ebreak,
+            "fence" => Op::Fence,
+            "lr.w" => Op::LrW,
+            "sc.w" => Op::ScW,
+            "amoswap.w" => Op::AmoswapW,
+            "amoadd.w" => Op::AmoaddW,
+            "amoxor.w" => Op::AmoxorW,
+            "amoand.w" => Op::AmoandW,
+            "amoor.w" => Op::AmoorW,
+            "amomin.w" => Op::AmominW,
+            "amomax.w" => Op::AmomaxW,
+            "amominu.w" => Op::AmominuW,
+            "amomaxu.w" => Op::AmomaxuW,
+            "csrrw" => Op::Csrrw,
+            "csrrs" => Op::Csrrs,
+            "csrrc" => Op::Csrrc,
+            "csrrwi" => Op::Csrrwi,
+            "csrrsi" => Op::Csrrsi,
+            "csrrci" => Op::Csrrci,
+            "mret" => Op::Mret,
+            "wfi" => Op::Wfi,
+            "fadd.s" => Op::FaddS,
+            "fdiv.s" => Op::FdivS,
+            "fmadd.s" => Op::FmaddS,
+            "fcvt.s.w" => Op::FcvtSW,
+            "fmv.w.x" => Op::FmvWX,
+            _ => Op::Unknown,
+        }
+    }
+}
+
+/// An instruction decoded once, so running it again doesn't look at its
+/// bits. Registers an instruction doesn't have are 0, as is a missing
+/// immediate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded {
+    pub op: Op,
+    pub rd: u8,
+    pub ra: u8,
+    pub rb: u8,
+    pub rc: u8,
+    pub imm: u32,
+    /// The instruction itself
+    pub word: u32,
+}
+
+impl Decoded {
+    pub fn new(word: u32) -> Self {
+        let Some((kind, name)) = Kind::to_op(word) else {
+            return Self {
+                op: Op::Unknown,
+                rd: 0,
+                ra: 0,
+                rb: 0,
+                rc: 0,
+                imm: 0,
+                word,
+            };
+        };
+        let regs = kind.get_regs().unwrap();
+        let reg = |i: usize| regs.get(i).copied().unwrap_or(0) as u8;
+        Self {
+            op: Op::from_name(&name),
+            rd: reg(0),
+            ra: reg(1),
+            rb: reg(2),
+            rc: reg(3),
+            imm: kind.get_imm().unwrap_or(0),
+            word,
+        }
+    }
+}
+
+/// Number of instructions `DecodeCache` holds, a power of two
+const ENTRIES: usize = 1 << 14;
+
+/// Instructions already decoded, by address.
+///
+/// Direct-mapped, an instruction only goes in the entry its address picks and
+/// pushes out whatever was there. Anything that changes code after it has
+/// run has to `invalidate` it.
+#[derive(Debug, Clone, Default)]
+pub struct DecodeCache {
+    /// Address and instruction of each entry, empty until first used
+    entries: Vec<(u32, Decoded)>,
+}
+
+/// Address of an entry with nothing in it, instructions are never there
+const EMPTY: u32 = u32::MAX;
+
+impl DecodeCache {
+    fn index(addr: u32) -> usize {
+        (addr as usize >> 2) & (ENTRIES - 1)
+    }
+
+    /// The instruction at `addr`, if it's been decoded
+    pub fn get(&self, addr: u32) -> Option<Decoded> {
+        match self.entries.get(Self::index(addr)) {
+            Some(&(at, decoded)) if at == addr => Some(decoded),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, addr: u32, decoded: Decoded) {
+        if self.entries.is_empty() {
+            self.entries = vec![(EMPTY, decoded); ENTRIES];
+        }
+        self.entries[Self::index(addr)] = (addr, decoded);
+    }
+
+    /// Forget the instructions overlapping `width` bytes at `addr`
+    pub fn invalidate(&mut self, addr: u32, width: u32) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let first = addr & !3;
+        let last = addr.wrapping_add(width.max(1) - 1) & !3;
+        let mut word = first;
+        loop {
+            let index = Self::index(word);
+            if self.entries[index].0 == word {
+                self.entries[index].0 = EMPTY;
+            }
+            if word == last {
+                break;
+            }
+            word = word.wrapping_add(4);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        env::Env,
+        tests::{load_program, run_program},
+    };
+
+    #[test]
+    fn decode_cache() {
+        use crate::execution::Stop;
+
+        let load = |input: &str| {
+            let mut env = Env::new();
+            load_program(&mut env, input);
+            env
+        };
+
+        // Overwrite an instruction that has already run with `addi a0 a0 100`
+        let mut env = load(
+            "li t0 0x06450513\ntarget:\naddi a0 a0 1\nbnez t1 done\nli t1 1\n\
+             la t2 target\nsw t0 0(t2)\nj target\ndone:\nli a7 93\necall",
+        );
+        let (count, stop) = run_program(&mut env, None, 0x100);
+        assert_eq!(stop, Stop::Exit(101));
+        assert_eq!(count, 13);
+
+        let mut env = load("loop:\nj loop");
+        assert_eq!(
+            run_program(&mut env, Some(1000), 0x100),
+            (1000, Stop::Limit)
+        );
+        let mut env = load("nop");
+        assert_eq!(run_program(&mut env, None, 4), (1, Stop::Finished));
+    }
+}
@@ -0,0 +1,1025 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::{self, Read, Write},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use termion::{
+    color, cursor,
+    raw::{IntoRawMode, RawTerminal},
+    AsyncReader,
+};
+
+use crate::{
+    csr,
+    err::{DeviceErr, SnapshotErr},
+};
+
+/// Base addresses of the built-in devices, out of the way of code, data and the stack
+pub const UART_BASE: u32 = 0x20000000;
+pub const GPIO_BASE: u32 = 0x20001000;
+/// Where the CLINT and PLIC of most RISC-V boards are
+pub const CLINT_BASE: u32 = 0x02000000;
+pub const PLIC_BASE: u32 = 0x0c000000;
+/// RARS' default base for the bitmap display, the start of its static data
+pub const BITMAP_BASE: u32 = 0x10010000;
+/// Where RARS' keyboard and display MMIO simulator is
+pub const KEYBOARD_BASE: u32 = 0xffff0000;
+
+/// A memory-mapped peripheral.
+///
+/// It claims `size()` bytes from `base()`, loads and stores there are given
+/// to the device as offsets from the base instead of going to memory.
+pub trait Device: Debug {
+    fn name(&self) -> &str;
+    fn base(&self) -> u32;
+    fn size(&self) -> u32;
+
+    /// Value of the `width` bytes at `offset`
+    fn read(&mut self, offset: u32, width: u32) -> u32;
+    fn write(&mut self, offset: u32, width: u32, value: u32);
+
+    /// Called after every instruction
+    fn tick(&mut self) {}
+
+    /// Called instead of `tick` for `ticks` instructions in a row, when
+    /// nothing could have looked at the device in between
+    fn tick_by(&mut self, ticks: u32) {
+        (0..ticks).for_each(|_| self.tick());
+    }
+
+    /// Called once the program stops, to show anything still pending
+    fn flush(&mut self) {}
+
+    /// A key pressed while the program is paused
+    fn key(&mut self, _byte: u8) {}
+
+    /// Whether the device is asking for an interrupt through the PLIC
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    /// The interrupt lines of every device, bit n for source n
+    fn external(&mut self, _lines: u32) {}
+
+    /// Bits the device sets in `mip`
+    fn mip(&self) -> u32 {
+        0
+    }
+
+    /// Text showing the state of the device, for the stepper
+    fn status(&self) -> Option<String> {
+        None
+    }
+
+    /// State of the device for a snapshot, `Null` if it can't be restored
+    fn save(&self) -> Value {
+        Value::Null
+    }
+}
+
+/// The devices attached to an `Env`.
+///
+/// The PLIC numbers interrupt sources by the order devices were attached
+/// in, starting at 1.
+#[derive(Debug, Default)]
+pub struct Devices {
+    devices: Vec<Box<dyn Device>>,
+    /// Where each device starts and how big it is, so finding the one at an
+    /// address doesn't ask every device
+    ranges: Vec<(u32, u32)>,
+}
+
+impl Devices {
+    /// A UART, CLINT, GPIO bank and PLIC at their usual addresses, the UART
+    /// also writing to stdout if `echo`
+    pub fn builtin(echo: bool) -> Self {
+        let mut devices = Self::default();
+        devices.add(Box::new(Uart::new(UART_BASE, echo))).unwrap();
+        devices.add(Box::new(Clint::new(CLINT_BASE))).unwrap();
+        devices.add(Box::new(Gpio::new(GPIO_BASE))).unwrap();
+        devices.add(Box::new(Plic::new(PLIC_BASE))).unwrap();
+        devices
+    }
+
+    /// Attach a device, as long as it doesn't overlap another one
+    pub fn add(&mut self, device: Box<dyn Device>) -> Result<(), DeviceErr> {
+        let range = |d: &dyn Device| (d.base() as u64, d.base() as u64 + d.size() as u64);
+        let (start, end) = range(&*device);
+        if let Some(other) = self.devices.iter().find(|d| {
+            let (other_start, other_end) = range(&***d);
+            start < other_end && other_start < end
+        }) {
+            return Err(DeviceErr::Overlap(
+                device.name().to_string(),
+                other.name().to_string(),
+            ));
+        }
+        self.push(device);
+        Ok(())
+    }
+
+    fn push(&mut self, device: Box<dyn Device>) {
+        self.ranges.push((device.base(), device.size()));
+        self.devices.push(device);
+    }
+
+    /// Whether a device claims `addr`
+    pub fn claims(&self, addr: u32) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(base, size)| addr.wrapping_sub(base) < size)
+    }
+
+    /// The device claiming `addr`, and the offset into it
+    pub fn at(&mut self, addr: u32) -> Option<(&mut dyn Device, u32)> {
+        let (i, offset) = self
+            .ranges
+            .iter()
+            .enumerate()
+            .find_map(|(i, &(base, size))| {
+                let offset = addr.wrapping_sub(base);
+                (offset < size).then_some((i, offset))
+            })?;
+        Some((self.devices[i].as_mut(), offset))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Device> {
+        self.devices.iter().map(|d| &**d)
+    }
+
+    pub fn tick(&mut self) {
+        self.tick_by(1);
+    }
+
+    /// Catch up on `ticks` instructions at once, as long as nothing looked at
+    /// the devices or their interrupts while they ran
+    pub fn tick_by(&mut self, ticks: u32) {
+        self.devices.iter_mut().for_each(|d| d.tick_by(ticks));
+        let lines = self
+            .devices
+            .iter()
+            .enumerate()
+            .take(31)
+            .filter(|(_, d)| d.interrupt())
+            .fold(0, |lines, (i, _)| lines | 1 << (i + 1));
+        // Usually nothing is asking, and no lines change nothing
+        if lines != 0 {
+            self.devices.iter_mut().for_each(|d| d.external(lines));
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.devices.iter_mut().for_each(|d| d.flush());
+    }
+
+    pub fn key(&mut self, byte: u8) {
+        self.devices.iter_mut().for_each(|d| d.key(byte));
+    }
+
+    /// The interrupts the devices have pending, as `mip`
+    pub fn mip(&self) -> u32 {
+        self.devices.iter().fold(0, |mip, d| mip | d.mip())
+    }
+
+    /// Name and state of every device, in the order they were attached
+    pub fn save(&self) -> Vec<(String, Value)> {
+        self.devices
+            .iter()
+            .map(|d| (d.name().to_string(), d.save()))
+            .collect()
+    }
+
+    /// Devices saved by `save`, recreated by name. Only the built-in ones can be
+    pub fn restore(saved: Vec<(String, Value)>) -> Result<Self, SnapshotErr> {
+        fn restore<T: Device + DeserializeOwned + 'static>(
+            name: &str,
+            state: Value,
+        ) -> Result<Box<dyn Device>, SnapshotErr> {
+            serde_json::from_value::<T>(state)
+                .map(|device| Box::new(device) as Box<dyn Device>)
+                .map_err(|e| SnapshotErr::Malformed(format!("device {}: {}", name, e)))
+        }
+
+        let mut devices = Self::default();
+        for (name, state) in saved {
+            let device = match name.as_str() {
+                "uart" => restore::<Uart>(&name, state)?,
+                "clint" => restore::<Clint>(&name, state)?,
+                "plic" => restore::<Plic>(&name, state)?,
+                "gpio" => restore::<Gpio>(&name, state)?,
+                "bitmap" => restore::<Bitmap>(&name, state)?,
+                "keyboard" => restore::<Keyboard>(&name, state)?,
+                _ => return Err(SnapshotErr::UnknownDevice(name)),
+            };
+            devices.push(device);
+        }
+        Ok(devices)
+    }
+}
+
+/// Serial port with a transmit, receive and status register.
+///
+/// - `0x0` TXDATA, writing sends the low byte
+/// - `0x4` RXDATA, reading takes the next received byte, or -1 when there is none
+/// - `0x8` STATUS, bit 0 is always set as sending never blocks, bit 1 means
+///   there is something to read
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Uart {
+    base: u32,
+    /// Also write what is sent to stdout as it happens
+    #[serde(skip)]
+    echo: bool,
+    /// Everything sent so far
+    pub output: Vec<u8>,
+    /// Bytes waiting to be received
+    pub input: VecDeque<u8>,
+}
+
+impl Uart {
+    pub fn new(base: u32, echo: bool) -> Self {
+        Self {
+            base,
+            echo,
+            output: Vec::new(),
+            input: VecDeque::new(),
+        }
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        12
+    }
+
+    fn read(&mut self, offset: u32, _: u32) -> u32 {
+        match offset {
+            4 => self.input.pop_front().map_or(u32::MAX, |b| b as u32),
+            8 => 1 | ((!self.input.is_empty() as u32) << 1),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _: u32, value: u32) {
+        if offset == 0 {
+            self.output.push(value as u8);
+            if self.echo {
+                let mut stdout = io::stdout();
+                let _ = stdout
+                    .write_all(&[value as u8])
+                    .and_then(|_| stdout.flush());
+            }
+        }
+    }
+
+    fn status(&self) -> Option<String> {
+        let text = String::from_utf8_lossy(&self.output);
+        // Only the last line fits
+        let last = text
+            .trim_end_matches('\n')
+            .rsplit('\n')
+            .next()
+            .unwrap_or("");
+        Some(format!("uart: {}", last.escape_debug()))
+    }
+
+    fn save(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+/// The software and timer interrupts of a CLINT, `mtime` counts instructions.
+///
+/// - `0x0` msip, bit 0 raises a software interrupt
+/// - `0x4000` mtimecmp, 64 bits, a timer interrupt is pending while `mtime` is past it
+/// - `0xbff8` mtime, 64 bits
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Clint {
+    base: u32,
+    pub msip: bool,
+    pub mtime: u64,
+    pub mtimecmp: u64,
+}
+
+const MTIMECMP: u32 = 0x4000;
+const MTIME: u32 = 0xbff8;
+
+impl Clint {
+    pub fn new(base: u32) -> Self {
+        Self {
+            base,
+            msip: false,
+            mtime: 0,
+            // Never goes off until set
+            mtimecmp: u64::MAX,
+        }
+    }
+
+    /// Whether `mtime` has reached `mtimecmp`
+    pub fn expired(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+}
+
+/// Read part of a 64 bit register, `offset` being relative to its start
+fn read_u64(register: u64, offset: u32, width: u32) -> u32 {
+    let value = (register >> (offset * 8)) as u32;
+    match width {
+        4 => value,
+        width => value & ((1 << (width * 8)) - 1),
+    }
+}
+
+/// Write part of a 64 bit register, `offset` being relative to its start
+fn write_u64(register: &mut u64, offset: u32, width: u32, value: u32) {
+    let mask = (u64::MAX >> (64 - width * 8)) << (offset * 8);
+    *register = (*register & !mask) | (((value as u64) << (offset * 8)) & mask);
+}
+
+impl Device for Clint {
+    fn name(&self) -> &str {
+        "clint"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        0x10000
+    }
+
+    fn read(&mut self, offset: u32, width: u32) -> u32 {
+        match offset {
+            0 => self.msip as u32,
+            MTIMECMP..=0x4007 => read_u64(self.mtimecmp, offset - MTIMECMP, width),
+            MTIME..=0xbfff => read_u64(self.mtime, offset - MTIME, width),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, width: u32, value: u32) {
+        match offset {
+            0 => self.msip = value & 1 == 1,
+            MTIMECMP..=0x4007 => write_u64(&mut self.mtimecmp, offset - MTIMECMP, width, value),
+            MTIME..=0xbfff => write_u64(&mut self.mtime, offset - MTIME, width, value),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        self.tick_by(1);
+    }
+
+    fn tick_by(&mut self, ticks: u32) {
+        self.mtime = self.mtime.wrapping_add(ticks as u64);
+    }
+
+    fn mip(&self) -> u32 {
+        (self.msip as u32) << csr::SOFTWARE | (self.expired() as u32) << csr::TIMER
+    }
+
+    fn status(&self) -> Option<String> {
+        let mtimecmp = match self.mtimecmp {
+            u64::MAX => "-".to_string(),
+            cmp => cmp.to_string(),
+        };
+        Some(format!(
+            "clint: msip {} mtime {} mtimecmp {}",
+            self.msip as u32, self.mtime, mtimecmp
+        ))
+    }
+
+    fn save(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+/// A PLIC with 31 sources, for the one hart in machine mode.
+///
+/// Sources latch when their device raises its line, and stay pending until
+/// claimed. A claimed source doesn't interrupt again until it's completed.
+///
+/// - `0x4 * n` priority of source n, 0 to 7, 0 never interrupts
+/// - `0x1000` pending sources, read only
+/// - `0x2000` enabled sources
+/// - `0x200000` threshold, only sources with a higher priority interrupt
+/// - `0x200004` claim, reading takes the highest priority pending source, or
+///   0 when there's none, writing its number back completes it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plic {
+    base: u32,
+    pub priorities: [u32; 32],
+    pub pending: u32,
+    pub enabled: u32,
+    pub threshold: u32,
+    /// Claimed and not completed yet
+    claimed: u32,
+}
+
+const PENDING: u32 = 0x1000;
+const ENABLE: u32 = 0x2000;
+const THRESHOLD: u32 = 0x200000;
+const CLAIM: u32 = 0x200004;
+
+impl Plic {
+    pub fn new(base: u32) -> Self {
+        Self {
+            base,
+            priorities: [0; 32],
+            pending: 0,
+            enabled: 0,
+            threshold: 0,
+            claimed: 0,
+        }
+    }
+
+    /// The source a claim would take, highest priority first, then lowest number
+    pub fn next(&self) -> Option<u32> {
+        (1..32)
+            .filter(|n| self.pending & self.enabled & 1 << n != 0)
+            .filter(|&n| self.priorities[n as usize] > self.threshold)
+            .max_by_key(|&n| (self.priorities[n as usize], std::cmp::Reverse(n)))
+    }
+}
+
+impl Device for Plic {
+    fn name(&self) -> &str {
+        "plic"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        0x4000000
+    }
+
+    fn read(&mut self, offset: u32, _: u32) -> u32 {
+        match offset {
+            4..=0x7c => self.priorities[offset as usize / 4],
+            PENDING => self.pending,
+            ENABLE => self.enabled,
+            THRESHOLD => self.threshold,
+            CLAIM => match self.next() {
+                Some(source) => {
+                    self.pending &= !(1 << source);
+                    self.claimed |= 1 << source;
+                    source
+                }
+                None => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _: u32, value: u32) {
+        match offset {
+            4..=0x7c => self.priorities[offset as usize / 4] = value & 7,
+            // Source 0 doesn't exist
+            ENABLE => self.enabled = value & !1,
+            THRESHOLD => self.threshold = value & 7,
+            CLAIM if value < 32 => self.claimed &= !(1 << value),
+            _ => {}
+        }
+    }
+
+    fn external(&mut self, lines: u32) {
+        self.pending |= lines & !self.claimed & !1;
+    }
+
+    fn mip(&self) -> u32 {
+        (self.next().is_some() as u32) << csr::EXTERNAL
+    }
+
+    fn status(&self) -> Option<String> {
+        Some(format!(
+            "plic: pending {:#x} enabled {:#x} threshold {}",
+            self.pending, self.enabled, self.threshold
+        ))
+    }
+
+    fn save(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+/// A bank of 32 LEDs and 32 switches.
+///
+/// - `0x0` LEDs, bit n lights LED n
+/// - `0x4` switches, read only, set from outside the program
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Gpio {
+    base: u32,
+    pub leds: u32,
+    pub switches: u32,
+}
+
+impl Gpio {
+    pub fn new(base: u32) -> Self {
+        Self {
+            base,
+            leds: 0,
+            switches: 0,
+        }
+    }
+}
+
+impl Device for Gpio {
+    fn name(&self) -> &str {
+        "gpio"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        8
+    }
+
+    fn read(&mut self, offset: u32, width: u32) -> u32 {
+        let register = match offset & !3 {
+            0 => self.leds,
+            4 => self.switches,
+            _ => 0,
+        };
+        read_u64(register as u64, offset & 3, width)
+    }
+
+    fn write(&mut self, offset: u32, width: u32, value: u32) {
+        if offset < 4 {
+            let mut leds = self.leds as u64;
+            write_u64(&mut leds, offset, width, value);
+            self.leds = leds as u32;
+        }
+    }
+
+    fn status(&self) -> Option<String> {
+        // LED 0 on the right, like the bits
+        let leds = (0..32)
+            .rev()
+            .map(|i| {
+                if self.leds >> i & 1 == 1 {
+                    '●'
+                } else {
+                    '○'
+                }
+            })
+            .collect::<String>();
+        Some(format!("leds: {}", leds))
+    }
+
+    fn save(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+/// Instructions between redraws of a live bitmap display
+const REFRESH_TICKS: u32 = 10_000;
+
+/// A framebuffer like RARS' Bitmap Display, a word per pixel holding
+/// `0x00RRGGBB`, row by row from the top left.
+///
+/// Drawn in the terminal with half blocks, so each character shows two pixels
+/// stacked on top of each other.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bitmap {
+    base: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u32>,
+    /// Redraw at the top of the terminal as the program runs, rather than
+    /// only when the stepper asks for `status`
+    #[serde(skip)]
+    live: bool,
+    #[serde(skip)]
+    dirty: bool,
+    #[serde(skip)]
+    since_draw: u32,
+}
+
+impl Bitmap {
+    pub fn new(base: u32, width: u32, height: u32, live: bool) -> Self {
+        Self {
+            base,
+            width,
+            height,
+            pixels: vec![0; (width * height) as usize],
+            live,
+            dirty: false,
+            since_draw: 0,
+        }
+    }
+
+    /// Colour of the pixel at `x`, `y`, black outside the display
+    pub fn pixel(&self, x: u32, y: u32) -> u32 {
+        if x < self.width && y < self.height {
+            self.pixels[(y * self.width + x) as usize]
+        } else {
+            0
+        }
+    }
+
+    /// The display as rows of `▀`, coloured with 24-bit escape codes
+    pub fn render(&self) -> String {
+        let rgb = |c: u32| color::Rgb((c >> 16) as u8, (c >> 8) as u8, c as u8);
+        let mut out = String::new();
+        for y in (0..self.height).step_by(2) {
+            for x in 0..self.width {
+                out += &format!(
+                    "{}{}\u{2580}",
+                    color::Fg(rgb(self.pixel(x, y))),
+                    color::Bg(rgb(self.pixel(x, y + 1)))
+                );
+            }
+            out += &format!("{}{}\n", color::Fg(color::Reset), color::Bg(color::Reset));
+        }
+        out
+    }
+
+    /// Draw over the top left of the terminal, leaving the cursor where it was
+    fn draw(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "{}", cursor::Save);
+        for (i, row) in self.render().lines().enumerate() {
+            let _ = write!(stdout, "{}{}", cursor::Goto(1, i as u16 + 1), row);
+        }
+        let _ = write!(stdout, "{}", cursor::Restore).and_then(|_| stdout.flush());
+        self.dirty = false;
+        self.since_draw = 0;
+    }
+}
+
+impl Device for Bitmap {
+    fn name(&self) -> &str {
+        "bitmap"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        self.width * self.height * 4
+    }
+
+    fn read(&mut self, offset: u32, width: u32) -> u32 {
+        read_u64(self.pixels[(offset / 4) as usize] as u64, offset & 3, width)
+    }
+
+    fn write(&mut self, offset: u32, width: u32, value: u32) {
+        let pixel = &mut self.pixels[(offset / 4) as usize];
+        let mut word = *pixel as u64;
+        write_u64(&mut word, offset & 3, width, value);
+        *pixel = word as u32;
+        self.dirty = true;
+    }
+
+    fn tick(&mut self) {
+        self.tick_by(1);
+    }
+
+    fn tick_by(&mut self, ticks: u32) {
+        self.since_draw = self.since_draw.saturating_add(ticks);
+        if self.live && self.dirty && self.since_draw >= REFRESH_TICKS {
+            self.draw();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.live && self.dirty {
+            self.draw();
+        }
+    }
+
+    fn status(&self) -> Option<String> {
+        Some(self.render().trim_end().to_string())
+    }
+
+    fn save(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+/// Instructions it takes the display to show a character, as in RARS
+const TRANSMIT_DELAY: u32 = 5;
+
+/// RARS' keyboard and display MMIO simulator.
+///
+/// - `0x0` receiver control, bit 0 is set when a key is waiting, bit 1 enables
+///   its interrupt
+/// - `0x4` receiver data, reading takes the key and clears the ready bit
+/// - `0x8` transmitter control, bit 0 is set when the display can take a
+///   character, bit 1 enables an interrupt for when it becomes ready again
+/// - `0xc` transmitter data, writing shows the low byte
+#[derive(Serialize, Deserialize)]
+pub struct Keyboard {
+    base: u32,
+    /// Keys waiting to be received
+    pub input: VecDeque<u8>,
+    /// Everything shown on the display so far
+    pub output: Vec<u8>,
+    receiver_interrupts: bool,
+    transmitter_interrupts: bool,
+    /// Instructions until the display is ready again
+    busy: u32,
+    /// The display became ready and hasn't been looked at since
+    transmitted: bool,
+    /// Keystrokes straight from the terminal, for running without pauses
+    #[serde(skip)]
+    terminal: Option<(AsyncReader, RawTerminal<io::Stdout>)>,
+}
+
+impl Keyboard {
+    /// Fed by `key`, from the stepper
+    pub fn new(base: u32) -> Self {
+        Self {
+            base,
+            input: VecDeque::new(),
+            output: Vec::new(),
+            receiver_interrupts: false,
+            transmitter_interrupts: false,
+            busy: 0,
+            transmitted: false,
+            terminal: None,
+        }
+    }
+
+    /// Puts the terminal in raw mode and takes keys as they are pressed,
+    /// the display is written to stdout
+    pub fn live(base: u32) -> io::Result<Self> {
+        let raw = io::stdout().into_raw_mode()?;
+        Ok(Self {
+            terminal: Some((termion::async_stdin(), raw)),
+            ..Self::new(base)
+        })
+    }
+}
+
+impl Debug for Keyboard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyboard")
+            .field("base", &self.base)
+            .field("input", &self.input)
+            .field("output", &self.output)
+            .field("live", &self.terminal.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Device for Keyboard {
+    fn name(&self) -> &str {
+        "keyboard"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        16
+    }
+
+    fn read(&mut self, offset: u32, _: u32) -> u32 {
+        match offset {
+            0 => !self.input.is_empty() as u32 | (self.receiver_interrupts as u32) << 1,
+            4 => self.input.pop_front().map_or(0, |b| b as u32),
+            8 => {
+                self.transmitted = false;
+                (self.busy == 0) as u32 | (self.transmitter_interrupts as u32) << 1
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, _: u32, value: u32) {
+        match offset {
+            0 => self.receiver_interrupts = value & 2 != 0,
+            8 => self.transmitter_interrupts = value & 2 != 0,
+            // Characters sent while it's busy are lost, like in RARS
+            12 if self.busy == 0 => {
+                self.output.push(value as u8);
+                self.busy = TRANSMIT_DELAY;
+                self.transmitted = false;
+                if self.terminal.is_some() {
+                    let mut stdout = io::stdout();
+                    // Raw mode doesn't return to the start of the line by itself
+                    let bytes: &[u8] = match value as u8 {
+                        b'\n' => b"\r\n",
+                        _ => &[value as u8],
+                    };
+                    let _ = stdout.write_all(bytes).and_then(|_| stdout.flush());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        self.tick_by(1);
+    }
+
+    fn tick_by(&mut self, ticks: u32) {
+        if self.busy > 0 {
+            self.busy = self.busy.saturating_sub(ticks);
+            self.transmitted = self.busy == 0;
+        }
+        let Some((keys, _)) = &mut self.terminal else {
+            return;
+        };
+        let mut bytes = Vec::new();
+        let _ = keys.read_to_end(&mut bytes);
+        if bytes.contains(&3) {
+            // Ctrl-C doesn't interrupt in raw mode, leave it first
+            self.terminal = None;
+            std::process::exit(130);
+        }
+        self.input.extend(bytes);
+    }
+
+    fn key(&mut self, byte: u8) {
+        self.input.push_back(byte);
+    }
+
+    fn interrupt(&self) -> bool {
+        (self.receiver_interrupts && !self.input.is_empty())
+            || (self.transmitter_interrupts && self.transmitted)
+    }
+
+    fn status(&self) -> Option<String> {
+        let text = String::from_utf8_lossy(&self.output);
+        let last = text
+            .trim_end_matches('\n')
+            .rsplit('\n')
+            .next()
+            .unwrap_or("");
+        Some(format!(
+            "display: {}  keys: {}",
+            last.escape_debug(),
+            String::from_utf8_lossy(&self.input.iter().copied().collect::<Vec<_>>()).escape_debug()
+        ))
+    }
+
+    fn save(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{env::Env, tests::load_program};
+
+    #[test]
+    fn devices() {
+        use super::{Devices, Gpio, Uart, GPIO_BASE, UART_BASE};
+        use crate::{err::DeviceErr, execution::step};
+
+        let input = "li t0 0x20000000
+            li t1 0x4f
+            sw t1 0(t0)
+            li t1 0x4b
+            sb t1 0(t0)
+            li t0 0x20001000
+            li t1 0b101
+            sw t1 0(t0)
+            li t0 0x0200c000
+            lw a0 -8(t0)";
+        let mut env = Env::new();
+        env.devices = Devices::builtin(false);
+        load_program(&mut env, input);
+        while env.memory.read_u32(env.pc) != 0 {
+            step(&mut env).unwrap();
+        }
+
+        let status = env
+            .devices
+            .iter()
+            .filter_map(|d| d.status())
+            .collect::<Vec<_>>();
+        assert_eq!(status[0], "uart: OK");
+        assert!(status[2].ends_with("●○●"));
+        // mtime counts the instructions before the load
+        assert_eq!(env.get_register(10), 9);
+        assert_eq!(env.load(0x0200bff8, 4), Ok(10));
+
+        assert_eq!(
+            env.devices.add(Box::new(Uart::new(GPIO_BASE + 4, false))),
+            Err(DeviceErr::Overlap("uart".to_string(), "gpio".to_string()))
+        );
+        assert!(env.devices.add(Box::new(Gpio::new(UART_BASE + 12))).is_ok());
+    }
+
+    #[test]
+    fn bitmap() {
+        use crate::device::{Bitmap, Device};
+
+        let mut bitmap = Bitmap::new(0x10010000, 2, 2, false);
+        assert_eq!(bitmap.size(), 16);
+        bitmap.write(4, 4, 0x00ff8000);
+        // A byte store only changes the blue of the bottom left pixel
+        bitmap.write(8, 1, 0x40);
+        assert_eq!(bitmap.read(4, 4), 0x00ff8000);
+        assert_eq!(bitmap.pixel(0, 1), 0x40);
+
+        // Two rows of pixels fit in one row of half blocks
+        let render = bitmap.render();
+        assert_eq!(render.lines().count(), 1);
+        assert!(render.contains("\x1b[38;2;255;128;0m\x1b[48;2;0;0;0m\u{2580}"));
+    }
+
+    #[test]
+    fn keyboard() {
+        use crate::device::{Device, Keyboard, KEYBOARD_BASE};
+
+        let mut keyboard = Keyboard::new(KEYBOARD_BASE);
+        assert_eq!(keyboard.read(0, 4), 0);
+        keyboard.write(0, 4, 2);
+        keyboard.key(b'w');
+        assert_eq!(keyboard.read(0, 4), 3);
+        assert!(keyboard.interrupt());
+        assert_eq!(keyboard.read(4, 4), b'w' as u32);
+        assert_eq!(keyboard.read(0, 4), 2);
+        assert!(!keyboard.interrupt());
+
+        // The display is busy for a few instructions after each character
+        keyboard.write(12, 4, b'A' as u32);
+        keyboard.write(12, 4, b'B' as u32);
+        assert_eq!(keyboard.read(8, 4), 0);
+        keyboard.write(8, 4, 2);
+        (0..5).for_each(|_| keyboard.tick());
+        assert!(keyboard.interrupt());
+        assert_eq!(keyboard.read(8, 4), 3);
+        assert!(!keyboard.interrupt());
+        assert_eq!(keyboard.output, b"A");
+    }
+
+    #[test]
+    fn interrupts() {
+        use super::{Devices, Keyboard, KEYBOARD_BASE, PLIC_BASE};
+        use crate::{csr, execution::step};
+
+        // The timer goes off while the program spins, the handler turns it off again
+        let input = "la t0 handler
+            csrw mtvec t0
+            li t1 0x02004000
+            li t2 12
+            sw t2 0(t1)
+            sw zero 4(t1)
+            li t0 0x80
+            csrs mie t0
+            csrsi mstatus 8
+            loop:
+            addi a1 a1 1
+            j loop
+            handler:
+            csrr a0 mcause
+            li t2 -1
+            sw t2 4(t1)
+            mret";
+        let mut env = Env::new();
+        env.devices = Devices::builtin(false);
+        load_program(&mut env, input);
+        (0..40).for_each(|_| step(&mut env).unwrap());
+        assert_eq!(env.get_register(10), csr::INTERRUPT | csr::TIMER);
+        assert_eq!(env.csrs.mstatus & csr::MSTATUS_MIE, csr::MSTATUS_MIE);
+        assert!(env.get_register(11) > 10);
+
+        // The keyboard is source 5, it only interrupts once enabled and above the threshold
+        let mut devices = Devices::builtin(false);
+        devices.add(Box::new(Keyboard::new(KEYBOARD_BASE))).unwrap();
+        let mut write = |addr, value| {
+            let (device, offset) = devices.at(addr).unwrap();
+            device.write(offset, 4, value);
+        };
+        write(KEYBOARD_BASE, 2);
+        write(PLIC_BASE + 0x2000, 1 << 5);
+        devices.key(b'a');
+        devices.tick();
+        assert_eq!(devices.mip(), 0);
+        let (plic, _) = devices.at(PLIC_BASE).unwrap();
+        plic.write(5 * 4, 4, 1);
+        assert_eq!(devices.mip(), 1 << csr::EXTERNAL);
+        let (plic, _) = devices.at(PLIC_BASE).unwrap();
+        assert_eq!(plic.read(0x200004, 4), 5);
+        assert_eq!(plic.read(0x200004, 4), 0);
+        // Still asking, but claimed until completed
+        devices.tick();
+        assert_eq!(devices.mip(), 0);
+        let (plic, _) = devices.at(PLIC_BASE).unwrap();
+        plic.write(0x200004, 4, 5);
+        devices.tick();
+        assert_eq!(devices.mip(), 1 << csr::EXTERNAL);
+    }
+}
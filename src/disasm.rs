@@ -0,0 +1,437 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+};
+
+use itertools::Itertools;
+
+use crate::{
+    csr,
+    elf::{self, Elf, Shndx},
+    err::ElfErr,
+    instructions::{instruction, kind::Kind, Arg},
+    parser::{Loc, Token},
+    srcmap::SourceMap,
+};
+
+/// ABI names of the integer registers, by number
+pub const REGISTERS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// ABI names of the floating point registers, by number
+pub const FREGISTERS: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Register(usize),
+    FRegister(usize),
+    Immediate(i32),
+    /// The 20 bit field of `lui` and `auipc`, shown in hex like objdump does
+    Upper(u32),
+    /// offset(register)
+    Memory(i32, usize),
+    /// Absolute address of a branch or jump target
+    Target(u32),
+    Csr(u32),
+}
+
+/// A decoded instruction, possibly shown as the pseudo-instruction it stands for
+#[derive(Debug, Clone, PartialEq)]
+pub struct Op {
+    pub name: String,
+    pub args: Vec<Operand>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Label(String),
+    Op {
+        addr: u32,
+        word: u32,
+        op: Op,
+    },
+    /// A word that isn't a known instruction
+    Word {
+        addr: u32,
+        word: u32,
+    },
+}
+
+/// Instructions of a piece of code, with labels for its symbols and branch targets
+#[derive(Debug, Clone, Default)]
+pub struct Disassembly {
+    pub lines: Vec<Line>,
+    pub labels: HashMap<u32, String>,
+    /// Shown as comments where the source line changes
+    pub source: SourceMap,
+}
+
+impl Op {
+    /// Decode the word at `addr`, shown as a pseudo-instruction where possible
+    pub fn decode(addr: u32, word: u32) -> Option<Self> {
+        Some(Self::decode_exact(addr, word)?.into_pseudo())
+    }
+
+    /// Decode the word at `addr` as exactly the instruction it is
+    pub fn decode_exact(addr: u32, word: u32) -> Option<Self> {
+        let (kind, name) = Kind::decode(word)?;
+        let regs = kind.get_regs()?;
+        let imm = kind.get_imm().unwrap_or(0);
+        let float = name.starts_with('f') && name != "fence";
+
+        let args = instruction(&name)?
+            .1
+            .into_iter()
+            .map(|arg| match arg {
+                // Conversions from integers read their source from an integer register
+                Arg::Register(1) if name == "fcvt.s.w" || name == "fmv.w.x" => {
+                    Operand::Register(regs[1])
+                }
+                Arg::Register(id) if float => Operand::FRegister(regs[id]),
+                Arg::Register(id) => Operand::Register(regs[id]),
+                Arg::Memory => Operand::Memory(imm as i32, regs[1]),
+                Arg::Csr => Operand::Csr(imm & 0xfff),
+                Arg::Uimm => Operand::Immediate(regs[1] as i32),
+                Arg::Immediate | Arg::Symbol => match kind {
+                    Kind::B(_) | Kind::J(_) => Operand::Target(addr.wrapping_add(imm)),
+                    Kind::U(_) => Operand::Upper(imm >> 12),
+                    _ => Operand::Immediate(imm as i32),
+                },
+            })
+            .collect();
+
+        Some(Op { name, args })
+    }
+
+    /// The pseudo-instruction this op is the only expansion of, if any
+    fn into_pseudo(self) -> Self {
+        use Operand::*;
+
+        let (name, args) = match (self.name.as_str(), self.args.as_slice()) {
+            ("addi", [Register(0), Register(0), Immediate(0)]) => ("nop", vec![]),
+            ("addi", [rd, Register(0), imm]) => ("li", vec![rd.clone(), imm.clone()]),
+            ("addi", [rd, rs, Immediate(0)]) => ("mv", vec![rd.clone(), rs.clone()]),
+            ("jal", [Register(0), target]) => ("j", vec![target.clone()]),
+            ("jalr", [Register(0), Register(1), Immediate(0)]) => ("ret", vec![]),
+            ("jalr", [Register(0), rs, Immediate(0)]) => ("jr", vec![rs.clone()]),
+            ("beq", [rs, Register(0), target]) => ("beqz", vec![rs.clone(), target.clone()]),
+            ("bne", [rs, Register(0), target]) => ("bnez", vec![rs.clone(), target.clone()]),
+            ("csrrs", [rd, csr, Register(0)]) => ("csrr", vec![rd.clone(), csr.clone()]),
+            ("csrrw", [Register(0), csr, rs]) => ("csrw", vec![csr.clone(), rs.clone()]),
+            ("csrrs", [Register(0), csr, rs]) => ("csrs", vec![csr.clone(), rs.clone()]),
+            ("csrrc", [Register(0), csr, rs]) => ("csrc", vec![csr.clone(), rs.clone()]),
+            ("csrrwi", [Register(0), csr, imm]) => ("csrwi", vec![csr.clone(), imm.clone()]),
+            ("csrrsi", [Register(0), csr, imm]) => ("csrsi", vec![csr.clone(), imm.clone()]),
+            ("csrrci", [Register(0), csr, imm]) => ("csrci", vec![csr.clone(), imm.clone()]),
+            _ => return self,
+        };
+
+        Op {
+            name: name.to_string(),
+            args,
+        }
+    }
+
+    /// The op as the assembler would have parsed it, with targets named by `labels`
+    /// and registers by their ABI names
+    pub fn to_token(&self, addr: u32, labels: &HashMap<u32, String>) -> Token {
+        let args = self
+            .args
+            .iter()
+            .map(|arg| {
+                let token = match arg {
+                    Operand::Register(r) => Token::Register(REGISTERS[*r].to_string()),
+                    Operand::FRegister(r) => Token::Register(FREGISTERS[*r].to_string()),
+                    Operand::Immediate(imm) => Token::Immediate(*imm as u32),
+                    Operand::Upper(imm) => Token::Immediate(imm << 12),
+                    Operand::Memory(imm, r) => Token::Memory(
+                        Box::new(Token::Immediate(*imm as u32)),
+                        Some(Box::new(Token::Register(REGISTERS[*r].to_string()))),
+                    ),
+                    Operand::Target(target) => match labels.get(target) {
+                        Some(label) => Token::Symbol(label.clone()),
+                        None => Token::Immediate(target.wrapping_sub(addr)),
+                    },
+                    Operand::Csr(number) => match csr::name(*number) {
+                        Some(name) => Token::Symbol(name.to_string()),
+                        None => Token::Immediate(*number),
+                    },
+                };
+                (token, Loc::default())
+            })
+            .collect();
+
+        Token::Op(self.name.clone(), args)
+    }
+
+    /// Format the op, naming targets by `labels` where possible
+    pub fn format(&self, addr: u32, labels: &HashMap<u32, String>) -> String {
+        let args = self
+            .args
+            .iter()
+            .map(|arg| match arg {
+                Operand::Register(r) => REGISTERS[*r].to_string(),
+                Operand::FRegister(r) => FREGISTERS[*r].to_string(),
+                Operand::Immediate(imm) => imm.to_string(),
+                Operand::Upper(imm) => format!("{:#x}", imm),
+                Operand::Memory(imm, r) => format!("{}({})", imm, REGISTERS[*r]),
+                Operand::Target(target) => match labels.get(target) {
+                    Some(label) => label.clone(),
+                    None => (target.wrapping_sub(addr) as i32).to_string(),
+                },
+                Operand::Csr(number) => {
+                    csr::name(*number).map_or_else(|| format!("{:#x}", number), str::to_string)
+                }
+            })
+            .join(", ");
+
+        if args.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} {}", self.name, args)
+        }
+    }
+}
+
+impl Disassembly {
+    /// Disassemble little-endian `code` loaded at `base`.
+    ///
+    /// `symbols` name addresses, any other branch target gets an `L<n>` label.
+    pub fn new(code: &[u8], base: u32, symbols: &[(String, u32)]) -> Self {
+        let ops = code
+            .chunks(4)
+            .enumerate()
+            .map(|(i, bytes)| {
+                let addr = base.wrapping_add(i as u32 * 4);
+                let mut word = [0; 4];
+                word[..bytes.len()].copy_from_slice(bytes);
+                let word = u32::from_le_bytes(word);
+                (addr, word, Op::decode(addr, word))
+            })
+            .collect::<Vec<_>>();
+
+        let mut labels: HashMap<u32, String> = HashMap::new();
+        for (name, addr) in symbols {
+            let label = labels.entry(*addr).or_insert_with(|| name.clone());
+            // Assembler-local `.L` names only show where nothing else names the address
+            if label.starts_with(".L") && !name.starts_with(".L") {
+                *label = name.clone();
+            }
+        }
+        let end = base.wrapping_add(ops.len() as u32 * 4);
+        let targets = ops
+            .iter()
+            .filter_map(|(_, _, op)| op.as_ref())
+            .flat_map(|op| op.args.iter())
+            .filter_map(|arg| match arg {
+                Operand::Target(target) if (base..end).contains(target) => Some(*target),
+                _ => None,
+            })
+            .filter(|target| !labels.contains_key(target))
+            .sorted()
+            .dedup()
+            .collect::<Vec<_>>();
+        for (i, target) in targets.into_iter().enumerate() {
+            labels.insert(target, format!("L{}", i));
+        }
+
+        let mut lines = Vec::new();
+        for (addr, word, op) in ops {
+            if let Some(label) = labels.get(&addr) {
+                lines.push(Line::Label(label.clone()));
+            }
+            lines.push(match op {
+                Some(op) => Line::Op { addr, word, op },
+                None => Line::Word { addr, word },
+            });
+        }
+        // Symbols can point just past the end, like a label on the last line
+        if let Some(label) = labels.get(&end) {
+            lines.push(Line::Label(label.clone()));
+        }
+
+        Self {
+            lines,
+            labels,
+            source: SourceMap::default(),
+        }
+    }
+
+    /// Disassemble the executable sections of an ELF file
+    pub fn from_elf(elf: &Elf) -> Self {
+        let mut disassembly = Self::default();
+
+        for (i, section) in elf.sections.iter().enumerate() {
+            if section.flags & elf::SHF_EXECINSTR == 0 || section.kind == elf::SHT_NOBITS {
+                continue;
+            }
+            let symbols = elf
+                .symbols
+                .iter()
+                .filter(|s| s.section == Shndx::Index(i) && s.kind != elf::STT_SECTION)
+                // Mapping symbols only mark the start of code and data
+                .filter(|s| !s.name.is_empty() && !s.name.starts_with('$'))
+                .filter_map(|s| Some((s.name.clone(), elf.symbol_address(s)?)))
+                .collect::<Vec<_>>();
+
+            let part = Self::new(&section.data, section.addr, &symbols);
+            disassembly.lines.extend(part.lines);
+            disassembly.labels.extend(part.labels);
+        }
+        disassembly.source = SourceMap::from_elf(elf).unwrap_or_default();
+
+        disassembly
+    }
+
+    /// Read an ELF file, `test.bin` style hex words or a raw little-endian binary
+    pub fn from_bytes(bytes: &[u8], base: u32) -> Result<Self, ElfErr> {
+        if bytes.starts_with(b"\x7fELF") {
+            return Ok(Self::from_elf(&Elf::parse(bytes)?));
+        }
+
+        let regions = match std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|t| read_hex(t, base))
+        {
+            Some(regions) => regions,
+            None => return Ok(Self::new(bytes, base, &[])),
+        };
+
+        let mut disassembly = Self::default();
+        for (addr, words) in regions {
+            let code = words
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect::<Vec<_>>();
+            let part = Self::new(&code, addr, &[]);
+            disassembly.lines.extend(part.lines);
+            disassembly.labels.extend(part.labels);
+        }
+        Ok(disassembly)
+    }
+}
+
+/// Hex words one per line, with `$readmemh` style `@` word addresses
+fn read_hex(text: &str, base: u32) -> Option<Vec<(u32, Vec<u32>)>> {
+    let mut regions = vec![(base, Vec::new())];
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(addr) = line.strip_prefix('@') {
+            regions.push((u32::from_str_radix(addr, 16).ok()? * 4, Vec::new()));
+        } else if line.len() <= 8 {
+            regions
+                .last_mut()?
+                .1
+                .push(u32::from_str_radix(line, 16).ok()?);
+        } else {
+            return None;
+        }
+    }
+    regions.retain(|(_, words)| !words.is_empty());
+    (!regions.is_empty()).then_some(regions)
+}
+
+impl Disassembly {
+    /// Like the `Display` output, with the address and word of every op in front
+    pub fn with_addresses(&self) -> String {
+        let comments = self.source_comments();
+        self.lines
+            .iter()
+            .zip(comments)
+            .map(|(line, comment)| match line {
+                Line::Label(label) => format!("{}:", label),
+                Line::Op { addr, word, op } => format!(
+                    "    {:08x}: {:08x}    {}{}",
+                    addr,
+                    word,
+                    op.format(*addr, &self.labels),
+                    comment
+                ),
+                Line::Word { addr, word } => {
+                    format!("    {:08x}: {:08x}    .word {:#010x}", addr, word, word)
+                }
+            })
+            .map(|line| line + "\n")
+            .collect()
+    }
+
+    /// `  # file:line:column` for each line where the source location changes
+    fn source_comments(&self) -> Vec<String> {
+        let mut previous = None;
+        self.lines
+            .iter()
+            .map(|line| match line {
+                Line::Op { addr, .. } => {
+                    let source = self.source.describe(*addr);
+                    if source.is_none() || source == previous {
+                        return String::new();
+                    }
+                    previous = source.clone();
+                    format!("  # {}", source.unwrap())
+                }
+                _ => String::new(),
+            })
+            .collect()
+    }
+}
+
+impl Display for Disassembly {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (line, comment) in self.lines.iter().zip(self.source_comments()) {
+            match line {
+                Line::Label(label) => writeln!(f, "{}:", label)?,
+                Line::Op { addr, op, .. } => {
+                    writeln!(f, "    {}{}", op.format(*addr, &self.labels), comment)?
+                }
+                Line::Word { word, .. } => writeln!(f, "    .word {:#010x}", word)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn disassemble() {
+        use crate::disasm::{Disassembly, Op};
+
+        let words: [u32; 5] = [
+            // addi x0, x0, 0
+            0b00000000000000000000000000010011,
+            // beq a0, x0, 8
+            0b00000000000001010000010001100011,
+            // addi sp, sp, -16
+            0b11111111000000010000000100010011,
+            // jal x0, -8
+            0b11111111100111111111000001101111,
+            // jalr x0, ra, 0
+            0b00000000000000001000000001100111,
+        ];
+        let code = words
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            Disassembly::new(&code, 0, &[("start".to_string(), 0)]).to_string(),
+            "start:\n    nop\nL0:\n    beqz a0, L1\n    addi sp, sp, -16\nL1:\n    j L0\n    ret\n"
+        );
+
+        // Assembler-local names give way to the program's own
+        let symbols = [(".Lpcrel_hi0".to_string(), 0), ("start".to_string(), 0)];
+        assert!(Disassembly::new(&code, 0, &symbols)
+            .to_string()
+            .starts_with("start:\n"));
+
+        // lui and auipc show the field itself, as objdump does
+        let op = Op::decode(0, 0x10000517).unwrap();
+        assert_eq!(op.format(0, &Default::default()), "auipc a0, 0x10000");
+    }
+}
@@ -0,0 +1,666 @@
+use std::collections::HashMap;
+
+use crate::err::ElfErr;
+
+pub const ET_REL: u16 = 1;
+pub const ET_EXEC: u16 = 2;
+pub const EM_RISCV: u16 = 243;
+
+pub const SHT_NULL: u32 = 0;
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_REL: u32 = 9;
+
+pub const SHF_WRITE: u32 = 0x1;
+pub const SHF_ALLOC: u32 = 0x2;
+pub const SHF_EXECINSTR: u32 = 0x4;
+
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+pub const STB_WEAK: u8 = 2;
+
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+pub const STT_FILE: u8 = 4;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const SHN_COMMON: u16 = 0xfff2;
+
+pub const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+pub const R_RISCV_NONE: u32 = 0;
+pub const R_RISCV_32: u32 = 1;
+pub const R_RISCV_BRANCH: u32 = 16;
+pub const R_RISCV_JAL: u32 = 17;
+pub const R_RISCV_CALL: u32 = 18;
+pub const R_RISCV_CALL_PLT: u32 = 19;
+pub const R_RISCV_PCREL_HI20: u32 = 23;
+pub const R_RISCV_PCREL_LO12_I: u32 = 24;
+pub const R_RISCV_PCREL_LO12_S: u32 = 25;
+pub const R_RISCV_HI20: u32 = 26;
+pub const R_RISCV_LO12_I: u32 = 27;
+pub const R_RISCV_LO12_S: u32 = 28;
+pub const R_RISCV_ALIGN: u32 = 43;
+pub const R_RISCV_RELAX: u32 = 51;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+const RELA_SIZE: usize = 12;
+const PAGE_SIZE: usize = 0x1000;
+
+/// Name of a relocation type, as printed by binutils
+pub fn reloc_name(kind: u32) -> String {
+    match kind {
+        R_RISCV_NONE => "R_RISCV_NONE".to_string(),
+        R_RISCV_32 => "R_RISCV_32".to_string(),
+        R_RISCV_BRANCH => "R_RISCV_BRANCH".to_string(),
+        R_RISCV_JAL => "R_RISCV_JAL".to_string(),
+        R_RISCV_CALL => "R_RISCV_CALL".to_string(),
+        R_RISCV_CALL_PLT => "R_RISCV_CALL_PLT".to_string(),
+        R_RISCV_PCREL_HI20 => "R_RISCV_PCREL_HI20".to_string(),
+        R_RISCV_PCREL_LO12_I => "R_RISCV_PCREL_LO12_I".to_string(),
+        R_RISCV_PCREL_LO12_S => "R_RISCV_PCREL_LO12_S".to_string(),
+        R_RISCV_HI20 => "R_RISCV_HI20".to_string(),
+        R_RISCV_LO12_I => "R_RISCV_LO12_I".to_string(),
+        R_RISCV_LO12_S => "R_RISCV_LO12_S".to_string(),
+        R_RISCV_ALIGN => "R_RISCV_ALIGN".to_string(),
+        R_RISCV_RELAX => "R_RISCV_RELAX".to_string(),
+        other => format!("R_RISCV_{}", other),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u32,
+    pub addr: u32,
+    pub align: u32,
+    /// Zero filled for `SHT_NOBITS` sections
+    pub data: Vec<u8>,
+}
+
+impl Section {
+    pub fn is_alloc(&self) -> bool {
+        self.flags & SHF_ALLOC != 0
+    }
+}
+
+/// Where a symbol is defined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shndx {
+    Undef,
+    Abs,
+    Common,
+    /// Index into `Elf::sections`
+    Index(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+    pub bind: u8,
+    pub kind: u8,
+    pub section: Shndx,
+}
+
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    /// Index into `Elf::sections` of the section being patched
+    pub section: usize,
+    pub offset: u32,
+    pub kind: u32,
+    /// Index into `Elf::symbols`
+    pub symbol: Option<usize>,
+    pub addend: i32,
+}
+
+/// A `PT_LOAD` program header, read back from an executable
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub vaddr: u32,
+    pub data: Vec<u8>,
+    pub mem_size: u32,
+    pub flags: u32,
+}
+
+/// An ELF32 little-endian RISC-V file.
+///
+/// The symbol, string and relocation tables are not kept in `sections`,
+/// they are rebuilt from `symbols` and `relocations` when writing.
+/// Program headers are generated from the allocated sections of executables.
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub kind: u16,
+    pub flags: u32,
+    pub entry: u32,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+    pub segments: Vec<Segment>,
+}
+
+impl Elf {
+    pub fn new(kind: u16) -> Self {
+        Self {
+            kind,
+            flags: 0,
+            entry: 0,
+            sections: Vec::new(),
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+            segments: Vec::new(),
+        }
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .find(|s| s.name == name && s.section != Shndx::Undef)
+    }
+
+    /// Address of a defined symbol, taking the section it lives in into account
+    /// for relocatable files
+    pub fn symbol_address(&self, symbol: &Symbol) -> Option<u32> {
+        match symbol.section {
+            Shndx::Undef | Shndx::Common => None,
+            Shndx::Abs => Some(symbol.value),
+            Shndx::Index(i) => Some(self.sections[i].addr.wrapping_add(symbol.value)),
+        }
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut shstrtab = StringTable::new();
+        let mut strtab = StringTable::new();
+
+        // Locals have to come before globals in the symbol table
+        let order = (0..self.symbols.len())
+            .filter(|&i| self.symbols[i].bind == STB_LOCAL)
+            .chain((0..self.symbols.len()).filter(|&i| self.symbols[i].bind != STB_LOCAL))
+            .collect::<Vec<_>>();
+        let mut symbol_index = vec![0; self.symbols.len()];
+        for (new, &old) in order.iter().enumerate() {
+            symbol_index[old] = new + 1;
+        }
+        let first_global = 1 + order
+            .iter()
+            .take_while(|&&i| self.symbols[i].bind == STB_LOCAL)
+            .count();
+
+        // Section 0 is the null section, user sections follow
+        let mut headers: Vec<SectionHeader> = vec![SectionHeader::default()];
+        let mut contents: Vec<Vec<u8>> = vec![Vec::new()];
+        for section in self.sections.iter() {
+            headers.push(SectionHeader {
+                name: shstrtab.add(&section.name),
+                kind: section.kind,
+                flags: section.flags,
+                addr: section.addr,
+                size: section.data.len() as u32,
+                align: section.align.max(1),
+                ..Default::default()
+            });
+            contents.push(if section.kind == SHT_NOBITS {
+                Vec::new()
+            } else {
+                section.data.clone()
+            });
+        }
+
+        let symtab_index = headers.len() + self.relocated_sections().len();
+        for target in self.relocated_sections() {
+            let mut data = Vec::new();
+            for reloc in self.relocations.iter().filter(|r| r.section == target) {
+                let symbol = reloc.symbol.map(|s| symbol_index[s]).unwrap_or(0) as u32;
+                data.extend(reloc.offset.to_le_bytes());
+                data.extend(((symbol << 8) | (reloc.kind & 0xff)).to_le_bytes());
+                data.extend(reloc.addend.to_le_bytes());
+            }
+            headers.push(SectionHeader {
+                name: shstrtab.add(&format!(".rela{}", self.sections[target].name)),
+                kind: SHT_RELA,
+                flags: 0x40, // SHF_INFO_LINK
+                size: data.len() as u32,
+                link: symtab_index as u32,
+                info: target as u32 + 1,
+                align: 4,
+                entsize: RELA_SIZE as u32,
+                ..Default::default()
+            });
+            contents.push(data);
+        }
+
+        let mut symtab = vec![0; SYM_SIZE];
+        for &i in order.iter() {
+            let symbol = &self.symbols[i];
+            let shndx = match symbol.section {
+                Shndx::Undef => SHN_UNDEF,
+                Shndx::Abs => SHN_ABS,
+                Shndx::Common => SHN_COMMON,
+                Shndx::Index(i) => i as u16 + 1,
+            };
+            symtab.extend(strtab.add(&symbol.name).to_le_bytes());
+            symtab.extend(symbol.value.to_le_bytes());
+            symtab.extend(symbol.size.to_le_bytes());
+            symtab.push((symbol.bind << 4) | (symbol.kind & 0xf));
+            symtab.push(0);
+            symtab.extend(shndx.to_le_bytes());
+        }
+        headers.push(SectionHeader {
+            name: shstrtab.add(".symtab"),
+            kind: SHT_SYMTAB,
+            size: symtab.len() as u32,
+            link: symtab_index as u32 + 1,
+            info: first_global as u32,
+            align: 4,
+            entsize: SYM_SIZE as u32,
+            ..Default::default()
+        });
+        contents.push(symtab);
+
+        headers.push(SectionHeader {
+            name: shstrtab.add(".strtab"),
+            kind: SHT_STRTAB,
+            size: strtab.0.len() as u32,
+            align: 1,
+            ..Default::default()
+        });
+        contents.push(strtab.0);
+
+        let shstrtab_name = shstrtab.add(".shstrtab");
+        headers.push(SectionHeader {
+            name: shstrtab_name,
+            kind: SHT_STRTAB,
+            size: shstrtab.0.len() as u32,
+            align: 1,
+            ..Default::default()
+        });
+        contents.push(shstrtab.0);
+
+        // Lay out the file: header, program headers, section contents, section headers
+        let loads = if self.kind == ET_EXEC {
+            (1..headers.len())
+                .filter(|&i| headers[i].flags & SHF_ALLOC != 0 && headers[i].size > 0)
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
+        let mut out = vec![0; EHDR_SIZE + PHDR_SIZE * loads.len()];
+        for (i, header) in headers.iter_mut().enumerate().skip(1) {
+            let align = if loads.contains(&i) {
+                // Keep loadable sections congruent to their address modulo the page size
+                let misalign = (header.addr as usize % PAGE_SIZE + PAGE_SIZE
+                    - out.len() % PAGE_SIZE)
+                    % PAGE_SIZE;
+                out.resize(out.len() + misalign, 0);
+                1
+            } else {
+                header.align.max(1) as usize
+            };
+            out.resize(out.len().next_multiple_of(align), 0);
+            header.offset = out.len() as u32;
+            out.extend(&contents[i]);
+        }
+        out.resize(out.len().next_multiple_of(4), 0);
+        let shoff = out.len();
+        for header in headers.iter() {
+            out.extend(header.to_bytes());
+        }
+
+        let phdrs = loads
+            .iter()
+            .flat_map(|&i| {
+                let header = &headers[i];
+                let mut flags = PF_R;
+                if header.flags & SHF_WRITE != 0 {
+                    flags |= PF_W;
+                }
+                if header.flags & SHF_EXECINSTR != 0 {
+                    flags |= PF_X;
+                }
+                let file_size = if header.kind == SHT_NOBITS {
+                    0
+                } else {
+                    header.size
+                };
+                [
+                    PT_LOAD,
+                    header.offset,
+                    header.addr,
+                    header.addr,
+                    file_size,
+                    header.size,
+                    flags,
+                    PAGE_SIZE as u32,
+                ]
+                .into_iter()
+                .flat_map(u32::to_le_bytes)
+                .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        out[EHDR_SIZE..EHDR_SIZE + phdrs.len()].copy_from_slice(&phdrs);
+
+        let mut ehdr = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0];
+        ehdr.resize(16, 0);
+        ehdr.extend(self.kind.to_le_bytes());
+        ehdr.extend(EM_RISCV.to_le_bytes());
+        ehdr.extend(1u32.to_le_bytes());
+        ehdr.extend(self.entry.to_le_bytes());
+        ehdr.extend(
+            (if loads.is_empty() {
+                0
+            } else {
+                EHDR_SIZE as u32
+            })
+            .to_le_bytes(),
+        );
+        ehdr.extend((shoff as u32).to_le_bytes());
+        ehdr.extend(self.flags.to_le_bytes());
+        ehdr.extend((EHDR_SIZE as u16).to_le_bytes());
+        ehdr.extend((PHDR_SIZE as u16).to_le_bytes());
+        ehdr.extend((loads.len() as u16).to_le_bytes());
+        ehdr.extend((SHDR_SIZE as u16).to_le_bytes());
+        ehdr.extend((headers.len() as u16).to_le_bytes());
+        ehdr.extend((headers.len() as u16 - 1).to_le_bytes());
+        out[..EHDR_SIZE].copy_from_slice(&ehdr);
+
+        out
+    }
+
+    /// Indexes of the sections that have relocations against them
+    fn relocated_sections(&self) -> Vec<usize> {
+        (0..self.sections.len())
+            .filter(|&i| self.relocations.iter().any(|r| r.section == i))
+            .collect()
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, ElfErr> {
+        let header = slice(bytes, 0, EHDR_SIZE)?;
+        if header[..4] != [0x7f, b'E', b'L', b'F'] {
+            return Err(ElfErr::NotElf);
+        }
+        if header[4] != 1 {
+            return Err(ElfErr::Unsupported(
+                "only 32 bit files are supported".to_string(),
+            ));
+        }
+        if header[5] != 1 {
+            return Err(ElfErr::Unsupported(
+                "only little endian files are supported".to_string(),
+            ));
+        }
+        let machine = u16_at(header, 18);
+        if machine != EM_RISCV {
+            return Err(ElfErr::Unsupported(format!(
+                "machine {} is not RISC-V",
+                machine
+            )));
+        }
+
+        let mut elf = Elf::new(u16_at(header, 16));
+        elf.entry = u32_at(header, 24);
+        elf.flags = u32_at(header, 36);
+        let phoff = u32_at(header, 28) as usize;
+        let phnum = u16_at(header, 44) as usize;
+        let shoff = u32_at(header, 32) as usize;
+        let shnum = u16_at(header, 48) as usize;
+        let shstrndx = u16_at(header, 50) as usize;
+
+        for i in 0..phnum {
+            let ph = slice(bytes, phoff + i * PHDR_SIZE, PHDR_SIZE)?;
+            if u32_at(ph, 0) != PT_LOAD {
+                continue;
+            }
+            elf.segments.push(Segment {
+                vaddr: u32_at(ph, 8),
+                data: slice(bytes, u32_at(ph, 4) as usize, u32_at(ph, 16) as usize)?.to_vec(),
+                mem_size: u32_at(ph, 20),
+                flags: u32_at(ph, 24),
+            });
+        }
+
+        let headers = (0..shnum)
+            .map(|i| slice(bytes, shoff + i * SHDR_SIZE, SHDR_SIZE).map(SectionHeader::from_bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+        let contents = |header: &SectionHeader| -> Result<&[u8], ElfErr> {
+            if header.kind == SHT_NOBITS {
+                Ok(&[])
+            } else {
+                slice(bytes, header.offset as usize, header.size as usize)
+            }
+        };
+        let shstrtab = match headers.get(shstrndx) {
+            Some(header) => contents(header)?,
+            None => &[],
+        };
+
+        // File section index -> index into `elf.sections`
+        let mut index = HashMap::new();
+        for (i, header) in headers.iter().enumerate() {
+            if matches!(
+                header.kind,
+                SHT_NULL | SHT_SYMTAB | SHT_STRTAB | SHT_RELA | SHT_REL
+            ) {
+                continue;
+            }
+            index.insert(i, elf.sections.len());
+            elf.sections.push(Section {
+                name: string_at(shstrtab, header.name as usize),
+                kind: header.kind,
+                flags: header.flags,
+                addr: header.addr,
+                align: header.align,
+                data: if header.kind == SHT_NOBITS {
+                    vec![0; header.size as usize]
+                } else {
+                    contents(header)?.to_vec()
+                },
+            });
+        }
+
+        // Symbol 0 is the null symbol, which isn't kept
+        if let Some(symtab) = headers.iter().find(|h| h.kind == SHT_SYMTAB) {
+            let data = contents(symtab)?;
+            let strtab = match headers.get(symtab.link as usize) {
+                Some(header) => contents(header)?,
+                None => &[],
+            };
+            for entry in data.chunks_exact(SYM_SIZE).skip(1) {
+                let shndx = u16_at(entry, 14);
+                elf.symbols.push(Symbol {
+                    name: string_at(strtab, u32_at(entry, 0) as usize),
+                    value: u32_at(entry, 4),
+                    size: u32_at(entry, 8),
+                    bind: entry[12] >> 4,
+                    kind: entry[12] & 0xf,
+                    section: match shndx {
+                        SHN_UNDEF => Shndx::Undef,
+                        SHN_ABS => Shndx::Abs,
+                        SHN_COMMON => Shndx::Common,
+                        i => index
+                            .get(&(i as usize))
+                            .map_or(Shndx::Undef, |&i| Shndx::Index(i)),
+                    },
+                });
+            }
+        }
+
+        for header in headers.iter().filter(|h| h.kind == SHT_RELA) {
+            let Some(&section) = index.get(&(header.info as usize)) else {
+                continue;
+            };
+            for entry in contents(header)?.chunks_exact(RELA_SIZE) {
+                let info = u32_at(entry, 4);
+                elf.relocations.push(Relocation {
+                    section,
+                    offset: u32_at(entry, 0),
+                    kind: info & 0xff,
+                    symbol: (info >> 8).checked_sub(1).map(|s| s as usize),
+                    addend: u32_at(entry, 8) as i32,
+                });
+            }
+        }
+
+        Ok(elf)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    info: u32,
+    align: u32,
+    entsize: u32,
+}
+
+impl SectionHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.name,
+            self.kind,
+            self.flags,
+            self.addr,
+            self.offset,
+            self.size,
+            self.link,
+            self.info,
+            self.align,
+            self.entsize,
+        ]
+        .into_iter()
+        .flat_map(u32::to_le_bytes)
+        .collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            name: u32_at(bytes, 0),
+            kind: u32_at(bytes, 4),
+            flags: u32_at(bytes, 8),
+            addr: u32_at(bytes, 12),
+            offset: u32_at(bytes, 16),
+            size: u32_at(bytes, 20),
+            link: u32_at(bytes, 24),
+            info: u32_at(bytes, 28),
+            align: u32_at(bytes, 32),
+            entsize: u32_at(bytes, 36),
+        }
+    }
+}
+
+/// A string table under construction, deduplicating repeated names
+struct StringTable(Vec<u8>, HashMap<String, u32>);
+
+impl StringTable {
+    fn new() -> Self {
+        Self(vec![0], HashMap::new())
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        if name.is_empty() {
+            return 0;
+        }
+        if let Some(&offset) = self.1.get(name) {
+            return offset;
+        }
+        let offset = self.0.len() as u32;
+        self.0.extend(name.as_bytes());
+        self.0.push(0);
+        self.1.insert(name.to_string(), offset);
+        offset
+    }
+}
+
+fn slice(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], ElfErr> {
+    bytes
+        .get(offset..offset.checked_add(len).ok_or(ElfErr::Truncated)?)
+        .ok_or(ElfErr::Truncated)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn string_at(table: &[u8], offset: usize) -> String {
+    table
+        .get(offset..)
+        .map(|s| s.split(|&b| b == 0).next().unwrap_or(&[]))
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+
+    #[test]
+    fn load_elf() {
+        use super::Elf;
+        use crate::{
+            execution::run_instruction,
+            linker::{self, Script},
+            object::Object,
+            parser::parse,
+        };
+
+        let input = ".globl _start
+            _start:
+            la a1 value
+            lw a0 0(a1)
+            call negate
+            sw a0 4(a1)
+            j end
+            negate:
+            sub a0 x0 a0
+            srai a0 a0 1
+            ret
+            end:
+            .data
+            value: .word 42";
+        let mut env = Env::new();
+        let tokens = parse(&env, input).unwrap();
+        let object = Object::assemble(&mut env, tokens).unwrap().to_elf();
+        let script = Script::parse(linker::DEFAULT_SCRIPT).unwrap();
+        let exe = linker::link(&[("test.o".to_string(), object)], &script).unwrap();
+
+        let mut env = Env::new();
+        env.load_elf(&Elf::parse(&exe.write()).unwrap()).unwrap();
+        assert_eq!(env.get_label("negate"), Some(0x1c));
+        assert_eq!(env.describe_address(0x20).as_deref(), Some("negate+0x4"));
+
+        let end = env.get_label("end").unwrap();
+        while env.pc != end {
+            let op = env.memory.fetch(env.pc).unwrap();
+            env.pc += 4 * !run_instruction(&mut env, op).unwrap() as u32;
+        }
+        assert_eq!(env.get_register(10), -21i32 as u32);
+        assert_eq!(env.memory.read_u32(0x10000004), -21i32 as u32);
+    }
+}
@@ -1,11 +1,73 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    err::RuntimeErr,
-    instructions::{handle_pseudo, instruction, kind::Kind, with, Arg},
+    block::BlockCache,
+    cache::Caches,
+    csr::{self, Csrs},
+    decode::DecodeCache,
+    device::Devices,
+    elf::{self, Elf, Shndx},
+    err::{ElfErr, MemoryErr, RuntimeErr, StartErr},
+    instructions::{get_instruction, handle_pseudo, instruction, kind::Kind, upper, with, Arg},
+    memory::{Access, Memory, Permissions, STACK_TOP},
     parser::{Loc, Token},
+    pipeline::Pipeline,
+    predictor::Predictor,
+    semihosting::Semihosting,
+    srcmap::SourceMap,
+    syscall::{Abi, Console, Syscalls},
 };
 
+pub type AssembleErr = (RuntimeErr, Loc, Option<String>);
+
+/// `ra` points here when the program starts, returning to it exits with `a0`
+pub const EXIT_ADDRESS: u32 = 0xfffffff0;
+
+/// What a relocatable object takes the offset to a label it doesn't define
+/// to be, too far for a branch
+const EXTERNAL_OFFSET: u32 = 1 << 20;
+
+/// Sections the assembler can place ops and data in.
+///
+/// Read-only data and zero-initialised data are kept in `Data`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Section {
+    Text,
+    Data,
+}
+
+impl Section {
+    /// The section a directive switches to, if it is a section directive
+    pub fn from_directive(name: &str, args: &[(Token, Loc)]) -> Option<Self> {
+        let name = match (name, args.first()) {
+            (".section", Some((Token::Symbol(s), _))) => s.as_str(),
+            (name, _) => name,
+        };
+        match name {
+            ".text" => Some(Section::Text),
+            ".data" | ".rodata" | ".bss" | ".sdata" | ".sbss" => Some(Section::Data),
+            _ if name.starts_with(".text.") => Some(Section::Text),
+            _ if name.starts_with(".data.")
+                || name.starts_with(".rodata.")
+                || name.starts_with(".bss.") =>
+            {
+                Some(Section::Data)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Section::Text => ".text",
+            Section::Data => ".data",
+        }
+    }
+}
+
 pub enum Variables {
     Byte(u8),
     Half(u16),
@@ -17,13 +79,46 @@ pub enum Variables {
 #[derive(Debug)]
 pub struct Env {
     register_alias: HashMap<String, usize>,
-    labels: HashMap<String, u32>,
+    pub(crate) labels: HashMap<String, u32>,
+    pub(crate) label_sections: HashMap<String, Section>,
+    pub(crate) globals: HashSet<String>,
+    /// Number of words reserved for the op at each memory offset
+    op_sizes: HashMap<usize, usize>,
     pub registers: [u32; 32],
     pub fregisters: [f32; 32],
-    pub prev_stacks: Vec<Vec<u32>>,
-    pub stack: Vec<u32>, // TODO: Find the actual size of the stack
+    pub csrs: Csrs,
     pub instructions: Vec<u32>,
+    pub memory: Memory,
+    /// Memory-mapped devices, checked before memory on every load and store
+    pub devices: Devices,
+    /// What `ecall` does, with none it traps
+    pub syscalls: Option<Box<dyn Syscalls>>,
+    /// What a semihosting `ebreak` does, with none it's a breakpoint exception
+    pub semihosting: Option<Semihosting>,
+    /// Word reserved by `lr.w` for each hart, shared so any store can break them
+    pub reservations: HashMap<u32, u32>,
     pub pc: u32,
+    /// Leave references to undefined labels for the linker instead of failing
+    pub relocatable: bool,
+    pub text_base: u32,
+    pub data_base: u32,
+    /// Where the loaded code came from, if known
+    pub source_map: SourceMap,
+    /// Exit code, once the program has exited
+    pub exit: Option<u32>,
+    /// Bytes of memory as they were before each store, while it's `Some`
+    pub journal: Option<Vec<(u32, u8)>>,
+    /// Instructions that have run, decoded. Stores through `store` keep it up
+    /// to date, changing code in `memory` directly has to invalidate it too
+    pub decoded: DecodeCache,
+    /// Basic blocks `run` has translated, kept up to date the same way
+    pub blocks: BlockCache,
+    /// Times the instructions `step` runs in a five-stage pipeline, if asked to
+    pub pipeline: Option<Pipeline>,
+    /// Caches the fetches of `step` and every load and store go through
+    pub caches: Caches,
+    /// Predicts every conditional branch that runs, if asked to
+    pub predictor: Option<Predictor>,
 }
 
 impl Env {
@@ -105,12 +200,31 @@ impl Env {
         Self {
             register_alias,
             labels: HashMap::new(),
-            registers: [0; 32],
+            label_sections: HashMap::new(),
+            globals: HashSet::new(),
+            op_sizes: HashMap::new(),
+            // sp starts at the top of the stack
+            registers: std::array::from_fn(|i| if i == 2 { STACK_TOP } else { 0 }),
             fregisters: [0.0; 32],
-            prev_stacks: Vec::new(),
-            stack: Vec::from([0; 1024]), // 1024 * 64 = 64 KiB stack
+            csrs: Csrs::default(),
             instructions: Vec::new(),
+            memory: Memory::new(),
+            devices: Devices::default(),
+            syscalls: Abi::default().syscalls(Console::default(), 0, None),
+            semihosting: None,
+            reservations: HashMap::new(),
             pc: 0,
+            relocatable: false,
+            text_base: 0,
+            data_base: 0x10000000,
+            source_map: SourceMap::default(),
+            exit: None,
+            journal: None,
+            decoded: DecodeCache::default(),
+            blocks: BlockCache::default(),
+            pipeline: None,
+            caches: Caches::default(),
+            predictor: None,
         }
     }
 
@@ -142,10 +256,13 @@ impl Env {
         self.fregisters[reg]
     }
     pub fn str_to_fregister(&self, reg: &str) -> Option<usize> {
-        if reg.starts_with("f") && !reg[1..].starts_with("0") {
+        if reg == "f0" {
+            Some(0)
+        } else if reg.starts_with("f") && !reg[1..].starts_with("0") {
             match reg[1..].parse::<usize>() {
                 Ok(n) if n < 32 => Some(n),
-                _ => None,
+                // ABI names like fa0 start with an f as well
+                _ => self.register_alias.get(reg).copied(),
             }
         } else {
             self.register_alias.get(reg).copied()
@@ -158,178 +275,556 @@ impl Env {
     pub fn get_label(&self, label: &str) -> Option<u32> {
         self.labels.get(label).copied()
     }
+    pub fn get_label_section(&self, label: &str) -> Option<Section> {
+        self.label_sections.get(label).copied()
+    }
+    pub fn is_global(&self, label: &str) -> bool {
+        self.globals.contains(label)
+    }
+    /// Labels in the order of their addresses
+    pub fn labels(&self) -> Vec<(&str, u32)> {
+        self.labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .sorted_by_key(|(name, value)| (*value, *name))
+            .collect()
+    }
+    /// Load `width` bytes at `addr` for the program, from a device or memory
+    pub fn load(&mut self, addr: u32, width: u32) -> Result<u32, MemoryErr> {
+        match self.devices.at(addr) {
+            Some(_) if !addr.is_multiple_of(width) => {
+                Err(MemoryErr::Misaligned(Access::Load, addr, width))
+            }
+            Some((device, offset)) => Ok(device.read(offset, width)),
+            None => {
+                self.caches.data(addr, width, false);
+                self.memory.load(addr, width)
+            }
+        }
+    }
+
+    /// Store the low `width` bytes of `value` at `addr` for the program, to a device or memory
+    pub fn store(&mut self, addr: u32, width: u32, value: u32) -> Result<(), MemoryErr> {
+        let end = addr as u64 + width as u64;
+        self.reservations
+            .retain(|_, word| end <= *word as u64 || *word as u64 + 4 <= addr as u64);
+        match self.devices.at(addr) {
+            Some(_) if !addr.is_multiple_of(width) => {
+                Err(MemoryErr::Misaligned(Access::Store, addr, width))
+            }
+            Some((device, offset)) => {
+                device.write(offset, width, value);
+                Ok(())
+            }
+            None => {
+                if let Some(journal) = &mut self.journal {
+                    journal.extend((0..width).map(|i| {
+                        let addr = addr.wrapping_add(i);
+                        (addr, self.memory.read_u8(addr))
+                    }));
+                }
+                self.decoded.invalidate(addr, width);
+                self.blocks.invalidate(addr, width);
+                self.caches.data(addr, width, true);
+                self.memory.store(addr, width, value)
+            }
+        }
+    }
+
+    /// Name of the label at exactly `addr`, preferring global ones
+    pub fn label_at(&self, addr: u32) -> Option<&str> {
+        self.labels()
+            .into_iter()
+            .filter(|(_, value)| *value == addr)
+            .max_by_key(|(name, _)| self.is_global(name))
+            .map(|(name, _)| name)
+    }
+    /// `addr` relative to the closest label before it, e.g. `main+0x8`
+    pub fn describe_address(&self, addr: u32) -> Option<String> {
+        let (name, value) = self
+            .labels()
+            .into_iter()
+            .rfind(|(_, value)| *value <= addr)?;
+        let name = self.label_at(value).unwrap_or(name);
+        Some(if value == addr {
+            name.to_string()
+        } else {
+            format!("{}+{:#x}", name, addr - value)
+        })
+    }
+
+    /// Map the loadable segments of an executable into memory, jump to its
+    /// entry point and import its symbols as labels
+    pub fn load_elf(&mut self, elf: &Elf) -> Result<(), ElfErr> {
+        if elf.kind != elf::ET_EXEC {
+            return Err(ElfErr::NotExecutable);
+        }
+
+        for segment in elf.segments.iter() {
+            self.memory.write_bytes(segment.vaddr, &segment.data);
+            // .bss and friends only take up space in memory
+            for i in segment.data.len() as u32..segment.mem_size {
+                self.memory.write_u8(segment.vaddr.wrapping_add(i), 0);
+            }
+            let name = elf
+                .sections
+                .iter()
+                .find(|s| s.is_alloc() && s.addr == segment.vaddr)
+                .map_or("segment", |s| &s.name);
+            let permissions = Permissions::new(
+                segment.flags & elf::PF_R != 0,
+                segment.flags & elf::PF_W != 0,
+                segment.flags & elf::PF_X != 0,
+            );
+            self.memory
+                .map(name, segment.vaddr, segment.mem_size, permissions);
+        }
+        let end = elf
+            .segments
+            .iter()
+            .map(|s| s.vaddr.wrapping_add(s.mem_size))
+            .max()
+            .unwrap_or(0);
+        self.memory.map_heap_and_stack(end);
+        self.pc = elf.entry;
+        // Line tables that can't be read only cost the source locations
+        self.source_map = SourceMap::from_elf(elf).unwrap_or_default();
+
+        for symbol in elf.symbols.iter() {
+            // `$x`/`$d` only mark where code and data start
+            if symbol.name.is_empty()
+                || symbol.name.starts_with('$')
+                || symbol.section == Shndx::Undef
+                || symbol.kind == elf::STT_SECTION
+                || symbol.kind == elf::STT_FILE
+            {
+                continue;
+            }
+            if let Some(value) = elf.symbol_address(symbol) {
+                self.add_label(&symbol.name, value);
+                if symbol.bind != elf::STB_LOCAL {
+                    self.globals.insert(symbol.name.clone());
+                }
+            }
+        }
+
+        Ok(())
+    }
 
-    pub fn assemble_op(
+    /// Set up the registers and stack the way a program expects to start.
+    ///
+    /// pc goes to `entry`, or `_start`, or `main`, or stays where loading left
+    /// it. `args` and `vars` are laid out on the stack like Linux does, with
+    /// a0, a1 and a2 set to argc, argv and envp as well for programs starting
+    /// at `main`.
+    pub fn start(
         &mut self,
-        op: (Token, Loc),
-    ) -> Result<Vec<u32>, (RuntimeErr, Loc, Option<String>)> {
+        entry: Option<&str>,
+        args: &[String],
+        vars: &[String],
+    ) -> Result<(), StartErr> {
+        match entry {
+            Some(name) => {
+                self.pc = self
+                    .get_label(name)
+                    .ok_or_else(|| StartErr::UnknownEntry(name.to_string()))?
+            }
+            None => {
+                if let Some(addr) = self.get_label("_start").or(self.get_label("main")) {
+                    self.pc = addr;
+                }
+            }
+        }
+
+        // The linker's gp if it made one, otherwise what it would have picked
+        let data = self
+            .memory
+            .regions
+            .iter()
+            .find(|r| r.name == ".sdata" || r.name == ".data")
+            .map_or(self.data_base, |r| r.start);
+        let gp = self
+            .get_label("__global_pointer$")
+            .unwrap_or(data.wrapping_add(0x800));
+        self.set_register(3, gp);
+        self.set_register(1, EXIT_ADDRESS);
+
+        // Strings at the top, then argc, argv, envp and an empty auxv
+        let mut top = self.get_register(2);
+        let mut pointers = |strings: &[String], memory: &mut Memory| {
+            strings
+                .iter()
+                .map(|s| {
+                    top -= s.len() as u32 + 1;
+                    memory.write_bytes(top, s.as_bytes());
+                    memory.write_u8(top + s.len() as u32, 0);
+                    top
+                })
+                .collect::<Vec<_>>()
+        };
+        let argv = pointers(args, &mut self.memory);
+        let envp = pointers(vars, &mut self.memory);
+        let words = [args.len() as u32]
+            .into_iter()
+            .chain(argv)
+            .chain([0])
+            .chain(envp)
+            .chain([0, 0, 0])
+            .collect::<Vec<_>>();
+        let sp = (top - 4 * words.len() as u32) & !15;
+        for (i, word) in words.iter().enumerate() {
+            self.memory.write_u32(sp + 4 * i as u32, *word);
+        }
+        self.set_register(2, sp);
+        self.set_register(10, args.len() as u32);
+        self.set_register(11, sp + 4);
+        self.set_register(12, sp + 4 * (args.len() as u32 + 2));
+        Ok(())
+    }
+
+    /// Absolute address of a label, or 0 when it is left for the linker
+    fn resolve_label(&self, label: &str, loc: Loc) -> Result<u32, AssembleErr> {
+        match self.get_label(label) {
+            Some(v) => Ok(v),
+            None if self.relocatable => Ok(0),
+            None => Err((RuntimeErr::LabelNotFound, loc, None)),
+        }
+    }
+
+    /// Offset of `label` from the op at `at`. A label from another object is
+    /// taken to be out of reach of a branch, so pseudo instructions to it
+    /// take their long form and leave the linker room to get there
+    fn resolve_offset(&self, label: &str, loc: Loc, at: usize) -> Result<u32, AssembleErr> {
+        match self.get_label(label) {
+            Some(v) => Ok(v.wrapping_sub(at as u32)),
+            None if self.relocatable => Ok(EXTERNAL_OFFSET),
+            None => Err((RuntimeErr::LabelNotFound, loc, None)),
+        }
+    }
+
+    /// Value of a `%hi`/`%lo` modifier
+    fn resolve_modifier(&self, modifier: &str, label: &str, loc: Loc) -> Result<u32, AssembleErr> {
+        let v = self.resolve_label(label, loc)?;
+        Ok(if modifier == "hi" {
+            upper(v)
+        } else {
+            v & 0xfff
+        })
+    }
+
+    /// Resolve the immediate and registers of an op's arguments
+    fn resolve_args(
+        &self,
+        name: &str,
+        args: &[(Token, Loc)],
+        loc: Loc,
+    ) -> Result<(u32, Vec<usize>), AssembleErr> {
+        let i = if let Some(i) = instruction(name) {
+            i
+        } else {
+            return Err((
+                RuntimeErr::InvalidMnemonic,
+                loc,
+                Some("no implementation exists".to_string()),
+            ));
+        };
+        // Ops without an immediate argument keep the one they are defined with
+        let mut imm = i.0.get_imm().unwrap_or(0);
+        let mut regs = vec![0; 4];
+        if args.len() != i.1.len() {
+            return Err((
+                RuntimeErr::InvalidOpArity(name.to_string(), args.len(), i.1.len()),
+                loc,
+                None,
+            ));
+        }
+
+        i.1.into_iter().enumerate().try_for_each(|(k, v)| match v {
+            Arg::Immediate => match args[k].0.clone() {
+                Token::Immediate(i) => {
+                    imm = i;
+                    Ok(())
+                }
+                Token::Symbol(s) => {
+                    imm = self.resolve_offset(&s, args[k].1, loc.mem_offset)?;
+                    Ok(())
+                }
+                Token::Modifier(m, s) => {
+                    imm = self.resolve_modifier(&m, &s, args[k].1)?;
+                    Ok(())
+                }
+                _ => Err((
+                    RuntimeErr::TypeMissmatch(args[k].0.kind().to_string(), v.kind()),
+                    args[k].1,
+                    None,
+                )),
+            },
+            Arg::Register(id) => {
+                if let Token::Register(r) = &args[k].0 {
+                    regs[id] = self.str_to_register(r).unwrap();
+                    Ok(())
+                } else {
+                    Err((
+                        RuntimeErr::TypeMissmatch(args[k].0.kind().to_string(), v.kind()),
+                        args[k].1,
+                        None,
+                    ))
+                }
+            }
+            Arg::Memory => {
+                if let Token::Memory(i, r) = &args[k].0 {
+                    if r.is_some() {
+                        regs[1] = self
+                            .str_to_register(&if let Token::Register(r) = *(r.clone().unwrap()) {
+                                r
+                            } else {
+                                unreachable!()
+                            })
+                            .unwrap();
+                    }
+                    imm = match &**i {
+                        Token::Immediate(i) => *i,
+                        Token::Modifier(m, s) => self.resolve_modifier(m, s, args[k].1)?,
+                        _ => unreachable!(),
+                    };
+                    Ok(())
+                } else {
+                    Err((
+                        RuntimeErr::TypeMissmatch(args[k].0.kind().to_string(), v.kind()),
+                        args[k].1,
+                        None,
+                    ))
+                }
+            }
+            Arg::Symbol => {
+                if let Token::Symbol(s) = &args[k].0 {
+                    imm = self.resolve_offset(s, args[k].1, loc.mem_offset)?;
+                    Ok(())
+                } else if let Token::Immediate(i) = &args[k].0 {
+                    imm = *i;
+                    Ok(())
+                } else {
+                    Err((
+                        RuntimeErr::TypeMissmatch(args[k].0.kind().to_string(), v.kind()),
+                        args[k].1,
+                        None,
+                    ))
+                }
+            }
+            Arg::Csr => match &args[k].0 {
+                Token::Symbol(s) => {
+                    imm = csr::number(s).ok_or((
+                        RuntimeErr::UnknownCsr(s.clone()),
+                        args[k].1,
+                        None,
+                    ))?;
+                    Ok(())
+                }
+                Token::Immediate(i) if *i < 0x1000 => {
+                    imm = *i;
+                    Ok(())
+                }
+                Token::Immediate(i) => {
+                    Err((RuntimeErr::ImmediateOutOfRange(*i, 12), args[k].1, None))
+                }
+                _ => Err((
+                    RuntimeErr::TypeMissmatch(args[k].0.kind().to_string(), v.kind()),
+                    args[k].1,
+                    None,
+                )),
+            },
+            Arg::Uimm => match &args[k].0 {
+                Token::Immediate(i) if *i < 32 => {
+                    regs[1] = *i as usize;
+                    Ok(())
+                }
+                Token::Immediate(i) => {
+                    Err((RuntimeErr::ImmediateOutOfRange(*i, 5), args[k].1, None))
+                }
+                _ => Err((
+                    RuntimeErr::TypeMissmatch(args[k].0.kind().to_string(), v.kind()),
+                    args[k].1,
+                    None,
+                )),
+            },
+        })?;
+
+        Ok((imm, regs))
+    }
+
+    pub fn assemble_op(&mut self, op: (Token, Loc)) -> Result<Vec<u32>, AssembleErr> {
         if let (Token::Op(name, args), loc) = op {
-            let i = if let Some(i) = instruction(&name) {
-                i
+            let (imm, regs) = self.resolve_args(&name, &args, loc)?;
+            let i = get_instruction(&name);
+
+            let mut words: Vec<u32> = if let Kind::Pseudo(_) = i.0 {
+                handle_pseudo(i, imm, regs)
+                    .into_iter()
+                    .map(|x| x.0.to_u32())
+                    .collect()
             } else {
-                return Err((
-                    RuntimeErr::InvalidMnemonic,
-                    loc,
-                    Some("no implementation exists".to_string()),
-                ));
+                vec![with(i, imm, regs).0.to_u32()]
             };
-            let mut imm = 0u32;
-            let mut regs = vec![0; 4];
-            if args.len() != i.1.len() {
-                return Err((
-                    RuntimeErr::InvalidOpArity(name, args.len(), i.1.len()),
-                    loc,
-                    None,
-                ));
+
+            // An expansion may end up shorter than the space the layout reserved for it,
+            // pad it so the following labels stay where they were placed
+            if let Some(&size) = self.op_sizes.get(&loc.mem_offset) {
+                let nop = handle_pseudo(get_instruction("nop"), 0, vec![])[0]
+                    .0
+                    .to_u32();
+                words.resize(words.len().max(size), nop);
             }
 
-            let _ = i
-                .1
-                .clone()
-                .into_iter()
-                .enumerate()
-                .try_for_each(|(k, v)| match v {
-                    Arg::Immediate => match args[k].0.clone() {
-                        Token::Immediate(i) => {
-                            imm = i as u32;
-                            Ok(())
-                        }
-                        Token::Symbol(s) => {
-                            if let Some(v) = self.get_label(&s) {
-                                imm = v - loc.mem_offset as u32;
-                                Ok(())
-                            } else {
-                                Err((
-                                    RuntimeErr::LabelNotFound,
-                                    args[k].1,
-                                    None,
-                                ))
-                            }
-                        }
-                        _ => Err((
-                            RuntimeErr::TypeMissmatch(Arg::from(args[k].0.clone()).kind(), v.kind()),
-                            args[k].1,
-                            None,
-                        )),
-                    },
-                    Arg::Register(id) => {
-                        if let Token::Register(r) = &args[k].0 {
-                            regs[id] = self.str_to_register(&r).unwrap();
-                            Ok(())
-                        } else {
-                            Err((
-                                RuntimeErr::TypeMissmatch(
-                                    Arg::from(args[k].0.clone()).kind(),
-                                    v.kind(),
-                                ),
-                                args[k].1,
-                                None,
-                            ))
-                        }
-                    }
-                    Arg::Memory => {
-                        if let Token::Memory(i, r) = &args[k].0 {
-                            if r.is_some() {
-                                regs[k] = self
-                                    .str_to_register(&if let Token::Register(r) =
-                                        *(r.clone().unwrap())
-                                    {
-                                        r
-                                    } else {
-                                        unreachable!()
-                                    })
-                                    .unwrap();
-                            }
-                            imm = if let Token::Immediate(i) = **i {
-                                i as u32
-                            } else {
-                                unreachable!()
-                            };
-                            Ok(())
-                        } else {
-                            Err((
+            Ok(words)
+        } else {
+            unreachable!()
+        }
+    }
+
+    /// Number of words an op expands to, given the labels known so far
+    fn op_size(&self, (token, loc): &(Token, Loc)) -> usize {
+        if let Token::Op(name, args) = token {
+            match (instruction(name), self.resolve_args(name, args, *loc)) {
+                (Some(i @ (Kind::Pseudo(_), _)), Ok((imm, regs))) => {
+                    handle_pseudo(i, imm, regs).len()
+                }
+                // Unresolved pseudo instructions are sized with a placeholder immediate,
+                // the error itself is reported when the op is assembled
+                (Some(i @ (Kind::Pseudo(_), _)), Err(_)) => handle_pseudo(i, 0, vec![0; 4]).len(),
+                _ => 1,
+            }
+        } else {
+            0
+        }
+    }
+
+    /// Assemble a data directive into the bytes it places in memory
+    pub fn assemble_directive(&mut self, directive: (Token, Loc)) -> Result<Vec<u8>, AssembleErr> {
+        let (name, args, loc) = if let (Token::Directive(name, args), loc) = directive {
+            (name, args, loc)
+        } else {
+            unreachable!()
+        };
+
+        let mut bytes = Vec::new();
+        match name.as_str() {
+            ".word" | ".half" | ".short" | ".byte" => {
+                let width = directive_width(&name);
+                for (arg, arg_loc) in args.iter() {
+                    let value = match arg {
+                        Token::Immediate(i) => *i,
+                        Token::Symbol(s) if width == 4 => self.resolve_label(s, *arg_loc)?,
+                        other => {
+                            return Err((
                                 RuntimeErr::TypeMissmatch(
-                                    Arg::from(args[k].0.clone()).kind(),
-                                    v.kind(),
+                                    other.kind().to_string(),
+                                    "immediate".to_string(),
                                 ),
-                                args[k].1,
+                                *arg_loc,
                                 None,
                             ))
                         }
-                    }
-                    Arg::Symbol => {
-                        if let Token::Symbol(s) = &args[k].0 {
-                            if let Some(v) = self.get_label(&s) {
-                                imm = (v).wrapping_sub(loc.mem_offset as u32);
-                                Ok(())
-                            } else {
-                                Err((
-                                    RuntimeErr::LabelNotFound,
-                                    args[k].1,
-                                    None,
-                                ))
-                            }
-                        } else if let Token::Immediate(i) = &args[k].0 {
-                            imm = *i as u32;
-                            Ok(())
-                        } else {
-                            Err((
-                                RuntimeErr::TypeMissmatch(
-                                    Arg::from(args[k].0.clone()).kind(),
-                                    v.kind(),
-                                ),
-                                args[k].1,
-                                None,
-                            ))
+                    };
+                    bytes.extend_from_slice(&value.to_le_bytes()[..width]);
+                }
+            }
+            ".string" | ".asciz" | ".ascii" => {
+                for (arg, arg_loc) in args.iter() {
+                    if let Token::String(s) = arg {
+                        bytes.extend_from_slice(s.as_bytes());
+                        if name != ".ascii" {
+                            bytes.push(0);
                         }
+                    } else {
+                        return Err((
+                            RuntimeErr::TypeMissmatch(arg.kind().to_string(), "string".to_string()),
+                            *arg_loc,
+                            None,
+                        ));
                     }
-                })?;
-            Ok({
-                if let Kind::Pseudo(_) = i.0 {
-                    handle_pseudo(i, imm, regs)
-                        .into_iter()
-                        .map(|x| u32::from_str_radix(&x.0.to_string(), 2).unwrap())
-                        .collect()
-                } else {
-                    vec![u32::from_str_radix(&with(i, imm, regs).0.to_string(), 2).unwrap()]
                 }
-            })
-        } else {
-            unreachable!()
+            }
+            _ => bytes.resize(directive_size(&name, &args, loc.mem_offset), 0),
         }
+
+        Ok(bytes)
     }
 
-    pub fn handle_mem_offsets(&mut self, mut tokens: Vec<(Token, Loc)>) -> Vec<(Token, Loc)> {
-        let mut i = 0;
-        // Calculate the instruction position for all opcodes to
-        // allow for labels to be used before they are defined
-        tokens
-            .clone()
-            .into_iter()
-            .enumerate()
-            .for_each(|(id, (token, _))| match token {
-                Token::Op(name, _) => {
-                    if let Some((kind, args)) = instruction(&name) {
-                        if let Kind::Pseudo(_) = kind {
-                            tokens[id].1.mem_offset = i;
-                            handle_pseudo((kind, args), 0, vec![0; 4])
-                                .into_iter()
-                                .for_each(|_| i += 4);
-                        } else {
-                            i += 4;
+    /// Place every op, directive and label in memory.
+    ///
+    /// The size of a pseudo instruction can depend on the value of its operands,
+    /// including labels defined further down, so the layout is repeated until it
+    /// settles. Sizes only ever grow between passes, which guarantees it does.
+    pub fn handle_mem_offsets(
+        &mut self,
+        mut tokens: Vec<(Token, Loc)>,
+    ) -> Result<Vec<(Token, Loc)>, AssembleErr> {
+        let mut sizes = vec![1; tokens.len()];
+
+        loop {
+            let mut section = Section::Text;
+            let mut offsets = HashMap::from([
+                (Section::Text, self.text_base as usize),
+                (Section::Data, self.data_base as usize),
+            ]);
+            for (id, (token, loc)) in tokens.iter_mut().enumerate() {
+                let i = offsets.get_mut(&section).unwrap();
+                match token {
+                    Token::Op(..) => {
+                        loc.mem_offset = *i;
+                        *i += 4 * sizes[id];
+                    }
+                    Token::Directive(name, args) => {
+                        loc.mem_offset = *i;
+                        *i += directive_size(name, args, *i);
+                        if let Some(new) = Section::from_directive(name, args) {
+                            section = new;
+                        } else if name == ".globl" || name == ".global" {
+                            for (arg, _) in args.iter() {
+                                if let Token::Symbol(s) = arg {
+                                    self.globals.insert(s.clone());
+                                }
+                            }
                         }
                     }
+                    Token::Label(name) => {
+                        self.add_label(name, *i as u32);
+                        self.label_sections.insert(name.clone(), section);
+                    }
+                    other => {
+                        return Err((
+                            RuntimeErr::TypeMissmatch(
+                                other.kind().to_string(),
+                                "op, directive or label".to_string(),
+                            ),
+                            *loc,
+                            None,
+                        ))
+                    }
                 }
-                Token::Label(name) => {
-                    self.add_label(&name, i as u32);
-                }
-                other => {
-                    dbg!(other);
-                    unreachable!()
+            }
+
+            let mut settled = true;
+            for (id, op) in tokens.iter().enumerate() {
+                let size = self.op_size(op);
+                if size > sizes[id] {
+                    sizes[id] = size;
+                    settled = false;
                 }
-            });
+            }
+            if settled {
+                break;
+            }
+        }
+
+        self.op_sizes = tokens
+            .iter()
+            .zip(sizes)
+            .filter(|((token, _), _)| matches!(token, Token::Op(..)))
+            .map(|((_, loc), size)| (loc.mem_offset, size))
+            .collect();
 
-        tokens
+        Ok(tokens)
     }
 
     /// Assume memory offsets have been handled
@@ -354,3 +849,73 @@ impl Env {
         todo!()
     }
 }
+
+/// Bytes per value of `.word`, `.half` and `.byte`
+fn directive_width(name: &str) -> usize {
+    match name {
+        ".word" => 4,
+        ".half" | ".short" => 2,
+        _ => 1,
+    }
+}
+
+/// Number of bytes a directive places at `offset`
+fn directive_size(name: &str, args: &[(Token, Loc)], offset: usize) -> usize {
+    let imm = || match args.first() {
+        Some((Token::Immediate(i), _)) => *i as usize,
+        _ => 0,
+    };
+    match name {
+        ".word" | ".half" | ".short" | ".byte" => directive_width(name) * args.len(),
+        ".string" | ".asciz" | ".ascii" => args
+            .iter()
+            .map(|(arg, _)| match arg {
+                Token::String(s) => s.len() + (name != ".ascii") as usize,
+                _ => 0,
+            })
+            .sum(),
+        ".space" | ".zero" => imm(),
+        ".align" | ".p2align" => offset.next_multiple_of(1 << imm()) - offset,
+        ".balign" => offset.next_multiple_of(imm().max(1)) - offset,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Env;
+    use crate::tests::load_program;
+
+    #[test]
+    fn startup() {
+        use super::EXIT_ADDRESS;
+        use crate::{err::StartErr, execution::step};
+
+        let mut env = Env::new();
+        load_program(&mut env, "li a0 1\nmain:\nlw a0 0(sp)\nret");
+        assert_eq!(
+            env.start(Some("_start"), &[], &[]),
+            Err(StartErr::UnknownEntry("_start".to_string()))
+        );
+
+        let args = ["prog".to_string(), "-v".to_string()];
+        env.start(None, &args, &["HOME=/".to_string()]).unwrap();
+        let sp = env.get_register(2);
+        assert_eq!(env.pc, 4);
+        assert_eq!(sp % 16, 0);
+        assert_eq!(env.get_register(3), 0x10000800);
+        assert_eq!(env.get_register(1), EXIT_ADDRESS);
+        assert_eq!(env.get_register(11), sp + 4);
+        let argv1 = env.memory.read_u32(sp + 8);
+        assert_eq!(env.memory.read_bytes(argv1, 3), b"-v\0");
+        assert_eq!(env.memory.read_u32(sp + 12), 0);
+        let envp0 = env.memory.read_u32(env.get_register(12));
+        assert_eq!(env.memory.read_bytes(envp0, 6), b"HOME=/");
+
+        // Returning from main exits with what it returned, argc here
+        step(&mut env).unwrap();
+        assert_eq!(env.exit, None);
+        step(&mut env).unwrap();
+        assert_eq!(env.exit, Some(2));
+    }
+}
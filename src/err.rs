@@ -5,7 +5,7 @@ use std::{
 
 use itertools::Itertools;
 
-use crate::instructions::instruction;
+use crate::{elf::reloc_name, instructions::instruction, memory::Access};
 
 #[derive(Debug, Clone)]
 pub enum SyntaxErr {
@@ -16,11 +16,14 @@ pub enum SyntaxErr {
     UnmatchedParen(bool),
     OutsideMnemonic(String),
     InvalidRegister,
+    InvalidModifier(String),
 
     // .data specific
     InvalidType,
     InvalidVarName,
     MalformedData,
+    UnterminatedString,
+    UnknownDirective(String),
 }
 
 impl Display for SyntaxErr {
@@ -30,9 +33,12 @@ impl Display for SyntaxErr {
             SyntaxErr::UnmatchedParen(_) => write!(f, "unmatched parenthesis"),
             SyntaxErr::OutsideMnemonic(kind) => write!(f, "unexpected '{kind}'"),
             SyntaxErr::InvalidRegister => write!(f, "invalid register"),
+            SyntaxErr::InvalidModifier(name) => write!(f, "unknown modifier '%{name}'"),
             SyntaxErr::InvalidType => write!(f, "invalid type"),
             SyntaxErr::InvalidVarName => write!(f, "invalid variable name"),
             SyntaxErr::MalformedData => write!(f, "malformed global definition"),
+            SyntaxErr::UnterminatedString => write!(f, "unterminated string"),
+            SyntaxErr::UnknownDirective(name) => write!(f, "unknown directive '{name}'"),
         }
     }
 }
@@ -47,9 +53,14 @@ impl SyntaxErr {
             SyntaxErr::InvalidRegister => {
                 "registers are either (x|f)N, for N < 32 with no leading 0, or an alias".to_string()
             }
+            SyntaxErr::InvalidModifier(_) => "the supported modifiers are %hi and %lo".to_string(),
             SyntaxErr::InvalidType => "check the spec for proper types".to_string(),
             SyntaxErr::InvalidVarName => "variable names must be alphanumeric".to_string(),
             SyntaxErr::MalformedData => "ensure the global definition is well-formed".to_string(),
+            SyntaxErr::UnterminatedString => "add `\"` at the end of the string".to_string(),
+            SyntaxErr::UnknownDirective(_) => {
+                "check the ref sheet for the avaliable directives".to_string()
+            }
         }
     }
 }
@@ -63,6 +74,9 @@ pub enum RuntimeErr {
     /// actual, expected
     TypeMissmatch(String, String),
     LabelNotFound,
+    UnknownCsr(String),
+    /// value, bits it has to fit in, unsigned
+    ImmediateOutOfRange(u32, u32),
 }
 
 impl Display for RuntimeErr {
@@ -76,6 +90,10 @@ impl Display for RuntimeErr {
                 write!(f, "expected '{}', got '{}'", expected, actual)
             }
             RuntimeErr::LabelNotFound => write!(f, "label not found"),
+            RuntimeErr::UnknownCsr(name) => write!(f, "unknown CSR '{}'", name),
+            RuntimeErr::ImmediateOutOfRange(imm, bits) => {
+                write!(f, "{} doesn't fit in {} bits", imm, bits)
+            }
         }
     }
 }
@@ -111,6 +129,296 @@ impl RuntimeErr {
                 "ensure the instruction is getting the right arguments".to_string()
             }
             RuntimeErr::LabelNotFound => "ensure the label is spelled correctly".to_string(),
+            RuntimeErr::UnknownCsr(_) => {
+                "use a machine-mode CSR like mstatus, or its number".to_string()
+            }
+            RuntimeErr::ImmediateOutOfRange(_, bits) => {
+                format!("use a value from 0 to {}", (1u32 << bits) - 1)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ElfErr {
+    NotElf,
+    Truncated,
+    NotExecutable,
+    Unsupported(String),
+}
+
+impl Display for ElfErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfErr::NotElf => write!(f, "not an ELF file"),
+            ElfErr::Truncated => write!(f, "truncated ELF file"),
+            ElfErr::NotExecutable => write!(f, "not an executable, link it first"),
+            ElfErr::Unsupported(reason) => write!(f, "unsupported ELF file: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ElfErr {}
+
+#[derive(Debug, Clone)]
+pub enum LinkErr {
+    /// file, error
+    Elf(String, ElfErr),
+    NotRelocatable(String),
+    DuplicateSymbol(String),
+    UndefinedSymbol(String),
+    /// relocation type, symbol
+    OutOfRange(u32, String),
+    UnsupportedRelocation(u32),
+    /// line, message
+    Script(usize, String),
+}
+
+impl Display for LinkErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkErr::Elf(file, err) => write!(f, "{}: {}", file, err),
+            LinkErr::NotRelocatable(file) => write!(f, "{}: not a relocatable object", file),
+            LinkErr::DuplicateSymbol(name) => write!(f, "multiple definitions of '{}'", name),
+            LinkErr::UndefinedSymbol(name) => write!(f, "undefined reference to '{}'", name),
+            LinkErr::OutOfRange(kind, name) => write!(
+                f,
+                "relocation {} against '{}' out of range",
+                reloc_name(*kind),
+                name
+            ),
+            LinkErr::UnsupportedRelocation(kind) => {
+                write!(f, "unsupported relocation {}", reloc_name(*kind))
+            }
+            LinkErr::Script(line, msg) => write!(f, "linker script:{}: {}", line, msg),
+        }
+    }
+}
+
+impl LinkErr {
+    pub fn note(&self) -> String {
+        match self {
+            LinkErr::Elf(..) => "only 32 bit little endian RISC-V files can be linked".to_string(),
+            LinkErr::NotRelocatable(_) => "assemble the file into an object first".to_string(),
+            LinkErr::DuplicateSymbol(_) => "make all but one of the definitions local".to_string(),
+            LinkErr::UndefinedSymbol(_) => {
+                "ensure the object defining it is linked and exports it with `.globl`".to_string()
+            }
+            LinkErr::OutOfRange(..) => {
+                "move the target closer or jump through a register".to_string()
+            }
+            LinkErr::UnsupportedRelocation(_) => "assemble without linker relaxation".to_string(),
+            LinkErr::Script(..) => "check the linker script syntax".to_string(),
+        }
+    }
+}
+
+impl std::error::Error for LinkErr {}
+
+/// A load, store or instruction fetch the memory doesn't allow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryErr {
+    /// access, address, width in bytes
+    Misaligned(Access, u32, u32),
+    /// access, address, name of the region it hit if any
+    Fault(Access, u32, Option<String>),
+}
+
+impl Display for MemoryErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryErr::Misaligned(access, addr, width) => {
+                write!(f, "misaligned {}-byte {} at 0x{:08x}", width, access, addr)
+            }
+            MemoryErr::Fault(access, addr, Some(region)) => {
+                write!(f, "{} fault at 0x{:08x} in {}", access, addr, region)
+            }
+            MemoryErr::Fault(access, addr, None) => {
+                write!(f, "{} fault at 0x{:08x}, outside any region", access, addr)
+            }
+        }
+    }
+}
+
+impl MemoryErr {
+    pub fn note(&self) -> String {
+        match self {
+            MemoryErr::Misaligned(..) => {
+                "align the address to the access width, or allow misaligned accesses".to_string()
+            }
+            MemoryErr::Fault(access, _, Some(_)) => {
+                format!("the region has no {} permission", access.permission())
+            }
+            MemoryErr::Fault(..) => "check the pointer, it may be uninitialised".to_string(),
+        }
+    }
+}
+
+impl std::error::Error for MemoryErr {}
+
+/// A syscall that couldn't be carried out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyscallErr {
+    /// syscall number
+    Unknown(u32),
+    /// What was wrong with what a syscall read
+    Input(String),
+}
+
+impl Display for SyscallErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SyscallErr::Unknown(number) => write!(f, "unknown syscall {}", number),
+            SyscallErr::Input(reason) => write!(f, "bad input: {}", reason),
+        }
+    }
+}
+
+impl SyscallErr {
+    pub fn note(&self) -> String {
+        match self {
+            SyscallErr::Unknown(_) => {
+                "check the number, or pick the --abi the program was written for".to_string()
+            }
+            SyscallErr::Input(_) => "type what the program asks for".to_string(),
+        }
+    }
+}
+
+impl std::error::Error for SyscallErr {}
+
+/// Why an instruction couldn't finish
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepErr {
+    Memory(MemoryErr),
+    Syscall(SyscallErr),
+}
+
+impl From<MemoryErr> for StepErr {
+    fn from(err: MemoryErr) -> Self {
+        StepErr::Memory(err)
+    }
+}
+
+impl From<SyscallErr> for StepErr {
+    fn from(err: SyscallErr) -> Self {
+        StepErr::Syscall(err)
+    }
+}
+
+impl Display for StepErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StepErr::Memory(err) => err.fmt(f),
+            StepErr::Syscall(err) => err.fmt(f),
+        }
+    }
+}
+
+impl StepErr {
+    pub fn note(&self) -> String {
+        match self {
+            StepErr::Memory(err) => err.note(),
+            StepErr::Syscall(err) => err.note(),
         }
     }
 }
+
+impl std::error::Error for StepErr {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceErr {
+    /// device being added, device already there
+    Overlap(String, String),
+}
+
+impl Display for DeviceErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceErr::Overlap(new, old) => {
+                write!(f, "{} overlaps the addresses of {}", new, old)
+            }
+        }
+    }
+}
+
+impl DeviceErr {
+    pub fn note(&self) -> String {
+        match self {
+            DeviceErr::Overlap(..) => "give the device a different base address".to_string(),
+        }
+    }
+}
+
+impl std::error::Error for DeviceErr {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartErr {
+    UnknownEntry(String),
+}
+
+impl Display for StartErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StartErr::UnknownEntry(name) => write!(f, "entry point '{}' isn't defined", name),
+        }
+    }
+}
+
+impl StartErr {
+    pub fn note(&self) -> String {
+        match self {
+            StartErr::UnknownEntry(_) => {
+                "define it as a label, or start somewhere else".to_string()
+            }
+        }
+    }
+}
+
+impl std::error::Error for StartErr {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotErr {
+    Malformed(String),
+    /// Format version of the snapshot
+    Version(u32),
+    UnknownDevice(String),
+    UnknownAbi(String),
+}
+
+impl Display for SnapshotErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotErr::Malformed(reason) => write!(f, "malformed snapshot: {}", reason),
+            SnapshotErr::Version(version) => {
+                write!(f, "snapshot is version {} of the format", version)
+            }
+            SnapshotErr::UnknownDevice(name) => {
+                write!(f, "snapshot has a device '{}' that can't be restored", name)
+            }
+            SnapshotErr::UnknownAbi(name) => {
+                write!(f, "snapshot has syscalls '{}' that can't be restored", name)
+            }
+        }
+    }
+}
+
+impl SnapshotErr {
+    pub fn note(&self) -> String {
+        match self {
+            SnapshotErr::Malformed(_) => "snapshots are JSON written by rizz-v".to_string(),
+            SnapshotErr::Version(_) => format!(
+                "this rizz-v reads version {}, take the snapshot again",
+                crate::snapshot::VERSION
+            ),
+            SnapshotErr::UnknownDevice(_) => {
+                "only the built-in devices are saved in snapshots".to_string()
+            }
+            SnapshotErr::UnknownAbi(_) => {
+                "only the built-in syscalls are saved in snapshots".to_string()
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotErr {}
@@ -1,6 +1,15 @@
 use std::mem;
 
-use crate::{env::Env, instructions::kind::Kind};
+use crate::{
+    block::BlockOp,
+    csr,
+    decode::{Decoded, Op},
+    env::{Env, EXIT_ADDRESS},
+    err::{MemoryErr, StepErr, SyscallErr},
+    hart::Harts,
+    memory::Access,
+    semihosting,
+};
 
 /// Always "safe" because f32 and i32 have the same size.
 fn u32_to_f32(i: u32) -> f32 {
@@ -17,6 +26,11 @@ fn lui(env: &mut Env, rd: usize, imm: u32) {
     env.set_register(rd, imm);
 }
 
+/// auipc rd, imm
+fn auipc(env: &mut Env, rd: usize, imm: u32) {
+    env.set_register(rd, env.pc.wrapping_add(imm));
+}
+
 /// add rd, ra, rb
 fn add(env: &mut Env, rd: usize, ra: usize, rb: usize) {
     env.set_register(rd, env.get_register(ra).wrapping_add(env.get_register(rb)));
@@ -41,7 +55,7 @@ fn mul(env: &mut Env, rd: usize, ra: usize, rb: usize) {
 fn mulh(env: &mut Env, rd: usize, ra: usize, rb: usize) {
     env.set_register(
         rd,
-        (env.get_register(ra) as i64 * env.get_register(rb) as i64 >> 32) as u32,
+        (env.get_register(ra) as i32 as i64 * env.get_register(rb) as i32 as i64 >> 32) as u32,
     );
 }
 
@@ -49,7 +63,7 @@ fn mulh(env: &mut Env, rd: usize, ra: usize, rb: usize) {
 fn mulhsu(env: &mut Env, rd: usize, ra: usize, rb: usize) {
     env.set_register(
         rd,
-        (env.get_register(ra) as i64 * env.get_register(rb) as i64 >> 32) as u32,
+        (env.get_register(ra) as i32 as i64 * env.get_register(rb) as i64 >> 32) as u32,
     );
 }
 
@@ -61,9 +75,116 @@ fn mulu(env: &mut Env, rd: usize, ra: usize, rb: usize) {
     );
 }
 
-/// beq ra, rb, imm
-fn beq(env: &mut Env, ra: usize, rb: usize, imm: u32) -> bool {
-    if env.get_register(ra) == env.get_register(rb) {
+/// div rd, ra, rb
+///
+/// Division by zero gives -1 and overflow gives the dividend, like the spec says
+fn div(env: &mut Env, rd: usize, ra: usize, rb: usize) {
+    let (a, b) = (env.get_register(ra) as i32, env.get_register(rb) as i32);
+    env.set_register(rd, if b == 0 { -1 } else { a.wrapping_div(b) } as u32);
+}
+
+/// divu rd, ra, rb
+fn divu(env: &mut Env, rd: usize, ra: usize, rb: usize) {
+    let (a, b) = (env.get_register(ra), env.get_register(rb));
+    env.set_register(rd, a.checked_div(b).unwrap_or(u32::MAX));
+}
+
+/// rem rd, ra, rb
+fn rem(env: &mut Env, rd: usize, ra: usize, rb: usize) {
+    let (a, b) = (env.get_register(ra) as i32, env.get_register(rb) as i32);
+    env.set_register(rd, if b == 0 { a } else { a.wrapping_rem(b) } as u32);
+}
+
+/// remu rd, ra, rb
+fn remu(env: &mut Env, rd: usize, ra: usize, rb: usize) {
+    let (a, b) = (env.get_register(ra), env.get_register(rb));
+    env.set_register(rd, a.checked_rem(b).unwrap_or(a));
+}
+
+/// Register-register ops that only differ in how the values are combined
+fn alu(env: &mut Env, rd: usize, ra: usize, rb: usize, f: fn(u32, u32) -> u32) {
+    env.set_register(rd, f(env.get_register(ra), env.get_register(rb)));
+}
+
+/// Register-immediate ops that only differ in how the values are combined
+fn alu_imm(env: &mut Env, rd: usize, ra: usize, imm: u32, f: fn(u32, u32) -> u32) {
+    env.set_register(rd, f(env.get_register(ra), imm));
+}
+
+/// lb/lh/lw/lbu/lhu rd, imm(ra)
+fn load(
+    env: &mut Env,
+    rd: usize,
+    ra: usize,
+    imm: u32,
+    width: u32,
+    signed: bool,
+) -> Result<(), MemoryErr> {
+    let addr = env.get_register(ra).wrapping_add(imm);
+    let mut value = env.load(addr, width)?;
+    if signed && width < 4 {
+        let shift = 32 - width * 8;
+        value = ((value << shift) as i32 >> shift) as u32;
+    }
+    env.set_register(rd, value);
+    Ok(())
+}
+
+/// sb/sh/sw rb, imm(ra)
+fn store(env: &mut Env, ra: usize, rb: usize, imm: u32, width: u32) -> Result<(), MemoryErr> {
+    let addr = env.get_register(ra).wrapping_add(imm);
+    env.store(addr, width, env.get_register(rb))
+}
+
+/// Address of an atomic's word, which has to be aligned whatever the misaligned policy
+fn atomic_addr(env: &Env, ra: usize, access: Access) -> Result<u32, MemoryErr> {
+    let addr = env.get_register(ra);
+    if !addr.is_multiple_of(4) {
+        return Err(MemoryErr::Misaligned(access, addr, 4));
+    }
+    Ok(addr)
+}
+
+/// lr.w rd, (ra)
+fn lr_w(env: &mut Env, rd: usize, ra: usize) -> Result<(), MemoryErr> {
+    let addr = atomic_addr(env, ra, Access::Load)?;
+    let value = env.load(addr, 4)?;
+    env.reservations.insert(env.csrs.hartid, addr);
+    env.set_register(rd, value);
+    Ok(())
+}
+
+/// sc.w rd, rb, (ra)
+///
+/// Only stores if the word is still reserved by this hart, rd is 0 if it did
+fn sc_w(env: &mut Env, rd: usize, ra: usize, rb: usize) -> Result<(), MemoryErr> {
+    let addr = atomic_addr(env, ra, Access::Store)?;
+    let reserved = env.reservations.remove(&env.csrs.hartid) == Some(addr);
+    if reserved {
+        env.store(addr, 4, env.get_register(rb))?;
+    }
+    env.set_register(rd, !reserved as u32);
+    Ok(())
+}
+
+/// amo*.w rd, rb, (ra), storing f(old value, rb) and putting the old value in rd
+fn amo(
+    env: &mut Env,
+    rd: usize,
+    ra: usize,
+    rb: usize,
+    f: fn(u32, u32) -> u32,
+) -> Result<(), MemoryErr> {
+    let addr = atomic_addr(env, ra, Access::Store)?;
+    let old = env.load(addr, 4)?;
+    env.store(addr, 4, f(old, env.get_register(rb)))?;
+    env.set_register(rd, old);
+    Ok(())
+}
+
+/// Conditional branches, jumping by imm if the condition holds
+fn branch(env: &mut Env, ra: usize, rb: usize, imm: u32, f: fn(u32, u32) -> bool) -> bool {
+    if f(env.get_register(ra), env.get_register(rb)) {
         env.pc = env.pc.wrapping_add(imm);
         return true;
     }
@@ -76,6 +197,13 @@ fn jal(env: &mut Env, rd: usize, imm: u32) {
     env.pc = env.pc.wrapping_add(imm);
 }
 
+/// jalr rd, ra, imm
+fn jalr(env: &mut Env, rd: usize, ra: usize, imm: u32) {
+    let target = env.get_register(ra).wrapping_add(imm) & !1;
+    env.set_register(rd, env.pc + 4);
+    env.pc = target;
+}
+
 /// fadd.s fd, fa, fb
 fn fadd_s(env: &mut Env, fd: usize, fa: usize, fb: usize) {
     env.set_fregister(fd, env.get_fregister(fa) + env.get_fregister(fb));
@@ -104,79 +232,446 @@ fn fmv_w_x(env: &mut Env, fd: usize, ra: usize) {
     env.set_fregister(fd, u32_to_f32(env.get_register(ra)));
 }
 
+/// csrrw/csrrs/csrrc rd, csr, value, where value is a register or a uimm.
+///
+/// The old value of the CSR goes to rd, `f` combines it with value into the new one
+fn csr_op(env: &mut Env, rd: usize, csr: u32, value: u32, write: bool, f: fn(u32, u32) -> u32) {
+    let old = env.csrs.read(csr, env.devices.mip());
+    if write {
+        env.csrs.write(csr, f(old, value));
+    }
+    env.set_register(rd, old);
+}
+
+/// ecall, made to the environment's syscalls, or a trap when there are none.
+///
+/// Returns true if it trapped.
+fn ecall(env: &mut Env) -> Result<bool, StepErr> {
+    let Some(mut syscalls) = env.syscalls.take() else {
+        env.pc = env.csrs.trap(env.pc, csr::MACHINE_ECALL, 0);
+        return Ok(true);
+    };
+    let result = syscalls.ecall(env);
+    env.syscalls = Some(syscalls);
+    result.map(|_| false)
+}
+
+/// ebreak, a semihosting call if it's between the instructions that mark one
+/// and semihosting is on, a breakpoint exception otherwise.
+///
+/// Returns true if it trapped.
+fn ebreak(env: &mut Env) -> Result<bool, StepErr> {
+    match env.semihosting.take() {
+        Some(mut semihosting) if semihosting::is_call(env, env.pc) => {
+            let result = semihosting.call(env);
+            env.semihosting = Some(semihosting);
+            result.map(|_| false)
+        }
+        semihosting => {
+            env.semihosting = semihosting;
+            env.pc = env.csrs.trap(env.pc, csr::BREAKPOINT, env.pc);
+            Ok(true)
+        }
+    }
+}
+
 /// Executes the instruction.
 ///
-/// Returns true if the instruction is a jump.
-pub fn run_instruction(env: &mut Env, instruction: u32) -> bool {
-    let (kind, _) = Kind::to_op(instruction);
-    let mut regs = kind.get_regs().unwrap();
-    // Ensure all four registers have a value
-    regs.extend([0].repeat(4 - regs.len()));
-    let (rd, ra, rb) = (regs[0], regs[1], regs[2]);
-    let (fd, fa, fb, fc) = (regs[0], regs[1], regs[2], regs[3]);
-    let imm = kind.get_imm();
-    let opcode = kind.get_opcode().unwrap();
-    let funct3 = instruction >> 12 & 0b111;
-    let funct7 = instruction >> 25 & 0b1111111;
-
-    match opcode {
-        0b0110111 => {
-            lui(env, rd, imm.unwrap());
-            false
-        }
-        0b0110011 if funct3 == 0b000 && funct7 == 0b0000000 => {
-            add(env, rd, ra, rb);
-            false
-        }
-        0b0010011 => {
-            addi(env, rd, ra, imm.unwrap());
-            false
-        }
-        0b0110011 if funct3 == 0b100 && funct7 == 0b0000000 => {
-            xor(env, rd, ra, rb);
-            false
-        }
-        0b0110011 if funct3 == 0b000 && funct7 == 0b0000001 => {
-            mul(env, rd, ra, rb);
-            false
-        }
-        0b0110011 if funct3 == 0b001 && funct7 == 0b0000001 => {
-            mulh(env, rd, ra, rb);
-            false
-        }
-        0b0110011 if funct3 == 0b010 && funct7 == 0b0000001 => {
-            mulhsu(env, rd, ra, rb);
-            false
-        }
-        0b0110011 if funct3 == 0b011 && funct7 == 0b0000001 => {
-            mulu(env, rd, ra, rb);
-            false
-        }
-        0b1100011 => beq(env, ra, rb, imm.unwrap()),
-        0b1101111 => {
-            jal(env, rd, imm.unwrap());
+/// Returns true if the instruction jumps or traps, or the access that faulted.
+pub fn run_instruction(env: &mut Env, instruction: u32) -> Result<bool, StepErr> {
+    execute(env, &Decoded::new(instruction))
+}
+
+/// Executes an instruction that's already been decoded, like `run_instruction`
+pub fn execute(env: &mut Env, decoded: &Decoded) -> Result<bool, StepErr> {
+    let (rd, ra, rb) = (
+        decoded.rd as usize,
+        decoded.ra as usize,
+        decoded.rb as usize,
+    );
+    let (fd, fa, fb, fc) = (rd, ra, rb, decoded.rc as usize);
+    let imm = decoded.imm;
+
+    match decoded.op {
+        Op::Lui => lui(env, rd, imm),
+        Op::Auipc => auipc(env, rd, imm),
+        Op::Add => add(env, rd, ra, rb),
+        Op::Addi => addi(env, rd, ra, imm),
+        Op::Sub => alu(env, rd, ra, rb, u32::wrapping_sub),
+        Op::And => alu(env, rd, ra, rb, |a, b| a & b),
+        Op::Or => alu(env, rd, ra, rb, |a, b| a | b),
+        Op::Xor => xor(env, rd, ra, rb),
+        Op::Sll => alu(env, rd, ra, rb, |a, b| a << (b & 0x1f)),
+        Op::Srl => alu(env, rd, ra, rb, |a, b| a >> (b & 0x1f)),
+        Op::Sra => alu(env, rd, ra, rb, |a, b| (a as i32 >> (b & 0x1f)) as u32),
+        Op::Slt => alu(env, rd, ra, rb, |a, b| ((a as i32) < b as i32) as u32),
+        Op::Sltu => alu(env, rd, ra, rb, |a, b| (a < b) as u32),
+        Op::Andi => alu_imm(env, rd, ra, imm, |a, b| a & b),
+        Op::Ori => alu_imm(env, rd, ra, imm, |a, b| a | b),
+        Op::Xori => alu_imm(env, rd, ra, imm, |a, b| a ^ b),
+        Op::Slti => alu_imm(env, rd, ra, imm, |a, b| ((a as i32) < b as i32) as u32),
+        Op::Sltiu => alu_imm(env, rd, ra, imm, |a, b| (a < b) as u32),
+        Op::Slli => alu_imm(env, rd, ra, imm, |a, b| a << (b & 0x1f)),
+        Op::Srli => alu_imm(env, rd, ra, imm, |a, b| a >> (b & 0x1f)),
+        Op::Srai => alu_imm(env, rd, ra, imm, |a, b| (a as i32 >> (b & 0x1f)) as u32),
+        Op::Mul => mul(env, rd, ra, rb),
+        Op::Mulh => mulh(env, rd, ra, rb),
+        Op::Mulhsu => mulhsu(env, rd, ra, rb),
+        Op::Mulhu => mulu(env, rd, ra, rb),
+        Op::Div => div(env, rd, ra, rb),
+        Op::Divu => divu(env, rd, ra, rb),
+        Op::Rem => rem(env, rd, ra, rb),
+        Op::Remu => remu(env, rd, ra, rb),
+        Op::Lb => load(env, rd, ra, imm, 1, true)?,
+        Op::Lh => load(env, rd, ra, imm, 2, true)?,
+        Op::Lw => load(env, rd, ra, imm, 4, true)?,
+        Op::Lbu => load(env, rd, ra, imm, 1, false)?,
+        Op::Lhu => load(env, rd, ra, imm, 2, false)?,
+        Op::Sb => store(env, ra, rb, imm, 1)?,
+        Op::Sh => store(env, ra, rb, imm, 2)?,
+        Op::Sw => store(env, ra, rb, imm, 4)?,
+        Op::Beq | Op::Bne | Op::Blt | Op::Bge | Op::Bltu | Op::Bgeu => {
+            let pc = env.pc;
+            let taken = match decoded.op {
+                Op::Beq => branch(env, ra, rb, imm, |a, b| a == b),
+                Op::Bne => branch(env, ra, rb, imm, |a, b| a != b),
+                Op::Blt => branch(env, ra, rb, imm, |a, b| (a as i32) < b as i32),
+                Op::Bge => branch(env, ra, rb, imm, |a, b| a as i32 >= b as i32),
+                Op::Bltu => branch(env, ra, rb, imm, |a, b| a < b),
+                _ => branch(env, ra, rb, imm, |a, b| a >= b),
+            };
+            if let Some(predictor) = &mut env.predictor {
+                predictor.update(pc, pc.wrapping_add(imm), taken);
+            }
+            return Ok(taken);
+        }
+        Op::Jal => {
+            jal(env, rd, imm);
+            return Ok(true);
+        }
+        Op::Jalr => {
+            jalr(env, rd, ra, imm);
+            return Ok(true);
+        }
+        Op::Ecall => {
+            if ecall(env)? {
+                return Ok(true);
+            }
+        }
+        Op::Ebreak => {
+            if ebreak(env)? {
+                return Ok(true);
+            }
+        }
+        // Harts run one instruction at a time, in order: nothing to order
+        Op::Fence => {}
+        Op::LrW => lr_w(env, rd, ra)?,
+        Op::ScW => sc_w(env, rd, ra, rb)?,
+        Op::AmoswapW => amo(env, rd, ra, rb, |_, b| b)?,
+        Op::AmoaddW => amo(env, rd, ra, rb, u32::wrapping_add)?,
+        Op::AmoxorW => amo(env, rd, ra, rb, |a, b| a ^ b)?,
+        Op::AmoandW => amo(env, rd, ra, rb, |a, b| a & b)?,
+        Op::AmoorW => amo(env, rd, ra, rb, |a, b| a | b)?,
+        Op::AmominW => amo(env, rd, ra, rb, |a, b| (a as i32).min(b as i32) as u32)?,
+        Op::AmomaxW => amo(env, rd, ra, rb, |a, b| (a as i32).max(b as i32) as u32)?,
+        Op::AmominuW => amo(env, rd, ra, rb, u32::min)?,
+        Op::AmomaxuW => amo(env, rd, ra, rb, u32::max)?,
+        // csrrs and csrrc with x0 only read
+        Op::Csrrw => csr_op(env, rd, imm & 0xfff, env.get_register(ra), true, |_, v| v),
+        Op::Csrrs => csr_op(
+            env,
+            rd,
+            imm & 0xfff,
+            env.get_register(ra),
+            ra != 0,
+            |o, v| o | v,
+        ),
+        Op::Csrrc => csr_op(
+            env,
+            rd,
+            imm & 0xfff,
+            env.get_register(ra),
+            ra != 0,
+            |o, v| o & !v,
+        ),
+        Op::Csrrwi => csr_op(env, rd, imm & 0xfff, ra as u32, true, |_, v| v),
+        Op::Csrrsi => csr_op(env, rd, imm & 0xfff, ra as u32, ra != 0, |o, v| o | v),
+        Op::Csrrci => csr_op(env, rd, imm & 0xfff, ra as u32, ra != 0, |o, v| o & !v),
+        Op::Mret => {
+            env.pc = env.csrs.mret();
+            return Ok(true);
+        }
+        // Interrupts are only taken between instructions anyway
+        Op::Wfi => {}
+        Op::FaddS => fadd_s(env, fd, fa, fb),
+        Op::FdivS => fdiv_s(env, fd, fa, fb),
+        Op::FmaddS => fmadd_s(env, fd, fa, fb, fc),
+        Op::FcvtSW => fcvt_s_w(env, fd, ra),
+        Op::FmvWX => fmv_w_x(env, fd, ra),
+        Op::Unknown => {
+            env.pc = env
+                .csrs
+                .trap(env.pc, csr::ILLEGAL_INSTRUCTION, decoded.word);
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Fetch and execute the instruction at pc, then let the devices catch up.
+///
+/// An interrupt that is pending and enabled afterwards is taken right away,
+/// so the next step starts at its handler.
+pub fn step(env: &mut Env) -> Result<(), StepErr> {
+    let decoded = match env.decoded.get(env.pc) {
+        Some(decoded) => decoded,
+        None => {
+            let decoded = Decoded::new(env.memory.fetch(env.pc)?);
+            env.decoded.insert(env.pc, decoded);
+            decoded
+        }
+    };
+    let pc = env.pc;
+    env.caches.fetch(pc);
+    retire(env, &decoded)?;
+    if let Some(pipeline) = &mut env.pipeline {
+        pipeline.issue(pc, &decoded, env.pc);
+    }
+    Ok(())
+}
+
+/// Execute `decoded`, the instruction at pc, and everything `step` does after.
+/// Returns whether the next instruction is the one right after it
+fn retire(env: &mut Env, decoded: &Decoded) -> Result<bool, StepErr> {
+    let jumped = execute(env, decoded)?;
+    if !jumped {
+        env.pc = env.pc.wrapping_add(4);
+    }
+    env.csrs.instret = env.csrs.instret.wrapping_add(1);
+    env.devices.tick();
+    if env.pc == EXIT_ADDRESS {
+        env.exit = Some(env.get_register(10));
+    }
+    Ok(!interrupt(env) && !jumped && env.exit.is_none())
+}
+
+/// Take an interrupt that is pending and enabled, returning whether there was one
+fn interrupt(env: &mut Env) -> bool {
+    // Asking the devices is the slow part, and only matters with interrupts on
+    if env.csrs.mstatus & csr::MSTATUS_MIE == 0 {
+        return false;
+    }
+    match env.csrs.interrupt(env.devices.mip()) {
+        Some(cause) => {
+            env.pc = env.csrs.trap(env.pc, cause, 0);
             true
         }
-        0b1010011 if funct7 == 0x00 => {
-            fadd_s(env, fd, fa, fb);
-            false
+        None => false,
+    }
+}
+
+/// Whether running `decoded` could let the program see the devices as they
+/// are: an access to one, a CSR like `mip`, or a call out of the program
+fn sees_devices(env: &Env, decoded: &Decoded) -> bool {
+    let base = env.get_register(decoded.ra as usize);
+    match decoded.op {
+        Op::Lb | Op::Lh | Op::Lw | Op::Lbu | Op::Lhu | Op::Sb | Op::Sh | Op::Sw => {
+            env.devices.claims(base.wrapping_add(decoded.imm))
+        }
+        Op::LrW
+        | Op::ScW
+        | Op::AmoswapW
+        | Op::AmoaddW
+        | Op::AmoxorW
+        | Op::AmoandW
+        | Op::AmoorW
+        | Op::AmominW
+        | Op::AmomaxW
+        | Op::AmominuW
+        | Op::AmomaxuW => env.devices.claims(base),
+        Op::Ecall
+        | Op::Ebreak
+        | Op::Csrrw
+        | Op::Csrrs
+        | Op::Csrrc
+        | Op::Csrrwi
+        | Op::Csrrsi
+        | Op::Csrrci
+        | Op::Mret
+        | Op::Wfi
+        | Op::Fence
+        | Op::Unknown => true,
+        _ => false,
+    }
+}
+
+/// Run a translated block from pc, as `step` would one instruction at a time,
+/// until its end, `room` instructions, or anything that leaves it: a jump, an
+/// interrupt, an exit or a store changing code. Returns how many ran.
+///
+/// With interrupts off, fused instructions run as one, and the devices only
+/// catch up on the instructions before one that could see them, and at the end
+fn run_block(env: &mut Env, ops: &[BlockOp], room: u64) -> (u64, Result<(), StepErr>) {
+    let flushes = env.blocks.flushes();
+    let mut ran = 0;
+    if env.csrs.mstatus & csr::MSTATUS_MIE != 0 {
+        // An interrupt could come between a fused pair
+        for decoded in ops.iter().flat_map(BlockOp::decoded) {
+            if ran == room {
+                break;
+            }
+            let straight = match retire(env, decoded) {
+                Ok(straight) => straight,
+                Err(err) => return (ran, Err(err)),
+            };
+            ran += 1;
+            if !straight || env.blocks.flushes() != flushes {
+                break;
+            }
+        }
+        return (ran, Ok(()));
+    }
+
+    let mut result = Ok(());
+    // Instructions the devices haven't ticked for yet
+    let mut owed = 0;
+    'ops: for op in ops {
+        if let BlockOp::Constant { rd, value, .. } = *op {
+            if room - ran >= 2 {
+                env.set_register(rd as usize, value);
+                env.pc = env.pc.wrapping_add(8);
+                env.csrs.instret = env.csrs.instret.wrapping_add(2);
+                ran += 2;
+                owed += 2;
+                if env.pc == EXIT_ADDRESS {
+                    env.exit = Some(env.get_register(10));
+                    break;
+                }
+                continue;
+            }
+        }
+        for decoded in op.decoded() {
+            if ran == room {
+                break 'ops;
+            }
+            if owed > 0 && sees_devices(env, decoded) {
+                env.devices.tick_by(owed);
+                owed = 0;
+            }
+            let jumped = match execute(env, decoded) {
+                Ok(jumped) => jumped,
+                Err(err) => {
+                    result = Err(err);
+                    break 'ops;
+                }
+            };
+            if !jumped {
+                env.pc = env.pc.wrapping_add(4);
+            }
+            env.csrs.instret = env.csrs.instret.wrapping_add(1);
+            ran += 1;
+            owed += 1;
+            if env.pc == EXIT_ADDRESS {
+                env.exit = Some(env.get_register(10));
+            }
+            if jumped || env.exit.is_some() || env.blocks.flushes() != flushes {
+                break 'ops;
+            }
+        }
+    }
+    if owed > 0 {
+        env.devices.tick_by(owed);
+        // Only the last instruction could have turned interrupts on
+        interrupt(env);
+    }
+    (ran, result)
+}
+
+/// Why `run` stopped
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    /// The program exited with this code
+    Exit(u32),
+    /// Every hart ran off the end of the code, a normal exit in RARS
+    Finished,
+    /// It ran as many instructions as it was allowed to
+    Limit,
+    /// The instruction at pc faulted
+    Fault(MemoryErr),
+    /// The syscall at pc couldn't be carried out
+    Syscall(SyscallErr),
+    /// The instruction at `mepc` took this exception, with no handler to go to
+    Exception(u32),
+}
+
+impl From<StepErr> for Stop {
+    fn from(err: StepErr) -> Self {
+        match err {
+            StepErr::Memory(err) => Stop::Fault(err),
+            StepErr::Syscall(err) => Stop::Syscall(err),
         }
-        0b1010011 if funct7 == 0x0c => {
-            fdiv_s(env, fd, fa, fb);
-            false
+    }
+}
+
+/// Step the harts until the program stops, or `limit` instructions have run,
+/// returning how many did and why it stopped.
+///
+/// With a single hart, code runs a basic block at a time from `env.blocks`,
+/// which only asks whether code is `runnable` when translating it. `step`
+/// stays the plain interpreter, for stepping and breakpoints.
+pub fn run(
+    env: &mut Env,
+    harts: &mut Harts,
+    limit: Option<u64>,
+    runnable: impl Fn(u32) -> bool,
+) -> (u64, Stop) {
+    let mut count = 0;
+    // The block that ran last, to go straight on to the next one
+    let mut last = None;
+    loop {
+        if let Some(code) = env.exit {
+            return (count, Stop::Exit(code));
+        }
+        if limit.is_some_and(|limit| count >= limit) {
+            return (count, Stop::Limit);
         }
-        0b1000011 if fb == 0 => {
-            fmadd_s(env, fd, fa, fb, fc);
-            false
+        if harts.len() > 1 && harts.next(env, &runnable).is_none() {
+            return (count, Stop::Finished);
         }
-        0b1010011 if funct7 == 0x68 => {
-            fcvt_s_w(env, fd, ra);
-            false
+
+        let pc = env.pc;
+        // Harts take turns an instruction at a time, so only one runs whole
+        // blocks, and the pipeline and caches see each fetch `step` makes
+        let block = match harts.len() {
+            1 if env.pipeline.is_none() && env.caches.is_empty() => {
+                env.blocks.find(&env.memory, last, pc, &runnable)
+            }
+            _ => None,
+        };
+        last = None;
+        if let Some(index) = block {
+            let ops = env.blocks.ops(index);
+            let room = limit.map_or(u64::MAX, |limit| limit - count);
+            let flushes = env.blocks.flushes();
+            let (ran, result) = run_block(env, &ops, room);
+            count += ran;
+            if let Err(err) = result {
+                return (count, err.into());
+            }
+            if env.blocks.flushes() == flushes {
+                last = Some(index);
+            }
+        } else {
+            if harts.len() == 1 && !runnable(pc) {
+                return (count, Stop::Finished);
+            }
+            if let Err(err) = step(env) {
+                return (count, err.into());
+            }
+            count += 1;
         }
-        0b1010011 if funct7 == 0x78 => {
-            fmv_w_x(env, fd, ra);
-            false
+        if let Some(cause) = env.csrs.unhandled.take() {
+            return (count, Stop::Exception(cause));
         }
-        _ => todo!("op: {:032b}", instruction),
     }
 }
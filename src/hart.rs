@@ -0,0 +1,242 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    csr::Csrs,
+    env::Env,
+    memory::{STACK_SIZE, STACK_TOP},
+};
+
+/// What a hart keeps to itself, everything else in `Env` is shared
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hart {
+    pub registers: [u32; 32],
+    #[serde(with = "crate::snapshot::fregisters")]
+    pub fregisters: [f32; 32],
+    pub pc: u32,
+    pub csrs: Csrs,
+}
+
+impl Hart {
+    fn save(env: &Env) -> Self {
+        Self {
+            registers: env.registers,
+            fregisters: env.fregisters,
+            pc: env.pc,
+            csrs: env.csrs.clone(),
+        }
+    }
+
+    fn restore(&self, env: &mut Env) {
+        env.registers = self.registers;
+        env.fregisters = self.fregisters;
+        env.pc = self.pc;
+        env.csrs = self.csrs.clone();
+    }
+}
+
+/// How the next hart to run an instruction is picked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Schedule {
+    /// Each hart in turn
+    #[default]
+    RoundRobin,
+    /// Any hart, from a seeded generator so the same seed gives the same interleaving
+    Random,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Schedule::RoundRobin),
+            "random" => Ok(Schedule::Random),
+            _ => Err(format!(
+                "unknown schedule '{}', expected round-robin or random",
+                s
+            )),
+        }
+    }
+}
+
+/// Where a schedule is, to go back to when stepping backwards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    turn: usize,
+    seed: u64,
+}
+
+/// Harts sharing one `Env`, one instruction at a time.
+///
+/// The hart that's running lives in the `Env` itself, the others are kept
+/// here until they get their turn.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Harts {
+    harts: Vec<Hart>,
+    /// The hart in the `Env`
+    pub current: usize,
+    schedule: Schedule,
+    /// Hart round-robin starts looking from
+    turn: usize,
+    /// splitmix64 state
+    seed: u64,
+}
+
+impl Harts {
+    /// `count` harts starting where `env` is, each with its own slice of the
+    /// stack below the one `env` started with, and `mhartid`
+    pub fn new(env: &mut Env, count: usize, schedule: Schedule, seed: u64) -> Self {
+        let count = count.max(1);
+        // What's left of the stack once the program's arguments are on it
+        let slice =
+            (env.get_register(2).saturating_sub(STACK_TOP - STACK_SIZE) / count as u32) & !15;
+        let harts = (0..count)
+            .map(|i| {
+                let mut hart = Hart::save(env);
+                hart.registers[2] -= i as u32 * slice;
+                hart.csrs.hartid = i as u32;
+                hart
+            })
+            .collect::<Vec<_>>();
+        harts[0].restore(env);
+
+        Self {
+            harts,
+            current: 0,
+            schedule,
+            turn: 0,
+            seed,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.harts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.harts.is_empty()
+    }
+
+    /// pc of hart `id`
+    pub fn pc(&self, env: &Env, id: usize) -> u32 {
+        if id == self.current {
+            env.pc
+        } else {
+            self.harts[id].pc
+        }
+    }
+
+    /// Registers of hart `id`
+    pub fn registers<'a>(&'a self, env: &'a Env, id: usize) -> &'a [u32; 32] {
+        if id == self.current {
+            &env.registers
+        } else {
+            &self.harts[id].registers
+        }
+    }
+
+    /// Put the next hart whose pc is `runnable` in `env`, returning it, or
+    /// `None` once every hart has stopped
+    pub fn next(&mut self, env: &mut Env, runnable: impl Fn(u32) -> bool) -> Option<usize> {
+        let ready = (0..self.len())
+            .filter(|&id| runnable(self.pc(env, id)))
+            .collect::<Vec<_>>();
+        let id = match self.schedule {
+            _ if ready.is_empty() => return None,
+            Schedule::RoundRobin => *ready
+                .iter()
+                .find(|&&id| id >= self.turn)
+                .unwrap_or(&ready[0]),
+            Schedule::Random => ready[(self.random() % ready.len() as u64) as usize],
+        };
+        self.turn = id + 1;
+        self.switch(env, id);
+        Some(id)
+    }
+
+    pub fn position(&self) -> Position {
+        Position {
+            turn: self.turn,
+            seed: self.seed,
+        }
+    }
+
+    /// Put hart `id` in `env` and the schedule back at `position`
+    pub fn rewind(&mut self, env: &mut Env, id: usize, position: Position) {
+        self.switch(env, id);
+        self.turn = position.turn;
+        self.seed = position.seed;
+    }
+
+    /// Put hart `id` in `env`, keeping the one that was there
+    fn switch(&mut self, env: &mut Env, id: usize) {
+        if id != self.current {
+            self.harts[self.current] = Hart::save(env);
+            self.harts[id].restore(env);
+            self.current = id;
+        }
+    }
+
+    fn random(&mut self) -> u64 {
+        splitmix64(&mut self.seed)
+    }
+}
+
+/// Next number from a splitmix64 generator, so a seed means the same thing everywhere
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{env::Env, tests::load_program};
+
+    #[test]
+    fn harts() {
+        use super::{Harts, Schedule};
+        use crate::execution::step;
+
+        // Two harts add 50 each to a shared counter
+        let run = |body: &str, schedule, seed| {
+            let input = format!(
+                "li t0 0x10000000\nli t1 50\nloop:\n{}\naddi t1 t1 -1\nbnez t1 loop",
+                body
+            );
+            let mut env = Env::new();
+            let end = load_program(&mut env, &input);
+            let mut harts = Harts::new(&mut env, 2, schedule, seed);
+            while harts.next(&mut env, |pc| pc < end).is_some() {
+                step(&mut env).unwrap();
+            }
+            env.memory.read_u32(0x10000000)
+        };
+
+        let racy = "lw t2 0(t0)\naddi t2 t2 1\nsw t2 0(t0)";
+        assert!(run(racy, Schedule::RoundRobin, 0) < 100);
+        assert_eq!(
+            run(racy, Schedule::Random, 7),
+            run(racy, Schedule::Random, 7)
+        );
+        assert_ne!(
+            run(racy, Schedule::Random, 7),
+            run(racy, Schedule::Random, 8)
+        );
+
+        let atomic = "li t2 1\namoadd.w zero, t2, (t0)";
+        assert_eq!(run(atomic, Schedule::Random, 7), 100);
+        // sc.w fails whenever the other hart stored in between, and is retried
+        let lr_sc = "retry:\nlr.w t2, (t0)\naddi t2 t2 1\nsc.w t3, t2, (t0)\nbnez t3 retry";
+        assert_eq!(run(lr_sc, Schedule::RoundRobin, 0), 100);
+        assert_eq!(run(lr_sc, Schedule::Random, 7), 100);
+
+        // misa says atomics are there
+        let misa = crate::csr::Csrs::default().read(crate::csr::MISA, 0);
+        assert_eq!(misa & 1, 1);
+    }
+}
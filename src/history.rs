@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use crate::{
+    csr::Csrs,
+    env::Env,
+    err::StepErr,
+    execution,
+    hart::{Harts, Position},
+};
+
+/// What one instruction overwrote, to put back when stepping backwards
+#[derive(Debug, Clone)]
+pub struct Undo {
+    /// The hart that ran it
+    pub hart: usize,
+    /// Where the schedule was before picking `hart`
+    position: Position,
+    /// Address of the instruction
+    pub pc: u32,
+    pub registers: Vec<(usize, u32)>,
+    pub fregisters: Vec<(usize, f32)>,
+    pub csrs: Csrs,
+    /// Oldest first
+    pub memory: Vec<(u32, u8)>,
+    /// Only kept when the instruction changed them
+    reservations: Option<HashMap<u32, u32>>,
+    exit: Option<u32>,
+}
+
+/// Every instruction run so far, as an undo log.
+///
+/// Registers, CSRs, memory, the pc and which hart ran are all wound back,
+/// as is the pipeline model. Devices, caches, the branch predictor and what
+/// syscalls printed or read aren't.
+#[derive(Debug, Default)]
+pub struct History {
+    undos: Vec<Undo>,
+}
+
+impl History {
+    /// Number of instructions run
+    pub fn len(&self) -> usize {
+        self.undos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undos.is_empty()
+    }
+
+    /// Pick a hart like `Harts::next` and run its next instruction, returning
+    /// the hart, or `None` once every hart has stopped or the program exited
+    pub fn step(
+        &mut self,
+        env: &mut Env,
+        harts: &mut Harts,
+        runnable: impl Fn(u32) -> bool,
+    ) -> Option<(usize, Result<(), StepErr>)> {
+        if env.exit.is_some() {
+            return None;
+        }
+        let position = harts.position();
+        let hart = harts.next(env, runnable)?;
+        let registers = env.registers;
+        let fregisters = env.fregisters;
+        let csrs = env.csrs.clone();
+        let pc = env.pc;
+        let reservations = env.reservations.clone();
+        let exit = env.exit;
+
+        env.journal = Some(Vec::new());
+        let result = execution::step(env);
+        let memory = env.journal.take().unwrap_or_default();
+
+        self.undos.push(Undo {
+            hart,
+            position,
+            pc,
+            registers: changed(&registers, &env.registers),
+            // By bits, NaN isn't equal to itself
+            fregisters: changed(
+                &fregisters.map(f32::to_bits),
+                &env.fregisters.map(f32::to_bits),
+            )
+            .into_iter()
+            .map(|(i, bits)| (i, f32::from_bits(bits)))
+            .collect(),
+            csrs,
+            memory,
+            reservations: (reservations != env.reservations).then_some(reservations),
+            exit,
+        });
+        Some((hart, result))
+    }
+
+    /// The last instruction run
+    pub fn last(&self) -> Option<&Undo> {
+        self.undos.last()
+    }
+
+    /// Undo the last instruction, returning what it had overwritten, or
+    /// `None` at the start
+    pub fn back(&mut self, env: &mut Env, harts: &mut Harts) -> Option<Undo> {
+        let undo = self.undos.pop()?;
+        harts.rewind(env, undo.hart, undo.position);
+        env.pc = undo.pc;
+        for &(i, value) in undo.registers.iter() {
+            env.registers[i] = value;
+        }
+        for &(i, value) in undo.fregisters.iter() {
+            env.fregisters[i] = value;
+        }
+        env.csrs = undo.csrs.clone();
+        for &(addr, byte) in undo.memory.iter().rev() {
+            env.memory.write_u8(addr, byte);
+            env.decoded.invalidate(addr, 1);
+            env.blocks.invalidate(addr, 1);
+        }
+        if let Some(reservations) = &undo.reservations {
+            env.reservations = reservations.clone();
+        }
+        env.exit = undo.exit;
+        if let Some(pipeline) = &mut env.pipeline {
+            pipeline.back();
+        }
+        Some(undo)
+    }
+}
+
+/// Registers that differ between `before` and `after`, with their values from before
+fn changed(before: &[u32; 32], after: &[u32; 32]) -> Vec<(usize, u32)> {
+    before
+        .iter()
+        .zip(after)
+        .enumerate()
+        .filter(|(_, (before, after))| before != after)
+        .map(|(i, (before, _))| (i, *before))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{env::Env, tests::load_program};
+
+    #[test]
+    fn history() {
+        use super::History;
+        use crate::hart::{Harts, Schedule};
+
+        let input = "li t0 0x10000000\nli t1 10\nloop:\nlw t2 0(t0)\naddi t2 t2 1\nsw t2 0(t0)\n\
+                     fcvt.s.w ft0 t2\namoadd.w zero, t1, (t0)\naddi t1 t1 -1\nbnez t1 loop";
+        let mut env = Env::new();
+        let end = load_program(&mut env, input);
+        let mut harts = Harts::new(&mut env, 2, Schedule::Random, 5);
+        let mut history = History::default();
+        let run = |env: &mut Env, harts: &mut Harts, history: &mut History| {
+            while let Some((_, result)) = history.step(env, harts, |pc| pc < end) {
+                result.unwrap();
+            }
+            (
+                env.memory.read_u32(0x10000000),
+                env.registers,
+                env.csrs.instret,
+            )
+        };
+
+        let finished = run(&mut env, &mut harts, &mut history);
+        let steps = history.len();
+        while history.back(&mut env, &mut harts).is_some() {}
+        assert_eq!(env.memory.read_u32(0x10000000), 0);
+        assert_eq!(env.pc, 0);
+        assert_eq!(env.fregisters[0], 0.0);
+        assert_eq!(env.csrs.instret, 0);
+
+        // Going forward again takes the same path, schedule included
+        assert_eq!(run(&mut env, &mut harts, &mut history), finished);
+        assert_eq!(history.len(), steps);
+    }
+}
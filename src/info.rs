@@ -1,6 +1,6 @@
 use colored::Colorize;
 
-use crate::env::Env;
+use crate::{env::Env, parser::Token};
 
 /// Display a helpful message about an instruction.
 ///
@@ -36,6 +36,11 @@ pub fn info(
             args[1].italic().yellow(),
             args[0].red()
         )],
+        "lui" if args[1].parse::<i32>().is_err() => vec![format!(
+            "load the upper 20 bits of {} into {}",
+            args[1].italic().yellow(),
+            args[0].blue()
+        )],
         "lui" => {
             let imm = format!("{:032b}", args[1].parse::<i32>().unwrap() as u32)
                 .chars()
@@ -57,6 +62,43 @@ pub fn info(
                 ),
             ]
         }
+        "auipc" => vec![
+            format!(
+                "add the upper 20 bits of {} to the pc and store the result in {}",
+                args[1].italic().yellow(),
+                args[0].blue()
+            ),
+            format!("{} ← pc + ({} << 12)", args[0].blue(), args[1].italic().yellow()),
+        ],
+        "mv" => {
+            tag = (vec![env.str_to_register(&args[1]).unwrap()], vec![]);
+            vec![format!(
+                "copy the value of {} into {}",
+                args[1].blue(),
+                args[0].blue()
+            )]
+        }
+        "la" => vec![format!(
+            "load the address of {} into {}",
+            args[1].italic().yellow(),
+            args[0].red()
+        )],
+        "jalr" => vec![
+            format!(
+                "jump to {} + {} and store the return address in {}",
+                args[1].blue(),
+                args[2].italic().yellow(),
+                args[0].blue()
+            ),
+            format!("{} ← pc + 4", args[0].blue()),
+        ],
+        "jr" => vec![format!("jump to the address in {}", args[0].blue())],
+        "call" => vec![format!(
+            "call {} and store the return address in {}",
+            args[0].italic().yellow(),
+            "ra".blue()
+        )],
+        "ret" => vec![format!("return to the address in {}", "ra".blue())],
         "add" => {
             tag = (
                 vec![
@@ -73,6 +115,22 @@ pub fn info(
             )]
         }
         "addi" => {
+            tag = (vec![env.str_to_register(&args[1]).unwrap()], vec![]);
+            vec![format!(
+                "add the values of {0} and {1} and store the result in {2}\n{2} ← {0} + {1}",
+                args[1].blue(),
+                args[2].italic().yellow(),
+                args[0].blue()
+            )]
+        }
+        "sub" => vec![format!(
+            "subtract the value of {} from the value of {} and store the result in {}",
+            args[1].blue(),
+            args[2].blue(),
+            args[0].blue()
+        )],
+        "and" | "or" | "xor" | "sll" | "srl" | "sra" | "slt" | "sltu" | "mulh" | "mulhsu"
+        | "mulhu" | "div" | "divu" | "rem" | "remu" => {
             tag = (
                 vec![
                     env.str_to_register(&args[1]).unwrap(),
@@ -80,26 +138,173 @@ pub fn info(
                 ],
                 vec![],
             );
+            let (what, expr) = binary(op);
             vec![format!(
-                "add the values of {0} and {1} and store the result in {2}\n{2} ← {0} + {1}",
+                "{3} {0} and {1} and store the result in {2}\n{2} ← {0} {4} {1}",
+                args[1].blue(),
                 args[2].blue(),
+                args[0].blue(),
+                what,
+                expr
+            )]
+        }
+        "andi" | "ori" | "xori" | "slli" | "srli" | "srai" | "slti" | "sltiu" => {
+            tag = (vec![env.str_to_register(&args[1]).unwrap()], vec![]);
+            let (what, expr) = binary(op.trim_end_matches('i'));
+            vec![format!(
+                "{3} {0} and {1} and store the result in {2}\n{2} ← {0} {4} {1}",
+                args[1].blue(),
+                args[2].italic().yellow(),
+                args[0].blue(),
+                what,
+                expr
+            )]
+        }
+        "lb" | "lh" | "lw" | "lbu" | "lhu" => vec![format!(
+            "load {} {} from memory at {} into {}",
+            if op.ends_with('u') { "an unsigned" } else { "a signed" },
+            width(op),
+            args[1].italic().yellow(),
+            args[0].blue()
+        )],
+        "sb" | "sh" | "sw" => {
+            tag = (vec![env.str_to_register(&args[0]).unwrap()], vec![]);
+            vec![format!(
+                "store the lower {} of {} in memory at {}",
+                width(op),
+                args[0].blue(),
+                args[1].italic().yellow()
+            )]
+        }
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+            tag = (
+                vec![
+                    env.str_to_register(&args[0]).unwrap(),
+                    env.str_to_register(&args[1]).unwrap(),
+                ],
+                vec![],
+            );
+            vec![format!(
+                "jump to {} if {} {} {}{}",
+                args[2].italic().yellow(),
+                args[0].blue(),
+                match op {
+                    "beq" => "=",
+                    "bne" => "≠",
+                    "blt" | "bltu" => "<",
+                    _ => "≥",
+                },
+                args[1].blue(),
+                if op.ends_with('u') { " (unsigned)" } else { "" }
+            )]
+        }
+        "beqz" | "bnez" => {
+            tag = (vec![env.str_to_register(&args[0]).unwrap()], vec![]);
+            vec![format!(
+                "jump to {} if {} {} 0",
                 args[1].italic().yellow(),
-                args[0].blue()
+                args[0].blue(),
+                if op == "beqz" { "=" } else { "≠" }
             )]
         }
-        "sub" => vec![format!(
-            "subtract the value of {} from the value of {} and store the result in {}",
-            args[1].blue(),
-            args[2].blue(),
+        "j" => vec![format!("jump to {}", args[0].italic().yellow())],
+        "jal" => vec![
+            format!(
+                "jump to {} and store the return address in {}",
+                args[1].italic().yellow(),
+                args[0].blue()
+            ),
+            format!("{} ← pc + 4", args[0].blue()),
+        ],
+        "ecall" => vec!["ask the environment to perform a system call".to_string()],
+        "ebreak" => vec!["stop and hand control to the debugger".to_string()],
+        "fence" => vec![
+            "order memory accesses, does nothing as harts run one instruction at a time"
+                .to_string(),
+        ],
+        "lr.w" => vec![
+            format!(
+                "load the word at {} into {} and reserve it",
+                args[1].yellow(),
+                args[0].blue()
+            ),
+            "a store to it by any hart breaks the reservation".to_string(),
+        ],
+        "sc.w" => vec![
+            format!(
+                "store {} at {} if this hart still has it reserved",
+                args[1].blue(),
+                args[2].yellow()
+            ),
+            format!("{} ← 0 if it did, 1 if it didn't", args[0].blue()),
+        ],
+        op if op.starts_with("amo") => vec![
+            format!(
+                "atomically {} {} and the word at {}, keeping the old word in {}",
+                match op {
+                    "amoswap.w" => "swap",
+                    "amoadd.w" => "add",
+                    "amoxor.w" => "xor",
+                    "amoand.w" => "and",
+                    "amoor.w" => "or",
+                    "amomin.w" | "amominu.w" => "take the minimum of",
+                    _ => "take the maximum of",
+                },
+                args[1].blue(),
+                args[2].yellow(),
+                args[0].blue()
+            ),
+        ],
+        "csrrw" | "csrrs" | "csrrc" | "csrrwi" | "csrrsi" | "csrrci" => {
+            let (what, symbol) = match &op[..5] {
+                "csrrw" => ("write", "←"),
+                "csrrs" => ("set the bits of", "|="),
+                _ => ("clear the bits of", "&= ~"),
+            };
+            vec![
+                format!(
+                    "read {} into {}, then {} it with {}",
+                    args[1].italic().yellow(),
+                    args[0].blue(),
+                    what,
+                    args[2].blue()
+                ),
+                format!(
+                    "{0} ← {1}, {1} {2} {3}",
+                    args[0].blue(),
+                    args[1].italic().yellow(),
+                    symbol,
+                    args[2].blue()
+                ),
+            ]
+        }
+        "csrr" => vec![format!(
+            "read {} into {}",
+            args[1].italic().yellow(),
             args[0].blue()
         )],
+        "csrw" | "csrwi" => vec![format!(
+            "write {} to {}",
+            args[1].blue(),
+            args[0].italic().yellow()
+        )],
+        "csrs" | "csrsi" | "csrc" | "csrci" => vec![format!(
+            "{} the bits of {} that are set in {}",
+            if op.starts_with("csrs") { "set" } else { "clear" },
+            args[0].italic().yellow(),
+            args[1].blue()
+        )],
+        "mret" => vec![
+            "return from the trap handler to mepc, restoring the interrupt enable".to_string(),
+        ],
+        "wfi" => vec!["wait for an interrupt".to_string()],
         "mul" => {
             tag = (
-                vec![],
                 vec![
-                    env.str_to_fregister(&args[1]).unwrap(),
-                    env.str_to_fregister(&args[2]).unwrap(),
+                    env.str_to_register(&args[1]).unwrap(),
+                    env.str_to_register(&args[2]).unwrap(),
                 ],
+                vec![],
             );
             vec![format!(
                 "multiply the values of {0} and {1} and store the result in {2}\n{2} ← {0} ✕ {1}",
@@ -154,9 +359,97 @@ pub fn info(
                 args[0].blue()
             )]
         }
+        "fmadd.s" => {
+            tag = (
+                vec![],
+                vec![
+                    env.str_to_fregister(&args[1]).unwrap(),
+                    env.str_to_fregister(&args[2]).unwrap(),
+                    env.str_to_fregister(&args[3]).unwrap(),
+                ],
+            );
+            vec![format!(
+                "multiply {0} by {1}, add {2} and store the result in {3}\n{3} ← {0} ✕ {1} + {2}",
+                args[1].blue(),
+                args[2].blue(),
+                args[3].blue(),
+                args[0].blue()
+            )]
+        }
         op => todo!("{}", op),
     }
     .join("\n");
 
     (msg, tag)
 }
+
+/// What a register-register op does and the operator to show it with
+fn binary(op: &str) -> (&'static str, &'static str) {
+    match op {
+        "and" => ("bitwise and", "&"),
+        "or" => ("bitwise or", "|"),
+        "xor" => ("bitwise xor", "^"),
+        "sll" => ("shift left", "<<"),
+        "srl" => ("logical shift right", ">>"),
+        "sra" => ("arithmetic shift right", ">>"),
+        "slt" => ("check if less than", "<"),
+        "sltu" => ("check if less than (unsigned)", "<"),
+        "mulh" | "mulhsu" | "mulhu" => ("multiply, keeping the upper 32 bits of", "✕"),
+        "div" | "divu" => ("divide", "÷"),
+        "rem" | "remu" => ("take the remainder of", "%"),
+        _ => unreachable!(),
+    }
+}
+
+fn width(op: &str) -> &'static str {
+    match &op[1..2] {
+        "b" => "byte",
+        "h" => "half",
+        _ => "word",
+    }
+}
+
+/// An operand as it was written, for `info`
+pub fn arg_text(token: &Token) -> String {
+    match token {
+        Token::Register(reg) => reg.clone(),
+        Token::Immediate(imm) => imm.to_string(),
+        Token::Symbol(sym) => sym.clone(),
+        Token::Modifier(modifier, sym) => format!("%{}({})", modifier, sym),
+        Token::Memory(imm, reg) => format!(
+            "{}({})",
+            arg_text(imm),
+            reg.as_deref().map(arg_text).unwrap_or_default()
+        ),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        env::Env,
+        parser::{parse, Token},
+    };
+
+    #[test]
+    fn step_info() {
+        use crate::info::{arg_text, info};
+
+        let env = Env::new();
+        let tokens = parse(&env, "lui a0 %hi(msg)\nlw a1 %lo(msg)(a0)").unwrap();
+        let texts = tokens
+            .iter()
+            .filter_map(|(token, _)| match token {
+                Token::Op(op, args) => Some((op, args.iter().map(|(a, _)| arg_text(a)).collect())),
+                _ => None,
+            })
+            .collect::<Vec<(&String, Vec<String>)>>();
+        assert_eq!(texts[0].1, ["a0", "%hi(msg)"]);
+        assert_eq!(texts[1].1, ["a1", "%lo(msg)(a0)"]);
+        for (op, args) in texts {
+            let (text, _) = info(&env, op, args, 'd');
+            assert!(text.contains("(msg)"));
+        }
+    }
+}
@@ -162,7 +162,11 @@ pub mod kind {
                 } else {
                     i2.imm()
                 }),
-                Kind::S(s) => Some(s.imm_11_5() | s.imm_4_0()),
+                Kind::S(s) => Some(if s.imm_11_5() >> 6 == 1 {
+                    (s.imm_11_5() << 5) | s.imm_4_0() | 0xFFFFF000
+                } else {
+                    (s.imm_11_5() << 5) | s.imm_4_0()
+                }),
                 Kind::B(b) => Some(
                     ((b.imm_12() as u32) << 12)
                         | ((b.imm_11() as u32) << 11)
@@ -200,42 +204,150 @@ pub mod kind {
             }
         }
 
-        pub fn to_op(instruction: u32) -> (Kind, String) {
+        /// The same as `decode`, under the name existing callers use
+        pub fn to_op(instruction: u32) -> Option<(Kind, String)> {
+            Kind::decode(instruction)
+        }
+
+        /// The kind and name of `instruction`, `None` if it isn't one rizz-v knows
+        pub fn decode(instruction: u32) -> Option<(Kind, String)> {
             let opcode = instruction & 0b00000000000000000000000001111111;
             let funct3 = (instruction & 0b00000000000000000111000000000000) >> 12;
             let funct7 = (instruction & 0b11111110000000000000000000000000) >> 25;
 
-            match opcode {
-                0b0110111 => (Kind::U(U(instruction)), "lui".into()),
-                0b0010011 => (Kind::I(I(instruction)), "addi".into()),
-                0b0110011 if funct3 == 0b000 && funct7 == 0b0000000 => {
-                    (Kind::R(R(instruction)), "add".into())
-                }
-                0b0110011 if funct3 == 0b000 && funct7 == 0b0100000 => {
-                    (Kind::R(R(instruction)), "sub".into())
-                }
-                0b0110011 if funct3 == 0b000 && funct7 == 0b0000001 => {
-                    (Kind::R(R(instruction)), "mul".into())
-                }
-                0b1100011 => (Kind::B(B(instruction)), "beq".into()),
-                0b1101111 => (Kind::J(J(instruction)), "jal".into()),
+            let (kind, name) = match opcode {
+                0b0110111 => (Kind::U(U(instruction)), "lui"),
+                0b0010111 => (Kind::U(U(instruction)), "auipc"),
+                0b0000011 => (
+                    Kind::I(I(instruction)),
+                    match funct3 {
+                        0b000 => "lb",
+                        0b001 => "lh",
+                        0b010 => "lw",
+                        0b100 => "lbu",
+                        0b101 => "lhu",
+                        _ => return None,
+                    },
+                ),
+                0b0100011 => (
+                    Kind::S(S(instruction)),
+                    match funct3 {
+                        0b000 => "sb",
+                        0b001 => "sh",
+                        0b010 => "sw",
+                        _ => return None,
+                    },
+                ),
+                0b0010011 if funct3 == 0b001 || funct3 == 0b101 => (
+                    Kind::I2(I2(instruction)),
+                    match (funct3, funct7) {
+                        (0b001, 0b0000000) => "slli",
+                        (0b101, 0b0000000) => "srli",
+                        (0b101, 0b0100000) => "srai",
+                        _ => return None,
+                    },
+                ),
+                0b0010011 => (
+                    Kind::I(I(instruction)),
+                    match funct3 {
+                        0b000 => "addi",
+                        0b010 => "slti",
+                        0b011 => "sltiu",
+                        0b100 => "xori",
+                        0b110 => "ori",
+                        0b111 => "andi",
+                        _ => unreachable!(),
+                    },
+                ),
+                0b0110011 => (
+                    Kind::R(R(instruction)),
+                    match (funct7, funct3) {
+                        (0b0000000, 0b000) => "add",
+                        (0b0100000, 0b000) => "sub",
+                        (0b0000000, 0b001) => "sll",
+                        (0b0000000, 0b010) => "slt",
+                        (0b0000000, 0b011) => "sltu",
+                        (0b0000000, 0b100) => "xor",
+                        (0b0000000, 0b101) => "srl",
+                        (0b0100000, 0b101) => "sra",
+                        (0b0000000, 0b110) => "or",
+                        (0b0000000, 0b111) => "and",
+                        (0b0000001, 0b000) => "mul",
+                        (0b0000001, 0b001) => "mulh",
+                        (0b0000001, 0b010) => "mulhsu",
+                        (0b0000001, 0b011) => "mulhu",
+                        (0b0000001, 0b100) => "div",
+                        (0b0000001, 0b101) => "divu",
+                        (0b0000001, 0b110) => "rem",
+                        (0b0000001, 0b111) => "remu",
+                        _ => return None,
+                    },
+                ),
+                0b1100011 => (
+                    Kind::B(B(instruction)),
+                    match funct3 {
+                        0b000 => "beq",
+                        0b001 => "bne",
+                        0b100 => "blt",
+                        0b101 => "bge",
+                        0b110 => "bltu",
+                        0b111 => "bgeu",
+                        _ => return None,
+                    },
+                ),
+                0b1101111 => (Kind::J(J(instruction)), "jal"),
+                0b1100111 if funct3 == 0b000 => (Kind::I(I(instruction)), "jalr"),
+                0b0001111 => (Kind::I(I(instruction)), "fence"),
+                0b1110011 if instruction == 0x00000073 => (Kind::I(I(instruction)), "ecall"),
+                0b1110011 if instruction == 0x00100073 => (Kind::I(I(instruction)), "ebreak"),
+                0b1110011 if instruction == 0x30200073 => (Kind::I(I(instruction)), "mret"),
+                0b1110011 if instruction == 0x10500073 => (Kind::I(I(instruction)), "wfi"),
+                0b1110011 => (
+                    Kind::I(I(instruction)),
+                    match funct3 {
+                        0b001 => "csrrw",
+                        0b010 => "csrrs",
+                        0b011 => "csrrc",
+                        0b101 => "csrrwi",
+                        0b110 => "csrrsi",
+                        0b111 => "csrrci",
+                        _ => return None,
+                    },
+                ),
+                // The aq and rl bits don't change anything with in order harts
+                0b0101111 if funct3 == 0b010 => (
+                    Kind::R(R(instruction)),
+                    match funct7 >> 2 {
+                        0b00010 if instruction >> 20 & 0x1f == 0 => "lr.w",
+                        0b00011 => "sc.w",
+                        0b00001 => "amoswap.w",
+                        0b00000 => "amoadd.w",
+                        0b00100 => "amoxor.w",
+                        0b01100 => "amoand.w",
+                        0b01000 => "amoor.w",
+                        0b10000 => "amomin.w",
+                        0b10100 => "amomax.w",
+                        0b11000 => "amominu.w",
+                        0b11100 => "amomaxu.w",
+                        _ => return None,
+                    },
+                ),
                 0b1010011 if funct7 == 0x00 => {
-                    (Kind::R(R(instruction)), "fadd.s".into())
+                    (Kind::R(R(instruction)), "fadd.s")
                 }
                 0b1010011 if funct7 == 0x0c => {
-                    (Kind::R(R(instruction)), "fdiv.s".into())
+                    (Kind::R(R(instruction)), "fdiv.s")
                 }
                 0b1010011 if funct7 == 0x68 => {
-                    (Kind::R(R(instruction)), "fcvt.s.w".into())
+                    (Kind::R(R(instruction)), "fcvt.s.w")
                 }
                 0b1010011 if funct7 == 0x78 => {
-                    (Kind::R(R(instruction)), "fmv.w.x".into())
-                }
-                other => {
-                    println!("todo: opcode={:07b}", other);
-                    todo!()
+                    (Kind::R(R(instruction)), "fmv.w.x")
                 }
-            }
+                0b1000011 if funct7 & 0b11 == 0 => (Kind::R4(R4(instruction)), "fmadd.s"),
+                _ => return None,
+            };
+            Some((kind, name.into()))
         }
 
         pub fn to_u32(&self) -> u32 {
@@ -380,6 +492,10 @@ pub enum Arg {
     Memory,
     // It's just an immediate but different name in the ref sheet
     Symbol,
+    /// A CSR, by name or number, in the immediate
+    Csr,
+    /// 5 bit immediate, in place of ra
+    Uimm,
 }
 
 impl Arg {
@@ -389,6 +505,8 @@ impl Arg {
             Arg::Immediate => "immediate",
             Arg::Memory => "memory",
             Arg::Symbol => "symbol",
+            Arg::Csr => "csr",
+            Arg::Uimm => "immediate",
         }
         .to_string()
     }
@@ -429,8 +547,69 @@ pub fn instruction(op: &str) -> Option<(Kind, Vec<Arg>)> {
             }),
             vec![Arg::Register(0), Arg::Immediate],
         ),
+        "auipc" => (
+            Kind::U({
+                let mut u = U(0);
+                u.set_opcode(0b0010111);
+                u
+            }),
+            vec![Arg::Register(0), Arg::Immediate],
+        ),
+        "la" => (
+            Kind::Pseudo(Pseudo("la")),
+            vec![Arg::Register(0), Arg::Symbol],
+        ),
+        "mv" => (
+            Kind::Pseudo(Pseudo("mv")),
+            vec![Arg::Register(0), Arg::Register(1)],
+        ),
 
         // Memory
+        "lb" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b000);
+                i.set_opcode(0b0000011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Memory],
+        ),
+        "lh" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b001);
+                i.set_opcode(0b0000011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Memory],
+        ),
+        "lw" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b010);
+                i.set_opcode(0b0000011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Memory],
+        ),
+        "lbu" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b100);
+                i.set_opcode(0b0000011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Memory],
+        ),
+        "lhu" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b101);
+                i.set_opcode(0b0000011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Memory],
+        ),
         "sb" => (
             Kind::S({
                 let mut s = S(0);
@@ -440,6 +619,24 @@ pub fn instruction(op: &str) -> Option<(Kind, Vec<Arg>)> {
             }),
             vec![Arg::Register(2), Arg::Memory],
         ),
+        "sh" => (
+            Kind::S({
+                let mut s = S(0);
+                s.set_funct3(0b001);
+                s.set_opcode(0b0100011);
+                s
+            }),
+            vec![Arg::Register(2), Arg::Memory],
+        ),
+        "sw" => (
+            Kind::S({
+                let mut s = S(0);
+                s.set_funct3(0b010);
+                s.set_opcode(0b0100011);
+                s
+            }),
+            vec![Arg::Register(2), Arg::Memory],
+        ),
 
         // Arithmetic, Logic, Shift
         "add" => (
@@ -462,6 +659,134 @@ pub fn instruction(op: &str) -> Option<(Kind, Vec<Arg>)> {
             vec![Arg::Register(0), Arg::Register(1), Arg::Immediate],
         ),
 
+        "sub" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0100000);
+                r.set_funct3(0b000);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "and" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000000);
+                r.set_funct3(0b111);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "or" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000000);
+                r.set_funct3(0b110);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "xor" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000000);
+                r.set_funct3(0b100);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "sll" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000000);
+                r.set_funct3(0b001);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "srl" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000000);
+                r.set_funct3(0b101);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "sra" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0100000);
+                r.set_funct3(0b101);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "andi" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b111);
+                i.set_opcode(0b0010011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Immediate],
+        ),
+        "ori" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b110);
+                i.set_opcode(0b0010011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Immediate],
+        ),
+        "xori" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b100);
+                i.set_opcode(0b0010011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Immediate],
+        ),
+        "slli" => (
+            Kind::I2({
+                let mut i2 = I2(0);
+                i2.set_funct6(0b000000);
+                i2.set_funct3(0b001);
+                i2.set_opcode(0b0010011);
+                i2
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Immediate],
+        ),
+        "srli" => (
+            Kind::I2({
+                let mut i2 = I2(0);
+                i2.set_funct6(0b000000);
+                i2.set_funct3(0b101);
+                i2.set_opcode(0b0010011);
+                i2
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Immediate],
+        ),
+        "srai" => (
+            Kind::I2({
+                let mut i2 = I2(0);
+                i2.set_funct6(0b010000);
+                i2.set_funct3(0b101);
+                i2.set_opcode(0b0010011);
+                i2
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Immediate],
+        ),
+
         // Multiply, Divide
         "mul" => (
             Kind::R({
@@ -473,6 +798,36 @@ pub fn instruction(op: &str) -> Option<(Kind, Vec<Arg>)> {
             }),
             vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
         ),
+        "mulh" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000001);
+                r.set_funct3(0b001);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "mulhsu" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000001);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "mulhu" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000001);
+                r.set_funct3(0b011);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
         "div" => (
             Kind::R({
                 let mut r = R(0);
@@ -484,7 +839,76 @@ pub fn instruction(op: &str) -> Option<(Kind, Vec<Arg>)> {
             vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
         ),
 
+        "divu" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000001);
+                r.set_funct3(0b101);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "rem" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000001);
+                r.set_funct3(0b110);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "remu" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000001);
+                r.set_funct3(0b111);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+
         // Compare
+        "slt" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000000);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "sltu" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b0000000);
+                r.set_funct3(0b011);
+                r.set_opcode(0b0110011);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
+        ),
+        "slti" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b010);
+                i.set_opcode(0b0010011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Immediate],
+        ),
+        "sltiu" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b011);
+                i.set_opcode(0b0010011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Immediate],
+        ),
 
         // Flow control (branch, jump, call, ret)
         "beq" => (
@@ -505,6 +929,42 @@ pub fn instruction(op: &str) -> Option<(Kind, Vec<Arg>)> {
             }),
             vec![Arg::Register(1), Arg::Register(2), Arg::Immediate],
         ),
+        "blt" => (
+            Kind::B({
+                let mut b = B(0);
+                b.set_funct3(0b100);
+                b.set_opcode(0b1100011);
+                b
+            }),
+            vec![Arg::Register(1), Arg::Register(2), Arg::Immediate],
+        ),
+        "bge" => (
+            Kind::B({
+                let mut b = B(0);
+                b.set_funct3(0b101);
+                b.set_opcode(0b1100011);
+                b
+            }),
+            vec![Arg::Register(1), Arg::Register(2), Arg::Immediate],
+        ),
+        "bltu" => (
+            Kind::B({
+                let mut b = B(0);
+                b.set_funct3(0b110);
+                b.set_opcode(0b1100011);
+                b
+            }),
+            vec![Arg::Register(1), Arg::Register(2), Arg::Immediate],
+        ),
+        "bgeu" => (
+            Kind::B({
+                let mut b = B(0);
+                b.set_funct3(0b111);
+                b.set_opcode(0b1100011);
+                b
+            }),
+            vec![Arg::Register(1), Arg::Register(2), Arg::Immediate],
+        ),
         "beqz" => (
             Kind::Pseudo(Pseudo("beqz")),
             vec![Arg::Register(1), Arg::Symbol],
@@ -522,6 +982,257 @@ pub fn instruction(op: &str) -> Option<(Kind, Vec<Arg>)> {
             }),
             vec![Arg::Register(0), Arg::Symbol],
         ),
+        "jalr" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b000);
+                i.set_opcode(0b1100111);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Register(1), Arg::Immediate],
+        ),
+        "jr" => (Kind::Pseudo(Pseudo("jr")), vec![Arg::Register(1)]),
+        "call" => (Kind::Pseudo(Pseudo("call")), vec![Arg::Symbol]),
+        "ret" => (Kind::Pseudo(Pseudo("ret")), vec![]),
+
+        // System
+        "ecall" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_imm(0);
+                i.set_funct3(0b000);
+                i.set_opcode(0b1110011);
+                i
+            }),
+            vec![],
+        ),
+        "ebreak" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_imm(1);
+                i.set_funct3(0b000);
+                i.set_opcode(0b1110011);
+                i
+            }),
+            vec![],
+        ),
+        "fence" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_imm(0);
+                i.set_funct3(0b000);
+                i.set_opcode(0b0001111);
+                i
+            }),
+            vec![],
+        ),
+
+        // Zicsr and machine mode
+        "csrrw" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b001);
+                i.set_opcode(0b1110011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Csr, Arg::Register(1)],
+        ),
+        "csrrs" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b010);
+                i.set_opcode(0b1110011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Csr, Arg::Register(1)],
+        ),
+        "csrrc" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b011);
+                i.set_opcode(0b1110011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Csr, Arg::Register(1)],
+        ),
+        "csrrwi" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b101);
+                i.set_opcode(0b1110011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Csr, Arg::Uimm],
+        ),
+        "csrrsi" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b110);
+                i.set_opcode(0b1110011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Csr, Arg::Uimm],
+        ),
+        "csrrci" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_funct3(0b111);
+                i.set_opcode(0b1110011);
+                i
+            }),
+            vec![Arg::Register(0), Arg::Csr, Arg::Uimm],
+        ),
+        "csrr" => (
+            Kind::Pseudo(Pseudo("csrr")),
+            vec![Arg::Register(0), Arg::Csr],
+        ),
+        "csrw" => (
+            Kind::Pseudo(Pseudo("csrw")),
+            vec![Arg::Csr, Arg::Register(1)],
+        ),
+        "csrs" => (
+            Kind::Pseudo(Pseudo("csrs")),
+            vec![Arg::Csr, Arg::Register(1)],
+        ),
+        "csrc" => (
+            Kind::Pseudo(Pseudo("csrc")),
+            vec![Arg::Csr, Arg::Register(1)],
+        ),
+        "csrwi" => (Kind::Pseudo(Pseudo("csrwi")), vec![Arg::Csr, Arg::Uimm]),
+        "csrsi" => (Kind::Pseudo(Pseudo("csrsi")), vec![Arg::Csr, Arg::Uimm]),
+        "csrci" => (Kind::Pseudo(Pseudo("csrci")), vec![Arg::Csr, Arg::Uimm]),
+        "mret" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_imm(0x302);
+                i.set_funct3(0b000);
+                i.set_opcode(0b1110011);
+                i
+            }),
+            vec![],
+        ),
+        "wfi" => (
+            Kind::I({
+                let mut i = I(0);
+                i.set_imm(0x105);
+                i.set_funct3(0b000);
+                i.set_opcode(0b1110011);
+                i
+            }),
+            vec![],
+        ),
+
+        // A Extension
+        "lr.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b00010 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Memory],
+        ),
+        "sc.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b00011 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(2), Arg::Memory],
+        ),
+        "amoswap.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b00001 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(2), Arg::Memory],
+        ),
+        "amoadd.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b00000 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(2), Arg::Memory],
+        ),
+        "amoxor.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b00100 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(2), Arg::Memory],
+        ),
+        "amoand.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b01100 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(2), Arg::Memory],
+        ),
+        "amoor.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b01000 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(2), Arg::Memory],
+        ),
+        "amomin.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b10000 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(2), Arg::Memory],
+        ),
+        "amomax.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b10100 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(2), Arg::Memory],
+        ),
+        "amominu.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b11000 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(2), Arg::Memory],
+        ),
+        "amomaxu.w" => (
+            Kind::R({
+                let mut r = R(0);
+                r.set_funct7(0b11100 << 2);
+                r.set_funct3(0b010);
+                r.set_opcode(0b0101111);
+                r
+            }),
+            vec![Arg::Register(0), Arg::Register(2), Arg::Memory],
+        ),
 
         // F Extension - assune rm is 0b000
 
@@ -546,6 +1257,21 @@ pub fn instruction(op: &str) -> Option<(Kind, Vec<Arg>)> {
             }),
             vec![Arg::Register(0), Arg::Register(1), Arg::Register(2)],
         ),
+        "fmadd.s" => (
+            Kind::R4({
+                let mut r4 = R4(0);
+                r4.set_funct2(0b00);
+                r4.set_funct3(0b000);
+                r4.set_opcode(0b1000011);
+                r4
+            }),
+            vec![
+                Arg::Register(0),
+                Arg::Register(1),
+                Arg::Register(2),
+                Arg::Register(3),
+            ],
+        ),
 
         // Move / Convert
         "fcvt.s.w" => (
@@ -578,61 +1304,88 @@ pub fn get_instruction(op: &str) -> (Kind, Vec<Arg>) {
 
 /// regs order: rd, ra, rb, rc
 pub fn with((kind, args): (Kind, Vec<Arg>), imm: u32, regs: Vec<usize>) -> (Kind, Vec<Arg>) {
-    match kind {
-        Kind::Pseudo(_) => (kind, args),
+    let kind = match kind {
+        Kind::Pseudo(_) => kind,
         Kind::R(mut r) => {
             r.set_rd(regs[0] as u32);
             r.set_ra(regs[1] as u32);
             r.set_rb(regs[2] as u32);
-            (Kind::R(r), args)
+            Kind::R(r)
         }
         Kind::R4(mut r4) => {
             r4.set_rd(regs[0] as u32);
             r4.set_ra(regs[1] as u32);
             r4.set_rb(regs[2] as u32);
             r4.set_rc(regs[3] as u32);
-            (Kind::R4(r4), args)
+            Kind::R4(r4)
         }
         Kind::I(mut i) => {
             i.set_rd(regs[0] as u32);
             i.set_ra(regs[1] as u32);
-            i.set_imm(imm);
-            (Kind::I(i), args)
+            Kind::I(i)
         }
         Kind::I2(mut i2) => {
             i2.set_rd(regs[0] as u32);
             i2.set_ra(regs[1] as u32);
-            i2.set_imm(imm);
-            (Kind::I2(i2), args)
+            Kind::I2(i2)
         }
         Kind::S(mut s) => {
             s.set_ra(regs[1] as u32);
             s.set_rb(regs[2] as u32);
-            s.set_imm_11_5(imm >> 5);
-            s.set_imm_4_10(imm);
-            (Kind::S(s), args)
+            Kind::S(s)
         }
         Kind::B(mut b) => {
             b.set_ra(regs[1] as u32);
             b.set_rb(regs[2] as u32);
+            Kind::B(b)
+        }
+        Kind::U(mut u) => {
+            u.set_rd(regs[0] as u32);
+            Kind::U(u)
+        }
+        Kind::J(mut j) => {
+            j.set_rd(regs[0] as u32);
+            Kind::J(j)
+        }
+    };
+
+    (with_imm(kind, imm), args)
+}
+
+/// Replace only the immediate of an instruction, leaving its registers alone
+pub fn with_imm(kind: Kind, imm: u32) -> Kind {
+    match kind {
+        Kind::Pseudo(_) | Kind::R(_) | Kind::R4(_) => kind,
+        Kind::I(mut i) => {
+            i.set_imm(imm);
+            Kind::I(i)
+        }
+        Kind::I2(mut i2) => {
+            i2.set_imm(imm);
+            Kind::I2(i2)
+        }
+        Kind::S(mut s) => {
+            s.set_imm_11_5(imm >> 5);
+            s.set_imm_4_10(imm);
+            Kind::S(s)
+        }
+        Kind::B(mut b) => {
             b.set_imm_12(to_bits::<1>(imm >> 12)[0]);
             b.set_imm_11(to_bits::<1>(imm >> 11)[0]);
             b.set_imm_10_5(imm >> 5);
             b.set_imm_4_1(imm >> 1);
-            (Kind::B(b), args)
+            Kind::B(b)
         }
         Kind::U(mut u) => {
-            u.set_rd(regs[0] as u32);
             u.set_imm_31_12(imm >> 12);
-            (Kind::U(u), args)
+            Kind::U(u)
         }
         Kind::J(mut j) => {
-            j.set_rd(regs[0] as u32);
             j.set_imm_20(to_bits::<1>(imm >> 20)[0]);
             j.set_imm_19_12(imm >> 12);
             j.set_imm_11(to_bits::<1>(imm >> 11)[0]);
             j.set_imm_10_1(imm >> 1);
-            (Kind::J(j), args)
+            Kind::J(j)
         }
     }
 }
@@ -655,8 +1408,8 @@ pub fn handle_pseudo(
             with(get_instruction("addi"), 0, vec![0, 0]),
         ],
         "li" => {
-            // if the immediate only has the lower 12 bits set, use addi
-            if imm >> 12 == 0 {
+            // if the immediate fits in 12 signed bits, use addi
+            if fits_signed(imm, 12) {
                 // addi rd, x0, imm
                 vec![with(get_instruction("addi"), imm, regs)]
             }
@@ -669,11 +1422,7 @@ pub fn handle_pseudo(
             else {
                 vec![
                     // lui rd, imm
-                    with(
-                        get_instruction("lui"),
-                        (imm & 0xfffff000) ^ ((imm >> 11) & 0x1) << 12,
-                        regs.clone(),
-                    ),
+                    with(get_instruction("lui"), upper(imm), regs.clone()),
                     // addi rd, rd, imm
                     with(
                         get_instruction("addi"),
@@ -683,18 +1432,76 @@ pub fn handle_pseudo(
                 ]
             }
         }
-        "beqz" => vec![
+        "la" => vec![
+            // auipc rd, imm
+            with(get_instruction("auipc"), upper(imm), regs.clone()),
+            // addi rd, rd, imm
+            with(
+                get_instruction("addi"),
+                imm & 0x00000fff,
+                vec![regs[0], regs[0]],
+            ),
+        ],
+        "mv" => vec![
+            // addi rd, rs, 0
+            with(get_instruction("addi"), 0, regs),
+        ],
+        "beqz" if fits_signed(imm, 13) => vec![
             // beq ra, x0, imm
             with(get_instruction("beq"), imm, regs),
         ],
-        "bnez" => vec![
+        "bnez" if fits_signed(imm, 13) => vec![
             // bne ra, x0, imm
             with(get_instruction("bne"), imm, regs),
         ],
+        // Out of range branches jump over a jal with the opposite condition
+        "beqz" => vec![
+            // bne ra, x0, 8
+            with(get_instruction("bne"), 8, regs),
+            // jal x0, imm
+            with(get_instruction("jal"), imm.wrapping_sub(4), vec![0]),
+        ],
+        "bnez" => vec![
+            // beq ra, x0, 8
+            with(get_instruction("beq"), 8, regs),
+            // jal x0, imm
+            with(get_instruction("jal"), imm.wrapping_sub(4), vec![0]),
+        ],
         "j" => vec![
             // jal x0, imm
             with(get_instruction("jal"), imm, regs),
         ],
+        "jr" => vec![
+            // jalr x0, ra, 0
+            with(get_instruction("jalr"), 0, regs),
+        ],
+        "call" => vec![
+            // auipc ra, imm
+            with(get_instruction("auipc"), upper(imm), vec![1]),
+            // jalr ra, ra, imm
+            with(get_instruction("jalr"), imm & 0x00000fff, vec![1, 1]),
+        ],
+        "ret" => vec![
+            // jalr x0, ra, 0
+            with(get_instruction("jalr"), 0, vec![0, 1]),
+        ],
+        "csrr" => vec![
+            // csrrs rd, csr, x0
+            with(get_instruction("csrrs"), imm, regs),
+        ],
+        // The same op, with the old value thrown away
+        "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => {
+            let real = match op {
+                "csrw" => "csrrw",
+                "csrs" => "csrrs",
+                "csrc" => "csrrc",
+                "csrwi" => "csrrwi",
+                "csrsi" => "csrrsi",
+                _ => "csrrci",
+            };
+            // csrrX x0, csr, rs
+            vec![with(get_instruction(real), imm, vec![0, regs[1]])]
+        }
         other => {
             dbg!(other);
             unimplemented!()
@@ -702,6 +1509,17 @@ pub fn handle_pseudo(
     }
 }
 
+/// Whether `imm` survives being truncated to `bits` bits and sign extended back
+pub const fn fits_signed(imm: u32, bits: u32) -> bool {
+    let shift = 32 - bits;
+    ((imm << shift) as i32 >> shift) as u32 == imm
+}
+
+/// Upper 20 bits of `imm`, rounded so that adding the sign extended lower 12 bits gives `imm` back
+pub const fn upper(imm: u32) -> u32 {
+    imm.wrapping_add(0x800) & 0xfffff000
+}
+
 const fn to_bits<const N: usize>(val: u32) -> [bool; N] {
     let mut bits = [false; N];
     for i in 0..N {
@@ -5,11 +5,31 @@
 #![feature(iterator_try_reduce)]
 #![feature(try_blocks)]
 
+pub mod block;
+pub mod cache;
 // pub mod colorizer;
+pub mod csr;
+pub mod decode;
+pub mod device;
+pub mod disasm;
+pub mod elf;
 pub mod env;
 pub mod err;
 pub mod execution;
+pub mod hart;
+pub mod history;
 pub mod info;
 pub mod instructions;
+pub mod linker;
+pub mod listing;
+pub mod memory;
+pub mod object;
+pub mod output;
 pub mod parser;
+pub mod pipeline;
+pub mod predictor;
+pub mod semihosting;
+pub mod snapshot;
+pub mod srcmap;
+pub mod syscall;
 pub mod tests;
@@ -0,0 +1,826 @@
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+
+use crate::{
+    elf::{self, Elf, Shndx},
+    err::LinkErr,
+    instructions::{fits_signed, kind::*, upper, with_imm},
+};
+
+/// Used when no linker script is given, matching the layout the stepper runs programs with
+pub const DEFAULT_SCRIPT: &str = "
+ENTRY(_start)
+
+SECTIONS
+{
+    . = 0x00000000;
+    .text : { *(.text .text.*) }
+
+    . = 0x10000000;
+    __global_pointer$ = . + 0x800;
+    .data : { *(.data .data.* .sdata .sdata.* .rodata .rodata.* .srodata .srodata.*) }
+    .bss : { *(.bss .bss.* .sbss .sbss.*) }
+    _end = .;
+}
+";
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(u32),
+    /// `.`
+    Location,
+    Symbol(String),
+    Align(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+}
+
+/// `file(section section ...)`, where both can use `*` and `?` wildcards
+#[derive(Debug, Clone)]
+pub struct InputSpec {
+    pub file: String,
+    pub sections: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// `. = expr;`
+    SetLocation(Expr),
+    /// `symbol = expr;`
+    Assign(String, Expr),
+    /// `.name [address] : { inputs }`
+    Output {
+        name: String,
+        address: Option<Expr>,
+        inputs: Vec<InputSpec>,
+    },
+}
+
+/// A small subset of GNU ld linker scripts
+#[derive(Debug, Clone)]
+pub struct Script {
+    pub entry: Option<String>,
+    pub commands: Vec<Command>,
+}
+
+impl Script {
+    pub fn parse(input: &str) -> Result<Self, LinkErr> {
+        let mut tokens = tokenize(input).into_iter().peekable();
+        let mut script = Script {
+            entry: None,
+            commands: Vec::new(),
+        };
+
+        fn expect(
+            tokens: &mut impl Iterator<Item = (String, usize)>,
+            expected: &str,
+        ) -> Result<(), LinkErr> {
+            match tokens.next() {
+                Some((t, _)) if t == expected => Ok(()),
+                Some((t, line)) => Err(LinkErr::Script(
+                    line,
+                    format!("expected `{}`, found `{}`", expected, t),
+                )),
+                None => Err(LinkErr::Script(0, format!("expected `{}`", expected))),
+            }
+        }
+
+        fn expr(
+            tokens: &mut std::iter::Peekable<impl Iterator<Item = (String, usize)>>,
+        ) -> Result<Expr, LinkErr> {
+            let term = match tokens.next() {
+                Some((t, _)) if t == "." => Expr::Location,
+                Some((t, _)) if t == "ALIGN" => {
+                    expect(tokens, "(")?;
+                    let align = expr(tokens)?;
+                    expect(tokens, ")")?;
+                    Expr::Align(Box::new(align))
+                }
+                Some((t, line)) if t.starts_with(|c: char| c.is_ascii_digit()) => Expr::Number(
+                    parse_number(&t)
+                        .ok_or(LinkErr::Script(line, format!("invalid number `{}`", t)))?,
+                ),
+                Some((t, _)) if t.starts_with(|c: char| c.is_alphabetic() || c == '_') => {
+                    Expr::Symbol(t)
+                }
+                Some((t, line)) => {
+                    return Err(LinkErr::Script(line, format!("unexpected `{}`", t)))
+                }
+                None => return Err(LinkErr::Script(0, "unexpected end of script".to_string())),
+            };
+            if tokens.peek().is_some_and(|(t, _)| t == "+") {
+                tokens.next();
+                Ok(Expr::Add(Box::new(term), Box::new(expr(tokens)?)))
+            } else {
+                Ok(term)
+            }
+        }
+
+        while let Some((token, line)) = tokens.next() {
+            match token.as_str() {
+                "ENTRY" => {
+                    expect(&mut tokens, "(")?;
+                    script.entry = tokens.next().map(|(t, _)| t);
+                    expect(&mut tokens, ")")?;
+                }
+                "SECTIONS" => {
+                    expect(&mut tokens, "{")?;
+                    loop {
+                        let (name, line) = tokens
+                            .next()
+                            .ok_or(LinkErr::Script(line, "unclosed `SECTIONS`".to_string()))?;
+                        if name == "}" {
+                            break;
+                        }
+
+                        if tokens.peek().is_some_and(|(t, _)| t == "=") {
+                            tokens.next();
+                            let value = expr(&mut tokens)?;
+                            expect(&mut tokens, ";")?;
+                            script.commands.push(if name == "." {
+                                Command::SetLocation(value)
+                            } else {
+                                Command::Assign(name, value)
+                            });
+                            continue;
+                        }
+
+                        let address = if tokens.peek().is_some_and(|(t, _)| t == ":") {
+                            None
+                        } else {
+                            Some(expr(&mut tokens)?)
+                        };
+                        expect(&mut tokens, ":")?;
+                        expect(&mut tokens, "{")?;
+                        let mut inputs = Vec::new();
+                        loop {
+                            let (file, _) = tokens.next().ok_or(LinkErr::Script(
+                                line,
+                                format!("unclosed output section `{}`", name),
+                            ))?;
+                            match file.as_str() {
+                                "}" => break,
+                                // KEEP(...) only matters for garbage collection
+                                "KEEP" | "(" | ")" => continue,
+                                _ => {}
+                            }
+                            expect(&mut tokens, "(")?;
+                            let mut sections = Vec::new();
+                            for (section, _) in tokens.by_ref() {
+                                if section == ")" {
+                                    break;
+                                }
+                                sections.push(section);
+                            }
+                            inputs.push(InputSpec { file, sections });
+                        }
+                        script.commands.push(Command::Output {
+                            name,
+                            address,
+                            inputs,
+                        });
+                    }
+                }
+                other => {
+                    return Err(LinkErr::Script(
+                        line,
+                        format!("unknown command `{}`", other),
+                    ))
+                }
+            }
+        }
+
+        Ok(script)
+    }
+}
+
+/// Split a script into words and punctuation, along with their line numbers
+fn tokenize(input: &str) -> Vec<(String, usize)> {
+    let mut tokens = Vec::new();
+    for (line, text) in input.lines().enumerate() {
+        let text = text.split("/*").next().unwrap_or("");
+        let mut word = String::new();
+        for c in text.chars() {
+            match c {
+                '{' | '}' | '(' | ')' | ';' | '=' | '+' | ':' | ',' | ' ' | '\t' => {
+                    if !word.is_empty() {
+                        tokens.push((std::mem::take(&mut word), line + 1));
+                    }
+                    if !matches!(c, ' ' | '\t' | ',') {
+                        tokens.push((c.to_string(), line + 1));
+                    }
+                }
+                c => word.push(c),
+            }
+        }
+        if !word.is_empty() {
+            tokens.push((word, line + 1));
+        }
+    }
+    tokens
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    if let Some(hex) = s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(k) = s.strip_suffix('K') {
+        k.parse::<u32>().ok().map(|k| k * 1024)
+    } else if let Some(m) = s.strip_suffix('M') {
+        m.parse::<u32>().ok().map(|m| m * 1024 * 1024)
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Shell style matching with `*` and `?`
+fn glob(pattern: &str, name: &str) -> bool {
+    match (pattern.chars().next(), name.chars().next()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            glob(&pattern[1..], name)
+                || (!name.is_empty()
+                    && glob(pattern, &name[name.chars().next().unwrap().len_utf8()..]))
+        }
+        (Some('?'), Some(c)) => glob(&pattern[1..], &name[c.len_utf8()..]),
+        (Some(p), Some(c)) if p == c => glob(&pattern[p.len_utf8()..], &name[c.len_utf8()..]),
+        _ => false,
+    }
+}
+
+impl Expr {
+    fn eval(&self, location: u32, symbols: &HashMap<String, u32>) -> Result<u32, LinkErr> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Location => location,
+            Expr::Symbol(s) => *symbols.get(s).ok_or(LinkErr::UndefinedSymbol(s.clone()))?,
+            Expr::Align(align) => {
+                let align = align.eval(location, symbols)?.max(1);
+                location.next_multiple_of(align)
+            }
+            Expr::Add(a, b) => a
+                .eval(location, symbols)?
+                .wrapping_add(b.eval(location, symbols)?),
+        })
+    }
+}
+
+/// An input section placed into the output
+struct Placement {
+    output: usize,
+    address: u32,
+}
+
+/// Link relocatable objects, given with their file names, into an executable
+pub fn link(objects: &[(String, Elf)], script: &Script) -> Result<Elf, LinkErr> {
+    for (file, object) in objects {
+        if object.kind != elf::ET_REL {
+            return Err(LinkErr::NotRelocatable(file.clone()));
+        }
+    }
+
+    // Relaxed objects pad alignment in code with as many nops as it could
+    // need, the ones it doesn't come out before anything is placed
+    let trimmed;
+    let objects = if objects
+        .iter()
+        .any(|(_, o)| o.relocations.iter().any(|r| r.kind == elf::R_RISCV_ALIGN))
+    {
+        trimmed = objects
+            .iter()
+            .map(|(file, object)| {
+                let mut object = object.clone();
+                trim_align(&mut object);
+                (file.clone(), object)
+            })
+            .collect::<Vec<_>>();
+        &trimmed[..]
+    } else {
+        objects
+    };
+
+    let mut out = Elf::new(elf::ET_EXEC);
+    out.flags = objects.first().map_or(0, |(_, o)| o.flags);
+
+    // (object, section) -> where it ended up
+    let mut placements: HashMap<(usize, usize), Placement> = HashMap::new();
+    let mut absolute: HashMap<String, u32> = HashMap::new();
+    let mut location = 0u32;
+
+    let place = |out: &mut Elf,
+                 placements: &mut HashMap<(usize, usize), Placement>,
+                 location: &mut u32,
+                 name: &str,
+                 inputs: &[(usize, usize)]| {
+        let align = inputs
+            .iter()
+            .map(|&(o, s)| objects[o].1.sections[s].align.max(1))
+            .max()
+            .unwrap_or(1);
+        *location = location.next_multiple_of(align);
+        let output = out.sections.len();
+        let mut section = elf::Section {
+            name: name.to_string(),
+            kind: elf::SHT_NOBITS,
+            flags: 0,
+            addr: *location,
+            align,
+            data: Vec::new(),
+        };
+        for &(o, s) in inputs {
+            let input = &objects[o].1.sections[s];
+            *location = location.next_multiple_of(input.align.max(1));
+            section.data.resize((*location - section.addr) as usize, 0);
+            placements.insert(
+                (o, s),
+                Placement {
+                    output,
+                    address: *location,
+                },
+            );
+            section.data.extend(&input.data);
+            section.flags |= input.flags;
+            if input.kind != elf::SHT_NOBITS {
+                section.kind = elf::SHT_PROGBITS;
+            }
+            *location += input.data.len() as u32;
+        }
+        out.sections.push(section);
+    };
+
+    let allocated = objects
+        .iter()
+        .enumerate()
+        .flat_map(|(o, (_, object))| {
+            object
+                .sections
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.is_alloc())
+                .map(move |(s, _)| (o, s))
+        })
+        .collect::<Vec<_>>();
+    let mut used = HashSet::new();
+
+    for command in script.commands.iter() {
+        match command {
+            Command::SetLocation(expr) => location = expr.eval(location, &absolute)?,
+            Command::Assign(name, expr) => {
+                let value = expr.eval(location, &absolute)?;
+                absolute.insert(name.clone(), value);
+            }
+            Command::Output {
+                name,
+                address,
+                inputs,
+            } => {
+                if let Some(address) = address {
+                    location = address.eval(location, &absolute)?;
+                }
+                let mut matched = Vec::new();
+                for spec in inputs {
+                    for &(o, s) in allocated.iter() {
+                        let file = objects[o].0.rsplit('/').next().unwrap_or("");
+                        let section = &objects[o].1.sections[s].name;
+                        if !used.contains(&(o, s))
+                            && (glob(&spec.file, file) || glob(&spec.file, &objects[o].0))
+                            && spec.sections.iter().any(|p| glob(p, section))
+                        {
+                            used.insert((o, s));
+                            matched.push((o, s));
+                        }
+                    }
+                }
+                if !matched.is_empty() {
+                    place(&mut out, &mut placements, &mut location, name, &matched);
+                }
+            }
+        }
+    }
+
+    // Sections the script doesn't mention go after everything else, grouped by name
+    let orphans = allocated
+        .iter()
+        .filter(|p| !used.contains(p))
+        .copied()
+        .collect::<Vec<_>>();
+    let names = orphans
+        .iter()
+        .map(|&(o, s)| objects[o].1.sections[s].name.clone())
+        .unique()
+        .collect::<Vec<_>>();
+    for name in names {
+        let inputs = orphans
+            .iter()
+            .filter(|&&(o, s)| objects[o].1.sections[s].name == name)
+            .copied()
+            .collect::<Vec<_>>();
+        place(&mut out, &mut placements, &mut location, &name, &inputs);
+    }
+
+    // Line tables don't take up memory, they're simply concatenated
+    let lines = objects
+        .iter()
+        .enumerate()
+        .filter_map(|(o, (_, object))| {
+            let s = object
+                .sections
+                .iter()
+                .position(|s| s.name == ".debug_line")?;
+            relocatable_lines(object, s).then_some((o, s))
+        })
+        .collect::<Vec<_>>();
+    if !lines.is_empty() {
+        place(&mut out, &mut placements, &mut 0, ".debug_line", &lines);
+    }
+
+    // Global symbols, visible to every object
+    let mut globals: HashMap<String, (u32, u8)> = absolute
+        .iter()
+        .map(|(name, &value)| (name.clone(), (value, elf::STB_GLOBAL)))
+        .collect();
+    for (o, (_, object)) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter() {
+            if symbol.bind == elf::STB_LOCAL || symbol.section == Shndx::Undef {
+                continue;
+            }
+            let value = match symbol.section {
+                Shndx::Abs => symbol.value,
+                Shndx::Index(s) => match placements.get(&(o, s)) {
+                    Some(p) => p.address.wrapping_add(symbol.value),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            match globals.get(&symbol.name) {
+                Some((_, elf::STB_GLOBAL)) if symbol.bind == elf::STB_GLOBAL => {
+                    return Err(LinkErr::DuplicateSymbol(symbol.name.clone()))
+                }
+                Some((_, elf::STB_GLOBAL)) => {}
+                _ => {
+                    globals.insert(symbol.name.clone(), (value, symbol.bind));
+                }
+            }
+        }
+    }
+
+    let resolve = |o: usize, symbol: usize| -> Result<u32, LinkErr> {
+        let symbol = &objects[o].1.symbols[symbol];
+        match symbol.section {
+            Shndx::Abs => Ok(symbol.value),
+            Shndx::Index(s) if symbol.bind == elf::STB_LOCAL => placements
+                .get(&(o, s))
+                .map(|p| p.address.wrapping_add(symbol.value))
+                .ok_or(LinkErr::UndefinedSymbol(symbol.name.clone())),
+            _ => match globals.get(&symbol.name) {
+                Some(&(value, _)) => Ok(value),
+                None if symbol.bind == elf::STB_WEAK => Ok(0),
+                None => Err(LinkErr::UndefinedSymbol(symbol.name.clone())),
+            },
+        }
+    };
+
+    // The pcrel_lo relocations refer to the auipc holding the high half, so those
+    // values have to be known first
+    let mut pcrel_hi: HashMap<(usize, u32), u32> = HashMap::new();
+    for (o, (_, object)) in objects.iter().enumerate() {
+        for reloc in object.relocations.iter() {
+            if let (elf::R_RISCV_PCREL_HI20, Some(p), Some(symbol)) = (
+                reloc.kind,
+                placements.get(&(o, reloc.section)),
+                reloc.symbol,
+            ) {
+                let place = p.address.wrapping_add(reloc.offset);
+                let value = resolve(o, symbol)?
+                    .wrapping_add(reloc.addend as u32)
+                    .wrapping_sub(place);
+                pcrel_hi.insert((o, place), value);
+            }
+        }
+    }
+
+    for (o, (_, object)) in objects.iter().enumerate() {
+        for reloc in object.relocations.iter() {
+            let Some(p) = placements.get(&(o, reloc.section)) else {
+                continue;
+            };
+            let place = p.address.wrapping_add(reloc.offset);
+            let name = reloc
+                .symbol
+                .map(|s| object.symbols[s].name.clone())
+                .unwrap_or_default();
+            let target = match reloc.symbol {
+                Some(symbol) => resolve(o, symbol)?.wrapping_add(reloc.addend as u32),
+                None => reloc.addend as u32,
+            };
+            let relative = target.wrapping_sub(place);
+
+            let at = (place - out.sections[p.output].addr) as usize;
+            let data = &mut out.sections[p.output].data;
+            let word = |data: &Vec<u8>, at: usize| {
+                u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+            };
+            let out_of_range = || LinkErr::OutOfRange(reloc.kind, name.clone());
+
+            let patched: Vec<u32> = match reloc.kind {
+                elf::R_RISCV_NONE | elf::R_RISCV_RELAX | elf::R_RISCV_ALIGN => continue,
+                elf::R_RISCV_32 => vec![target],
+                elf::R_RISCV_BRANCH => {
+                    if !fits_signed(relative, 13) {
+                        return Err(out_of_range());
+                    }
+                    vec![with_imm(Kind::B(B(word(data, at))), relative).to_u32()]
+                }
+                elf::R_RISCV_JAL => {
+                    if !fits_signed(relative, 21) {
+                        return Err(out_of_range());
+                    }
+                    vec![with_imm(Kind::J(J(word(data, at))), relative).to_u32()]
+                }
+                elf::R_RISCV_CALL | elf::R_RISCV_CALL_PLT => vec![
+                    with_imm(Kind::U(U(word(data, at))), upper(relative)).to_u32(),
+                    with_imm(Kind::I(I(word(data, at + 4))), relative & 0xfff).to_u32(),
+                ],
+                elf::R_RISCV_PCREL_HI20 => {
+                    vec![with_imm(Kind::U(U(word(data, at))), upper(relative)).to_u32()]
+                }
+                elf::R_RISCV_PCREL_LO12_I | elf::R_RISCV_PCREL_LO12_S => {
+                    let hi = *pcrel_hi
+                        .get(&(o, target))
+                        .ok_or(LinkErr::UndefinedSymbol(name.clone()))?;
+                    vec![if reloc.kind == elf::R_RISCV_PCREL_LO12_I {
+                        with_imm(Kind::I(I(word(data, at))), hi & 0xfff).to_u32()
+                    } else {
+                        with_imm(Kind::S(S(word(data, at))), hi & 0xfff).to_u32()
+                    }]
+                }
+                elf::R_RISCV_HI20 => {
+                    vec![with_imm(Kind::U(U(word(data, at))), upper(target)).to_u32()]
+                }
+                elf::R_RISCV_LO12_I => {
+                    vec![with_imm(Kind::I(I(word(data, at))), target & 0xfff).to_u32()]
+                }
+                elf::R_RISCV_LO12_S => {
+                    vec![with_imm(Kind::S(S(word(data, at))), target & 0xfff).to_u32()]
+                }
+                other => return Err(LinkErr::UnsupportedRelocation(other)),
+            };
+            for (i, w) in patched.into_iter().enumerate() {
+                data[at + 4 * i..at + 4 * i + 4].copy_from_slice(&w.to_le_bytes());
+            }
+        }
+    }
+
+    // Keep the program's symbols around, so the result can still be debugged
+    for (name, &(value, bind)) in globals.iter().sorted_by_key(|(name, _)| *name) {
+        out.symbols.push(elf::Symbol {
+            name: name.clone(),
+            value,
+            size: 0,
+            bind,
+            kind: elf::STT_NOTYPE,
+            section: section_of(&out, value),
+        });
+    }
+    for (o, (_, object)) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter() {
+            // `.L` names are the assembler's own, like the `auipc` anchors of
+            // `%pcrel_lo`, and ld leaves them out too
+            if symbol.bind != elf::STB_LOCAL
+                || symbol.name.is_empty()
+                || symbol.name.starts_with(".L")
+                || matches!(symbol.kind, elf::STT_SECTION | elf::STT_FILE)
+            {
+                continue;
+            }
+            if let Shndx::Index(s) = symbol.section {
+                if let Some(p) = placements.get(&(o, s)) {
+                    let value = p.address.wrapping_add(symbol.value);
+                    out.symbols.push(elf::Symbol {
+                        name: symbol.name.clone(),
+                        value,
+                        size: symbol.size,
+                        bind: elf::STB_LOCAL,
+                        kind: symbol.kind,
+                        section: Shndx::Index(p.output),
+                    });
+                }
+            }
+        }
+    }
+
+    out.entry = match script.entry.as_ref().and_then(|e| globals.get(e)) {
+        Some(&(value, _)) => value,
+        // Like ld, fall back to the start of the code
+        None => out
+            .sections
+            .iter()
+            .find(|s| s.flags & elf::SHF_EXECINSTR != 0)
+            .map_or(0, |s| s.addr),
+    };
+
+    Ok(out)
+}
+
+/// Take out the nops of `R_RISCV_ALIGN` padding that aren't needed, as ld does.
+///
+/// Sections are placed at a multiple of their alignment, so how much of the
+/// padding is needed only depends on where it is in its section
+fn trim_align(object: &mut Elf) {
+    let aligns = (0..object.relocations.len())
+        .filter(|&i| object.relocations[i].kind == elf::R_RISCV_ALIGN)
+        .sorted_by_key(|&i| (object.relocations[i].section, object.relocations[i].offset))
+        .collect::<Vec<_>>();
+    for i in aligns {
+        let (section, offset) = (object.relocations[i].section, object.relocations[i].offset);
+        let padding = object.relocations[i].addend.max(0) as u32;
+        if (offset + padding) as usize > object.sections[section].data.len() {
+            continue;
+        }
+        // The padding is the alignment less the smallest instruction
+        let align = (padding + 2).next_power_of_two();
+        let needed = (offset.next_multiple_of(align) - offset).min(padding);
+        let (start, removed) = (offset + needed, padding - needed);
+
+        let nops = (0..needed / 4)
+            .flat_map(|_| 0x00000013u32.to_le_bytes())
+            .chain(
+                (needed % 4 == 2)
+                    .then_some([0x01, 0x00])
+                    .into_iter()
+                    .flatten(),
+            )
+            .collect::<Vec<_>>();
+        let target = &mut object.sections[section];
+        target
+            .data
+            .splice(offset as usize..(offset + padding) as usize, nops);
+        target.align = target.align.max(align);
+
+        // Whatever comes after moves back
+        let moved = |at: u32| {
+            if at >= start + removed {
+                at - removed
+            } else {
+                at.min(start)
+            }
+        };
+        for symbol in object.symbols.iter_mut() {
+            if symbol.section != Shndx::Index(section) {
+                continue;
+            }
+            if symbol.value <= start && symbol.value + symbol.size >= start + removed {
+                symbol.size -= removed;
+            }
+            symbol.value = moved(symbol.value);
+        }
+        for reloc in object.relocations.iter_mut() {
+            if reloc.section == section {
+                reloc.offset = moved(reloc.offset);
+            }
+        }
+        // References to places in the section by the section's own symbol
+        for j in 0..object.relocations.len() {
+            let Some(symbol) = object.relocations[j].symbol else {
+                continue;
+            };
+            let symbol = &object.symbols[symbol];
+            if symbol.kind == elf::STT_SECTION && symbol.section == Shndx::Index(section) {
+                let addend = &mut object.relocations[j].addend;
+                *addend = moved(*addend as u32) as i32;
+            }
+        }
+    }
+}
+
+/// The output section an address falls in, for symbols defined by the script
+fn section_of(elf: &Elf, value: u32) -> Shndx {
+    elf.sections
+        .iter()
+        .position(|s| s.is_alloc() && s.addr <= value && value < s.addr + s.data.len() as u32)
+        .map_or(Shndx::Abs, Shndx::Index)
+}
+
+/// Whether a line table only points at code by plain addresses, like the ones
+/// the assembler writes.
+///
+/// gcc's tables also use relaxation relocations and refer to other debug sections,
+/// those are left out rather than linked wrong.
+fn relocatable_lines(object: &Elf, section: usize) -> bool {
+    object
+        .relocations
+        .iter()
+        .filter(|r| r.section == section)
+        .all(|r| {
+            r.kind == elf::R_RISCV_32
+                && r.symbol.is_some_and(|s| match object.symbols[s].section {
+                    Shndx::Index(i) => object.sections[i].is_alloc(),
+                    _ => false,
+                })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+
+    #[test]
+    fn link() {
+        use super::{link, Script, DEFAULT_SCRIPT};
+        use crate::{elf::Elf, object::Object, parser::parse};
+
+        let assemble = |input: &str| {
+            let mut env = Env::new();
+            let tokens = parse(&env, input).unwrap();
+            let elf = Object::assemble(&mut env, tokens).unwrap().to_elf();
+            Elf::parse(&elf.write()).unwrap()
+        };
+
+        let start = assemble(".globl _start\n_start:\nnop\njal ra func");
+        let func = assemble(".globl func\nfunc:\nnop\nnop\nret");
+
+        let script = Script::parse(DEFAULT_SCRIPT).unwrap();
+        let exe = link(
+            &[("start.o".to_string(), start), ("func.o".to_string(), func)],
+            &script,
+        )
+        .unwrap();
+
+        assert_eq!(exe.entry, 0);
+        assert_eq!(exe.symbol("func").map(|s| s.value), Some(8));
+
+        // jal ra, 4
+        let text = &exe.section(".text").unwrap().data;
+        assert_eq!(
+            u32::from_le_bytes(text[4..8].try_into().unwrap()),
+            0b00000000010000000000000011101111
+        );
+
+        // A branch to another object might not reach, so it jumps over a jal the
+        // linker fills in, which gets anywhere
+        let far = assemble(".globl _start\n_start:\nbeqz a0 func\nret");
+        assert_eq!(far.section(".text").unwrap().data.len(), 12);
+        assert_eq!(
+            far.relocations
+                .iter()
+                .map(|r| (r.offset, r.kind))
+                .collect::<Vec<_>>(),
+            [(4, crate::elf::R_RISCV_JAL)]
+        );
+        let exe = link(
+            &[
+                ("far.o".to_string(), far),
+                ("func.o".to_string(), assemble(".globl func\nfunc:\nret")),
+            ],
+            &script,
+        )
+        .unwrap();
+        assert_eq!(exe.symbol("func").map(|s| s.value), Some(12));
+
+        // Sections the script leaves out are each placed once, however the
+        // objects interleave them
+        let script = Script::parse("SECTIONS { . = 0x1000; .bss : { *(.bss) } }").unwrap();
+        let objects = [
+            ".text\nnop\n.data\n.word 1",
+            ".data\n.word 2",
+            ".text\nnop\nnop",
+        ]
+        .map(|input| ("x.o".to_string(), assemble(input)));
+        let exe = link(&objects, &script).unwrap();
+        let sizes = |name: &str| {
+            exe.sections
+                .iter()
+                .filter(|s| s.name == name)
+                .map(|s| s.data.len())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sizes(".text"), [12]);
+        assert_eq!(sizes(".data"), [8]);
+
+        // The anchors `la` leaves for its `%pcrel_lo` stay in the object
+        let la = assemble(".globl _start\n_start:\nla a0 msg\nret\n.data\nmsg:\n.word 1");
+        assert!(la.symbols.iter().any(|s| s.name.starts_with(".L")));
+        let script = Script::parse(DEFAULT_SCRIPT).unwrap();
+        let exe = link(&[("la.o".to_string(), la)], &script).unwrap();
+        assert!(!exe.symbols.iter().any(|s| s.name.starts_with(".L")));
+        assert_eq!(exe.symbol("msg").map(|s| s.value), Some(0x10000000));
+
+        // Relaxed objects pad `.p2align 4` with 12 bytes of nops, only what
+        // lines the code up stays
+        let mut relaxed = assemble(".globl _start\nnop\nnop\nnop\nnop\nnop\n_start:\nj _start");
+        let text = relaxed
+            .sections
+            .iter()
+            .position(|s| s.name == ".text")
+            .unwrap();
+        relaxed.relocations.push(crate::elf::Relocation {
+            section: text,
+            offset: 8,
+            kind: crate::elf::R_RISCV_ALIGN,
+            symbol: None,
+            addend: 12,
+        });
+        let exe = link(&[("relaxed.o".to_string(), relaxed)], &script).unwrap();
+        assert_eq!(exe.section(".text").unwrap().data.len(), 20);
+        assert_eq!(exe.entry, 16);
+    }
+}
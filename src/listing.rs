@@ -0,0 +1,207 @@
+use std::collections::{BTreeMap, HashMap};
+
+use itertools::Itertools;
+
+use crate::{
+    disasm::Op,
+    env::Env,
+    instructions::{instruction, kind::Kind},
+    parser::{Loc, Token},
+};
+
+/// Bytes of data shown per line, longer directives are cut short
+const DATA_PER_LINE: usize = 4;
+const DATA_LINES: usize = 2;
+
+/// Write a `.lst` listing of `source`.
+///
+/// `items` are the tokens `env` assembled with the bytes each produced. Every word gets
+/// its own address, pseudo-instructions show the instructions they expanded to, and the
+/// listing ends with the symbol table and where every label is defined and used.
+pub fn lst(env: &Env, source: &str, items: &[(Token, Loc, Vec<u8>)]) -> String {
+    let labels: HashMap<u32, String> = env
+        .labels()
+        .into_iter()
+        .map(|(name, addr)| (addr, name.to_string()))
+        .collect();
+    let by_line = items.iter().into_group_map_by(|(_, loc, _)| loc.line);
+    let width = source.lines().map(|l| l.len()).max().unwrap_or(0);
+
+    let mut out = format!(
+        "{:>5}  {:<8}  {:<11}  {}\n",
+        "LINE", "ADDR", "CODE", "SOURCE"
+    );
+    for (i, text) in source.lines().enumerate() {
+        let mut rows = Vec::new();
+        for (token, loc, bytes) in by_line.get(&(i + 1)).into_iter().flatten() {
+            let addr = loc.mem_offset as u32;
+            match token {
+                Token::Label(name) => rows.push((env.get_label(name), None, String::new())),
+                Token::Op(name, _) => {
+                    let pseudo = matches!(instruction(name), Some((Kind::Pseudo(_), _)));
+                    for (k, word) in words(bytes).into_iter().enumerate() {
+                        let addr = addr + k as u32 * 4;
+                        let expanded = match Op::decode_exact(addr, word) {
+                            Some(op) if pseudo => op.format(addr, &labels),
+                            _ => String::new(),
+                        };
+                        rows.push((Some(addr), Some(format!("{:08x}", word)), expanded));
+                    }
+                }
+                Token::Directive(..) => {
+                    let chunks = bytes.chunks(DATA_PER_LINE).collect::<Vec<_>>();
+                    for (k, chunk) in chunks.iter().take(DATA_LINES).enumerate() {
+                        let mut code = chunk.iter().map(|b| format!("{:02x}", b)).join("");
+                        if k + 1 == DATA_LINES && chunks.len() > DATA_LINES {
+                            code += "...";
+                        }
+                        let addr = addr + (k * DATA_PER_LINE) as u32;
+                        rows.push((Some(addr), Some(code), String::new()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if rows.is_empty() {
+            rows.push((None, None, String::new()));
+        }
+        for (k, (addr, code, expanded)) in rows.into_iter().enumerate() {
+            let line = if k == 0 {
+                (i + 1).to_string()
+            } else {
+                String::new()
+            };
+            let addr = addr.map(|a| format!("{:08x}", a)).unwrap_or_default();
+            let text = if k == 0 { text } else { "" };
+            let mut row = format!(
+                "{:>5}  {:<8}  {:<11}  {:<4$}",
+                line,
+                addr,
+                code.unwrap_or_default(),
+                text,
+                width
+            );
+            if !expanded.is_empty() {
+                row += &format!("  # {}", expanded);
+            }
+            out += row.trim_end();
+            out += "\n";
+        }
+    }
+
+    out += "\nSYMBOLS\n";
+    for (name, addr) in env.labels() {
+        out += &format!(
+            "  {:08x}  {:<6}  {:<5}  {}\n",
+            addr,
+            if env.is_global(name) {
+                "global"
+            } else {
+                "local"
+            },
+            env.get_label_section(name).map_or("", |s| s.name()),
+            name
+        );
+    }
+
+    out += "\nCROSS REFERENCE\n";
+    let mut uses: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (token, loc, _) in items {
+        if let Token::Directive(name, _) = token {
+            // Only name symbols or sections, these aren't uses
+            if [".globl", ".global", ".section"].contains(&name.as_str()) {
+                continue;
+            }
+        }
+        if let Token::Op(_, args) | Token::Directive(_, args) = token {
+            for (arg, _) in args {
+                if let Some(symbol) = referenced(arg) {
+                    uses.entry(symbol).or_default().push(loc.line);
+                }
+            }
+        }
+    }
+    let definitions: HashMap<&str, usize> = items
+        .iter()
+        .filter_map(|(token, loc, _)| match token {
+            Token::Label(name) => Some((name.as_str(), loc.line)),
+            _ => None,
+        })
+        .collect();
+    for name in definitions.keys().chain(uses.keys()).sorted().dedup() {
+        out += &format!(
+            "  {:<16}  {:<14}  {}\n",
+            name,
+            match definitions.get(name) {
+                Some(line) => format!("defined {}", line),
+                None => "undefined".to_string(),
+            },
+            match uses.get(name) {
+                Some(lines) => format!("used {}", lines.iter().dedup().join(", ")),
+                None => "unused".to_string(),
+            }
+        );
+    }
+
+    out
+}
+
+/// Label an argument refers to, if any
+fn referenced(arg: &Token) -> Option<&str> {
+    match arg {
+        Token::Symbol(symbol) | Token::Modifier(_, symbol) => Some(symbol),
+        Token::Memory(imm, _) => referenced(imm),
+        _ => None,
+    }
+}
+
+fn words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|b| {
+            let mut word = [0; 4];
+            word[..b.len()].copy_from_slice(b);
+            u32::from_le_bytes(word)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+
+    #[test]
+    fn listing() {
+        use super::lst;
+        use crate::parser::{parse, Token};
+
+        let mut env = Env::new();
+        let source = "start:\nli a0 0x12345\nj start";
+        let tokens = env
+            .handle_mem_offsets(parse(&env, source).unwrap())
+            .unwrap();
+        let items = tokens
+            .into_iter()
+            .map(|(token, loc)| {
+                let bytes = match token {
+                    Token::Op(..) => env
+                        .assemble_op((token.clone(), loc))
+                        .unwrap()
+                        .iter()
+                        .flat_map(|w| w.to_le_bytes())
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                (token, loc, bytes)
+            })
+            .collect::<Vec<_>>();
+        let lst = lst(&env, source, &items);
+
+        // Every word of li gets its own address and shows what it expanded to
+        assert!(lst.contains("    2  00000000  00012537     li a0 0x12345  # lui a0, 0x12\n"));
+        assert!(lst.contains("       00000004  34550513                    # addi a0, a0, 837\n"));
+        assert!(lst.contains("  00000000  local   .text  start\n"));
+        assert!(lst.contains("  start             defined 1       used 3\n"));
+    }
+}
@@ -1,5 +1,11 @@
-use std::io::Write;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    path::PathBuf,
+    str::FromStr,
+};
 
+use clap::{Args, Parser, Subcommand};
 use codespan_reporting::{
     diagnostic::{Diagnostic, Label},
     files::SimpleFile,
@@ -12,239 +18,1214 @@ use codespan_reporting::{
 use colored::Colorize;
 use itertools::Itertools;
 use rizz_v::{
-    env::Env,
-    execution::run_instruction,
-    info::info,
-    parser::{parse, Token},
+    cache::{Cache, CacheConfig, Caches},
+    csr,
+    device::{Bitmap, Devices, Keyboard, BITMAP_BASE, KEYBOARD_BASE},
+    disasm::{Disassembly, Line, REGISTERS},
+    elf::Elf,
+    env::{AssembleErr, Env, Section},
+    err::{LinkErr, MemoryErr, StepErr, SyntaxErr},
+    execution::{self, Stop},
+    hart::{Harts, Schedule},
+    history::{History, Undo},
+    info::{arg_text, info},
+    linker::{self, Script},
+    listing,
+    memory::{Misaligned, Permissions},
+    object::Object,
+    output::{Format, Region},
+    parser::{parse, Loc, Token},
+    pipeline::{Forwarding, Pipeline},
+    predictor::{Predictor, PredictorConfig},
+    semihosting::Semihosting,
+    snapshot::Snapshot,
+    srcmap::SourceMap,
+    syscall::{Abi, Console},
 };
-use termion::input::TermRead;
+use termion::{input::TermRead, raw::IntoRawMode};
+
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Assembly file, ELF executable or snapshot to step through
+    #[arg(default_value = "test.s")]
+    file: PathBuf,
+
+    /// Where to write the assembled program
+    #[arg(short, long, default_value = "test.bin")]
+    output: PathBuf,
+
+    /// Format of the assembled program, one of binary, ihex, readmemh, readmemb,
+    /// logisim, coe, mif, c or rust
+    #[arg(short, long, default_value = "readmemh")]
+    format: Format,
+
+    /// Also write a listing with addresses, encodings, symbols and cross-references
+    #[arg(short, long)]
+    listing: Option<PathBuf>,
+
+    /// Save the whole machine here every time the stepper pauses, stepping
+    /// through the snapshot carries on from there
+    #[arg(long)]
+    snapshot: Option<PathBuf>,
+
+    #[command(flatten)]
+    machine: Machine,
+}
+
+/// What the program runs on, a snapshot brings its own
+#[derive(Args)]
+struct Machine {
+    /// What loads and stores not aligned to their width do, allow or trap
+    #[arg(long, default_value = "allow")]
+    misaligned: Misaligned,
+
+    /// Attach a bitmap display of WIDTHxHEIGHT pixels, like `64x32`
+    #[arg(long, value_parser = parse_size)]
+    bitmap: Option<(u32, u32)>,
+
+    /// Address of the bitmap display's first pixel
+    #[arg(long, default_value_t = BITMAP_BASE, value_parser = parse_address)]
+    bitmap_base: u32,
+
+    /// Attach RARS' keyboard and display MMIO device, keys typed while paused
+    /// go to the program. It's PLIC interrupt source 5
+    #[arg(long)]
+    keyboard: bool,
+
+    /// Number of harts, all starting at the entry point and sharing memory
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=32))]
+    harts: u32,
+
+    /// Which hart runs next, round-robin or random
+    #[arg(long, default_value = "round-robin")]
+    schedule: Schedule,
+
+    /// Seed of the random schedule and of random number syscalls, the same
+    /// seed gives the same interleaving and numbers
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Syscalls `ecall` makes, rars, venus, linux, or none to trap to `mtvec` instead
+    #[arg(long, default_value = "rars")]
+    abi: Abi,
+
+    /// Directory the linux abi opens files in, the program sees it as `/`.
+    /// Without one it can't open any
+    #[arg(long, value_name = "DIR")]
+    sandbox: Option<PathBuf>,
+
+    /// Handle RISC-V semihosting calls, an `ebreak` between `slli x0, x0, 0x1f`
+    /// and `srai x0, x0, 7`, with files on the host
+    #[arg(long)]
+    semihosting: bool,
+
+    /// Time the program in a five-stage pipeline, forwarding results none, wb
+    /// (from MEM/WB) or full
+    #[arg(long, value_name = "FORWARDING")]
+    pipeline: Option<Forwarding>,
+
+    /// Write a diagram of the pipeline's last instructions here when the program
+    /// stops, as CSV if it ends in `.csv`
+    #[arg(long, value_name = "FILE", requires = "pipeline")]
+    pipeline_out: Option<PathBuf>,
+
+    /// L1 instruction cache, like `4k,32,2` for its size, line size and ways,
+    /// then any of lru, fifo or random, wb or wt, and wa or nwa
+    #[arg(long, value_name = "CACHE")]
+    l1i: Option<CacheConfig>,
+
+    /// L1 data cache, set up like `--l1i`
+    #[arg(long, value_name = "CACHE")]
+    l1d: Option<CacheConfig>,
+
+    /// L2 cache behind both L1 caches, set up like `--l1i`
+    #[arg(long, value_name = "CACHE")]
+    l2: Option<CacheConfig>,
+
+    /// Predict conditional branches with not-taken, btfn, 1bit, 2bit, gshare or
+    /// tournament, then optionally the entries in its tables, like `gshare,4096`
+    #[arg(long, value_name = "PREDICTOR")]
+    predictor: Option<PredictorConfig>,
+
+    /// Entries in a branch target buffer for the predictor, without one a
+    /// taken prediction always has its target
+    #[arg(long, value_name = "ENTRIES", requires = "predictor", value_parser = parse_entries)]
+    btb: Option<u32>,
+
+    /// Label to start at, by default `_start`, then `main`, then the start of the code
+    #[arg(long)]
+    entry: Option<String>,
+
+    /// Environment variable for the program, like `HOME=/`
+    #[arg(long = "env", value_name = "VAR")]
+    vars: Vec<String>,
+
+    /// Arguments for the program, after `--`
+    #[arg(last = true)]
+    args: Vec<String>,
+}
+
+impl Machine {
+    /// An `Env` with the devices and memory behaviour asked for. When `live`
+    /// the program's output goes straight to stdout and the keyboard and
+    /// display use the terminal, rather than waiting for the stepper
+    fn env(&self, live: bool) -> anyhow::Result<Env> {
+        let mut env = Env::new();
+        env.memory.misaligned = self.misaligned;
+        if let Some(sandbox) = &self.sandbox {
+            if !sandbox.is_dir() {
+                anyhow::bail!("sandbox {} isn't a directory", sandbox.display());
+            }
+        }
+        if self.pipeline.is_some() && self.harts > 1 {
+            anyhow::bail!("the pipeline model only runs one hart");
+        }
+        env.syscalls = self
+            .abi
+            .syscalls(Console::new(live, true), self.seed, self.sandbox.clone());
+        if self.semihosting {
+            env.semihosting = Some(Semihosting::new(Console::new(live, true)));
+        }
+        env.devices = Devices::builtin(live);
+        self.instrument(&mut env);
+        if self.keyboard {
+            let keyboard = if live {
+                Keyboard::live(KEYBOARD_BASE)?
+            } else {
+                Keyboard::new(KEYBOARD_BASE)
+            };
+            env.devices.add(Box::new(keyboard))?;
+        }
+        if let Some((width, height)) = self.bitmap {
+            env.devices
+                .add(Box::new(Bitmap::new(self.bitmap_base, width, height, live)))?;
+        }
+        Ok(env)
+    }
+
+    /// Attach the pipeline, cache and branch predictor models asked for
+    fn instrument(&self, env: &mut Env) {
+        env.pipeline = self.pipeline.map(Pipeline::new);
+        env.predictor = self
+            .predictor
+            .map(|config| Predictor::new(config, self.btb));
+        env.caches = Caches {
+            l1i: self.l1i.map(|config| Cache::new("L1i", config)),
+            l1d: self.l1d.map(|config| Cache::new("L1d", config)),
+            l2: self.l2.map(|config| Cache::new("L2", config)),
+        };
+    }
+
+    /// Start the program loaded from `path` in `env`
+    fn start(&self, env: &mut Env, path: &Path) -> anyhow::Result<()> {
+        let args = [path.display().to_string()]
+            .into_iter()
+            .chain(self.args.iter().cloned())
+            .collect::<Vec<_>>();
+        env.start(self.entry.as_deref(), &args, &self.vars)?;
+        Ok(())
+    }
+
+    /// Write out the pipeline diagram, if asked for
+    fn write_pipeline(&self, env: &Env) -> anyhow::Result<()> {
+        if let (Some(path), Some(pipeline)) = (&self.pipeline_out, &env.pipeline) {
+            let text = if path.extension().is_some_and(|e| e == "csv") {
+                pipeline.csv()
+            } else {
+                pipeline.diagram(usize::MAX)
+            };
+            std::fs::write(path, text)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a program to the end without stopping. Exits with the program's
+    /// exit code, 124 if it reached the limit, 125 if it faulted, or 1 if a
+    /// syscall couldn't be carried out
+    Run {
+        /// Assembly file, ELF executable or snapshot to run
+        file: PathBuf,
+        /// Don't print the registers and how the program ended
+        #[arg(short, long)]
+        quiet: bool,
+        /// Most instructions to run before giving up
+        #[arg(long)]
+        limit: Option<u64>,
+        #[command(flatten)]
+        machine: Machine,
+    },
+    /// Assemble a file into a relocatable ELF object
+    Assemble {
+        file: PathBuf,
+        /// Defaults to the input file with an `.o` extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Write a program in a format for simulators, FPGA tools or other code
+    Export {
+        /// Assembly file or ELF executable
+        file: PathBuf,
+        /// One of binary, ihex, readmemh, readmemb, logisim, coe, mif, c or rust
+        #[arg(short, long)]
+        format: Format,
+        /// Defaults to the input file with the format's extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Also write a listing with addresses, encodings, symbols and cross-references
+        #[arg(short, long)]
+        listing: Option<PathBuf>,
+    },
+    /// Disassemble an ELF file, hex words like `test.bin` or a raw binary
+    Disassemble {
+        file: PathBuf,
+        /// Address the first word of a hex or raw file is loaded at
+        #[arg(short, long, default_value_t = 0, value_parser = parse_address)]
+        base: u32,
+        /// Show the address and encoding of every op
+        #[arg(short, long)]
+        addresses: bool,
+    },
+    /// Link relocatable ELF objects into an executable
+    Link {
+        #[arg(required = true)]
+        objects: Vec<PathBuf>,
+        /// Linker script, by default code is placed at 0 and data at 0x10000000
+        #[arg(short = 'T', long)]
+        script: Option<PathBuf>,
+        #[arg(short, long, default_value = "a.out")]
+        output: PathBuf,
+    },
+}
 
 fn main() -> anyhow::Result<()> {
-    let display_mode = 's';
+    let cli = Cli::parse();
+
+    match cli.command {
+        None => step(
+            &cli.file,
+            &cli.output,
+            cli.format,
+            cli.listing.as_deref(),
+            cli.snapshot.as_deref(),
+            &cli.machine,
+        ),
+        Some(Command::Run {
+            file,
+            quiet,
+            limit,
+            machine,
+        }) => run(&file, quiet, limit, &machine),
+        Some(Command::Assemble { file, output }) => {
+            let output = output.unwrap_or_else(|| file.with_extension("o"));
+            assemble(&file, &output)
+        }
+        Some(Command::Export {
+            file,
+            format,
+            output,
+            listing,
+        }) => {
+            let output = output.unwrap_or_else(|| file.with_extension(format.extension()));
+            let mut env = Env::new();
+            match load(&mut env, &file)? {
+                Some(program) => export(
+                    &program,
+                    &env.source_map,
+                    format,
+                    &output,
+                    listing.as_deref(),
+                ),
+                None => std::process::exit(1),
+            }
+        }
+        Some(Command::Disassemble {
+            file,
+            base,
+            addresses,
+        }) => {
+            let mut disassembly = Disassembly::from_bytes(&std::fs::read(&file)?, base)?;
+            if let Ok(json) = std::fs::read_to_string(SourceMap::sidecar(&file)) {
+                disassembly.source = SourceMap::from_json(&json)?;
+            }
+            if addresses {
+                print!("{}", disassembly.with_addresses());
+            } else {
+                print!("{}", disassembly);
+            }
+            Ok(())
+        }
+        Some(Command::Link {
+            objects,
+            script,
+            output,
+        }) => link(&objects, script.as_deref(), &output),
+    }
+}
+
+fn parse_address(s: &str) -> Result<u32, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+fn parse_entries(s: &str) -> Result<u32, String> {
+    s.parse()
+        .ok()
+        .filter(|n: &u32| n.is_power_of_two())
+        .ok_or(format!("'{}' isn't a power of two", s))
+}
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    s.split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .filter(|&(w, h)| w > 0 && h > 0)
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, like 64x32, found '{}'", s))
+}
+
+fn report_syntax_err(
+    file: &SimpleFile<String, String>,
+    err: &(SyntaxErr, Loc, Vec<(Token, Loc)>, Option<String>),
+) {
     let writer = StandardStream::stderr(ColorChoice::Always);
-    let config = Config::default();
-    let input = std::fs::read_to_string("test.s").unwrap();
-    let term_width = term_size::dimensions().map(|(w, _)| w).unwrap_or(80);
+    let start = err.1.start;
+    let end = err.1.end + 1;
+
+    let diagnostic = Diagnostic::error()
+        .with_message("Syntax Error")
+        .with_labels(vec![
+            Label::primary((), start..end).with_message(err.0.to_string())
+        ])
+        .with_notes({
+            let mut notes = Vec::new();
+            if let Some(note) = &err.3 {
+                notes.push(note.to_string());
+            }
+            notes.push(err.0.note());
+            notes
+        });
+
+    term::emit(&mut writer.lock(), &Config::default(), file, &diagnostic).unwrap();
+}
+
+fn report_engine_err(file: &SimpleFile<String, String>, err: &AssembleErr) {
+    let writer = StandardStream::stderr(ColorChoice::Always);
+    let diagnostic = Diagnostic::error()
+        .with_message("Engine Error")
+        .with_labels(vec![
+            Label::primary((), err.1.start..(err.1.end + 1)).with_message(err.0.to_string())
+        ])
+        .with_notes({
+            let mut notes = Vec::new();
+            if let Some(note) = &err.2 {
+                notes.push(note.to_string());
+            }
+            notes.push(err.0.note());
+            notes
+        });
+
+    term::emit(&mut writer.lock(), &Config::default(), file, &diagnostic).unwrap();
+}
+
+/// Report a fault of the program being run, pointing at the source if known
+fn report_memory_err(env: &Env, pc: u32, err: &MemoryErr) {
+    report_at(env, pc, &err.to_string(), &err.note());
+}
+
+/// Report what went wrong running the instruction at `pc`
+fn report_at(env: &Env, pc: u32, message: &str, note: &str) {
+    let writer = StandardStream::stderr(ColorChoice::Always);
+    let mut notes = vec![note.to_string()];
+    let location = env
+        .describe_address(pc)
+        .map_or(format!("0x{:08x}", pc), |l| format!("0x{:08x} ({})", pc, l));
+    notes.push(format!("pc = {}", location));
+    if let Some(source) = env.source_map.describe(pc) {
+        notes.push(format!("at {}", source));
+    }
+    let diagnostic = Diagnostic::error().with_message(message).with_notes(notes);
 
-    let file = SimpleFile::new("test.s", input.clone());
+    let file = SimpleFile::new(String::new(), String::new());
+    term::emit(&mut writer.lock(), &Config::default(), &file, &diagnostic).unwrap();
+}
 
+fn report_link_err(err: &LinkErr) {
+    let writer = StandardStream::stderr(ColorChoice::Always);
+    let diagnostic = Diagnostic::error()
+        .with_message(err.to_string())
+        .with_notes(vec![err.note()]);
+
+    let file = SimpleFile::new(String::new(), String::new());
+    term::emit(&mut writer.lock(), &Config::default(), &file, &diagnostic).unwrap();
+}
+
+fn assemble(path: &Path, output: &Path) -> anyhow::Result<()> {
+    let input = std::fs::read_to_string(path)?;
+    let file = SimpleFile::new(path.display().to_string(), input.clone());
     let mut env = Env::new();
 
-    let mut toks: Vec<Token> = Vec::new();
-    let mut ops: Vec<u32> = Vec::new();
+    let tokens = match parse(&env, &input) {
+        Ok(tokens) => tokens,
+        Err(errs) => {
+            errs.iter().for_each(|err| report_syntax_err(&file, err));
+            std::process::exit(1);
+        }
+    };
+    let object = match Object::assemble(&mut env, tokens) {
+        Ok(object) => object,
+        Err(errs) => {
+            errs.iter().for_each(|err| report_engine_err(&file, err));
+            std::process::exit(1);
+        }
+    };
+
+    let elf = object.to_elf_with_source(&path.display().to_string(), &input);
+    std::fs::write(output, elf.write())?;
+    Ok(())
+}
+
+fn link(objects: &[PathBuf], script: Option<&Path>, output: &Path) -> anyhow::Result<()> {
+    let result = (|| {
+        let objects = objects
+            .iter()
+            .map(|path| {
+                let name = path.display().to_string();
+                let bytes = std::fs::read(path).map_err(|e| {
+                    LinkErr::Elf(
+                        name.clone(),
+                        rizz_v::err::ElfErr::Unsupported(e.to_string()),
+                    )
+                })?;
+                Elf::parse(&bytes)
+                    .map(|elf| (name.clone(), elf))
+                    .map_err(|e| LinkErr::Elf(name, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let script = match script {
+            Some(path) => Script::parse(
+                &std::fs::read_to_string(path)
+                    .map_err(|e| LinkErr::Script(0, format!("{}: {}", path.display(), e)))?,
+            )?,
+            None => Script::parse(linker::DEFAULT_SCRIPT)?,
+        };
+        linker::link(&objects, &script)
+    })();
+
+    match result {
+        Ok(elf) => {
+            std::fs::write(output, elf.write())?;
+            Ok(())
+        }
+        Err(err) => {
+            report_link_err(&err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// What the stepper shows, and which line and op belong to each address
+#[derive(Default)]
+struct Listing {
+    text: String,
+    lines: HashMap<u32, usize>,
+    ops: HashMap<u32, Token>,
+}
+
+impl Listing {
+    fn push(&mut self, line: String, op: Option<(u32, Token)>) {
+        if let Some((addr, token)) = op {
+            self.lines.insert(addr, self.text.lines().count());
+            self.ops.insert(addr, token);
+        }
+        self.text += &format!("{}\n", line);
+    }
+}
+
+/// Assemble a source file or load an ELF executable into `env`
+fn load(env: &mut Env, path: &Path) -> anyhow::Result<Option<Program>> {
+    let bytes = std::fs::read(path)?;
+
+    if bytes.starts_with(b"\x7fELF") {
+        let elf = Elf::parse(&bytes)?;
+        env.load_elf(&elf)?;
+        Ok(Some(Program {
+            listing: elf_listing(&elf),
+            regions: Region::from_elf(&elf),
+            lst: None,
+        }))
+    } else {
+        assemble_listing(env, path, String::from_utf8(bytes)?)
+    }
+}
+
+/// Write the sections of a program in `format`, a file per section if it has no addresses,
+/// with its source map next to it and its listing if asked for
+fn export(
+    program: &Program,
+    source_map: &SourceMap,
+    format: Format,
+    output: &Path,
+    lst: Option<&Path>,
+) -> anyhow::Result<()> {
+    for (path, contents) in format.outputs(output, &program.regions) {
+        std::fs::write(path, contents)?;
+    }
+    if !source_map.is_empty() {
+        std::fs::write(SourceMap::sidecar(output), source_map.to_json())?;
+    }
+    match (lst, &program.lst) {
+        (Some(path), Some(contents)) => std::fs::write(path, contents)?,
+        (Some(_), None) => eprintln!("{}", "listings can only be made from source files".yellow()),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// A machine ready to run, its listing, and the program if it was assembled
+/// or loaded rather than restored
+type Booted = (Env, Harts, Listing, Option<Program>);
+
+/// Load the program or snapshot at `path` and get it ready to run. `None` if
+/// it didn't assemble, the errors have been reported
+fn boot(path: &Path, machine: &Machine, live: bool) -> anyhow::Result<Option<Booted>> {
+    let bytes = std::fs::read(path)?;
+    if Snapshot::is_snapshot(&bytes) {
+        let (mut env, harts) = Snapshot::from_json(&String::from_utf8(bytes)?)?.restore()?;
+        let harts = harts.unwrap_or_else(|| Harts::new(&mut env, 1, machine.schedule, 0));
+        if harts.len() > 1 && machine.pipeline.is_some() {
+            anyhow::bail!("the pipeline model only runs one hart");
+        }
+        machine.instrument(&mut env);
+        let listing = snapshot_listing(&env);
+        return Ok(Some((env, harts, listing, None)));
+    }
 
-    let mut parse_asm_result = String::new();
+    let mut env = machine.env(live)?;
+    let Some(mut program) = load(&mut env, path)? else {
+        return Ok(None);
+    };
+    let listing = std::mem::take(&mut program.listing);
+    machine.start(&mut env, path)?;
+    let harts = Harts::new(
+        &mut env,
+        machine.harts as usize,
+        machine.schedule,
+        machine.seed,
+    );
+    Ok(Some((env, harts, listing, Some(program))))
+}
+
+/// Where the branch at `pc` is, by source line and label where known
+fn describe_branch(env: &Env, pc: u32) -> String {
+    [env.source_map.describe(pc), env.describe_address(pc)]
+        .into_iter()
+        .flatten()
+        .join(" ")
+}
+
+/// Instructions in the stepper's pipeline diagram
+const PIPELINE_ROWS: usize = 8;
 
-    match parse(&env, &input) {
+/// Exit status of `run` when the program hit the instruction limit
+const LIMIT_STATUS: i32 = 124;
+/// Exit status of `run` when the program faulted, or took an exception with
+/// no handler
+const FAULT_STATUS: i32 = 125;
+
+fn run(path: &Path, quiet: bool, limit: Option<u64>, machine: &Machine) -> anyhow::Result<()> {
+    let Some((mut env, mut harts, listing, _)) = boot(path, machine, true)? else {
+        std::process::exit(1);
+    };
+    let runnable = |pc: u32| listing.lines.contains_key(&pc);
+
+    let (count, stop) = execution::run(&mut env, &mut harts, limit, runnable);
+    let (status, message) = match stop {
+        Stop::Exit(code) => (code as i32, format!("exited with code {}", code as i32)),
+        // Like RARS, running off the end of the program is a normal exit
+        Stop::Finished => (0, "finished".to_string()),
+        Stop::Limit => (LIMIT_STATUS, "stopped at the instruction limit".to_string()),
+        Stop::Fault(err) => {
+            report_memory_err(&env, env.pc, &err);
+            (FAULT_STATUS, "faulted".to_string())
+        }
+        Stop::Syscall(err) => {
+            report_at(&env, env.pc, &err.to_string(), &err.note());
+            (1, "stopped".to_string())
+        }
+        Stop::Exception(cause) => {
+            report_at(
+                &env,
+                env.csrs.mepc,
+                &format!("unhandled {}", csr::describe_exception(cause)),
+                "set mtvec to a trap handler",
+            );
+            (FAULT_STATUS, "trapped".to_string())
+        }
+    };
+    env.devices.flush();
+    // Gives the terminal back if the keyboard had it
+    env.devices = Devices::default();
+    machine.write_pipeline(&env)?;
+
+    if !quiet {
+        eprintln!(
+            "\n{} after {} instructions, pc = {:#010x}",
+            message, count, env.pc
+        );
+        for row in env.registers.iter().enumerate().chunks(4).into_iter() {
+            let row = row
+                .map(|(i, value)| format!("{:>4} = {:#010x}", REGISTERS[i], value))
+                .join("  ");
+            eprintln!("{}", row);
+        }
+        if let Some(pipeline) = &env.pipeline {
+            eprintln!("pipeline: {}", pipeline.stats);
+        }
+        for cache in env.caches.iter() {
+            eprintln!("{}", cache);
+        }
+        if let Some(predictor) = &env.predictor {
+            eprint!("{}", predictor.report(|pc| describe_branch(&env, pc)));
+        }
+    }
+    std::process::exit(status);
+}
+
+fn step(
+    path: &Path,
+    output: &Path,
+    format: Format,
+    lst: Option<&Path>,
+    snapshot: Option<&Path>,
+    machine: &Machine,
+) -> anyhow::Result<()> {
+    let display_mode = 's';
+    let term_width = term_size::dimensions().map(|(w, _)| w).unwrap_or(80);
+
+    let Some((mut env, mut harts, listing, program)) = boot(path, machine, false)? else {
+        return Ok(());
+    };
+    if let Some(program) = &program {
+        export(program, &env.source_map, format, output, lst)?;
+    }
+    let keyboard = env.devices.iter().any(|d| d.name() == "keyboard");
+
+    let runnable = |pc: u32| listing.lines.contains_key(&pc);
+    let mut history = History::default();
+    let mut breakpoints = HashSet::new();
+    let mut action = Action::Step;
+
+    loop {
+        let (undo, fault) = match travel(
+            &action,
+            &mut env,
+            &mut harts,
+            &mut history,
+            &breakpoints,
+            &runnable,
+        ) {
+            Ok(undo) => (undo, None),
+            Err(err) => (history.last().cloned(), Some(err)),
+        };
+
+        if let Some(undo) = &undo {
+            let pc = undo.pc;
+            let current = listing.lines[&pc];
+            let changed = undo.registers.iter().map(|(i, _)| *i).collect();
+            let fchanged = undo.fregisters.iter().map(|(i, _)| *i).collect();
+
+            println!(
+                "{}\n",
+                listing
+                    .text
+                    .lines()
+                    .enumerate()
+                    .map(|(i, line)| {
+                        if i == current {
+                            format!("> {}", line).bright_green()
+                        } else {
+                            format!("  {}", line).normal()
+                        }
+                    })
+                    .join("\n")
+            );
+            match (env.describe_address(pc), env.source_map.describe(pc)) {
+                (Some(location), Some(source)) => {
+                    println!("in {} ({})\n", location.italic(), source)
+                }
+                (Some(location), None) => println!("in {}\n", location.italic()),
+                (None, Some(source)) => println!("at {}\n", source),
+                (None, None) => {}
+            }
+            let (right, tag) = if let Token::Op(op, args) = &listing.ops[&pc] {
+                info(
+                    &env,
+                    op,
+                    args.iter().map(|(token, _)| arg_text(token)).collect(),
+                    display_mode,
+                )
+            } else {
+                unreachable!()
+            };
+            let left = make_box(
+                term_width as u32 / 2,
+                pc as usize,
+                env.registers.into_iter().collect(),
+                changed,
+                display_mode,
+                true,
+                tag.clone(),
+            ) + &make_box_fp(
+                term_width as u32 / 2,
+                env.fregisters.into_iter().collect(),
+                fchanged,
+                display_mode,
+                true,
+                tag,
+            );
+
+            println!(
+                "{}",
+                left.lines()
+                    .zip(right.lines().chain([""].repeat(left.lines().count())))
+                    .map(|(l, r)| format!("{}   {}", l, r))
+                    .join("\n")
+            );
+            for status in env.devices.iter().filter_map(|d| d.status()) {
+                println!("{}", status);
+            }
+            if let Some(status) = env.syscalls.as_ref().and_then(|s| s.status()) {
+                println!("{}", status);
+            }
+            if let Some(semihosting) = &env.semihosting {
+                println!("{}", semihosting.status());
+            }
+            if let Some(pipeline) = &env.pipeline {
+                println!("pipeline: {}", pipeline.stats);
+                print!("{}", pipeline.diagram(PIPELINE_ROWS));
+            }
+            for cache in env.caches.iter() {
+                println!("{}", cache);
+                if let Some(view) = cache.view() {
+                    println!("  {}", view);
+                }
+            }
+            if let Some(predictor) = &env.predictor {
+                println!("{}", predictor);
+            }
+            println!(
+                "interrupts: {}  enabled {}  pending {}",
+                if env.csrs.mstatus & csr::MSTATUS_MIE != 0 {
+                    "on"
+                } else {
+                    "off"
+                },
+                csr::describe_interrupts(env.csrs.mie),
+                csr::describe_interrupts(env.devices.mip())
+            );
+            if harts.len() > 1 {
+                for id in 0..harts.len() {
+                    let pc = harts.pc(&env, id);
+                    let state = if !runnable(pc) {
+                        "done".to_string()
+                    } else {
+                        env.describe_address(pc)
+                            .unwrap_or_else(|| format!("{:#010x}", pc))
+                    };
+                    let line = format!("hart {}: {}", id, state);
+                    if id == undo.hart {
+                        println!("{}", line.bright_green());
+                    } else {
+                        println!("{}", line);
+                    }
+                }
+            }
+
+            if let Some(path) = snapshot {
+                let harts = (harts.len() > 1).then_some(&harts);
+                std::fs::write(path, Snapshot::new(&env, harts).to_json())?;
+            }
+        } else if history.is_empty() {
+            println!("{}", "at the start of the program".yellow());
+        }
+
+        if let Some(err) = &fault {
+            let pc = undo.as_ref().map_or(env.pc, |u| u.pc);
+            report_at(&env, pc, &err.to_string(), &err.note());
+        }
+        if let Some(code) = env.exit {
+            println!("\nexited with code {}", code as i32);
+        }
+        let stopped = fault.is_some()
+            || env.exit.is_some()
+            || !(0..harts.len()).any(|id| runnable(harts.pc(&env, id)));
+
+        action = loop {
+            println!(
+                "\n{} instructions run. {}",
+                history.len(),
+                if stopped {
+                    "The program has stopped, press enter to quit,"
+                } else {
+                    "Press enter to step,"
+                }
+            );
+            println!("or b to step back, c/rc to continue forwards/backwards to a breakpoint,");
+            println!("break ADDR to set or clear one, goto N to go to instruction N, q to quit");
+            match prompt(keyboard, &mut env)? {
+                Some(Action::Step) if stopped => break Action::Quit,
+                Some(Action::Break(addr)) => {
+                    match env.get_label(&addr).or_else(|| parse_address(&addr).ok()) {
+                        Some(addr) if breakpoints.remove(&addr) => {
+                            println!("cleared the breakpoint at {:#x}", addr)
+                        }
+                        Some(addr) => {
+                            breakpoints.insert(addr);
+                            println!("set a breakpoint at {:#x}", addr)
+                        }
+                        None => println!("{}", format!("no label or address '{}'", addr).yellow()),
+                    }
+                }
+                Some(action) => break action,
+                None => println!("{}", "unknown command".yellow()),
+            }
+        };
+        if let Action::Quit = action {
+            machine.write_pipeline(&env)?;
+            if fault.is_some() {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// What the stepper was told to do at the prompt
+enum Action {
+    Step,
+    Back,
+    Continue,
+    ReverseContinue,
+    /// Go to the point where this many instructions have run
+    Goto(usize),
+    /// Label or address to set or clear a breakpoint at
+    Break(String),
+    Quit,
+}
+
+impl FromStr for Action {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [] | ["s"] => Ok(Action::Step),
+            ["b"] => Ok(Action::Back),
+            ["c"] => Ok(Action::Continue),
+            ["rc"] => Ok(Action::ReverseContinue),
+            ["goto", n] => n.parse().map(Action::Goto).map_err(|_| ()),
+            ["break", addr] => Ok(Action::Break(addr.to_string())),
+            ["q"] => Ok(Action::Quit),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Read what to do next, `None` if it isn't a command. Keys typed in keyboard
+/// mode go to the program, with tab to type a command instead
+fn prompt(keyboard: bool, env: &mut Env) -> anyhow::Result<Option<Action>> {
+    if keyboard {
+        println!("Other keys go to the keyboard device, tab types a command, Ctrl-C quits");
+        // Raw mode to get keys as they're typed, not once enter is pressed
+        let raw = std::io::stdout().into_raw_mode()?;
+        for c in std::io::stdin().keys() {
+            match c? {
+                termion::event::Key::Char('\n') => return Ok(Some(Action::Step)),
+                termion::event::Key::Char('\t') => break,
+                termion::event::Key::Ctrl('c') => {
+                    drop(raw);
+                    std::process::exit(130);
+                }
+                termion::event::Key::Char(c) => {
+                    let mut bytes = [0; 4];
+                    c.encode_utf8(&mut bytes)
+                        .bytes()
+                        .for_each(|b| env.devices.key(b));
+                }
+                termion::event::Key::Backspace => env.devices.key(8),
+                termion::event::Key::Esc => env.devices.key(0x1b),
+                _ => {}
+            }
+        }
+        drop(raw);
+        print!("> ");
+        std::io::Write::flush(&mut std::io::stdout())?;
+    }
+
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line)? == 0 {
+        return Ok(Some(Action::Quit));
+    }
+    Ok(line.parse().ok())
+}
+
+/// Carry out a stepper action that moves through the program, returning the
+/// last instruction it went over, forwards or backwards
+fn travel(
+    action: &Action,
+    env: &mut Env,
+    harts: &mut Harts,
+    history: &mut History,
+    breakpoints: &HashSet<u32>,
+    runnable: &impl Fn(u32) -> bool,
+) -> Result<Option<Undo>, StepErr> {
+    let forward = |env: &mut Env, harts: &mut Harts, history: &mut History| match history
+        .step(env, harts, runnable)
+    {
+        Some((_, result)) => result.map(|_| history.last().cloned()),
+        None => Ok(None),
+    };
+
+    let mut last = None;
+    match action {
+        Action::Step => last = forward(env, harts, history)?,
+        Action::Back => last = history.back(env, harts),
+        Action::Continue => {
+            while let Some(undo) = forward(env, harts, history)? {
+                last = Some(undo);
+                if breakpoints.contains(&env.pc) {
+                    break;
+                }
+            }
+        }
+        Action::ReverseContinue => {
+            while let Some(undo) = history.back(env, harts) {
+                last = Some(undo);
+                if breakpoints.contains(&env.pc) {
+                    break;
+                }
+            }
+        }
+        Action::Goto(n) => {
+            while history.len() > *n {
+                last = history.back(env, harts);
+            }
+            while history.len() < *n {
+                match forward(env, harts, history)? {
+                    Some(undo) => last = Some(undo),
+                    None => break,
+                }
+            }
+        }
+        Action::Break(_) | Action::Quit => {}
+    }
+    Ok(last)
+}
+
+/// A program loaded into an `Env`
+struct Program {
+    listing: Listing,
+    /// Contents of each section
+    regions: Vec<Region>,
+    /// `.lst` file, for programs assembled from source
+    lst: Option<String>,
+}
+
+/// Assemble `input` into `env`'s memory. `None` if it didn't assemble, the
+/// errors have been reported
+fn assemble_listing(env: &mut Env, path: &Path, input: String) -> anyhow::Result<Option<Program>> {
+    let file = SimpleFile::new(path.display().to_string(), input.clone());
+
+    let mut listing = Listing::default();
+    let mut items = Vec::new();
+    let mut locs = Vec::new();
+    let mut sections: HashMap<Section, Vec<u8>> = HashMap::new();
+    let mut section = Section::Text;
+    // Every error is reported before giving up
+    let mut failed = false;
+    let mut emit = |env: &mut Env, section: Section, addr: u32, bytes: &[u8]| {
+        let base = match section {
+            Section::Text => env.text_base,
+            Section::Data => env.data_base,
+        };
+        let data = sections.entry(section).or_default();
+        let offset = (addr - base) as usize;
+        if data.len() < offset + bytes.len() {
+            data.resize(offset + bytes.len(), 0);
+        }
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        env.memory.write_bytes(addr, bytes);
+    };
+
+    match parse(env, &input) {
         Ok(tokens) => {
             let lines: Vec<&str> = input.lines().collect();
             let size = lines.iter().map(|l| l.len()).max().unwrap();
 
-            env.handle_mem_offsets(tokens)
-                .iter()
-                .for_each(|(token, loc)| {
-                    let token = token.clone();
-
-                    match token.clone() {
-                        Token::Op(..) => match env.assemble_op((token.clone(), loc.clone())) {
-                            Ok(op) => {
-                                let mut formatted = format!(
-                                    "{:<1$} {3:02x}: {2:032b}",
-                                    lines[loc.line - 1],
-                                    size + 3,
-                                    op[0],
-                                    loc.mem_offset
+            let tokens = match env.handle_mem_offsets(tokens) {
+                Ok(tokens) => tokens,
+                Err(err) => {
+                    report_engine_err(&file, &err);
+                    return Ok(None);
+                }
+            };
+            for (token, loc) in tokens {
+                match token.clone() {
+                    Token::Op(..) => match env.assemble_op((token.clone(), loc)) {
+                        Ok(op) => {
+                            let bytes = op.iter().flat_map(|w| w.to_le_bytes()).collect();
+                            items.push((token.clone(), loc, bytes));
+                            for (i, word) in op.iter().enumerate() {
+                                let addr = (loc.mem_offset + i * 4) as u32;
+                                listing.push(
+                                    format!(
+                                        "{:<1$} {3:02x}: {2:032b}",
+                                        if i == 0 { lines[loc.line - 1] } else { "" },
+                                        size + 3,
+                                        word,
+                                        addr
+                                    ),
+                                    Some((addr, token.clone())),
                                 );
-                                ops.push(op[0]);
-                                toks.push(token.clone());
-
-                                if op.len() > 1 {
-                                    for op in op[1..].iter() {
-                                        formatted += &format!(
-                                            "\n{:<1$} {3:02x}: {2:032b}",
-                                            "",
-                                            size + 3,
-                                            op,
-                                            loc.mem_offset
-                                        );
-                                        ops.push(*op);
-                                        toks.push(token.clone());
-                                    }
+                                emit(env, section, addr, &word.to_le_bytes());
+                                if section == Section::Text {
+                                    locs.push((addr, loc));
                                 }
-                                parse_asm_result += &format!("{}\n", formatted);
                             }
-                            Err(err) => {
-                                let diagnostic = Diagnostic::error()
-                                    .with_message("Engine Error")
-                                    .with_labels(vec![Label::primary(
-                                        (),
-                                        err.1.start..(err.1.end + 1),
-                                    )
-                                    .with_message(err.0.to_string())])
-                                    .with_notes({
-                                        let mut notes = Vec::new();
-                                        if let Some(note) = &err.2 {
-                                            notes.push(note.to_string());
-                                        }
-                                        notes.push(err.0.note());
-                                        notes
-                                    });
-
-                                term::emit(&mut writer.lock(), &config, &file, &diagnostic)
-                                    .unwrap();
-                            }
-                        },
-                        Token::Label(name) => {
-                            parse_asm_result += &format!(
-                                "{:<1$}     <{2:02x}>\n",
+                        }
+                        Err(err) => {
+                            report_engine_err(&file, &err);
+                            failed = true;
+                        }
+                    },
+                    Token::Label(name) => {
+                        items.push((token.clone(), loc, Vec::new()));
+                        listing.push(
+                            format!(
+                                "{:<1$}     <{2:02x}>",
                                 name.clone() + ":",
                                 size + 3,
                                 env.get_label(&name).unwrap()
-                            );
+                            ),
+                            None,
+                        );
+                    }
+                    Token::Directive(name, args) => {
+                        match env.assemble_directive((token.clone(), loc)) {
+                            Ok(bytes) => {
+                                emit(env, section, loc.mem_offset as u32, &bytes);
+                                items.push((token, loc, bytes));
+                            }
+                            Err(err) => {
+                                report_engine_err(&file, &err);
+                                failed = true;
+                            }
+                        }
+                        if let Some(new) = Section::from_directive(&name, &args) {
+                            section = new;
                         }
-                        _ => unreachable!(),
                     }
-                });
+                    _ => unreachable!(),
+                }
+            }
         }
         Err(errs) => {
-            let err = errs.first().unwrap();
-            let start = err.1.start;
-            let end = err.1.end + 1;
-
-            let diagnostic = Diagnostic::error()
-                .with_message("Syntax Error")
-                .with_labels(vec![
-                    Label::primary((), start..end).with_message(err.0.to_string())
-                ])
-                .with_notes({
-                    let mut notes = Vec::new();
-                    if let Some(note) = &err.3 {
-                        notes.push(note.to_string());
-                    }
-                    notes.push(err.0.note());
-                    notes
-                });
+            report_syntax_err(&file, errs.first().unwrap());
 
-            term::emit(&mut writer.lock(), &config, &file, &diagnostic).unwrap();
-
-            return Ok(());
+            return Ok(None);
         }
     };
-
-    let mut file = std::fs::File::create("test.bin")?;
-    for op in ops.iter() {
-        let formatted = format!("{:08x}\n", op);
-        file.write_all(formatted.as_bytes()).unwrap();
+    if failed {
+        return Ok(None);
     }
 
-    // Print the register values
+    let text_size = sections.get(&Section::Text).map_or(0, Vec::len) as u32;
+    let data_size = sections.get(&Section::Data).map_or(0, Vec::len) as u32;
+    env.source_map = SourceMap::from_locs(
+        &path.display().to_string(),
+        &input,
+        &locs,
+        env.text_base + text_size,
+    );
+    env.memory
+        .map(".text", env.text_base, text_size, Permissions::RX);
+    env.memory
+        .map(".data", env.data_base, data_size, Permissions::RW);
+    env.memory.map_heap_and_stack(env.data_base + data_size);
 
-    while env.pc / 4 < ops.clone().len() as u32 {
-        let pc = env.pc.clone();
-        let prev_regs = env.registers.clone();
-        let prev_fregs = env.fregisters.clone();
+    let regions = [
+        (Section::Text, env.text_base),
+        (Section::Data, env.data_base),
+    ]
+    .into_iter()
+    .filter_map(|(section, addr)| {
+        Some(Region {
+            name: section.name().to_string(),
+            addr,
+            data: sections.remove(&section).filter(|data| !data.is_empty())?,
+        })
+    })
+    .collect();
 
-        env.pc += 4 * !run_instruction(&mut env, ops[pc as usize >> 2]) as u32;
+    Ok(Some(Program {
+        listing,
+        regions,
+        lst: Some(listing::lst(env, &input, &items)),
+    }))
+}
 
-        let mut changed = Vec::new();
-        for (i, _) in prev_regs
-            .iter()
-            .zip(env.registers.iter())
-            .enumerate()
-            .filter(|(_, (prev, curr))| prev != curr)
-        {
-            changed.push(i);
-        }
+/// Disassemble the executable sections of a loaded ELF file
+fn elf_listing(elf: &Elf) -> Listing {
+    disassembly_listing(&Disassembly::from_elf(elf))
+}
 
-        let mut fchanged = Vec::new();
-        for (i, _) in prev_fregs
-            .iter()
-            .zip(env.fregisters.iter())
-            .enumerate()
-            .filter(|(_, (prev, curr))| prev != curr)
-        {
-            fchanged.push(i);
-        }
-
-        println!(
-            "{}\n",
-            parse_asm_result
-                .lines()
-                .enumerate()
-                .map(|(i, line)| {
-                    if i == pc as usize >> 2 {
-                        format!("> {}", line).bright_green()
-                    } else {
-                        format!("  {}", line).normal()
-                    }
-                })
-                .join("\n")
-        );
-        let (right, tag) = if let Token::Op(op, args) = &toks[pc as usize >> 2] {
-            info(
-                &env,
-                &op,
-                args.iter()
-                    .map(|(token, _)| match token {
-                        Token::Register(reg) => reg.clone(),
-                        Token::Immediate(imm) => imm.to_string(),
-                        Token::Memory(imm, reg) => format!(
-                            "{}({})",
-                            match **imm {
-                                Token::Immediate(imm) => imm.to_string(),
-                                _ => "".to_string(),
-                            },
-                            match reg {
-                                Some(reg) => match **reg {
-                                    Token::Register(ref r) => r.clone(),
-                                    _ => unreachable!(),
-                                },
-                                _ => "".to_string(),
-                            }
-                        ),
-                        _ => unreachable!(),
-                    })
-                    .collect(),
-                display_mode,
-            )
-        } else {
-            unreachable!()
-        };
-        let left = make_box(
-            term_width as u32 / 2,
-            pc as usize,
-            env.registers.clone().into_iter().collect(),
-            changed,
-            display_mode,
-            true,
-            tag.clone(),
-        ) + &make_box_fp(
-            term_width as u32 / 2,
-            env.fregisters.clone().into_iter().collect(),
-            fchanged,
-            display_mode,
-            true,
-            tag,
-        );
+/// Disassemble the executable regions of a restored snapshot
+fn snapshot_listing(env: &Env) -> Listing {
+    let symbols = env
+        .labels()
+        .into_iter()
+        .map(|(name, addr)| (name.to_string(), addr))
+        .collect::<Vec<_>>();
+    let mut disassembly = Disassembly::default();
+    for region in env.memory.regions.iter().filter(|r| r.permissions.execute) {
+        let code = env
+            .memory
+            .read_bytes(region.start, region.end - region.start);
+        let part = Disassembly::new(&code, region.start, &symbols);
+        disassembly.lines.extend(part.lines);
+        disassembly.labels.extend(part.labels);
+    }
+    disassembly_listing(&disassembly)
+}
 
-        println!(
-            "{}",
-            left.lines()
-                .zip(right.lines().chain([""].repeat(left.lines().count())))
-                .map(|(l, r)| format!("{}   {}", l, r))
-                .join("\n")
-        );
+fn disassembly_listing(disassembly: &Disassembly) -> Listing {
+    let mut listing = Listing::default();
 
-        println!("\nPress enter to continue...");
-        for c in std::io::stdin().keys() {
-            match c.unwrap() {
-                termion::event::Key::Char('\n') => break,
-                _ => {}
+    for line in disassembly.lines.iter() {
+        match line {
+            Line::Label(label) => listing.push(format!("{}:", label), None),
+            Line::Op { addr, word, op } => listing.push(
+                format!(
+                    "  {:08x}: {:08x}  {}",
+                    addr,
+                    word,
+                    op.format(*addr, &disassembly.labels)
+                ),
+                Some((*addr, op.to_token(*addr, &disassembly.labels))),
+            ),
+            Line::Word { addr, word } => {
+                listing.push(format!("  {:08x}: {:08x}  .word", addr, word), None)
             }
         }
     }
 
-    Ok(())
+    listing
 }
 
 const fn round_down_to_power_of_two(n: u32) -> u32 {
@@ -0,0 +1,413 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    hash::{BuildHasherDefault, Hasher},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::err::MemoryErr;
+
+pub const PAGE_SIZE: u32 = 4096;
+
+/// The stack grows down from here, `sp` starts at it
+pub const STACK_TOP: u32 = 0x7ffff000;
+pub const STACK_SIZE: u32 = 0x100000;
+/// Space for dynamic allocation right after the data
+pub const HEAP_SIZE: u32 = 0x100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Load,
+    Store,
+    Fetch,
+}
+
+impl Access {
+    /// The permission a region needs for this access
+    pub fn permission(&self) -> &'static str {
+        match self {
+            Access::Load => "read",
+            Access::Store => "write",
+            Access::Fetch => "execute",
+        }
+    }
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Load => write!(f, "load"),
+            Access::Store => write!(f, "store"),
+            Access::Fetch => write!(f, "instruction fetch"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const RW: Self = Self::new(true, true, false);
+    pub const RX: Self = Self::new(true, false, true);
+    pub const RWX: Self = Self::new(true, true, true);
+
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Load => self.read,
+            Access::Store => self.write,
+            Access::Fetch => self.execute,
+        }
+    }
+}
+
+impl Display for Permissions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let flag = |set, c| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+/// A named range of addresses and what it may be used for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    pub name: String,
+    pub start: u32,
+    /// Exclusive
+    pub end: u32,
+    pub permissions: Permissions,
+}
+
+impl Region {
+    pub fn contains(&self, addr: u32) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+}
+
+/// What happens to loads and stores not aligned to their width
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Misaligned {
+    /// Done byte by byte, like hardware that handles them transparently
+    #[default]
+    Allow,
+    /// Raise an address misaligned exception
+    Trap,
+}
+
+impl FromStr for Misaligned {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Misaligned::Allow),
+            "trap" => Ok(Misaligned::Trap),
+            _ => Err(format!(
+                "unknown misaligned access policy '{}', expected allow or trap",
+                s
+            )),
+        }
+    }
+}
+
+/// Hashes page numbers, which need mixing but not protection from collisions
+/// the way `HashMap`'s default hasher gives, at a fraction of the cost
+#[derive(Default)]
+pub struct PageHasher(u64);
+
+impl Hasher for PageHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_u8(*byte);
+        }
+    }
+
+    fn write_u8(&mut self, n: u8) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0 ^ n).wrapping_mul(0x9e3779b97f4a7c15);
+    }
+}
+
+type Pages = HashMap<u32, Box<[u8; PAGE_SIZE as usize]>, BuildHasherDefault<PageHasher>>;
+
+/// Sparse byte-addressable memory, pages are only allocated once written to.
+///
+/// Reading memory that was never written returns zeros. Code and data share
+/// the one address space. `load`, `store` and `fetch` are what the program
+/// does and are checked against `regions`, the other accessors are for
+/// loaders and debuggers and aren't.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Memory {
+    #[serde(with = "pages")]
+    pages: Pages,
+    /// With none, every address can be used for anything
+    pub regions: Vec<Region>,
+    pub misaligned: Misaligned,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read_u8(&self, addr: u32) -> u8 {
+        self.pages
+            .get(&(addr / PAGE_SIZE))
+            .map_or(0, |page| page[(addr % PAGE_SIZE) as usize])
+    }
+
+    pub fn write_u8(&mut self, addr: u32, value: u8) {
+        self.pages
+            .entry(addr / PAGE_SIZE)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE as usize]))[(addr % PAGE_SIZE) as usize] =
+            value;
+    }
+
+    /// Little-endian half at `addr`
+    pub fn read_u16(&self, addr: u32) -> u16 {
+        u16::from_le_bytes([self.read_u8(addr), self.read_u8(addr.wrapping_add(1))])
+    }
+
+    pub fn write_u16(&mut self, addr: u32, value: u16) {
+        self.write_bytes(addr, &value.to_le_bytes());
+    }
+
+    /// Little-endian word at `addr`
+    pub fn read_u32(&self, addr: u32) -> u32 {
+        self.read(addr, 4)
+    }
+
+    /// Little-endian value of the `width` bytes at `addr`, zero extended
+    fn read(&self, addr: u32, width: u32) -> u32 {
+        let offset = (addr % PAGE_SIZE) as usize;
+        // Looking the page up once when it's all in the one page
+        if offset + width as usize <= PAGE_SIZE as usize {
+            return self.pages.get(&(addr / PAGE_SIZE)).map_or(0, |page| {
+                page[offset..offset + width as usize]
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, byte| acc << 8 | *byte as u32)
+            });
+        }
+        (0..width).rev().fold(0, |acc, i| {
+            acc << 8 | self.read_u8(addr.wrapping_add(i)) as u32
+        })
+    }
+
+    pub fn write_u32(&mut self, addr: u32, value: u32) {
+        self.write_bytes(addr, &value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, addr: u32, bytes: &[u8]) {
+        let offset = (addr % PAGE_SIZE) as usize;
+        if offset + bytes.len() <= PAGE_SIZE as usize {
+            self.pages
+                .entry(addr / PAGE_SIZE)
+                .or_insert_with(|| Box::new([0; PAGE_SIZE as usize]))[offset..offset + bytes.len()]
+                .copy_from_slice(bytes);
+            return;
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            self.write_u8(addr.wrapping_add(i as u32), *byte);
+        }
+    }
+
+    pub fn read_bytes(&self, addr: u32, len: u32) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.read_u8(addr.wrapping_add(i)))
+            .collect()
+    }
+
+    /// Add a region, ignored if it's empty
+    pub fn map(&mut self, name: &str, start: u32, size: u32, permissions: Permissions) {
+        if size == 0 {
+            return;
+        }
+        self.regions.push(Region {
+            name: name.to_string(),
+            start,
+            end: start.saturating_add(size),
+            permissions,
+        });
+    }
+
+    /// Map a heap starting at `end`, the end of the program's data, and the stack
+    pub fn map_heap_and_stack(&mut self, end: u32) {
+        self.map("heap", end, HEAP_SIZE, Permissions::RW);
+        self.map("stack", STACK_TOP - STACK_SIZE, STACK_SIZE, Permissions::RW);
+    }
+
+    /// The region `map_heap_and_stack` made for the heap
+    pub fn heap(&self) -> Option<&Region> {
+        self.regions.iter().find(|r| r.name == "heap")
+    }
+
+    pub fn region_at(&self, addr: u32) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(addr))
+    }
+
+    /// Whether `width` bytes at `addr` can be used for `access`
+    pub fn check(&self, addr: u32, width: u32, access: Access) -> Result<(), MemoryErr> {
+        let misaligned = !addr.is_multiple_of(width);
+        if misaligned && (access == Access::Fetch || self.misaligned == Misaligned::Trap) {
+            return Err(MemoryErr::Misaligned(access, addr, width));
+        }
+        if self.regions.is_empty() {
+            return Ok(());
+        }
+
+        // A misaligned access can straddle two regions, both have to allow it
+        for addr in [addr, addr.wrapping_add(width - 1)] {
+            match self.region_at(addr) {
+                Some(region) if region.permissions.allows(access) => {}
+                region => {
+                    return Err(MemoryErr::Fault(
+                        access,
+                        addr,
+                        region.map(|r| r.name.clone()),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read `width` bytes at `addr` for the program, zero extended
+    pub fn load(&self, addr: u32, width: u32) -> Result<u32, MemoryErr> {
+        self.check(addr, width, Access::Load)?;
+        Ok(self.read(addr, width))
+    }
+
+    /// Write the low `width` bytes of `value` at `addr` for the program
+    pub fn store(&mut self, addr: u32, width: u32, value: u32) -> Result<(), MemoryErr> {
+        self.check(addr, width, Access::Store)?;
+        self.write_bytes(addr, &value.to_le_bytes()[..width as usize]);
+        Ok(())
+    }
+
+    /// The instruction at `addr`
+    pub fn fetch(&self, addr: u32) -> Result<u32, MemoryErr> {
+        self.check(addr, 4, Access::Fetch)?;
+        Ok(self.read_u32(addr))
+    }
+}
+
+/// Pages as hex strings by page number, leaving out the ones that are all zeros
+mod pages {
+    use std::collections::BTreeMap;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{Pages, PAGE_SIZE};
+
+    pub fn serialize<S: Serializer>(pages: &Pages, serializer: S) -> Result<S::Ok, S::Error> {
+        pages
+            .iter()
+            .filter(|(_, page)| page.iter().any(|&b| b != 0))
+            .map(|(n, page)| {
+                let hex = page
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>();
+                (*n, hex)
+            })
+            .collect::<BTreeMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pages, D::Error> {
+        BTreeMap::<u32, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(n, hex)| {
+                let mut page = Box::new([0; PAGE_SIZE as usize]);
+                if hex.len() != PAGE_SIZE as usize * 2 {
+                    return Err(D::Error::custom(format!(
+                        "page {:#x} isn't {} bytes",
+                        n, PAGE_SIZE
+                    )));
+                }
+                for (i, byte) in page.iter_mut().enumerate() {
+                    *byte = hex
+                        .get(i * 2..i * 2 + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                        .ok_or_else(|| D::Error::custom(format!("page {:#x} isn't hex", n)))?;
+                }
+                Ok((n, page))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn memory() {
+        use super::{Access, Memory, Misaligned, Permissions, PAGE_SIZE};
+        use crate::err::MemoryErr;
+
+        let mut memory = Memory::new();
+        // Little-endian and across a page boundary
+        memory.store(PAGE_SIZE - 2, 4, 0x12345678).unwrap();
+        assert_eq!(memory.load(PAGE_SIZE - 2, 2), Ok(0x5678));
+        assert_eq!(memory.load(PAGE_SIZE, 1), Ok(0x34));
+
+        memory.misaligned = Misaligned::Trap;
+        assert_eq!(
+            memory.load(PAGE_SIZE - 2, 4),
+            Err(MemoryErr::Misaligned(Access::Load, PAGE_SIZE - 2, 4))
+        );
+
+        memory.map(".text", 0, 0x100, Permissions::RX);
+        memory.map(".data", 0x100, 0x100, Permissions::RW);
+        assert_eq!(memory.fetch(0xfc), Ok(0));
+        assert_eq!(
+            memory.store(0x10, 4, 0),
+            Err(MemoryErr::Fault(
+                Access::Store,
+                0x10,
+                Some(".text".to_string())
+            ))
+        );
+        assert_eq!(
+            memory.fetch(0x100),
+            Err(MemoryErr::Fault(
+                Access::Fetch,
+                0x100,
+                Some(".data".to_string())
+            ))
+        );
+        assert_eq!(
+            memory.load(0x200, 1),
+            Err(MemoryErr::Fault(Access::Load, 0x200, None))
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+use crate::{
+    elf::{self, Elf, Shndx},
+    env::{AssembleErr, Env, Section},
+    instructions::{instruction, kind::Kind},
+    parser::{Loc, Token},
+    srcmap::SourceMap,
+};
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub offset: u32,
+    pub global: bool,
+}
+
+/// A reference to a symbol the linker has to patch in
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    pub section: Section,
+    pub offset: u32,
+    pub kind: u32,
+    pub symbol: String,
+}
+
+/// The assembled sections of a single source file, with every symbol
+/// reference left for the linker to resolve
+#[derive(Debug, Default)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+    /// Where each word of code came from
+    pub locs: Vec<(u32, Loc)>,
+}
+
+impl Object {
+    pub fn assemble(env: &mut Env, tokens: Vec<(Token, Loc)>) -> Result<Self, Vec<AssembleErr>> {
+        // Every section starts at 0, their final address is up to the linker
+        env.relocatable = true;
+        env.text_base = 0;
+        env.data_base = 0;
+
+        let mut object = Object::default();
+        let mut errs = Vec::new();
+        let mut section = Section::Text;
+
+        for (token, loc) in env.handle_mem_offsets(tokens).map_err(|err| vec![err])? {
+            let offset = loc.mem_offset as u32;
+            match &token {
+                Token::Op(name, args) => match env.assemble_op((token.clone(), loc)) {
+                    Ok(words) => {
+                        object.relocate_op(section, name, args, offset, &words);
+                        if section == Section::Text {
+                            object
+                                .locs
+                                .extend((0..words.len()).map(|i| (offset + 4 * i as u32, loc)));
+                        }
+                        object
+                            .section_mut(section)
+                            .extend(words.iter().flat_map(|w| w.to_le_bytes()));
+                    }
+                    Err(err) => errs.push(err),
+                },
+                Token::Directive(name, args) => {
+                    match env.assemble_directive((token.clone(), loc)) {
+                        Ok(bytes) => {
+                            if name == ".word" {
+                                for (i, (arg, _)) in args.iter().enumerate() {
+                                    if let Token::Symbol(symbol) = arg {
+                                        object.relocate(
+                                            section,
+                                            offset + 4 * i as u32,
+                                            elf::R_RISCV_32,
+                                            symbol,
+                                        );
+                                    }
+                                }
+                            }
+                            object.section_mut(section).extend(bytes);
+                        }
+                        Err(err) => errs.push(err),
+                    }
+                    if let Some(new) = Section::from_directive(name, args) {
+                        section = new;
+                    }
+                }
+                Token::Label(_) => {}
+                _ => unreachable!(),
+            }
+        }
+
+        if !errs.is_empty() {
+            return Err(errs);
+        }
+
+        for (name, value) in env.labels() {
+            object.symbols.push(Symbol {
+                name: name.to_string(),
+                section: env.get_label_section(name).unwrap(),
+                offset: value,
+                global: env.is_global(name),
+            });
+        }
+
+        Ok(object)
+    }
+
+    fn section_mut(&mut self, section: Section) -> &mut Vec<u8> {
+        match section {
+            Section::Text => &mut self.text,
+            Section::Data => &mut self.data,
+        }
+    }
+
+    fn relocate(&mut self, section: Section, offset: u32, kind: u32, symbol: &str) {
+        self.relocations.push(Relocation {
+            section,
+            offset,
+            kind,
+            symbol: symbol.to_string(),
+        });
+    }
+
+    /// Record the relocations needed by the symbols an op refers to
+    fn relocate_op(
+        &mut self,
+        section: Section,
+        name: &str,
+        args: &[(Token, Loc)],
+        offset: u32,
+        words: &[u32],
+    ) {
+        let kind = instruction(name).map(|i| i.0);
+        // Out of range branches are relaxed into a branch over a jal
+        let relaxed = words.get(1).is_some_and(|w| w & 0x7f == 0b1101111);
+
+        for (arg, _) in args {
+            match (arg, &kind) {
+                (Token::Symbol(symbol), _) if name == "la" => {
+                    // The low half refers back to the auipc holding the high half
+                    let label = format!(".Lpcrel_hi{}", self.symbols.len());
+                    self.symbols.push(Symbol {
+                        name: label.clone(),
+                        section,
+                        offset,
+                        global: false,
+                    });
+                    self.relocate(section, offset, elf::R_RISCV_PCREL_HI20, symbol);
+                    self.relocate(section, offset + 4, elf::R_RISCV_PCREL_LO12_I, &label);
+                }
+                (Token::Symbol(symbol), _) if name == "call" => {
+                    self.relocate(section, offset, elf::R_RISCV_CALL, symbol);
+                }
+                (Token::Symbol(symbol), Some(Kind::Pseudo(_))) if relaxed => {
+                    self.relocate(section, offset + 4, elf::R_RISCV_JAL, symbol);
+                }
+                (Token::Symbol(symbol), Some(Kind::B(_))) => {
+                    self.relocate(section, offset, elf::R_RISCV_BRANCH, symbol);
+                }
+                (Token::Symbol(symbol), _) if name == "beqz" || name == "bnez" => {
+                    self.relocate(section, offset, elf::R_RISCV_BRANCH, symbol);
+                }
+                (Token::Symbol(symbol), Some(Kind::J(_))) => {
+                    self.relocate(section, offset, elf::R_RISCV_JAL, symbol);
+                }
+                (Token::Symbol(symbol), _) if name == "j" => {
+                    self.relocate(section, offset, elf::R_RISCV_JAL, symbol);
+                }
+                (Token::Modifier(m, symbol), _) if m == "hi" => {
+                    self.relocate(section, offset, elf::R_RISCV_HI20, symbol);
+                }
+                (Token::Modifier(_, symbol), _) => {
+                    self.relocate(section, offset, elf::R_RISCV_LO12_I, symbol);
+                }
+                (Token::Memory(imm, _), kind) => {
+                    if let Token::Modifier(m, symbol) = &**imm {
+                        let reloc = match (m.as_str(), kind) {
+                            ("hi", _) => elf::R_RISCV_HI20,
+                            (_, Some(Kind::S(_))) => elf::R_RISCV_LO12_S,
+                            _ => elf::R_RISCV_LO12_I,
+                        };
+                        self.relocate(section, offset, reloc, symbol);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Like `to_elf`, with a `.debug_line` section mapping the code back to `source`
+    pub fn to_elf_with_source(&self, file: &str, source: &str) -> Elf {
+        let mut elf = self.to_elf();
+        SourceMap::from_locs(file, source, &self.locs, self.text.len() as u32)
+            .add_to_object(&mut elf, 0);
+        elf
+    }
+
+    pub fn to_elf(&self) -> Elf {
+        let mut elf = Elf::new(elf::ET_REL);
+        for (section, flags) in [
+            (Section::Text, elf::SHF_ALLOC | elf::SHF_EXECINSTR),
+            (Section::Data, elf::SHF_ALLOC | elf::SHF_WRITE),
+        ] {
+            elf.sections.push(elf::Section {
+                name: section.name().to_string(),
+                kind: elf::SHT_PROGBITS,
+                flags,
+                addr: 0,
+                align: 4,
+                data: match section {
+                    Section::Text => self.text.clone(),
+                    Section::Data => self.data.clone(),
+                },
+            });
+        }
+        let index = |section: Section| match section {
+            Section::Text => 0,
+            Section::Data => 1,
+        };
+
+        let mut symbols = HashMap::new();
+        for symbol in self.symbols.iter() {
+            symbols.insert(symbol.name.clone(), elf.symbols.len());
+            elf.symbols.push(elf::Symbol {
+                name: symbol.name.clone(),
+                value: symbol.offset,
+                size: 0,
+                bind: if symbol.global {
+                    elf::STB_GLOBAL
+                } else {
+                    elf::STB_LOCAL
+                },
+                kind: elf::STT_NOTYPE,
+                section: Shndx::Index(index(symbol.section)),
+            });
+        }
+
+        for reloc in self.relocations.iter() {
+            // Anything not defined here is expected from another object
+            let symbol = *symbols.entry(reloc.symbol.clone()).or_insert_with(|| {
+                elf.symbols.push(elf::Symbol {
+                    name: reloc.symbol.clone(),
+                    value: 0,
+                    size: 0,
+                    bind: elf::STB_GLOBAL,
+                    kind: elf::STT_NOTYPE,
+                    section: Shndx::Undef,
+                });
+                elf.symbols.len() - 1
+            });
+            elf.relocations.push(elf::Relocation {
+                section: index(reloc.section),
+                offset: reloc.offset,
+                kind: reloc.kind,
+                symbol: Some(symbol),
+                addend: 0,
+            });
+        }
+
+        elf
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use itertools::Itertools;
+
+use crate::elf::{self, Elf};
+
+/// A contiguous piece of memory to write out, usually a whole section
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+impl Region {
+    /// The allocated sections of an ELF file that have contents
+    pub fn from_elf(elf: &Elf) -> Vec<Region> {
+        elf.sections
+            .iter()
+            .filter(|s| s.is_alloc() && s.kind != elf::SHT_NOBITS && !s.data.is_empty())
+            .map(|s| Region {
+                name: s.name.clone(),
+                addr: s.addr,
+                data: s.data.clone(),
+            })
+            .collect()
+    }
+
+    /// Little-endian words, the last one padded with zeros
+    pub fn words(&self) -> Vec<u32> {
+        self.data
+            .chunks(4)
+            .map(|bytes| {
+                let mut word = [0; 4];
+                word[..bytes.len()].copy_from_slice(bytes);
+                u32::from_le_bytes(word)
+            })
+            .collect()
+    }
+
+    /// Section name usable as an identifier or in a file name, `.text` -> `text`
+    fn ident(&self) -> String {
+        self.name
+            .trim_start_matches('.')
+            .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Raw little-endian bytes
+    Binary,
+    IntelHex,
+    /// Verilog `$readmemh`, one word per line
+    Readmemh,
+    /// Verilog `$readmemb`, one word per line
+    Readmemb,
+    /// Logisim-evolution ROM image
+    Logisim,
+    /// Xilinx coefficient file
+    Coe,
+    /// Altera memory initialization file
+    Mif,
+    C,
+    Rust,
+}
+
+impl Format {
+    pub const ALL: [Format; 9] = [
+        Format::Binary,
+        Format::IntelHex,
+        Format::Readmemh,
+        Format::Readmemb,
+        Format::Logisim,
+        Format::Coe,
+        Format::Mif,
+        Format::C,
+        Format::Rust,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Binary => "binary",
+            Format::IntelHex => "ihex",
+            Format::Readmemh => "readmemh",
+            Format::Readmemb => "readmemb",
+            Format::Logisim => "logisim",
+            Format::Coe => "coe",
+            Format::Mif => "mif",
+            Format::C => "c",
+            Format::Rust => "rust",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Binary => "bin",
+            Format::IntelHex => "hex",
+            Format::Readmemh => "mem",
+            Format::Readmemb => "mem",
+            Format::Logisim => "img",
+            Format::Coe => "coe",
+            Format::Mif => "mif",
+            Format::C => "h",
+            Format::Rust => "rs",
+        }
+    }
+
+    /// Whether a single file can hold regions at different addresses
+    pub fn is_addressed(&self) -> bool {
+        matches!(
+            self,
+            Format::IntelHex | Format::Readmemh | Format::Readmemb | Format::C | Format::Rust
+        )
+    }
+
+    /// Files to write for `regions`.
+    ///
+    /// Formats without addresses get a file per region, named after `path` with
+    /// the section in front of the extension (`out.text.coe`) when there is more than one.
+    pub fn outputs(&self, path: &Path, regions: &[Region]) -> Vec<(PathBuf, Vec<u8>)> {
+        if self.is_addressed() || regions.len() <= 1 {
+            return vec![(path.to_path_buf(), self.write(regions))];
+        }
+
+        regions
+            .iter()
+            .map(|region| {
+                let mut name = path.file_stem().unwrap_or_default().to_os_string();
+                name.push(format!(".{}", region.ident()));
+                if let Some(ext) = path.extension() {
+                    name.push(".");
+                    name.push(ext);
+                }
+                (
+                    path.with_file_name(name),
+                    self.write(std::slice::from_ref(region)),
+                )
+            })
+            .collect()
+    }
+
+    /// Write `regions` into one file, formats without addresses simply concatenate them
+    pub fn write(&self, regions: &[Region]) -> Vec<u8> {
+        match self {
+            Format::Binary => regions.iter().flat_map(|r| r.data.clone()).collect(),
+            Format::IntelHex => intel_hex(regions).into_bytes(),
+            Format::Readmemh => readmem(regions, |w| format!("{:08x}", w)).into_bytes(),
+            Format::Readmemb => readmem(regions, |w| format!("{:032b}", w)).into_bytes(),
+            Format::Logisim => logisim(regions).into_bytes(),
+            Format::Coe => coe(regions).into_bytes(),
+            Format::Mif => mif(regions).into_bytes(),
+            Format::C => c_array(regions).into_bytes(),
+            Format::Rust => rust_array(regions).into_bytes(),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Format::ALL
+            .into_iter()
+            .find(|f| f.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown format '{}', expected one of {}",
+                    s,
+                    Format::ALL.iter().join(", ")
+                )
+            })
+    }
+}
+
+fn words(regions: &[Region]) -> Vec<u32> {
+    regions.iter().flat_map(|r| r.words()).collect()
+}
+
+/// 16 bytes per data record, with an extended linear address record whenever
+/// the upper half of the address changes
+fn intel_hex(regions: &[Region]) -> String {
+    fn record(kind: u8, addr: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+        bytes.extend(data);
+        let checksum = bytes
+            .iter()
+            .fold(0u8, |acc, b| acc.wrapping_add(*b))
+            .wrapping_neg();
+        bytes.push(checksum);
+        format!(":{}\n", bytes.iter().map(|b| format!("{:02X}", b)).join(""))
+    }
+
+    let mut out = String::new();
+    let mut upper = 0;
+    for region in regions {
+        for (i, chunk) in region.data.chunks(16).enumerate() {
+            let addr = region.addr.wrapping_add(i as u32 * 16);
+            if addr >> 16 != upper {
+                upper = addr >> 16;
+                out += &record(0x04, 0, &(upper as u16).to_be_bytes());
+            }
+            out += &record(0x00, addr as u16, chunk);
+        }
+    }
+    out + &record(0x01, 0, &[])
+}
+
+/// `@` sets the word address, only needed where a region doesn't follow the previous one
+fn readmem(regions: &[Region], word: fn(u32) -> String) -> String {
+    let mut out = String::new();
+    let mut next = 0;
+    for region in regions {
+        if region.addr / 4 != next {
+            out += &format!("@{:08x}\n", region.addr / 4);
+        }
+        for w in region.words() {
+            out += &word(w);
+            out += "\n";
+        }
+        next = region.addr / 4 + region.words().len() as u32;
+    }
+    out
+}
+
+fn logisim(regions: &[Region]) -> String {
+    let mut out = "v3.0 hex words plain\n".to_string();
+    for line in words(regions).chunks(8) {
+        out += &line.iter().map(|w| format!("{:08x}", w)).join(" ");
+        out += "\n";
+    }
+    out
+}
+
+fn coe(regions: &[Region]) -> String {
+    format!(
+        "memory_initialization_radix=16;\nmemory_initialization_vector=\n{};\n",
+        words(regions)
+            .iter()
+            .map(|w| format!("{:08x}", w))
+            .join(",\n")
+    )
+}
+
+fn mif(regions: &[Region]) -> String {
+    let words = words(regions);
+    let mut out = format!(
+        "DEPTH = {};\nWIDTH = 32;\nADDRESS_RADIX = HEX;\nDATA_RADIX = HEX;\nCONTENT\nBEGIN\n",
+        words.len()
+    );
+    for (i, w) in words.iter().enumerate() {
+        out += &format!("{:x} : {:08x};\n", i, w);
+    }
+    out + "END;\n"
+}
+
+fn c_array(regions: &[Region]) -> String {
+    let mut out = "#include <stdint.h>\n".to_string();
+    for region in regions {
+        let words = region.words();
+        out += &format!(
+            "\n#define {0}_BASE 0x{1:08x}u\nstatic const uint32_t {2}[{3}] = {{\n{4}}};\n",
+            region.ident().to_uppercase(),
+            region.addr,
+            region.ident(),
+            words.len(),
+            array_body(&words)
+        );
+    }
+    out
+}
+
+fn rust_array(regions: &[Region]) -> String {
+    regions
+        .iter()
+        .map(|region| {
+            let words = region.words();
+            format!(
+                "pub const {0}_BASE: u32 = 0x{1:08x};\npub const {0}: [u32; {2}] = [\n{3}];\n",
+                region.ident().to_uppercase(),
+                region.addr,
+                words.len(),
+                array_body(&words)
+            )
+        })
+        .join("\n")
+}
+
+fn array_body(words: &[u32]) -> String {
+    words
+        .chunks(4)
+        .map(|line| {
+            format!(
+                "    {},\n",
+                line.iter().map(|w| format!("0x{:08x}", w)).join(", ")
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn output_formats() {
+        use crate::output::{Format, Region};
+
+        let regions = [
+            Region {
+                name: ".text".to_string(),
+                addr: 0,
+                // nop
+                data: 0b00000000000000000000000000010011u32.to_le_bytes().to_vec(),
+            },
+            Region {
+                name: ".data".to_string(),
+                addr: 0x10000000,
+                data: vec![1, 2, 3],
+            },
+        ];
+
+        assert_eq!(
+            String::from_utf8(Format::Readmemh.write(&regions)).unwrap(),
+            "00000013\n@04000000\n00030201\n"
+        );
+        assert_eq!(
+            String::from_utf8(Format::IntelHex.write(&regions)).unwrap(),
+            ":0400000013000000E9\n:020000041000EA\n:03000000010203F7\n:00000001FF\n"
+        );
+
+        let outputs = Format::Coe.outputs(std::path::Path::new("out.coe"), &regions);
+        assert_eq!(outputs[1].0, std::path::Path::new("out.data.coe"));
+        assert_eq!(
+            String::from_utf8(outputs[1].1.clone()).unwrap(),
+            "memory_initialization_radix=16;\nmemory_initialization_vector=\n00030201;\n"
+        );
+    }
+}
@@ -1,4 +1,3 @@
-/// TODO: Strings, Symbols
 use crate::{env::Env, err::SyntaxErr};
 use itertools::Itertools;
 
@@ -14,6 +13,8 @@ pub enum Token {
     Register(String),
     /// add, xor, j
     Op(String, Vec<(Token, Loc)>),
+    /// .text, .word, .globl
+    Directive(String, Vec<(Token, Loc)>),
     /// \<label>:
     Label(String),
     /// 0(a0)
@@ -22,6 +23,8 @@ pub enum Token {
     Symbol(String),
     /// "string"
     String(String),
+    /// %hi(symbol), %lo(symbol)
+    Modifier(String, String),
 
     /// Error token
     Error(ParseErr),
@@ -35,15 +38,24 @@ impl Token {
             Immediate(_) => "immediate",
             Register(_) => "register",
             Op(_, _) => "op",
+            Directive(_, _) => "directive",
             Label(_) => "label",
             Memory(_, _) => "memory",
             Symbol(_) => "symbol",
             String(_) => "string",
+            Modifier(_, _) => "modifier",
             Error(_) => "error",
         }
     }
 }
 
+/// Directives understood by the assembler
+pub const DIRECTIVES: &[&str] = &[
+    ".text", ".data", ".rodata", ".bss", ".sdata", ".sbss", ".section", ".globl", ".global",
+    ".word", ".half", ".short", ".byte", ".string", ".asciz", ".ascii", ".space", ".zero",
+    ".align", ".p2align", ".balign",
+];
+
 type ParseErr = (SyntaxErr, Loc, Vec<(Token, Loc)>, Option<String>);
 
 #[derive(Debug, Clone, Copy)]
@@ -73,7 +85,7 @@ fn parse_line(env: &Env, input: &str, loc: &mut Loc) -> Result<Vec<(Token, Loc)>
 
     while let Some(c) = chars.next() {
         let token = match c {
-            '\t' | ' ' => Spacing,
+            '\t' | ' ' | ',' => Spacing,
 
             '#' => {
                 while let Some(_) = chars.peek() {
@@ -91,7 +103,7 @@ fn parse_line(env: &Env, input: &str, loc: &mut Loc) -> Result<Vec<(Token, Loc)>
                     num.push(chars.next().unwrap());
                     loc.end += 1;
                 }
-                if let Some('(') | Some(' ') | None = chars.peek() {
+                if let Some('(') | Some(' ') | Some('\t') | Some(',') | None = chars.peek() {
                     Immediate(u32::from_str_radix(&num, 16).unwrap())
                 } else {
                     let err = Err((
@@ -116,7 +128,7 @@ fn parse_line(env: &Env, input: &str, loc: &mut Loc) -> Result<Vec<(Token, Loc)>
                     num.push(chars.next().unwrap());
                     loc.end += 1;
                 }
-                if let Some('(') | Some(' ') | None = chars.peek() {
+                if let Some('(') | Some(' ') | Some('\t') | Some(',') | None = chars.peek() {
                     Immediate(u32::from_str_radix(&num, 2).unwrap())
                 } else {
                     let err = Err((
@@ -141,7 +153,7 @@ fn parse_line(env: &Env, input: &str, loc: &mut Loc) -> Result<Vec<(Token, Loc)>
                     num.push(chars.next().unwrap());
                     loc.end += 1;
                 }
-                if let Some('(') | Some(' ') | None = chars.peek() {
+                if let Some('(') | Some(' ') | Some('\t') | Some(',') | None = chars.peek() {
                     Immediate(u32::from_str_radix(&num, 8).unwrap())
                 } else {
                     let err = Err((
@@ -164,8 +176,8 @@ fn parse_line(env: &Env, input: &str, loc: &mut Loc) -> Result<Vec<(Token, Loc)>
                     num.push(chars.next().unwrap());
                     loc.end += 1;
                 }
-                if let Some('(') | Some(' ') | None = chars.peek() {
-                    dbg!(Immediate(num.parse().unwrap()))
+                if let Some('(') | Some(' ') | Some('\t') | Some(',') | None = chars.peek() {
+                    Immediate(num.parse().unwrap())
                 } else {
                     let err = Err((
                         SyntaxErr::UnexpectedChar,
@@ -193,15 +205,20 @@ fn parse_line(env: &Env, input: &str, loc: &mut Loc) -> Result<Vec<(Token, Loc)>
                 let start = loc.start + 2;
 
                 let imm;
-                if let Some((Immediate(_), _)) = tokens.last() {
+                if let Some((Immediate(_), _)) | Some((Modifier(_, _), _)) = tokens.last() {
                     imm = Box::new(tokens.pop().unwrap());
                     loc.start = imm.1.start;
+                } else if let Some((Spacing, _)) = tokens.last() {
+                    // `(reg)` is `0(reg)`, as the atomics are written
+                    imm = Box::new((Immediate(0), *loc));
                 } else {
                     let err = Err((
                         SyntaxErr::UnexpectedChar,
                         loc.clone(),
                         tokens.clone(),
-                        Some("a memory index must be of the form imm(reg) or imm".to_string()),
+                        Some(
+                            "a memory index must be of the form imm(reg), (reg) or imm".to_string(),
+                        ),
                     ));
                     advance_to_next_line(&mut chars, loc);
                     return err;
@@ -241,7 +258,8 @@ fn parse_line(env: &Env, input: &str, loc: &mut Loc) -> Result<Vec<(Token, Loc)>
                     advance_to_next_line(&mut chars, loc);
                     return err;
                 }
-                loc.end += 2;
+                // The `)`
+                loc.end += 1;
 
                 Memory(
                     Box::new(imm.0),
@@ -259,8 +277,95 @@ fn parse_line(env: &Env, input: &str, loc: &mut Loc) -> Result<Vec<(Token, Loc)>
                 return err;
             }
 
-            // Opcode or Label definition
-            'a'..='z' | 'A'..='Z' | '_' => {
+            '"' => {
+                let mut str = std::string::String::new();
+                loop {
+                    loc.end += 1;
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            loc.end += 1;
+                            str.push(match chars.next() {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some('0') => '\0',
+                                Some(c) => c,
+                                None => '\\',
+                            });
+                        }
+                        Some(c) => str.push(c),
+                        None => {
+                            let err = Err((
+                                SyntaxErr::UnterminatedString,
+                                loc.clone(),
+                                tokens.clone(),
+                                None,
+                            ));
+                            advance_to_next_line(&mut chars, loc);
+                            return err;
+                        }
+                    }
+                }
+                String(str)
+            }
+            '%' => {
+                let mut name = std::string::String::new();
+                while let Some('a'..='z') | Some('_') = chars.peek() {
+                    name.push(chars.next().unwrap());
+                    loc.end += 1;
+                }
+                if name != "hi" && name != "lo" {
+                    let err = Err((
+                        SyntaxErr::InvalidModifier(name),
+                        loc.clone(),
+                        tokens.clone(),
+                        None,
+                    ));
+                    advance_to_next_line(&mut chars, loc);
+                    return err;
+                }
+                if chars.next() != Some('(') {
+                    let err = Err((
+                        SyntaxErr::UnexpectedChar,
+                        Loc {
+                            start: loc.end + 1,
+                            end: loc.end + 1,
+                            ..*loc
+                        },
+                        tokens.clone(),
+                        Some(
+                            "a modifier must be of the form %hi(symbol) or %lo(symbol)".to_string(),
+                        ),
+                    ));
+                    advance_to_next_line(&mut chars, loc);
+                    return err;
+                }
+                loc.end += 1;
+
+                let mut symbol = std::string::String::new();
+                while let Some('a'..='z') | Some('A'..='Z') | Some('_') | Some('0'..='9')
+                | Some('.') | Some('$') = chars.peek()
+                {
+                    symbol.push(chars.next().unwrap());
+                    loc.end += 1;
+                }
+                if chars.next() != Some(')') {
+                    let err = Err((
+                        SyntaxErr::UnmatchedParen(false),
+                        loc.clone(),
+                        tokens.clone(),
+                        None,
+                    ));
+                    advance_to_next_line(&mut chars, loc);
+                    return err;
+                }
+                loc.end += 1;
+
+                Modifier(name, symbol)
+            }
+
+            // Opcode, Directive or Label definition
+            'a'..='z' | 'A'..='Z' | '_' | '.' => {
                 let mut str = c.to_string();
                 while let Some('a'..='z') | Some('A'..='Z') | Some('_') | Some('0'..='9')
                 | Some('.') = chars.peek()
@@ -296,7 +401,10 @@ fn parse_line(env: &Env, input: &str, loc: &mut Loc) -> Result<Vec<(Token, Loc)>
         .into_iter()
         .filter(|(token, _)| !matches!(token, Token::Spacing))
         .group_by(|(token, _)| {
-            matches!(token, Immediate(_) | Register(_) | Memory(_, _) | Symbol(_))
+            matches!(
+                token,
+                Immediate(_) | Register(_) | Memory(_, _) | Symbol(_) | String(_) | Modifier(_, _)
+            )
         })
         .into_iter()
         .flat_map(|group| {
@@ -344,7 +452,22 @@ fn parse_line(env: &Env, input: &str, loc: &mut Loc) -> Result<Vec<(Token, Loc)>
                     }
                 }
 
-                vec![(Op(name, args), loc)]
+                if name.starts_with('.') {
+                    if !DIRECTIVES.contains(&name.as_str()) {
+                        return vec![(
+                            Token::Error((
+                                SyntaxErr::UnknownDirective(name),
+                                loc,
+                                group.clone(),
+                                None,
+                            )),
+                            loc,
+                        )];
+                    }
+                    vec![(Directive(name, args), loc)]
+                } else {
+                    vec![(Op(name, args), loc)]
+                }
             } else {
                 group.collect::<Vec<_>>()
             }
@@ -0,0 +1,406 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use itertools::Itertools;
+
+use crate::{
+    decode::{Decoded, Op},
+    disasm,
+};
+
+pub const STAGES: [&str; 5] = ["IF", "ID", "EX", "MEM", "WB"];
+
+/// Instructions kept for diagrams and stepping back
+const KEPT: usize = 1000;
+
+/// Where a result can get to the EX stage of a later instruction from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Forwarding {
+    /// Only through the register file, written in the first half of WB and
+    /// read in the second half of ID
+    None,
+    /// From the MEM/WB pipeline register
+    Wb,
+    /// From EX/MEM and MEM/WB, so only a load used right away stalls
+    #[default]
+    Full,
+}
+
+impl FromStr for Forwarding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Forwarding::None),
+            "wb" => Ok(Forwarding::Wb),
+            "full" => Ok(Forwarding::Full),
+            _ => Err(format!(
+                "unknown forwarding '{}', expected none, wb or full",
+                s
+            )),
+        }
+    }
+}
+
+/// One instruction's way through the pipeline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timing {
+    pub pc: u32,
+    pub word: u32,
+    /// Cycle it went into each of `STAGES`
+    pub stages: [u64; 5],
+    /// Cycles it waited in ID for an operand
+    pub stalls: u64,
+    /// Whether the operand it waited for came from a load
+    pub load_use: bool,
+    /// Whether it was a taken branch or jump, or trapped, flushing the two
+    /// instructions fetched after it
+    pub flush: bool,
+    /// The register it writes and what was in `Pipeline::ready` for it before
+    written: Option<(usize, (u64, bool))>,
+}
+
+impl Timing {
+    /// The stage it's in during `cycle`, and whether it is stalled there
+    pub fn stage_at(&self, cycle: u64) -> Option<(usize, bool)> {
+        let left = |s: usize| {
+            self.stages
+                .get(s + 1)
+                .copied()
+                .unwrap_or(self.stages[4] + 1)
+        };
+        (0..5)
+            .find(|&s| self.stages[s] <= cycle && cycle < left(s))
+            .map(|s| (s, cycle != self.stages[s]))
+    }
+
+    fn text(&self) -> String {
+        disasm::Op::decode(self.pc, self.word).map_or_else(
+            || format!("{:#010x}", self.word),
+            |op| op.format(self.pc, &HashMap::new()),
+        )
+    }
+
+    /// What held it up, if anything
+    fn hazards(&self) -> String {
+        let mut hazards = Vec::new();
+        if self.stalls > 0 {
+            let kind = if self.load_use { "load-use" } else { "data" };
+            hazards.push(format!("{} stall {}", kind, self.stalls));
+        }
+        if self.flush {
+            hazards.push("flush 2".to_string());
+        }
+        hazards.join(", ")
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub instructions: u64,
+    pub cycles: u64,
+    /// Cycles instructions waited in ID for operands
+    pub stalls: u64,
+    /// Of those, waiting for a load
+    pub load_use: u64,
+    /// Taken branches, jumps and traps, each throwing away two instructions
+    pub flushes: u64,
+}
+
+impl Stats {
+    /// Cycles per instruction
+    pub fn cpi(&self) -> f64 {
+        self.cycles as f64 / self.instructions.max(1) as f64
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} cycles, {} instructions, CPI {:.2}, {} stall cycles ({} load-use), {} flushes",
+            self.cycles,
+            self.instructions,
+            self.cpi(),
+            self.stalls,
+            self.load_use,
+            self.flushes
+        )
+    }
+}
+
+/// Whether `op` gets its result in MEM rather than EX
+fn loads(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Lb
+            | Op::Lh
+            | Op::Lw
+            | Op::Lbu
+            | Op::Lhu
+            | Op::LrW
+            | Op::ScW
+            | Op::AmoswapW
+            | Op::AmoaddW
+            | Op::AmoxorW
+            | Op::AmoandW
+            | Op::AmoorW
+            | Op::AmominW
+            | Op::AmomaxW
+            | Op::AmominuW
+            | Op::AmomaxuW
+    )
+}
+
+/// Registers `decoded` reads and the one it writes, float registers counting
+/// from 32
+fn registers(decoded: &Decoded) -> (Vec<usize>, Option<usize>) {
+    let (rd, ra, rb, rc) = (
+        decoded.rd as usize,
+        decoded.ra as usize,
+        decoded.rb as usize,
+        decoded.rc as usize,
+    );
+    match decoded.op {
+        Op::Lui | Op::Auipc | Op::Jal | Op::Csrrwi | Op::Csrrsi | Op::Csrrci => (vec![], Some(rd)),
+        Op::Addi
+        | Op::Andi
+        | Op::Ori
+        | Op::Xori
+        | Op::Slti
+        | Op::Sltiu
+        | Op::Slli
+        | Op::Srli
+        | Op::Srai
+        | Op::Lb
+        | Op::Lh
+        | Op::Lw
+        | Op::Lbu
+        | Op::Lhu
+        | Op::Jalr
+        | Op::LrW
+        | Op::Csrrw
+        | Op::Csrrs
+        | Op::Csrrc => (vec![ra], Some(rd)),
+        Op::Sb | Op::Sh | Op::Sw | Op::Beq | Op::Bne | Op::Blt | Op::Bge | Op::Bltu | Op::Bgeu => {
+            (vec![ra, rb], None)
+        }
+        Op::Ecall | Op::Ebreak | Op::Fence | Op::Mret | Op::Wfi | Op::Unknown => (vec![], None),
+        Op::FaddS | Op::FdivS => (vec![32 + ra, 32 + rb], Some(32 + rd)),
+        Op::FmaddS => (vec![32 + ra, 32 + rb, 32 + rc], Some(32 + rd)),
+        Op::FcvtSW | Op::FmvWX => (vec![ra], Some(32 + rd)),
+        // Everything else is register-register
+        _ => (vec![ra, rb], Some(rd)),
+    }
+}
+
+/// A classic in-order IF/ID/EX/MEM/WB pipeline, timing the instructions the
+/// simulator runs as it goes.
+///
+/// Branches and jumps are predicted not taken and resolved in EX. Instructions
+/// wait in ID until their operands can be forwarded, see `Forwarding`.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    pub forwarding: Forwarding,
+    /// The last `KEPT` instructions, oldest first
+    timings: VecDeque<Timing>,
+    /// First cycle EX can have each register's value, and whether it's coming
+    /// from a load. Float registers count from 32
+    ready: [(u64, bool); 64],
+    pub stats: Stats,
+}
+
+impl Pipeline {
+    pub fn new(forwarding: Forwarding) -> Self {
+        Self {
+            forwarding,
+            timings: VecDeque::new(),
+            ready: [(0, false); 64],
+            stats: Stats::default(),
+        }
+    }
+
+    /// Time `decoded`, which ran at `pc` and went on to `next`
+    pub fn issue(&mut self, pc: u32, decoded: &Decoded, next: u32) {
+        let prev = self.timings.back().map(|t| (t.stages, t.flush));
+        let after = |stage: usize| prev.map_or(0, |(stages, _)| stages[stage]);
+
+        let mut stages = [0; 5];
+        // A flush fetches again once the branch is through EX
+        stages[0] = match prev {
+            Some((prev, true)) => prev[2] + 1,
+            Some((prev, false)) => prev[1],
+            None => 0,
+        };
+        stages[1] = (stages[0] + 1).max(after(2));
+        let (sources, dest) = registers(decoded);
+        let unstalled = (stages[1] + 1).max(after(3));
+        let (operands, load_use) = sources
+            .iter()
+            .filter(|&&r| r != 0)
+            .map(|&r| self.ready[r])
+            .max()
+            .unwrap_or((0, false));
+        stages[2] = unstalled.max(operands);
+        stages[3] = (stages[2] + 1).max(after(4));
+        stages[4] = (stages[3] + 1).max(prev.map_or(0, |_| after(4) + 1));
+
+        let stalls = stages[2] - unstalled;
+        let load = loads(decoded.op);
+        let written = dest.filter(|&r| r != 0).map(|r| {
+            let ready = match self.forwarding {
+                Forwarding::Full if load => stages[3] + 1,
+                Forwarding::Full => stages[2] + 1,
+                Forwarding::Wb => stages[4],
+                Forwarding::None => stages[4] + 1,
+            };
+            (r, std::mem::replace(&mut self.ready[r], (ready, load)))
+        });
+        let timing = Timing {
+            pc,
+            word: decoded.word,
+            stages,
+            stalls,
+            load_use: stalls > 0 && load_use,
+            flush: next != pc.wrapping_add(4),
+            written,
+        };
+
+        self.stats.instructions += 1;
+        self.stats.cycles = stages[4] + 1;
+        self.stats.stalls += timing.stalls;
+        self.stats.load_use += timing.load_use as u64 * timing.stalls;
+        self.stats.flushes += timing.flush as u64;
+        if self.timings.len() == KEPT {
+            self.timings.pop_front();
+        }
+        self.timings.push_back(timing);
+    }
+
+    /// Forget the last instruction, for stepping back. Only the last `KEPT`
+    /// can be
+    pub fn back(&mut self) {
+        let Some(timing) = self.timings.pop_back() else {
+            return;
+        };
+        if let Some((r, ready)) = timing.written {
+            self.ready[r] = ready;
+        }
+        self.stats.instructions -= 1;
+        self.stats.cycles = self.timings.back().map_or(0, |t| t.stages[4] + 1);
+        self.stats.stalls -= timing.stalls;
+        self.stats.load_use -= timing.load_use as u64 * timing.stalls;
+        self.stats.flushes -= timing.flush as u64;
+    }
+
+    /// The last `rows` instructions, oldest first
+    pub fn timings(&self, rows: usize) -> impl Iterator<Item = &Timing> {
+        self.timings
+            .iter()
+            .skip(self.timings.len().saturating_sub(rows))
+    }
+
+    /// The last `rows` instructions as a diagram of the stage each is in every
+    /// cycle, `--` while it's stalled there
+    pub fn diagram(&self, rows: usize) -> String {
+        let timings = self.timings(rows).collect::<Vec<_>>();
+        let (Some(first), Some(last)) = (timings.first(), timings.last()) else {
+            return String::new();
+        };
+        let cycles = first.stages[0]..=last.stages[4];
+        let texts = timings.iter().map(|t| t.text()).collect::<Vec<_>>();
+        let width = texts.iter().map(String::len).max().unwrap_or(0);
+
+        let header = cycles.clone().map(|c| format!("{:<4}", c)).join("");
+        let mut out = format!("{:>8}  {:width$}  {}\n", "", "cycle", header.trim_end());
+        for (timing, text) in timings.iter().zip(texts) {
+            let cells = cycles
+                .clone()
+                .map(|c| match timing.stage_at(c) {
+                    Some((_, true)) => "--  ".to_string(),
+                    Some((s, false)) => format!("{:<4}", STAGES[s]),
+                    None => "    ".to_string(),
+                })
+                .join("");
+            let line = format!(
+                "{:08x}  {:width$}  {}  {}",
+                timing.pc,
+                text,
+                cells,
+                timing.hazards()
+            );
+            out += line.trim_end();
+            out.push('\n');
+        }
+        out
+    }
+
+    /// The instructions kept, a row each with the cycle it went into every stage
+    pub fn csv(&self) -> String {
+        let mut out = "pc,instruction,if,id,ex,mem,wb,stalls,load_use,flush\n".to_string();
+        for timing in self.timings.iter() {
+            out += &format!(
+                "{:#010x},\"{}\",{},{},{},{}\n",
+                timing.pc,
+                timing.text(),
+                timing.stages.iter().join(","),
+                timing.stalls,
+                timing.load_use,
+                timing.flush
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{env::Env, tests::load_program};
+
+    #[test]
+    fn pipeline() {
+        use super::{Forwarding, Pipeline, Stats};
+        use crate::execution::step;
+
+        let timed = |forwarding: Forwarding| {
+            let mut env = Env::new();
+            let input =
+                "li t0 8\nloop:\nlw a0 0(sp)\nadd a1 a1 a0\naddi t0 t0 -4\nbnez t0 loop\nnop";
+            load_program(&mut env, input);
+            env.pipeline = Some(Pipeline::new(forwarding));
+            let mut stats = Vec::new();
+            while env.memory.read_u32(env.pc) != 0 {
+                step(&mut env).unwrap();
+                stats.push(env.pipeline.as_ref().unwrap().stats);
+            }
+            (env.pipeline.unwrap(), stats)
+        };
+
+        // The add waits a cycle for each load, and the taken branch flushes two
+        let (mut pipeline, stats) = timed(Forwarding::Full);
+        assert_eq!(
+            pipeline.stats,
+            Stats {
+                instructions: 10,
+                cycles: 18,
+                stalls: 2,
+                load_use: 2,
+                flushes: 1,
+            }
+        );
+        let add = pipeline.timings(10).nth(2).unwrap();
+        assert_eq!(add.stages, [2, 3, 5, 6, 7]);
+        assert!(pipeline.diagram(4).contains("ID  --  EX"));
+
+        pipeline.back();
+        assert_eq!(pipeline.stats, stats[8]);
+
+        // Without forwarding every dependent instruction waits for write back
+        let (pipeline, _) = timed(Forwarding::None);
+        assert_eq!(pipeline.stats.stalls, 8);
+        assert_eq!(pipeline.stats.cycles, 24);
+    }
+}
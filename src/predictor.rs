@@ -0,0 +1,336 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+/// How a `Predictor` guesses which way a branch goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Never taken
+    NotTaken,
+    /// Backward taken, forward not taken, like a loop
+    Btfn,
+    /// Whatever the branch did last time
+    OneBit,
+    /// Saturating counters per branch, so one odd outcome doesn't flip them
+    TwoBit,
+    /// Counters indexed by the branch address xor the global history
+    Gshare,
+    /// Two-bit and gshare, with counters per branch choosing between them
+    Tournament,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "not-taken" => Ok(Kind::NotTaken),
+            "btfn" => Ok(Kind::Btfn),
+            "1bit" => Ok(Kind::OneBit),
+            "2bit" => Ok(Kind::TwoBit),
+            "gshare" => Ok(Kind::Gshare),
+            "tournament" => Ok(Kind::Tournament),
+            _ => Err(format!(
+                "unknown predictor '{}', expected not-taken, btfn, 1bit, 2bit, gshare or tournament",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Kind::NotTaken => "not-taken",
+            Kind::Btfn => "btfn",
+            Kind::OneBit => "1bit",
+            Kind::TwoBit => "2bit",
+            Kind::Gshare => "gshare",
+            Kind::Tournament => "tournament",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A predictor and the entries in each of its tables, parsed from
+/// `KIND[,ENTRIES]` like `gshare,4096`. 1024 entries by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PredictorConfig {
+    pub kind: Kind,
+    /// A power of two
+    pub entries: u32,
+}
+
+impl FromStr for PredictorConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, entries) = s.split_once(',').unwrap_or((s, "1024"));
+        let entries = entries
+            .trim()
+            .parse()
+            .ok()
+            .filter(|n: &u32| n.is_power_of_two())
+            .ok_or(format!("entries '{}' isn't a power of two", entries))?;
+        Ok(Self {
+            kind: kind.trim().parse()?,
+            entries,
+        })
+    }
+}
+
+/// What one branch did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Site {
+    pub executed: u64,
+    pub taken: u64,
+    /// Times it went where it was predicted to, target included with a BTB
+    pub correct: u64,
+}
+
+impl Site {
+    pub fn accuracy(&self) -> f64 {
+        self.correct as f64 * 100.0 / self.executed.max(1) as f64
+    }
+}
+
+/// Branch target buffer, direct-mapped. A branch predicted taken only goes
+/// the right way straight away if its target is in here
+#[derive(Debug, Clone)]
+pub struct Btb {
+    entries: Vec<Option<(u32, u32)>>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Btb {
+    pub fn new(entries: u32) -> Self {
+        Self {
+            entries: vec![None; entries as usize],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        (pc as usize >> 2) & (self.entries.len() - 1)
+    }
+
+    /// The target the branch at `pc` went to last time it was taken, if it's
+    /// still here
+    fn lookup(&mut self, pc: u32) -> Option<u32> {
+        let target = match self.entries[self.index(pc)] {
+            Some((at, target)) if at == pc => Some(target),
+            _ => None,
+        };
+        match target {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        target
+    }
+
+    fn insert(&mut self, pc: u32, target: u32) {
+        let index = self.index(pc);
+        self.entries[index] = Some((pc, target));
+    }
+}
+
+/// Bump a two-bit saturating counter towards `taken`
+fn train(counter: &mut u8, taken: bool) {
+    *counter = if taken {
+        (*counter + 1).min(3)
+    } else {
+        counter.saturating_sub(1)
+    };
+}
+
+/// Predicts the conditional branches the program runs, and keeps score per
+/// branch.
+///
+/// Counters start weakly not taken.
+#[derive(Debug, Clone)]
+pub struct Predictor {
+    pub config: PredictorConfig,
+    /// Per branch: last outcome for 1bit, two-bit counters for 2bit and the
+    /// tournament
+    local: Vec<u8>,
+    /// Two-bit counters for gshare and the tournament
+    global: Vec<u8>,
+    /// Two-bit counters per branch for the tournament, high for gshare
+    chooser: Vec<u8>,
+    /// Outcomes of the last branches, newest in bit 0
+    history: u32,
+    pub btb: Option<Btb>,
+    /// By address of the branch
+    pub sites: BTreeMap<u32, Site>,
+}
+
+impl Predictor {
+    pub fn new(config: PredictorConfig, btb: Option<u32>) -> Self {
+        let entries = config.entries as usize;
+        let initial = match config.kind {
+            Kind::OneBit => 0,
+            _ => 1,
+        };
+        Self {
+            config,
+            local: vec![initial; entries],
+            global: vec![1; entries],
+            chooser: vec![1; entries],
+            history: 0,
+            btb: btb.map(Btb::new),
+            sites: BTreeMap::new(),
+        }
+    }
+
+    fn local_index(&self, pc: u32) -> usize {
+        (pc as usize >> 2) & (self.config.entries as usize - 1)
+    }
+
+    fn global_index(&self, pc: u32) -> usize {
+        ((pc >> 2) ^ self.history) as usize & (self.config.entries as usize - 1)
+    }
+
+    /// Which way the branch at `pc` to `target` is predicted to go
+    pub fn predict(&self, pc: u32, target: u32) -> bool {
+        let local = self.local[self.local_index(pc)];
+        let global = self.global[self.global_index(pc)];
+        match self.config.kind {
+            Kind::NotTaken => false,
+            Kind::Btfn => target <= pc,
+            Kind::OneBit => local == 1,
+            Kind::TwoBit => local >= 2,
+            Kind::Gshare => global >= 2,
+            Kind::Tournament if self.chooser[self.local_index(pc)] >= 2 => global >= 2,
+            Kind::Tournament => local >= 2,
+        }
+    }
+
+    /// Score the prediction for the branch at `pc` to `target`, which was
+    /// `taken` or not, and learn from it
+    pub fn update(&mut self, pc: u32, target: u32, taken: bool) {
+        let guess = self.predict(pc, target);
+        let mut correct = guess == taken;
+        if let Some(btb) = &mut self.btb {
+            let cached = btb.lookup(pc);
+            if guess && taken {
+                correct = cached == Some(target);
+            }
+            if taken {
+                btb.insert(pc, target);
+            }
+        }
+
+        let (local, global) = (self.local_index(pc), self.global_index(pc));
+        match self.config.kind {
+            Kind::NotTaken | Kind::Btfn => {}
+            Kind::OneBit => self.local[local] = taken as u8,
+            Kind::TwoBit => train(&mut self.local[local], taken),
+            Kind::Gshare => train(&mut self.global[global], taken),
+            Kind::Tournament => {
+                let bimodal = (self.local[local] >= 2) == taken;
+                let gshare = (self.global[global] >= 2) == taken;
+                if bimodal != gshare {
+                    train(&mut self.chooser[local], gshare);
+                }
+                train(&mut self.local[local], taken);
+                train(&mut self.global[global], taken);
+            }
+        }
+        self.history = (self.history << 1 | taken as u32) & (self.config.entries - 1);
+
+        let site = self.sites.entry(pc).or_default();
+        site.executed += 1;
+        site.taken += taken as u64;
+        site.correct += correct as u64;
+    }
+
+    /// Every branch added together
+    pub fn total(&self) -> Site {
+        self.sites
+            .values()
+            .fold(Site::default(), |total, site| Site {
+                executed: total.executed + site.executed,
+                taken: total.taken + site.taken,
+                correct: total.correct + site.correct,
+            })
+    }
+
+    /// The totals then a line per branch, named by `describe`
+    pub fn report(&self, describe: impl Fn(u32) -> String) -> String {
+        let mut out = format!("{}\n", self);
+        for (&pc, site) in self.sites.iter() {
+            out += &format!(
+                "  {:#010x} {}: {} run, {} taken, {:.1}% predicted\n",
+                pc,
+                describe(pc),
+                site.executed,
+                site.taken,
+                site.accuracy()
+            );
+        }
+        out
+    }
+}
+
+impl Display for Predictor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let total = self.total();
+        write!(
+            f,
+            "{} predictor: {} branches, {} taken, {} predicted ({:.1}%)",
+            self.config.kind,
+            total.executed,
+            total.taken,
+            total.correct,
+            total.accuracy()
+        )?;
+        if let Some(btb) = &self.btb {
+            write!(f, ", BTB {} hits, {} misses", btb.hits, btb.misses)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+
+    #[test]
+    fn branch_predictors() {
+        use super::{Predictor, PredictorConfig};
+        use crate::execution::run_instruction;
+
+        let accuracy = |spec: &str, btb: Option<u32>, outcomes: &[bool]| {
+            let mut predictor = Predictor::new(spec.parse::<PredictorConfig>().unwrap(), btb);
+            for &taken in outcomes {
+                predictor.update(0x40, 0x20, taken);
+            }
+            predictor.total().accuracy().round() as u32
+        };
+        // A loop branch, taken nine times then falling through, ten times over
+        let looping = [[true; 9].as_slice(), &[false]].concat().repeat(10);
+        assert_eq!(accuracy("not-taken", None, &looping), 10);
+        assert_eq!(accuracy("btfn", None, &looping), 90);
+        assert_eq!(accuracy("1bit", None, &looping), 80);
+        assert_eq!(accuracy("2bit", None, &looping), 89);
+        assert_eq!(accuracy("2bit", Some(16), &looping), 89);
+
+        // Only history tells taking turns apart
+        let alternating = [true, false].repeat(50);
+        assert_eq!(accuracy("2bit", None, &alternating), 0);
+        assert!(accuracy("gshare,64", None, &alternating) >= 90);
+        assert!(accuracy("tournament,64", None, &alternating) >= 80);
+        assert!("gshare,100".parse::<PredictorConfig>().is_err());
+
+        // beq zero zero 8
+        let mut env = Env::new();
+        env.predictor = Some(Predictor::new("2bit".parse().unwrap(), None));
+        run_instruction(&mut env, 0x00000463).unwrap();
+        let site = env.predictor.unwrap().sites[&0];
+        assert_eq!((site.executed, site.taken, site.correct), (1, 1, 0));
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    env::Env,
+    err::{StepErr, SyscallErr},
+    syscall::{self, Console, OpenFile, A0, A1},
+};
+
+/// `slli x0, x0, 0x1f`, just before the `ebreak`
+const ENTRY: u32 = 0x01f01013;
+/// `srai x0, x0, 7`, just after it
+const EXIT: u32 = 0x40705013;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// Reason `SYS_EXIT` gives when the program finished normally
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+/// What the 12 fopen modes of `SYS_OPEN` are as `O_` flags, `b` or not
+const MODES: [u32; 6] = [
+    0,
+    syscall::O_RDWR,
+    syscall::O_WRONLY | syscall::O_CREAT | syscall::O_TRUNC,
+    syscall::O_RDWR | syscall::O_CREAT | syscall::O_TRUNC,
+    syscall::O_WRONLY | syscall::O_CREAT | syscall::O_APPEND,
+    syscall::O_RDWR | syscall::O_CREAT | syscall::O_APPEND,
+];
+
+/// Whether the `ebreak` at `pc` is a semihosting call rather than a breakpoint
+pub fn is_call(env: &Env, pc: u32) -> bool {
+    env.memory.fetch(pc.wrapping_sub(4)) == Ok(ENTRY)
+        && env.memory.fetch(pc.wrapping_add(4)) == Ok(EXIT)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Handle {
+    /// `:tt`, the console
+    Console,
+    File(OpenFile),
+}
+
+/// Semihosting calls, the operation in a0 and a pointer to its arguments in
+/// a1, made to the host the way a debugger would.
+///
+/// Files are opened on the host as named, `:tt` is the console.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Semihosting {
+    console: Console,
+    handles: BTreeMap<u32, Handle>,
+    /// What `SYS_ERRNO` gives, from the last call that failed
+    errno: i32,
+    /// When the program started, in ms since the epoch
+    start: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_millis() as u64)
+}
+
+impl Semihosting {
+    pub fn new(console: Console) -> Self {
+        Self {
+            console,
+            handles: BTreeMap::new(),
+            errno: 0,
+            start: now(),
+        }
+    }
+
+    /// Text showing what the program printed, for the stepper
+    pub fn status(&self) -> String {
+        format!("semihosting {}", self.console.status())
+    }
+
+    /// Carry out the call `env`'s registers ask for
+    pub fn call(&mut self, env: &mut Env) -> Result<(), StepErr> {
+        let block = env.get_register(A1);
+        let arg = |env: &mut Env, i: u32| env.load(block.wrapping_add(4 * i), 4);
+        let value = match env.get_register(A0) {
+            SYS_OPEN => {
+                let (name, mode, len) = (arg(env, 0)?, arg(env, 1)?, arg(env, 2)?);
+                let name = (0..len)
+                    .map(|i| env.load(name.wrapping_add(i), 1).map(|byte| byte as u8))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.open(&name, mode)
+            }
+            SYS_CLOSE => match self.handles.remove(&arg(env, 0)?) {
+                Some(_) => 0,
+                None => self.fail(syscall::EBADF),
+            },
+            SYS_WRITEC => {
+                let byte = env.load(block, 1)?;
+                self.console.print(&[byte as u8]);
+                0
+            }
+            SYS_WRITE0 => {
+                let text = syscall::read_string(env, block)?;
+                self.console.print(&text);
+                0
+            }
+            // The number of bytes it didn't write
+            SYS_WRITE => {
+                let (handle, buf, len) = (arg(env, 0)?, arg(env, 1)?, arg(env, 2)?);
+                let bytes = (0..len)
+                    .map(|i| env.load(buf.wrapping_add(i), 1).map(|byte| byte as u8))
+                    .collect::<Result<Vec<_>, _>>()?;
+                match self.handles.get_mut(&handle) {
+                    Some(Handle::Console) => {
+                        self.console.print(&bytes);
+                        0
+                    }
+                    Some(Handle::File(file)) => match file.with(|f| f.write_all(&bytes)) {
+                        Ok(()) => 0,
+                        Err(err) => {
+                            self.fail(syscall::errno(&err));
+                            len
+                        }
+                    },
+                    None => {
+                        self.fail(syscall::EBADF);
+                        len
+                    }
+                }
+            }
+            // The number of bytes it didn't read, all of them at the end of the file
+            SYS_READ => {
+                let (handle, buf, len) = (arg(env, 0)?, arg(env, 1)?, arg(env, 2)?);
+                let len = len.min(syscall::MAX_READ);
+                let bytes = match self.handles.get_mut(&handle) {
+                    Some(Handle::Console) => {
+                        let mut bytes = Vec::new();
+                        while bytes.len() < len as usize && bytes.last() != Some(&b'\n') {
+                            match self.console.read_byte() {
+                                Some(byte) => bytes.push(byte),
+                                None => break,
+                            }
+                        }
+                        bytes
+                    }
+                    Some(Handle::File(file)) => {
+                        let mut bytes = vec![0; len as usize];
+                        match file.with(|f| f.read(&mut bytes)) {
+                            Ok(read) => bytes.truncate(read),
+                            Err(err) => {
+                                self.fail(syscall::errno(&err));
+                                bytes.clear();
+                            }
+                        }
+                        bytes
+                    }
+                    None => {
+                        self.fail(syscall::EBADF);
+                        Vec::new()
+                    }
+                };
+                for (i, byte) in bytes.iter().enumerate() {
+                    env.store(buf.wrapping_add(i as u32), 1, *byte as u32)?;
+                }
+                len - bytes.len() as u32
+            }
+            // Centiseconds since the program started
+            SYS_CLOCK => (now().saturating_sub(self.start) / 10) as u32,
+            SYS_ERRNO => self.errno as u32,
+            // RV32 passes the reason itself, with no exit code
+            SYS_EXIT => {
+                env.exit = Some((block != ADP_STOPPED_APPLICATION_EXIT) as u32);
+                return Ok(());
+            }
+            SYS_EXIT_EXTENDED => {
+                let (reason, code) = (arg(env, 0)?, arg(env, 1)?);
+                env.exit = Some(if reason == ADP_STOPPED_APPLICATION_EXIT {
+                    code
+                } else {
+                    1
+                });
+                return Ok(());
+            }
+            op => return Err(SyscallErr::Unknown(op).into()),
+        };
+        env.set_register(A0, value);
+        Ok(())
+    }
+
+    fn open(&mut self, name: &[u8], mode: u32) -> u32 {
+        let handle = (1..).find(|h| !self.handles.contains_key(h)).unwrap();
+        if name == b":tt" {
+            self.handles.insert(handle, Handle::Console);
+            return handle;
+        }
+        let Some(&flags) = MODES.get(mode as usize / 2) else {
+            return self.fail(syscall::EINVAL);
+        };
+        let path = PathBuf::from(String::from_utf8_lossy(name).into_owned());
+        match OpenFile::open(path, flags) {
+            Ok(file) => {
+                self.handles.insert(handle, Handle::File(file));
+                handle
+            }
+            Err(err) => self.fail(syscall::errno(&err)),
+        }
+    }
+
+    /// Remember `errno` for `SYS_ERRNO`, returning the -1 calls fail with
+    fn fail(&mut self, errno: i32) -> u32 {
+        self.errno = errno;
+        u32::MAX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        env::Env,
+        tests::{load_program, step_to_exit},
+    };
+
+    #[test]
+    fn semihosting() {
+        use super::Semihosting;
+        use crate::{csr, memory::Permissions, syscall::Console};
+
+        let path = std::env::temp_dir().join(format!("rizz-v-semihosting-{}", std::process::id()));
+        let run = |input: &str, semihosting: bool| {
+            let mut env = Env::new();
+            env.syscalls = None;
+            if semihosting {
+                env.semihosting = Some(Semihosting::new(Console::new(false, false)));
+            }
+            env.memory.map_heap_and_stack(0x10001000);
+            env.memory.map(".text", 0, 0x1000, Permissions::RX);
+            let name = path.to_str().unwrap().as_bytes();
+            env.memory.write_bytes(0x10001800, b"hi\n\0");
+            env.memory.write_bytes(0x10001810, b"data");
+            env.memory.write_bytes(0x10001a00, name);
+            // SYS_OPEN's block: name, mode "w" and length of the name
+            env.memory.write_u32(0x10001900, 0x10001a00);
+            env.memory.write_u32(0x10001904, 4);
+            env.memory.write_u32(0x10001908, name.len() as u32);
+            // SYS_EXIT_EXTENDED's: a normal exit with code 7
+            env.memory.write_u32(0x10001920, 0x20026);
+            env.memory.write_u32(0x10001924, 7);
+            load_program(&mut env, input);
+            step_to_exit(&mut env, 100).unwrap();
+            env
+        };
+
+        // Print, write a file, close it twice and exit
+        let call = "slli x0 x0 0x1f\nebreak\nsrai x0 x0 7\n";
+        let program = format!(
+            "li a0 4\nli a1 0x10001800\n{call}\
+             li a0 1\nli a1 0x10001900\n{call}mv s2 a0\n\
+             li t0 0x10001910\nsw s2 0(t0)\nli t1 0x10001810\nsw t1 4(t0)\nli t1 4\nsw t1 8(t0)\n\
+             li a0 5\nli a1 0x10001910\n{call}mv s3 a0\n\
+             li a0 2\nli a1 0x10001910\n{call}mv s4 a0\n\
+             li a0 2\nli a1 0x10001910\n{call}\
+             li a0 0x13\n{call}mv s5 a0\n\
+             li a0 0x20\nli a1 0x10001920\n{call}"
+        );
+        let env = run(&program, true);
+        let written = std::fs::read(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(written.unwrap(), b"data");
+        assert_eq!(env.exit, Some(7));
+        assert_eq!(env.get_register(18), 1);
+        assert_eq!(env.get_register(19), 0);
+        assert_eq!(env.get_register(20), 0);
+        assert_eq!(env.get_register(21), 9);
+        assert_eq!(
+            env.semihosting.unwrap().status(),
+            "semihosting console: hi".to_string()
+        );
+
+        // Otherwise it's a breakpoint
+        let env = run(
+            &format!("la t0 handler\ncsrw mtvec t0\n{call}handler:\nj handler"),
+            false,
+        );
+        assert_eq!(env.csrs.mcause, csr::BREAKPOINT);
+        assert_eq!(env.csrs.mepc, 16);
+        assert_eq!(env.pc, 24);
+    }
+}
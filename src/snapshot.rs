@@ -0,0 +1,210 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    csr::Csrs,
+    device::Devices,
+    env::{Env, Section},
+    err::SnapshotErr,
+    hart::Harts,
+    memory::Memory,
+    srcmap::SourceMap,
+    syscall,
+};
+
+/// Version of the snapshot format, bumped whenever what's saved changes
+pub const VERSION: u32 = 3;
+
+/// A whole machine, saved to carry on running it from the same point later.
+///
+/// Saved as JSON, with the memory as hex. Devices and syscalls are recreated
+/// by name, so only the built-in ones can be saved, and come back without the
+/// terminal they were drawing on.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    pub registers: [u32; 32],
+    #[serde(with = "fregisters")]
+    pub fregisters: [f32; 32],
+    pub pc: u32,
+    pub csrs: Csrs,
+    pub memory: Memory,
+    pub devices: Vec<(String, Value)>,
+    /// Name and state of the syscalls, if there are any
+    pub syscalls: Option<(String, Value)>,
+    /// State of semihosting, if it's on
+    pub semihosting: Option<Value>,
+    pub reservations: HashMap<u32, u32>,
+    pub exit: Option<u32>,
+    pub text_base: u32,
+    pub data_base: u32,
+    pub labels: HashMap<String, u32>,
+    pub label_sections: HashMap<String, Section>,
+    pub globals: HashSet<String>,
+    pub source_map: SourceMap,
+    /// The other harts, when the program runs on more than one
+    pub harts: Option<Harts>,
+}
+
+impl Snapshot {
+    pub fn new(env: &Env, harts: Option<&Harts>) -> Self {
+        Self {
+            version: VERSION,
+            registers: env.registers,
+            fregisters: env.fregisters,
+            pc: env.pc,
+            csrs: env.csrs.clone(),
+            memory: env.memory.clone(),
+            devices: env.devices.save(),
+            syscalls: env
+                .syscalls
+                .as_ref()
+                .map(|s| (s.name().to_string(), s.save())),
+            semihosting: env
+                .semihosting
+                .as_ref()
+                .map(|s| serde_json::to_value(s).unwrap()),
+            reservations: env.reservations.clone(),
+            exit: env.exit,
+            text_base: env.text_base,
+            data_base: env.data_base,
+            labels: env.labels.clone(),
+            label_sections: env.label_sections.clone(),
+            globals: env.globals.clone(),
+            source_map: env.source_map.clone(),
+            harts: harts.cloned(),
+        }
+    }
+
+    /// The machine as it was saved
+    pub fn restore(self) -> Result<(Env, Option<Harts>), SnapshotErr> {
+        let mut env = Env::new();
+        env.registers = self.registers;
+        env.fregisters = self.fregisters;
+        env.pc = self.pc;
+        env.csrs = self.csrs;
+        env.memory = self.memory;
+        env.devices = Devices::restore(self.devices)?;
+        env.syscalls = self
+            .syscalls
+            .map(|(name, state)| syscall::restore(&name, state))
+            .transpose()?;
+        env.semihosting = self
+            .semihosting
+            .map(|state| {
+                serde_json::from_value(state)
+                    .map_err(|e| SnapshotErr::Malformed(format!("semihosting: {}", e)))
+            })
+            .transpose()?;
+        env.reservations = self.reservations;
+        env.exit = self.exit;
+        env.text_base = self.text_base;
+        env.data_base = self.data_base;
+        env.labels = self.labels;
+        env.label_sections = self.label_sections;
+        env.globals = self.globals;
+        env.source_map = self.source_map;
+        Ok((env, self.harts))
+    }
+
+    /// Whether `bytes` are a snapshot rather than a program
+    pub fn is_snapshot(bytes: &[u8]) -> bool {
+        bytes.trim_ascii_start().starts_with(b"{")
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, SnapshotErr> {
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+
+        // Checked first so an old snapshot says so, rather than that it's malformed
+        let Version { version } =
+            serde_json::from_str(json).map_err(|e| SnapshotErr::Malformed(e.to_string()))?;
+        if version != VERSION {
+            return Err(SnapshotErr::Version(version));
+        }
+        serde_json::from_str(json).map_err(|e| SnapshotErr::Malformed(e.to_string()))
+    }
+}
+
+/// Floating point registers as their bits, JSON has no NaNs or infinities
+pub(crate) mod fregisters {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        fregisters: &[f32; 32],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        fregisters.map(f32::to_bits).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[f32; 32], D::Error> {
+        Ok(<[u32; 32]>::deserialize(deserializer)?.map(f32::from_bits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{env::Env, tests::load_program};
+
+    #[test]
+    fn snapshot() {
+        use super::{Snapshot, VERSION};
+        use crate::{
+            device::Devices,
+            err::SnapshotErr,
+            execution::step,
+            hart::{Harts, Schedule},
+        };
+
+        // Two harts racing on a counter, and printing to the UART
+        let input = "li t0 0x10000000\nli t1 20\nloop:\nlw t2 0(t0)\naddi t2 t2 1\nsw t2 0(t0)\n\
+                     li t3 0x20000000\nli t4 0x2e\nsw t4 0(t3)\naddi t1 t1 -1\nbnez t1 loop";
+        let mut env = Env::new();
+        env.devices = Devices::builtin(false);
+        let end = load_program(&mut env, input);
+        env.fregisters[1] = f32::NAN;
+        let mut harts = Harts::new(&mut env, 2, Schedule::Random, 3);
+        for _ in 0..50 {
+            harts.next(&mut env, |pc| pc < end);
+            step(&mut env).unwrap();
+        }
+
+        let json = Snapshot::new(&env, Some(&harts)).to_json();
+        let (mut restored, restored_harts) = Snapshot::from_json(&json).unwrap().restore().unwrap();
+        let mut restored_harts = restored_harts.unwrap();
+        assert_eq!(restored.registers, env.registers);
+        assert!(restored.fregisters[1].is_nan());
+        assert_eq!(restored.pc, env.pc);
+        assert_eq!(restored.get_label("loop"), env.get_label("loop"));
+
+        // Both carry on exactly the same way, schedule included
+        let run = |env: &mut Env, harts: &mut Harts| {
+            while harts.next(env, |pc| pc < end).is_some() {
+                step(env).unwrap();
+            }
+            let uart = env.devices.iter().find(|d| d.name() == "uart").unwrap();
+            (env.memory.read_u32(0x10000000), uart.status())
+        };
+        let (count, uart) = run(&mut env, &mut harts);
+        assert_eq!(uart, Some(format!("uart: {}", ".".repeat(40))));
+        assert_eq!(run(&mut restored, &mut restored_harts), (count, uart));
+
+        assert_eq!(
+            Snapshot::from_json(&json.replacen(
+                &format!("\"version\":{}", VERSION),
+                "\"version\":0",
+                1
+            ))
+            .unwrap_err(),
+            SnapshotErr::Version(0)
+        );
+    }
+}
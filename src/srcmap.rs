@@ -0,0 +1,606 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    elf::{self, Elf},
+    err::ElfErr,
+    parser::Loc,
+};
+
+/// The source position an address was assembled from.
+///
+/// A `line` of 0 marks the end of a run of code, addresses from there on
+/// have no source until the next entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub addr: u32,
+    /// Index into `SourceMap::files`
+    pub file: usize,
+    pub line: usize,
+    /// Starting at 1, 0 when unknown
+    pub column: usize,
+}
+
+/// Which file, line and column each address of a program came from.
+///
+/// Saved as a DWARF `.debug_line` section in ELF files and as JSON next to
+/// anything else, so traces and debuggers can still find the source.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceMap {
+    pub files: Vec<String>,
+    /// Sorted by address
+    pub entries: Vec<Entry>,
+}
+
+impl SourceMap {
+    /// Map the addresses in `locs` to where their tokens start in `source`,
+    /// with the code ending at `end`
+    pub fn from_locs(file: &str, source: &str, locs: &[(u32, Loc)], end: u32) -> Self {
+        let mut map = Self::default();
+        if locs.is_empty() {
+            return map;
+        }
+
+        let file = map.file(file);
+        let mut starts = vec![0];
+        starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        for (addr, loc) in locs {
+            let start = starts.get(loc.line.max(1) - 1).copied().unwrap_or(0);
+            map.insert(Entry {
+                addr: *addr,
+                file,
+                line: loc.line,
+                column: loc.start.saturating_sub(start) + 1,
+            });
+        }
+        map.insert(Entry {
+            addr: end,
+            file,
+            line: 0,
+            column: 0,
+        });
+        map
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Index of `name` in `files`, adding it if needed
+    fn file(&mut self, name: &str) -> usize {
+        match self.files.iter().position(|f| f == name) {
+            Some(i) => i,
+            None => {
+                self.files.push(name.to_string());
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// Add an entry, where an end marker and the start of the next run
+    /// share an address the end goes first
+    pub fn insert(&mut self, entry: Entry) {
+        let key = |e: &Entry| (e.addr, e.line != 0);
+        let i = self.entries.partition_point(|e| key(e) <= key(&entry));
+        self.entries.insert(i, entry);
+    }
+
+    /// File, line and column of the code at `addr`
+    pub fn lookup(&self, addr: u32) -> Option<(&str, usize, usize)> {
+        let i = self.entries.partition_point(|e| e.addr <= addr);
+        let entry = self.entries[..i].last().filter(|e| e.line != 0)?;
+        Some((self.files.get(entry.file)?, entry.line, entry.column))
+    }
+
+    /// `file:line:column` of the code at `addr`
+    pub fn describe(&self, addr: u32) -> Option<String> {
+        let (file, line, column) = self.lookup(addr)?;
+        Some(if column == 0 {
+            format!("{}:{}", file, line)
+        } else {
+            format!("{}:{}:{}", file, line, column)
+        })
+    }
+
+    /// Where the JSON map of an output file goes, `test.bin` -> `test.bin.map.json`
+    pub fn sidecar(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".map.json");
+        PathBuf::from(name)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// The map of an ELF file, empty without a `.debug_line` section
+    pub fn from_elf(elf: &Elf) -> Result<Self, ElfErr> {
+        match elf.section(".debug_line") {
+            Some(section) => Self::from_debug_line(
+                &section.data,
+                elf.section(".debug_line_str").map_or(&[], |s| &s.data),
+            ),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Encode the map as a DWARF 3 line number program.
+    ///
+    /// Also returns the offsets of the `DW_LNE_set_address` operands, which
+    /// need relocating in an object file.
+    pub fn to_debug_line(&self) -> (Vec<u8>, Vec<usize>) {
+        let mut header = vec![
+            1,         // minimum_instruction_length
+            1,         // default_is_stmt
+            LINE_BASE, // line_base
+            LINE_RANGE,
+            OPCODE_BASE,
+        ];
+        header.extend(STANDARD_LENGTHS);
+        header.push(0); // No include directories
+        for file in self.files.iter() {
+            header.extend(file.as_bytes());
+            header.extend([0, 0, 0, 0]); // NUL, directory, time and length
+        }
+        header.push(0);
+
+        let mut program = Vec::new();
+        let mut relocations = Vec::new();
+        // Registers of the state machine, `None` between sequences
+        let mut state: Option<(u32, usize, usize, usize)> = None;
+        for entry in self.entries.iter() {
+            let (addr, file, line, column) = match state {
+                Some(state) => state,
+                None if entry.line == 0 => continue,
+                None => {
+                    program.extend([0, 5, DW_LNE_SET_ADDRESS]);
+                    relocations.push(program.len());
+                    program.extend(entry.addr.to_le_bytes());
+                    (entry.addr, 0, 1, 0)
+                }
+            };
+            if entry.addr != addr {
+                program.push(DW_LNS_ADVANCE_PC);
+                uleb(&mut program, entry.addr.wrapping_sub(addr) as u64);
+            }
+            if entry.line == 0 {
+                program.extend([0, 1, DW_LNE_END_SEQUENCE]);
+                state = None;
+                continue;
+            }
+            if entry.file != file {
+                program.push(DW_LNS_SET_FILE);
+                uleb(&mut program, entry.file as u64 + 1);
+            }
+            if entry.line != line {
+                program.push(DW_LNS_ADVANCE_LINE);
+                sleb(&mut program, entry.line as i64 - line as i64);
+            }
+            if entry.column != column {
+                program.push(DW_LNS_SET_COLUMN);
+                uleb(&mut program, entry.column as u64);
+            }
+            program.push(DW_LNS_COPY);
+            state = Some((entry.addr, entry.file, entry.line, entry.column));
+        }
+        if state.is_some() {
+            program.extend([DW_LNS_ADVANCE_PC, 4, 0, 1, DW_LNE_END_SEQUENCE]);
+        }
+
+        let mut unit = 3u16.to_le_bytes().to_vec();
+        unit.extend((header.len() as u32).to_le_bytes());
+        unit.extend(header);
+        let start = 4 + unit.len();
+        unit.extend(program);
+
+        let mut out = (unit.len() as u32).to_le_bytes().to_vec();
+        out.extend(unit);
+        (out, relocations.into_iter().map(|r| r + start).collect())
+    }
+
+    /// Decode the line number programs of a `.debug_line` section, DWARF 2 to 5.
+    ///
+    /// `line_str` is the `.debug_line_str` section DWARF 5 file names may point into.
+    pub fn from_debug_line(data: &[u8], line_str: &[u8]) -> Result<Self, ElfErr> {
+        let mut map = Self::default();
+        let mut reader = Reader { data, at: 0 };
+
+        while reader.at < data.len() {
+            let length = reader.u32()? as usize;
+            if length == 0xffffffff {
+                return Err(ElfErr::Unsupported("64 bit DWARF".to_string()));
+            }
+            let end = reader.at + length;
+            let version = reader.u16()?;
+            if !(2..=5).contains(&version) {
+                return Err(ElfErr::Unsupported(format!("DWARF version {}", version)));
+            }
+            if version >= 5 {
+                reader.at += 2; // address_size, segment_selector_size
+            }
+            let header_length = reader.u32()? as usize;
+            let program = reader.at + header_length;
+            let min_length = reader.u8()? as u32;
+            if version >= 4 {
+                reader.u8()?; // maximum_operations_per_instruction
+            }
+            reader.u8()?; // default_is_stmt
+            let line_base = reader.u8()? as i8 as i64;
+            let line_range = reader.u8()?.max(1);
+            let opcode_base = reader.u8()?;
+            let lengths = reader
+                .bytes(opcode_base.saturating_sub(1) as usize)?
+                .to_vec();
+
+            // Files of this unit, by their index in the program
+            let mut files = Vec::new();
+            if version >= 5 {
+                let directories = reader.entries(line_str)?;
+                for (path, directory) in reader.entries(line_str)? {
+                    let directory = directories.get(directory).map(|(d, _)| d.as_str());
+                    files.push(map.file(&join(directory, &path)));
+                }
+            } else {
+                let mut directories = Vec::new();
+                loop {
+                    let directory = reader.string()?;
+                    if directory.is_empty() {
+                        break;
+                    }
+                    directories.push(directory);
+                }
+                // Numbered from 1, so 0 is a placeholder
+                files.push(usize::MAX);
+                loop {
+                    let path = reader.string()?;
+                    if path.is_empty() {
+                        break;
+                    }
+                    let directory = reader.uleb()? as usize;
+                    reader.uleb()?;
+                    reader.uleb()?;
+                    let directory = directory
+                        .checked_sub(1)
+                        .and_then(|d| directories.get(d))
+                        .map(String::as_str);
+                    files.push(map.file(&join(directory, &path)));
+                }
+            }
+
+            reader.at = program;
+            let (mut addr, mut file, mut line, mut column) = (0u32, 1, 1i64, 0);
+            let row = |map: &mut Self, addr: u32, file: usize, line: i64, column: u64| {
+                if let Some(&file) = files.get(file).filter(|&&f| f != usize::MAX) {
+                    map.insert(Entry {
+                        addr,
+                        file,
+                        line: line.max(0) as usize,
+                        column: column as usize,
+                    });
+                }
+            };
+            while reader.at < end {
+                match reader.u8()? {
+                    op if op >= opcode_base => {
+                        let adjusted = op - opcode_base;
+                        addr = addr.wrapping_add((adjusted / line_range) as u32 * min_length);
+                        line += line_base + (adjusted % line_range) as i64;
+                        row(&mut map, addr, file, line, column);
+                    }
+                    0 => {
+                        let length = reader.uleb()? as usize;
+                        let next = reader.at + length;
+                        match reader.u8()? {
+                            DW_LNE_END_SEQUENCE => {
+                                row(&mut map, addr, file, 0, 0);
+                                (addr, file, line, column) = (0, 1, 1, 0);
+                            }
+                            DW_LNE_SET_ADDRESS => addr = reader.u32()?,
+                            _ => {}
+                        }
+                        reader.at = next;
+                    }
+                    DW_LNS_COPY => row(&mut map, addr, file, line, column),
+                    DW_LNS_ADVANCE_PC => {
+                        addr = addr.wrapping_add(reader.uleb()? as u32 * min_length)
+                    }
+                    DW_LNS_ADVANCE_LINE => line += reader.sleb()?,
+                    DW_LNS_SET_FILE => file = reader.uleb()? as usize,
+                    DW_LNS_SET_COLUMN => column = reader.uleb()?,
+                    DW_LNS_CONST_ADD_PC => {
+                        addr = addr
+                            .wrapping_add(((255 - opcode_base) / line_range) as u32 * min_length)
+                    }
+                    DW_LNS_FIXED_ADVANCE_PC => addr = addr.wrapping_add(reader.u16()? as u32),
+                    op => {
+                        for _ in 0..lengths.get(op as usize - 1).copied().unwrap_or(0) {
+                            reader.uleb()?;
+                        }
+                    }
+                }
+            }
+            reader.at = end;
+        }
+
+        Ok(map)
+    }
+
+    /// Add the map as the `.debug_line` section of an object, relative to its `text` section
+    pub fn add_to_object(&self, object: &mut Elf, text: usize) {
+        if self.is_empty() {
+            return;
+        }
+
+        let (data, relocations) = self.to_debug_line();
+        let section = object.sections.len();
+        object.sections.push(elf::Section {
+            name: ".debug_line".to_string(),
+            kind: elf::SHT_PROGBITS,
+            flags: 0,
+            addr: 0,
+            align: 1,
+            data,
+        });
+        object.symbols.push(elf::Symbol {
+            name: String::new(),
+            value: 0,
+            size: 0,
+            bind: elf::STB_LOCAL,
+            kind: elf::STT_SECTION,
+            section: elf::Shndx::Index(text),
+        });
+        let symbol = object.symbols.len() - 1;
+        for offset in relocations {
+            let addend = &object.sections[section].data[offset..offset + 4];
+            object.relocations.push(elf::Relocation {
+                section,
+                offset: offset as u32,
+                kind: elf::R_RISCV_32,
+                symbol: Some(symbol),
+                addend: i32::from_le_bytes(addend.try_into().unwrap()),
+            });
+        }
+    }
+}
+
+const LINE_BASE: u8 = -5i8 as u8;
+const LINE_RANGE: u8 = 14;
+const OPCODE_BASE: u8 = 13;
+/// Operand counts of the standard opcodes
+const STANDARD_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+fn join(directory: Option<&str>, path: &str) -> String {
+    match directory {
+        Some(directory) if !path.starts_with('/') && !directory.is_empty() => {
+            format!("{}/{}", directory, path)
+        }
+        _ => path.to_string(),
+    }
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// An attribute of a DWARF 5 file name entry
+enum Value {
+    String(String),
+    Number(u64),
+    Skipped,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], ElfErr> {
+        let bytes = self
+            .data
+            .get(self.at..self.at + len)
+            .ok_or(ElfErr::Truncated)?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ElfErr> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ElfErr> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ElfErr> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn uleb(&mut self) -> Result<u64, ElfErr> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, ElfErr> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    /// NUL terminated string
+    fn string(&mut self) -> Result<String, ElfErr> {
+        let len = self.data[self.at.min(self.data.len())..]
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(ElfErr::Truncated)?;
+        let string = String::from_utf8_lossy(self.bytes(len)?).into_owned();
+        self.at += 1;
+        Ok(string)
+    }
+
+    /// A DWARF 5 directory or file name table, as paths and directory indexes
+    fn entries(&mut self, line_str: &[u8]) -> Result<Vec<(String, usize)>, ElfErr> {
+        let formats = (0..self.u8()?)
+            .map(|_| Ok((self.uleb()?, self.uleb()?)))
+            .collect::<Result<Vec<_>, ElfErr>>()?;
+        let count = self.uleb()?;
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let (mut path, mut directory) = (String::new(), 0);
+            for &(content, form) in formats.iter() {
+                let value = match form {
+                    DW_FORM_STRING => Value::String(self.string()?),
+                    DW_FORM_LINE_STRP => {
+                        let mut strings = Reader {
+                            data: line_str,
+                            at: self.u32()? as usize,
+                        };
+                        Value::String(strings.string()?)
+                    }
+                    DW_FORM_UDATA => Value::Number(self.uleb()?),
+                    DW_FORM_DATA1 => Value::Number(self.u8()? as u64),
+                    DW_FORM_DATA2 => Value::Number(self.u16()? as u64),
+                    DW_FORM_DATA4 => Value::Number(self.u32()? as u64),
+                    DW_FORM_DATA8 => {
+                        Value::Number(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+                    }
+                    // MD5 checksums
+                    DW_FORM_DATA16 => self.bytes(16).map(|_| Value::Skipped)?,
+                    DW_FORM_BLOCK => {
+                        let len = self.uleb()? as usize;
+                        self.bytes(len).map(|_| Value::Skipped)?
+                    }
+                    other => {
+                        return Err(ElfErr::Unsupported(format!(
+                            "DWARF form {:#x} in line table",
+                            other
+                        )))
+                    }
+                };
+                match (content, value) {
+                    (DW_LNCT_PATH, Value::String(string)) => path = string,
+                    (DW_LNCT_DIRECTORY_INDEX, Value::Number(index)) => directory = index as usize,
+                    _ => {}
+                }
+            }
+            entries.push((path, directory));
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+
+    #[test]
+    fn source_map() {
+        use super::SourceMap;
+        use crate::{
+            elf::Elf,
+            linker::{self, Script},
+            object::Object,
+            parser::parse,
+        };
+
+        let assemble = |file: &str, input: &str| {
+            let mut env = Env::new();
+            let tokens = parse(&env, input).unwrap();
+            let elf = Object::assemble(&mut env, tokens)
+                .unwrap()
+                .to_elf_with_source(file, input);
+            Elf::parse(&elf.write()).unwrap()
+        };
+
+        let start = assemble("start.s", ".globl _start\n_start:\n  call func");
+        let func = assemble("func.s", ".globl func\nfunc:\n\n  li a0 0x12345\n  ret");
+
+        let script = Script::parse(linker::DEFAULT_SCRIPT).unwrap();
+        let exe = linker::link(
+            &[("start.o".to_string(), start), ("func.o".to_string(), func)],
+            &script,
+        )
+        .unwrap();
+        let map = SourceMap::from_elf(&Elf::parse(&exe.write()).unwrap()).unwrap();
+
+        // call is 2 words, li of a large value too
+        assert_eq!(map.lookup(4), Some(("start.s", 3, 3)));
+        assert_eq!(map.lookup(8), Some(("func.s", 4, 3)));
+        assert_eq!(map.lookup(12), Some(("func.s", 4, 3)));
+        assert_eq!(map.describe(16).as_deref(), Some("func.s:5:3"));
+        assert_eq!(map.lookup(20), None);
+
+        assert_eq!(SourceMap::from_json(&map.to_json()).unwrap(), map);
+    }
+}
@@ -0,0 +1,989 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    env::Env,
+    err::{MemoryErr, SnapshotErr, StepErr, SyscallErr},
+    hart::splitmix64,
+};
+
+pub(crate) const A0: usize = 10;
+pub(crate) const A1: usize = 11;
+const A7: usize = 17;
+const FA0: usize = 10;
+
+/// The system calls `ecall` makes, the way the environment a program was
+/// written for has them
+pub trait Syscalls: Debug {
+    fn name(&self) -> &str;
+
+    /// Carry out the call `env`'s registers ask for
+    fn ecall(&mut self, env: &mut Env) -> Result<(), StepErr>;
+
+    /// Text showing what the program printed, for the stepper
+    fn status(&self) -> Option<String> {
+        None
+    }
+
+    /// State for a snapshot, `Null` if it can't be restored
+    fn save(&self) -> Value {
+        Value::Null
+    }
+}
+
+/// Which syscalls a program is written for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Abi {
+    #[default]
+    Rars,
+    Venus,
+    /// Linux', for statically linked C programs
+    Linux,
+    /// None at all, `ecall` traps to `mtvec` for the program to handle
+    None,
+}
+
+impl FromStr for Abi {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rars" => Ok(Abi::Rars),
+            "venus" => Ok(Abi::Venus),
+            "linux" => Ok(Abi::Linux),
+            "none" => Ok(Abi::None),
+            _ => Err(format!(
+                "unknown abi '{}', expected rars, venus, linux or none",
+                s
+            )),
+        }
+    }
+}
+
+impl Abi {
+    /// The syscalls, printing to and reading from `console`, with random
+    /// numbers starting from `seed` and files opened in `sandbox`
+    pub fn syscalls(
+        &self,
+        console: Console,
+        seed: u64,
+        sandbox: Option<PathBuf>,
+    ) -> Option<Box<dyn Syscalls>> {
+        match self {
+            Abi::Rars => Some(Box::new(Rars(Runtime::new(console, seed)))),
+            Abi::Venus => Some(Box::new(Venus(Runtime::new(console, seed)))),
+            Abi::Linux => Some(Box::new(Linux::new(console, seed, sandbox))),
+            Abi::None => None,
+        }
+    }
+}
+
+/// Syscalls saved by `Syscalls::save`, recreated by name
+pub fn restore(name: &str, state: Value) -> Result<Box<dyn Syscalls>, SnapshotErr> {
+    let runtime = |state| {
+        serde_json::from_value::<Runtime>(state)
+            .map_err(|e| SnapshotErr::Malformed(format!("syscalls {}: {}", name, e)))
+    };
+    match name {
+        "rars" => Ok(Box::new(Rars(runtime(state)?))),
+        "venus" => Ok(Box::new(Venus(runtime(state)?))),
+        "linux" => Ok(Box::new(serde_json::from_value::<Linux>(state).map_err(
+            |e| SnapshotErr::Malformed(format!("syscalls {}: {}", name, e)),
+        )?)),
+        _ => Err(SnapshotErr::UnknownAbi(name.to_string())),
+    }
+}
+
+/// Where a program's prints go and its reads come from
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Console {
+    /// Everything printed so far
+    pub output: Vec<u8>,
+    /// Typed ahead, read before stdin
+    pub input: VecDeque<u8>,
+    /// Also print to stdout as it happens
+    #[serde(skip)]
+    echo: bool,
+    /// Read lines from stdin once `input` runs out
+    #[serde(skip)]
+    stdin: bool,
+}
+
+impl Console {
+    pub fn new(echo: bool, stdin: bool) -> Self {
+        Self {
+            echo,
+            stdin,
+            ..Self::default()
+        }
+    }
+
+    pub fn print(&mut self, bytes: &[u8]) {
+        self.output.extend(bytes);
+        if self.echo {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(bytes).and_then(|_| stdout.flush());
+        }
+    }
+
+    /// Next byte of input, `None` once there's no more
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if self.input.is_empty() && self.stdin {
+            let mut line = String::new();
+            let _ = io::stdin().lock().read_line(&mut line);
+            self.input.extend(line.bytes());
+        }
+        self.input.pop_front()
+    }
+
+    /// Next line of input without its newline, `None` once there's no more
+    pub fn read_line(&mut self) -> Option<String> {
+        let mut line = Vec::new();
+        loop {
+            match self.read_byte() {
+                Some(b'\n') => break,
+                Some(byte) => line.push(byte),
+                None if line.is_empty() => return None,
+                None => break,
+            }
+        }
+        Some(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    /// The last line printed
+    pub fn status(&self) -> String {
+        let text = String::from_utf8_lossy(&self.output);
+        let last = text
+            .trim_end_matches('\n')
+            .rsplit('\n')
+            .next()
+            .unwrap_or("");
+        format!("console: {}", last.escape_debug())
+    }
+}
+
+/// What the calls do, RARS and Venus only differ in how they're numbered
+/// and which registers hold the arguments
+#[derive(Debug, Clone, Copy)]
+enum Call {
+    PrintInt,
+    PrintFloat,
+    PrintString,
+    PrintChar,
+    PrintHex,
+    PrintBinary,
+    PrintUnsigned,
+    ReadInt,
+    ReadFloat,
+    ReadString,
+    ReadChar,
+    Sbrk,
+    Exit,
+    Exit2,
+    Time,
+    Sleep,
+    RandSeed,
+    RandInt,
+    RandRange,
+    RandFloat,
+}
+
+/// State the calls keep between them
+#[derive(Debug, Serialize, Deserialize)]
+struct Runtime {
+    console: Console,
+    /// End of what sbrk has handed out, once it's been called
+    brk: Option<u32>,
+    /// Random streams the program didn't seed start from this
+    seed: u64,
+    /// State of each random stream, by number
+    streams: HashMap<u32, u64>,
+}
+
+impl Runtime {
+    fn new(console: Console, seed: u64) -> Self {
+        Self {
+            console,
+            brk: None,
+            seed,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Carry out `call` with its arguments in the registers from `args` on,
+    /// results go in a0, a1 and fa0
+    fn call(&mut self, env: &mut Env, call: Call, args: usize) -> Result<(), StepErr> {
+        let arg = |i: usize| env.get_register(args + i);
+        let (first, second) = (arg(0), arg(1));
+        match call {
+            Call::PrintInt => self.console.print((first as i32).to_string().as_bytes()),
+            Call::PrintFloat => {
+                let text = format!("{:?}", env.get_fregister(FA0));
+                self.console.print(text.as_bytes())
+            }
+            Call::PrintString => {
+                let text = read_string(env, first)?;
+                self.console.print(&text)
+            }
+            Call::PrintChar => self.console.print(&[first as u8]),
+            Call::PrintHex => self.console.print(format!("0x{:08x}", first).as_bytes()),
+            Call::PrintBinary => self.console.print(format!("{:032b}", first).as_bytes()),
+            Call::PrintUnsigned => self.console.print(first.to_string().as_bytes()),
+            Call::ReadInt => {
+                let line = self.read_line()?;
+                let value = line.trim().parse::<i32>().map_err(|_| {
+                    SyscallErr::Input(format!("'{}' isn't an integer", line.trim()))
+                })?;
+                env.set_register(A0, value as u32);
+            }
+            Call::ReadFloat => {
+                let line = self.read_line()?;
+                let value = line
+                    .trim()
+                    .parse::<f32>()
+                    .map_err(|_| SyscallErr::Input(format!("'{}' isn't a number", line.trim())))?;
+                env.set_fregister(FA0, value);
+            }
+            // Like fgets, up to `second - 1` bytes and the newline if it fits
+            Call::ReadString => {
+                let mut len = 0;
+                while len + 1 < second {
+                    let Some(byte) = self.console.read_byte() else {
+                        break;
+                    };
+                    env.store(first.wrapping_add(len), 1, byte as u32)?;
+                    len += 1;
+                    if byte == b'\n' {
+                        break;
+                    }
+                }
+                if second > 0 {
+                    env.store(first.wrapping_add(len), 1, 0)?;
+                }
+            }
+            Call::ReadChar => {
+                let byte = self
+                    .console
+                    .read_byte()
+                    .ok_or_else(|| SyscallErr::Input("there's nothing left to read".to_string()))?;
+                env.set_register(A0, byte as u32);
+            }
+            Call::Sbrk => {
+                let old = self.sbrk(env, first as i32);
+                env.set_register(A0, old);
+            }
+            Call::Exit => env.exit = Some(0),
+            Call::Exit2 => env.exit = Some(first),
+            Call::Time => {
+                let ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |t| t.as_millis() as u64);
+                env.set_register(A0, ms as u32);
+                env.set_register(A1, (ms >> 32) as u32);
+            }
+            Call::Sleep => std::thread::sleep(Duration::from_millis(first as u64)),
+            Call::RandSeed => {
+                self.streams.insert(first, second as u64);
+            }
+            Call::RandInt => {
+                let value = self.random(first);
+                env.set_register(A0, value as u32);
+            }
+            // An upper bound of 0 always gives 0
+            Call::RandRange => {
+                let value = self.random(first).checked_rem(second as u64).unwrap_or(0);
+                env.set_register(A0, value as u32);
+            }
+            Call::RandFloat => {
+                let value = (self.random(first) >> 40) as f32 / (1 << 24) as f32;
+                env.set_fregister(FA0, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> Result<String, SyscallErr> {
+        self.console
+            .read_line()
+            .ok_or_else(|| SyscallErr::Input("there's nothing left to read".to_string()))
+    }
+
+    /// Move the end of the heap by `increment`, returning where it was, or
+    /// -1 when that would leave the heap
+    fn sbrk(&mut self, env: &Env, increment: i32) -> u32 {
+        let Some(heap) = env.memory.heap() else {
+            return u32::MAX;
+        };
+        let brk = *self.brk.get_or_insert(heap.start);
+        match brk.checked_add_signed(increment) {
+            Some(new) if (heap.start..=heap.end).contains(&new) => {
+                self.brk = Some(new);
+                brk
+            }
+            _ => u32::MAX,
+        }
+    }
+
+    /// Next number of random stream `id`
+    fn random(&mut self, id: u32) -> u64 {
+        let seed = self.seed;
+        let state = self.streams.entry(id).or_insert_with(|| seed ^ id as u64);
+        splitmix64(state)
+    }
+}
+
+/// The NUL terminated string at `addr`
+pub(crate) fn read_string(env: &mut Env, addr: u32) -> Result<Vec<u8>, MemoryErr> {
+    let mut bytes = Vec::new();
+    loop {
+        match env.load(addr.wrapping_add(bytes.len() as u32), 1)? {
+            0 => return Ok(bytes),
+            byte => bytes.push(byte as u8),
+        }
+    }
+}
+
+/// RARS' syscalls, the number in a7 and the arguments from a0
+#[derive(Debug)]
+pub struct Rars(Runtime);
+
+impl Syscalls for Rars {
+    fn name(&self) -> &str {
+        "rars"
+    }
+
+    fn ecall(&mut self, env: &mut Env) -> Result<(), StepErr> {
+        let call = match env.get_register(A7) {
+            1 => Call::PrintInt,
+            2 => Call::PrintFloat,
+            4 => Call::PrintString,
+            5 => Call::ReadInt,
+            6 => Call::ReadFloat,
+            8 => Call::ReadString,
+            9 => Call::Sbrk,
+            10 => Call::Exit,
+            11 => Call::PrintChar,
+            12 => Call::ReadChar,
+            30 => Call::Time,
+            32 => Call::Sleep,
+            34 => Call::PrintHex,
+            35 => Call::PrintBinary,
+            36 => Call::PrintUnsigned,
+            40 => Call::RandSeed,
+            41 => Call::RandInt,
+            42 => Call::RandRange,
+            43 => Call::RandFloat,
+            93 => Call::Exit2,
+            number => return Err(SyscallErr::Unknown(number).into()),
+        };
+        self.0.call(env, call, A0)
+    }
+
+    fn status(&self) -> Option<String> {
+        Some(self.0.console.status())
+    }
+
+    fn save(&self) -> Value {
+        serde_json::to_value(&self.0).unwrap()
+    }
+}
+
+/// Venus' syscalls, the number in a0 and the arguments from a1.
+///
+/// Calls Venus doesn't have are there with RARS' numbers.
+#[derive(Debug)]
+pub struct Venus(Runtime);
+
+impl Syscalls for Venus {
+    fn name(&self) -> &str {
+        "venus"
+    }
+
+    fn ecall(&mut self, env: &mut Env) -> Result<(), StepErr> {
+        let call = match env.get_register(A0) {
+            1 => Call::PrintInt,
+            2 => Call::PrintFloat,
+            4 => Call::PrintString,
+            5 => Call::ReadInt,
+            6 => Call::ReadFloat,
+            8 => Call::ReadString,
+            9 => Call::Sbrk,
+            10 => Call::Exit,
+            11 => Call::PrintChar,
+            12 => Call::ReadChar,
+            17 => Call::Exit2,
+            30 => Call::Time,
+            32 => Call::Sleep,
+            34 => Call::PrintHex,
+            35 => Call::PrintBinary,
+            36 => Call::PrintUnsigned,
+            40 => Call::RandSeed,
+            41 => Call::RandInt,
+            42 => Call::RandRange,
+            43 => Call::RandFloat,
+            number => return Err(SyscallErr::Unknown(number).into()),
+        };
+        self.0.call(env, call, A1)
+    }
+
+    fn status(&self) -> Option<String> {
+        Some(self.0.console.status())
+    }
+
+    fn save(&self) -> Value {
+        serde_json::to_value(&self.0).unwrap()
+    }
+}
+
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+pub(crate) const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EEXIST: i32 = 17;
+pub(crate) const EINVAL: i32 = 22;
+const ESPIPE: i32 = 29;
+const ENOSYS: i32 = 38;
+
+/// `dirfd` meaning the working directory, the only one there is
+const AT_FDCWD: i32 = -100;
+const O_ACCMODE: u32 = 3;
+pub(crate) const O_WRONLY: u32 = 1;
+pub(crate) const O_RDWR: u32 = 2;
+pub(crate) const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+pub(crate) const O_TRUNC: u32 = 0o1000;
+pub(crate) const O_APPEND: u32 = 0o2000;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Most a single read or write copies, however much the program asks for
+pub(crate) const MAX_READ: u32 = 1 << 20;
+
+/// Linux' syscalls, the number in a7, the arguments from a0 and the result,
+/// or minus an errno, in a0.
+///
+/// Files are opened in the sandbox, which is `/` and the working directory as
+/// far as the program can tell. Without one only stdin, stdout and stderr,
+/// which are the console, are there to use.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Linux {
+    console: Console,
+    sandbox: Option<PathBuf>,
+    /// The program break, once it's been asked for
+    brk: Option<u32>,
+    /// splitmix64 state getrandom reads from
+    random: u64,
+    /// Files the program has open, by descriptor
+    files: BTreeMap<u32, OpenFile>,
+}
+
+/// A file the program opened, that can be opened again where it was after a
+/// snapshot
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct OpenFile {
+    path: PathBuf,
+    read: bool,
+    write: bool,
+    append: bool,
+    /// Where the next read or write happens
+    position: u64,
+    #[serde(skip)]
+    handle: Option<File>,
+}
+
+impl OpenFile {
+    /// Open `path` the way Linux' `O_` `flags` ask for
+    pub(crate) fn open(path: PathBuf, flags: u32) -> io::Result<Self> {
+        let read = flags & O_ACCMODE != O_WRONLY;
+        let write = flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR;
+        let append = flags & O_APPEND != 0;
+        let create = flags & O_CREAT != 0;
+        let exclusive = flags & O_EXCL != 0;
+        // Rust only creates files it opens for writing, Linux creates them
+        // for reading too
+        if create && !write && !append {
+            OpenOptions::new()
+                .write(true)
+                .create(!exclusive)
+                .create_new(exclusive)
+                .open(&path)?;
+        }
+        let create = create && (write || append);
+        let handle = OpenOptions::new()
+            .read(read)
+            .write(write && !append)
+            .append(append)
+            .create(create && !exclusive)
+            .create_new(create && exclusive)
+            .truncate(flags & O_TRUNC != 0)
+            .open(&path)?;
+        Ok(Self {
+            path,
+            read,
+            write,
+            append,
+            position: 0,
+            handle: Some(handle),
+        })
+    }
+
+    fn handle(&mut self) -> io::Result<&mut File> {
+        if self.handle.is_none() {
+            let mut file = OpenOptions::new()
+                .read(self.read)
+                .write(self.write)
+                .append(self.append)
+                .open(&self.path)?;
+            file.seek(SeekFrom::Start(self.position))?;
+            self.handle = Some(file);
+        }
+        Ok(self.handle.as_mut().unwrap())
+    }
+
+    /// Do `f` with the file, keeping track of where it leaves it
+    pub(crate) fn with<T>(&mut self, f: impl FnOnce(&mut File) -> io::Result<T>) -> io::Result<T> {
+        let file = self.handle()?;
+        let result = f(file)?;
+        self.position = file.stream_position()?;
+        Ok(result)
+    }
+}
+
+/// The errno closest to `err`
+pub(crate) fn errno(err: &io::Error) -> i32 {
+    match err.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        io::ErrorKind::NotSeekable => ESPIPE,
+        _ => EIO,
+    }
+}
+
+/// What a call returns in a0, minus the errno if it failed
+fn result(value: Result<u32, i32>) -> u32 {
+    value.unwrap_or_else(|errno| errno.wrapping_neg() as u32)
+}
+
+impl Linux {
+    pub fn new(console: Console, seed: u64, sandbox: Option<PathBuf>) -> Self {
+        Self {
+            console,
+            sandbox,
+            brk: None,
+            random: seed,
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Where `path` is on the host, as long as it's inside the sandbox
+    fn resolve(&self, path: &[u8]) -> Result<PathBuf, i32> {
+        let root = self
+            .sandbox
+            .as_ref()
+            .and_then(|root| root.canonicalize().ok())
+            .ok_or(EACCES)?;
+        let path = std::str::from_utf8(path).map_err(|_| ENOENT)?;
+        // `..` stops at the root, like it does at `/`
+        let mut inside = PathBuf::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => inside.push(name),
+                Component::ParentDir => {
+                    inside.pop();
+                }
+                _ => {}
+            }
+        }
+        let full = root.join(inside);
+        // Symbolic links could still lead out of it
+        let real = match full.canonicalize() {
+            Ok(real) => real,
+            // A link to somewhere that doesn't exist yet, creating the file
+            // would follow it
+            Err(_) if full.symlink_metadata().is_ok() => return Err(EACCES),
+            Err(_) => match full.parent().map(Path::canonicalize) {
+                Some(Ok(parent)) => parent,
+                _ => return Err(ENOENT),
+            },
+        };
+        if real.starts_with(&root) {
+            Ok(full)
+        } else {
+            Err(EACCES)
+        }
+    }
+
+    fn openat(
+        &mut self,
+        env: &mut Env,
+        dirfd: i32,
+        path: u32,
+        flags: u32,
+    ) -> Result<u32, MemoryErr> {
+        let path = read_string(env, path)?;
+        if dirfd != AT_FDCWD && path.first() != Some(&b'/') {
+            return Ok(result(Err(EBADF)));
+        }
+        let path = match self.resolve(&path) {
+            Ok(path) => path,
+            Err(errno) => return Ok(result(Err(errno))),
+        };
+        Ok(result(match OpenFile::open(path, flags) {
+            Ok(file) => {
+                let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap();
+                self.files.insert(fd, file);
+                Ok(fd)
+            }
+            Err(err) => Err(errno(&err)),
+        }))
+    }
+
+    fn read(&mut self, env: &mut Env, fd: u32, buf: u32, count: u32) -> Result<u32, MemoryErr> {
+        let count = count.min(MAX_READ);
+        let bytes = match fd {
+            // Up to the end of the line, like a terminal
+            0 => {
+                let mut bytes = Vec::new();
+                while bytes.len() < count as usize && bytes.last() != Some(&b'\n') {
+                    match self.console.read_byte() {
+                        Some(byte) => bytes.push(byte),
+                        None => break,
+                    }
+                }
+                bytes
+            }
+            _ => {
+                let Some(file) = self.files.get_mut(&fd) else {
+                    return Ok(result(Err(EBADF)));
+                };
+                let mut bytes = vec![0; count as usize];
+                match file.with(|f| f.read(&mut bytes)) {
+                    Ok(len) => bytes.truncate(len),
+                    Err(err) => return Ok(result(Err(errno(&err)))),
+                }
+                bytes
+            }
+        };
+        for (i, byte) in bytes.iter().enumerate() {
+            env.store(buf.wrapping_add(i as u32), 1, *byte as u32)?;
+        }
+        Ok(bytes.len() as u32)
+    }
+
+    fn write(&mut self, env: &mut Env, fd: u32, buf: u32, count: u32) -> Result<u32, MemoryErr> {
+        let count = count.min(MAX_READ);
+        let bytes = (0..count)
+            .map(|i| env.load(buf.wrapping_add(i), 1).map(|byte| byte as u8))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(result(match fd {
+            1 | 2 => {
+                self.console.print(&bytes);
+                Ok(count)
+            }
+            _ => match self.files.get_mut(&fd) {
+                Some(file) => file
+                    .with(|f| f.write_all(&bytes))
+                    .map(|_| count)
+                    .map_err(|err| errno(&err)),
+                None => Err(EBADF),
+            },
+        }))
+    }
+
+    /// newlib's lseek, a 32 bit offset rather than llseek's two halves
+    fn lseek(&mut self, fd: u32, offset: i32, whence: u32) -> Result<u32, i32> {
+        let Some(file) = self.files.get_mut(&fd) else {
+            return Err(if fd <= 2 { ESPIPE } else { EBADF });
+        };
+        let to = match whence {
+            0 => SeekFrom::Start(u64::try_from(offset).map_err(|_| EINVAL)?),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(EINVAL),
+        };
+        let position = file.with(|f| f.seek(to)).map_err(|err| errno(&err))?;
+        u32::try_from(position).map_err(|_| EINVAL)
+    }
+
+    /// Fill in a `struct stat` the way newlib lays it out
+    fn fstat(&mut self, env: &mut Env, fd: u32, buf: u32) -> Result<u32, MemoryErr> {
+        let (mode, size, block) = match fd {
+            0..=2 => (S_IFCHR | 0o620, 0, 1024),
+            _ => {
+                let Some(file) = self.files.get_mut(&fd) else {
+                    return Ok(result(Err(EBADF)));
+                };
+                match file.handle().and_then(|f| f.metadata()) {
+                    Ok(metadata) => {
+                        let kind = if metadata.is_dir() {
+                            S_IFDIR | 0o111
+                        } else {
+                            S_IFREG
+                        };
+                        let access = if metadata.permissions().readonly() {
+                            0o444
+                        } else {
+                            0o644
+                        };
+                        (kind | access, metadata.len(), 4096)
+                    }
+                    Err(err) => return Ok(result(Err(errno(&err)))),
+                }
+            }
+        };
+        for offset in (0..128).step_by(4) {
+            env.store(buf.wrapping_add(offset), 4, 0)?;
+        }
+        let fields = [
+            (16, mode as u64),
+            (20, 1),
+            (48, size),
+            (56, block),
+            (64, size.div_ceil(512)),
+        ];
+        for (offset, value) in fields {
+            env.store(buf.wrapping_add(offset), 4, value as u32)?;
+            if offset == 48 || offset == 64 {
+                env.store(buf.wrapping_add(offset + 4), 4, (value >> 32) as u32)?;
+            }
+        }
+        Ok(0)
+    }
+
+    /// Move the program break to `addr` if it's in the heap, returning where
+    /// it ends up. 0 asks where it is
+    fn brk(&mut self, env: &Env, addr: u32) -> u32 {
+        let Some(heap) = env.memory.heap() else {
+            return 0;
+        };
+        let brk = self.brk.get_or_insert(heap.start);
+        if (heap.start..=heap.end).contains(&addr) {
+            *brk = addr;
+        }
+        *brk
+    }
+
+    /// A 64 bit `tv_sec` and then `tv_nsec`, the way time64 and newlib have it
+    fn clock_gettime(&mut self, env: &mut Env, buf: u32) -> Result<u32, MemoryErr> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        env.store(buf, 4, now.as_secs() as u32)?;
+        env.store(buf.wrapping_add(4), 4, (now.as_secs() >> 32) as u32)?;
+        env.store(buf.wrapping_add(8), 4, now.subsec_nanos())?;
+        env.store(buf.wrapping_add(12), 4, 0)?;
+        Ok(0)
+    }
+
+    /// Bytes from the seeded generator, so the same seed gives the same bytes
+    fn getrandom(&mut self, env: &mut Env, buf: u32, len: u32) -> Result<u32, MemoryErr> {
+        let len = len.min(MAX_READ);
+        let mut bytes = Vec::new();
+        while bytes.len() < len as usize {
+            bytes.extend(splitmix64(&mut self.random).to_le_bytes());
+        }
+        for (i, byte) in bytes.into_iter().take(len as usize).enumerate() {
+            env.store(buf.wrapping_add(i as u32), 1, byte as u32)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Syscalls for Linux {
+    fn name(&self) -> &str {
+        "linux"
+    }
+
+    fn ecall(&mut self, env: &mut Env) -> Result<(), StepErr> {
+        let arg = |i: usize| env.get_register(A0 + i);
+        let (first, second, third) = (arg(0), arg(1), arg(2));
+        let value = match env.get_register(A7) {
+            56 => self.openat(env, first as i32, second, third)?,
+            57 => result(match self.files.remove(&first) {
+                Some(_) => Ok(0),
+                None if first <= 2 => Ok(0),
+                None => Err(EBADF),
+            }),
+            62 => result(self.lseek(first, second as i32, third)),
+            63 => self.read(env, first, second, third)?,
+            64 => self.write(env, first, second, third)?,
+            80 => self.fstat(env, first, second)?,
+            93 | 94 => {
+                env.exit = Some(first);
+                return Ok(());
+            }
+            // clock_gettime, and clock_gettime64 for programs built with a 64 bit time_t
+            113 | 403 => self.clock_gettime(env, second)?,
+            214 => self.brk(env, first),
+            278 => self.getrandom(env, first, second)?,
+            _ => result(Err(ENOSYS)),
+        };
+        env.set_register(A0, value);
+        Ok(())
+    }
+
+    fn status(&self) -> Option<String> {
+        Some(self.console.status())
+    }
+
+    fn save(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        env::Env,
+        tests::{load_program, step_to_exit},
+    };
+
+    #[test]
+    fn syscalls() {
+        use super::{Abi, Console};
+        use crate::{
+            csr,
+            err::{StepErr, SyscallErr},
+            memory::Permissions,
+        };
+
+        let run = |input: &str, abi: Abi, typed: &str| {
+            let mut env = Env::new();
+            let mut console = Console::new(false, false);
+            console.input.extend(typed.bytes());
+            env.syscalls = abi.syscalls(console, 1, None);
+            env.memory.map_heap_and_stack(0x10001000);
+            env.memory.map(".text", 0, 0x1000, Permissions::RX);
+            load_program(&mut env, input);
+            let result = step_to_exit(&mut env, 100);
+            (env, result)
+        };
+
+        // Read a number and a line, print them back, with a bit of heap in between
+        let rars = "li a7 5\necall\naddi s0 a0 1\n\
+                    li a7 9\nli a0 16\necall\nmv s1 a0\nli a7 9\nli a0 16\necall\nsub s2 a0 s1\n\
+                    li a7 8\nmv a0 s1\nli a1 16\necall\n\
+                    li a7 1\nmv a0 s0\necall\nli a7 11\nli a0 32\necall\n\
+                    li a7 4\nmv a0 s1\necall\nli a7 93\nli a0 3\necall";
+        let (env, result) = run(rars, Abi::Rars, "41\nhello\n");
+        assert_eq!(result, Ok(()));
+        assert_eq!(env.exit, Some(3));
+        assert_eq!(env.get_register(18), 16);
+        assert_eq!(
+            env.syscalls.unwrap().status(),
+            Some("console: 42 hello".to_string())
+        );
+        let (_, result) = run("li a7 5\necall", Abi::Rars, "forty\n");
+        assert!(matches!(
+            result,
+            Err(StepErr::Syscall(SyscallErr::Input(_)))
+        ));
+        let (_, result) = run("li a7 1000\necall", Abi::Rars, "");
+        assert_eq!(result, Err(StepErr::Syscall(SyscallErr::Unknown(1000))));
+
+        // Venus takes the number in a0
+        let venus = "li a0 1\nli a1 -5\necall\nli a0 17\nli a1 4\necall";
+        let (env, _) = run(venus, Abi::Venus, "");
+        assert_eq!(env.exit, Some(4));
+        assert_eq!(
+            env.syscalls.unwrap().status(),
+            Some("console: -5".to_string())
+        );
+
+        // Without syscalls it's an exception for the program to handle
+        let (env, _) = run(
+            "la t0 handler\ncsrw mtvec t0\necall\nhandler:\nj handler",
+            Abi::None,
+            "",
+        );
+        assert_eq!(env.csrs.mcause, csr::MACHINE_ECALL);
+        assert_eq!(env.csrs.mepc, 12);
+        assert_eq!(env.pc, 16);
+    }
+
+    #[test]
+    fn linux_syscalls() {
+        use super::{Abi, Console, OpenFile, O_CREAT};
+        use crate::memory::Permissions;
+
+        let run = |input: &str, sandbox: Option<std::path::PathBuf>| {
+            let mut env = Env::new();
+            env.syscalls = Abi::Linux.syscalls(Console::new(false, false), 1, sandbox);
+            env.memory.map_heap_and_stack(0x10001000);
+            env.memory.map(".text", 0, 0x1000, Permissions::RX);
+            env.memory.write_bytes(0x10001800, b"/../out.txt\0");
+            env.memory.write_bytes(0x10001810, b"data");
+            load_program(&mut env, input);
+            step_to_exit(&mut env, 200).unwrap();
+            env
+        };
+
+        let sandbox = std::env::temp_dir().join(format!("rizz-v-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(&sandbox).unwrap();
+        // Write a file, read it back, stat it and seek in it, then print to stdout.
+        // `..` can't climb out of the sandbox
+        let program = "li a0 -100\nli a1 0x10001800\nli a2 0x241\nli a7 56\necall\nmv s2 a0\n\
+                       li a1 0x10001810\nli a2 4\nli a7 64\necall\nmv a0 s2\nli a7 57\necall\n\
+                       li a0 -100\nli a1 0x10001800\nli a2 0\nli a7 56\necall\nmv s2 a0\n\
+                       li a1 0x10001900\nli a2 16\nli a7 63\necall\nmv s3 a0\n\
+                       mv a0 s2\nli a1 0x10001a00\nli a7 80\necall\n\
+                       mv a0 s2\nli a1 -2\nli a2 2\nli a7 62\necall\nmv s4 a0\n\
+                       li a0 1\nli a1 0x10001900\nli a2 4\nli a7 64\necall\n\
+                       li a0 0\nli a7 214\necall\nmv s5 a0\n\
+                       li a7 1000\necall\nmv s6 a0\nli a0 5\nli a7 94\necall";
+        let mut env = run(program, Some(sandbox.clone()));
+        let written = std::fs::read(sandbox.join("out.txt"));
+        // Read-only opens create the file too
+        let created = OpenFile::open(sandbox.join("new.txt"), O_CREAT).map(|_| ());
+        let exists = sandbox.join("new.txt").exists();
+        std::fs::remove_dir_all(&sandbox).unwrap();
+        assert!(created.is_ok() && exists);
+        assert_eq!(written.unwrap(), b"data");
+        assert_eq!(env.exit, Some(5));
+        assert_eq!(env.get_register(19), 4);
+        assert_eq!(env.load(0x10001a00 + 48, 4), Ok(4));
+        assert_eq!(env.load(0x10001a00 + 16, 4).unwrap() & 0o170000, 0o100000);
+        assert_eq!(env.get_register(20), 2);
+        assert_eq!(env.get_register(21), 0x10001000);
+        assert_eq!(env.get_register(22) as i32, -38);
+        assert_eq!(
+            env.syscalls.unwrap().status(),
+            Some("console: data".to_string())
+        );
+
+        let open =
+            "li a0 -100\nli a1 0x10001800\nli a2 0x241\nli a7 56\necall\nmv s2 a0\nli a7 93\necall";
+        // Nor can a link that points out of it and doesn't lead anywhere yet
+        #[cfg(unix)]
+        {
+            let outside =
+                std::env::temp_dir().join(format!("rizz-v-outside-{}", std::process::id()));
+            std::fs::create_dir_all(&sandbox).unwrap();
+            std::os::unix::fs::symlink(&outside, sandbox.join("out.txt")).unwrap();
+            let env = run(open, Some(sandbox.clone()));
+            std::fs::remove_dir_all(&sandbox).unwrap();
+            let escaped = outside.exists();
+            let _ = std::fs::remove_file(&outside);
+            assert_eq!(env.get_register(18) as i32, -13);
+            assert!(!escaped);
+        }
+
+        // No files at all without a sandbox
+        let env = run(open, None);
+        assert_eq!(env.get_register(18) as i32, -13);
+    }
+}
@@ -2,9 +2,54 @@
 /// Test values come from Ripes
 use crate::{
     env::Env,
+    err::StepErr,
+    execution::{run, step, Stop},
+    hart::{Harts, Schedule},
     instructions::{get_instruction, handle_pseudo, with},
+    parser::{parse, Token},
 };
 
+#[cfg(test)]
+/// Assemble `input` into memory, returning the address after its last
+/// instruction
+pub(crate) fn load_program(env: &mut Env, input: &str) -> u32 {
+    let tokens = parse(env, input).unwrap();
+    let mut end = 0;
+    for (token, loc) in env.handle_mem_offsets(tokens).unwrap() {
+        let bytes = match token {
+            Token::Op(..) => {
+                let words = env.assemble_op((token, loc)).unwrap();
+                end = (loc.mem_offset + 4 * words.len()) as u32;
+                words.iter().flat_map(|w| w.to_le_bytes()).collect()
+            }
+            Token::Directive(..) => env.assemble_directive((token, loc)).unwrap(),
+            _ => continue,
+        };
+        env.memory.write_bytes(loc.mem_offset as u32, &bytes);
+    }
+    end
+}
+
+#[cfg(test)]
+/// Step until the program exits, at most `limit` times, stopping at the first
+/// error
+pub(crate) fn step_to_exit(env: &mut Env, limit: usize) -> Result<(), StepErr> {
+    for _ in 0..limit {
+        if env.exit.is_some() {
+            break;
+        }
+        step(env)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+/// Run the program on a single hart, with the code ending at `end`
+pub(crate) fn run_program(env: &mut Env, limit: Option<u64>, end: u32) -> (u64, Stop) {
+    let mut harts = Harts::new(env, 1, Schedule::RoundRobin, 0);
+    run(env, &mut harts, limit, |pc| pc < end)
+}
+
 #[test]
 fn nop() {
     #[rustfmt::skip]
@@ -187,3 +232,73 @@ fn beq() {
         0b00000000101101010000001001100011
     );
 }
+
+#[test]
+fn layout() {
+    use crate::parser::parse;
+
+    let mut env = Env::new();
+
+    // li a0 0x12345 -> lui + addi, so `end` sits after three words
+    let tokens = parse(&env, "j end\nli a0 0x12345\nend:\nnop").unwrap();
+    let tokens = env.handle_mem_offsets(tokens).unwrap();
+    assert_eq!(env.get_label("end"), Some(12));
+
+    // jal x0, 12
+    assert_eq!(
+        env.assemble_op(tokens[0].clone()).unwrap(),
+        vec![0b00000000110000000000000001101111]
+    );
+}
+
+#[test]
+fn far_branch() {
+    use crate::parser::parse;
+
+    let mut env = Env::new();
+
+    // 1100 nops puts `end` out of reach of a single beq
+    let input = format!("beqz a0 end\n{}end:", "nop\n".repeat(1100));
+    let tokens = env
+        .handle_mem_offsets(parse(&env, &input).unwrap())
+        .unwrap();
+    assert_eq!(env.get_label("end"), Some(8 + 1100 * 4));
+
+    // bne a0, x0, 8; jal x0, 4404
+    assert_eq!(
+        env.assemble_op(tokens[0].clone()).unwrap(),
+        vec![
+            0b00000000000001010001010001100011,
+            0b00010011010000000001000001101111
+        ]
+    );
+}
+
+#[test]
+fn illegal_instruction() {
+    use crate::csr;
+
+    let run = |input: &str| {
+        let mut env = Env::new();
+        load_program(&mut env, input);
+        let (_, stop) = run_program(&mut env, Some(100), 0x100);
+        (env, stop)
+    };
+
+    // The handler gets the word in mtval
+    let (env, stop) = run("la t0 handler\ncsrw mtvec t0\n.word 0xffffffff\nhandler:\n\
+         csrr a0 mcause\ncsrr a1 mtval\nli a7 93\necall");
+    assert_eq!(stop, Stop::Exit(csr::ILLEGAL_INSTRUCTION));
+    assert_eq!(env.get_register(11), 0xffffffff);
+    assert_eq!(env.csrs.mepc, 12);
+
+    let (_, stop) = run("nop\n.word 0xffffffff");
+    assert_eq!(stop, Stop::Exception(csr::ILLEGAL_INSTRUCTION));
+
+    // A handler at the very start of the code is still one
+    let (_, stop) = run(
+        "bnez s1 trapped\nli s1 1\ncsrw mtvec zero\n.word 0xffffffff\n\
+         trapped:\ncsrr a0 mcause\nli a7 93\necall",
+    );
+    assert_eq!(stop, Stop::Exit(csr::ILLEGAL_INSTRUCTION));
+}
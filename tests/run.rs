@@ -0,0 +1,60 @@
+use std::process::{Command, Output};
+
+/// `rizz-v run` on `source`, with `args` before the file
+fn run(name: &str, source: &str, args: &[&str]) -> Output {
+    let path = std::env::temp_dir().join(format!("rizz-v-run-{}-{}.s", name, std::process::id()));
+    std::fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rizz-v"))
+        .arg("run")
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn exit_statuses() {
+    let exit = "li a0 3\nli a7 93\necall";
+    let output = run("exit", exit, &[]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("exited with code 3 after 3 instructions"));
+
+    let output = run("limit", "loop:\nj loop", &["--limit", "100"]);
+    assert_eq!(output.status.code(), Some(124));
+    assert!(stderr(&output).contains("stopped at the instruction limit after 100"));
+
+    let output = run("fault", "li t0 0x7ffffff0\nlw a0 0(t0)", &[]);
+    assert_eq!(output.status.code(), Some(125));
+    assert!(stderr(&output).contains("load fault at 0x7ffffff0"));
+
+    let output = run("trap", "ebreak", &[]);
+    assert_eq!(output.status.code(), Some(125));
+    assert!(stderr(&output).contains("unhandled breakpoint"));
+
+    // A syscall that can't be carried out isn't a fault
+    let output = run("syscall", "li a7 1000\necall", &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("unknown syscall 1000"));
+    assert!(!stderr(&output).contains("fault"));
+
+    // Nothing runs if any of it didn't assemble
+    let output = run("broken", "addi a0 a0 nowhere\nli a7 93\necall", &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!stderr(&output).contains("exited"));
+
+    // Quiet only leaves what the program printed
+    let output = run(
+        "quiet",
+        &format!("li a0 65\nli a7 11\necall\n{}", exit),
+        &["-q"],
+    );
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"A");
+    assert_eq!(stderr(&output), "");
+}